@@ -1,3 +1,7 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use binary_io::uuid::UUID;
+use net::chat::Roster;
 use pokemonsms_core::resource::ResourceLocation;
 use text::TextComponent;
 
@@ -9,3 +13,69 @@ pub struct Player {
     name: TextComponent,
     pc_sprite: ResourceLocation,
 }
+
+impl Player {
+    pub fn new(name: TextComponent, pc_sprite: ResourceLocation) -> Self {
+        Self { name, pc_sprite }
+    }
+
+    // The name is styled, and is shown as-is wherever the player is named, including chat
+    pub fn name(&self) -> &TextComponent {
+        &self.name
+    }
+
+    pub fn pc_sprite(&self) -> &ResourceLocation {
+        &self.pc_sprite
+    }
+}
+
+// The players known to a server, by UUID
+#[derive(Debug, Default)]
+pub struct PlayerList {
+    players: RwLock<HashMap<UUID, Player>>,
+}
+
+impl PlayerList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, id: UUID, player: Player) -> Option<Player> {
+        self.players.write().unwrap().insert(id, player)
+    }
+
+    pub fn remove(&self, id: UUID) -> Option<Player> {
+        self.players.write().unwrap().remove(&id)
+    }
+
+    pub fn get(&self, id: UUID) -> Option<Player> {
+        self.players.read().unwrap().get(&id).cloned()
+    }
+
+    pub fn players(&self) -> Vec<(UUID, Player)> {
+        self.players
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, player)| (*id, player.clone()))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.players.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.players.read().unwrap().is_empty()
+    }
+}
+
+impl Roster for PlayerList {
+    fn name(&self, player: UUID) -> Option<TextComponent> {
+        self.players
+            .read()
+            .unwrap()
+            .get(&player)
+            .map(|p| p.name.clone())
+    }
+}
@@ -5,3 +5,6 @@ pub mod types;
 
 #[macro_use]
 extern crate lazy_static;
+
+#[cfg(feature = "pkmcom")]
+pub use net;
@@ -1,3 +1,4 @@
+use binary_io::nbt::compound::NbtCompound;
 use fused_lock::FusedRwLock;
 use rlua::{prelude::*, Context, Value};
 
@@ -12,8 +13,33 @@ pub trait RegistryEntry {
     fn registry_name(&self) -> &ResourceLocation;
 }
 
+// Entries which can be converted to and from NBT, so that they can be sent between the server and client
+pub trait NbtEntry: RegistryEntry + Sized {
+    fn to_nbt(&self) -> NbtCompound;
+    fn from_nbt(name: ResourceLocation, nbt: &NbtCompound) -> std::io::Result<Self>;
+}
+
+// Numeric ids of the entries, where the id of an entry is its index in names
+#[derive(Default)]
+struct Ids {
+    names: Vec<ResourceLocation>,
+    ids: HashMap<ResourceLocation, u32>,
+}
+
+impl Ids {
+    fn assign(names: Vec<ResourceLocation>) -> Self {
+        let ids = names
+            .iter()
+            .enumerate()
+            .map(|(id, name)| (name.clone(), id as u32))
+            .collect();
+        Self { names, ids }
+    }
+}
+
 pub struct Registry<E> {
     underlying: FusedRwLock<HashMap<ResourceLocation, E>>,
+    ids: FusedRwLock<Ids>,
 }
 
 impl<E> Default for Registry<E> {
@@ -26,18 +52,57 @@ impl<E> Registry<E> {
     pub fn new() -> Self {
         Self {
             underlying: FusedRwLock::new(HashMap::new()),
+            ids: FusedRwLock::new(Ids::default()),
         }
     }
 
+    // Unless ids were installed from the server, they are assigned in order of the entries' names
     pub fn lock(&self) {
+        if let (Some(entries), Some(mut ids)) = (self.underlying.try_write(), self.ids.try_write())
+        {
+            if ids.names.len() != entries.len() {
+                let mut names = entries.keys().cloned().collect::<Vec<_>>();
+                names.sort();
+                *ids = Ids::assign(names);
+            }
+        }
+        self.ids.lock();
         self.underlying.lock()
     }
 
+    pub fn is_locked(&self) -> bool {
+        self.underlying.is_locked()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.try_read().map_or(0, |ids| ids.names.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn id(&self, name: &ResourceLocation) -> Option<u32> {
+        self.ids
+            .try_read()
+            .and_then(|ids| ids.ids.get(name).copied())
+    }
+
+    pub fn name_of(&self, id: u32) -> Option<&ResourceLocation> {
+        self.ids
+            .try_read()
+            .and_then(|ids| ids.names.get(id as usize))
+    }
+
+    pub fn by_id(&self, id: u32) -> Option<&E> {
+        self.name_of(id).and_then(|name| self.get(name))
+    }
+
     pub fn get(&self, name: &ResourceLocation) -> Option<&E> {
-        self.underlying.try_read().map(|e| e.get(name)).flatten()
+        self.underlying.try_read().and_then(|e| e.get(name))
     }
 
-    pub fn iter(&self) -> Iter<E> {
+    pub fn iter(&self) -> Iter<'_, E> {
         Iter(self.underlying.try_read().map(HashMap::values))
     }
 }
@@ -54,7 +119,7 @@ pub struct Iter<'a, E>(Option<std::collections::hash_map::Values<'a, ResourceLoc
 impl<'a, E> Iterator for Iter<'a, E> {
     type Item = &'a E;
     fn next(&mut self) -> Option<&'a E> {
-        self.0.as_mut().map(Iterator::next).flatten()
+        self.0.as_mut().and_then(Iterator::next)
     }
 }
 
@@ -78,7 +143,34 @@ impl<E: RegistryEntry> Registry<E> {
         }
     }
 
-    pub fn get_object(&self, key: ResourceLocation) -> RegistryObject<E> {
+    // Replaces every entry, with ids given by their positions, as the server's registry is authoritative.
+    // Gives back the entries if the registry is locked or two entries have the same name
+    pub fn install(&self, entries: Vec<E>) -> Option<Vec<E>> {
+        if self.underlying.is_locked() {
+            return Some(entries);
+        }
+        let names = entries
+            .iter()
+            .map(|e| e.registry_name().clone())
+            .collect::<Vec<_>>();
+        let ids = Ids::assign(names);
+        if ids.ids.len() != entries.len() {
+            return Some(entries);
+        }
+        match (self.underlying.try_write(), self.ids.try_write()) {
+            (Some(mut underlying), Some(mut guard)) => {
+                *underlying = entries
+                    .into_iter()
+                    .map(|e| (e.registry_name().clone(), e))
+                    .collect();
+                *guard = ids;
+                None
+            }
+            _ => Some(entries),
+        }
+    }
+
+    pub fn get_object(&self, key: ResourceLocation) -> RegistryObject<'_, E> {
         RegistryObject {
             registry: self,
             key,
@@ -131,3 +223,121 @@ impl<E: RegistryEntry + for<'lua> FromLua<'lua>> Registry<E> {
         }
     }
 }
+
+#[cfg(feature = "pkmcom")]
+mod digest {
+    use binary_io::data::{DataOutput, OutOfRange, Serializeable};
+    use net::digest::{ContentDigest, ContentSummary};
+
+    use super::{Registry, RegistryEntry};
+
+    impl<E: RegistryEntry> Registry<E> {
+        fn sorted_entries(&self) -> Vec<&E> {
+            let mut entries = self.iter().collect::<Vec<_>>();
+            entries.sort_by(|a, b| a.registry_name().cmp(b.registry_name()));
+            entries
+        }
+    }
+
+    // The entries in order of their names, each encoded as its name followed by its content
+    impl<E: RegistryEntry + ContentDigest> ContentDigest for Registry<E> {
+        fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+            let entries = self.sorted_entries();
+            if entries.len() > u32::MAX as usize {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    OutOfRange(entries.len()),
+                ));
+            }
+            (entries.len() as u32).serialize(output)?;
+            for e in entries {
+                e.registry_name().write_canonical(output)?;
+                e.write_canonical(output)?;
+            }
+            Ok(())
+        }
+    }
+
+    impl<E: RegistryEntry + ContentDigest> Registry<E> {
+        // Entries are named kind/name, so that registries with entries of the same name do not collide
+        pub fn summarize(&self, kind: &str, summary: &mut ContentSummary) -> std::io::Result<()> {
+            for e in self.sorted_entries() {
+                let name = e.registry_name();
+                summary.insert(name.domain(), format!("{}/{}", kind, name), e.digest()?);
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "pkmcom")]
+mod sync {
+    use std::{collections::HashSet, io::ErrorKind};
+
+    use net::sync::{SyncError, SyncTarget, SyncedEntry};
+
+    use super::{NbtEntry, Registry};
+    use crate::resource::ResourceLocation;
+
+    impl<E: NbtEntry> Registry<E> {
+        // The entries in order of their ids, to be sent with net::sync::send_registry
+        pub fn synced_entries(&self) -> std::io::Result<Vec<SyncedEntry>> {
+            if !self.is_locked() {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "Registries must be locked before they are synchronized",
+                ));
+            }
+            (0..self.len() as u32)
+                .map(|id| {
+                    let entry = self.by_id(id).unwrap();
+                    Ok(SyncedEntry {
+                        id,
+                        name: entry.registry_name().to_string(),
+                        data: entry.to_nbt(),
+                    })
+                })
+                .collect()
+        }
+    }
+
+    impl<E: NbtEntry> SyncTarget for Registry<E> {
+        fn install(&self, registry: &str, entries: Vec<SyncedEntry>) -> Result<(), SyncError> {
+            let invalid = |name: &str, reason: String| SyncError::InvalidEntry {
+                registry: registry.to_string(),
+                name: name.to_string(),
+                reason,
+            };
+            let mut names = HashSet::new();
+            let entries = entries
+                .iter()
+                .map(|entry| {
+                    let name = entry
+                        .name
+                        .parse::<ResourceLocation>()
+                        .map_err(|e| invalid(&entry.name, e.to_string()))?;
+                    let value = E::from_nbt(name.clone(), &entry.data)
+                        .map_err(|e| invalid(&entry.name, e.to_string()))?;
+                    if value.registry_name() != &name {
+                        return Err(invalid(
+                            &entry.name,
+                            format!("converted to {}", value.registry_name()),
+                        ));
+                    }
+                    if !names.insert(name) {
+                        return Err(SyncError::DuplicateName {
+                            registry: registry.to_string(),
+                            name: entry.name.clone(),
+                        });
+                    }
+                    Ok(value)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if Registry::install(self, entries).is_some() {
+                return Err(SyncError::Locked(registry.to_string()));
+            }
+            self.lock();
+            Ok(())
+        }
+    }
+}
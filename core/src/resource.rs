@@ -111,3 +111,25 @@ impl ResourceLocation {
         &self.path
     }
 }
+
+// Matches ResourceLocation.hashCode in the Java implementation
+#[cfg(feature = "pkmcom")]
+impl net::hashsum::Hashcode for ResourceLocation {
+    fn hashcode(&self) -> i32 {
+        self.domain
+            .hashcode()
+            .wrapping_mul(31)
+            .wrapping_add(self.path.hashcode())
+    }
+}
+
+// Encoded as the string form of the location
+#[cfg(feature = "pkmcom")]
+impl net::digest::ContentDigest for ResourceLocation {
+    fn write_canonical<W: binary_io::data::DataOutput + ?Sized>(
+        &self,
+        output: &mut W,
+    ) -> std::io::Result<()> {
+        self.to_string().write_canonical(output)
+    }
+}
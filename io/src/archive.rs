@@ -0,0 +1,646 @@
+//!
+//! An indexed archive format which stores many keyed NBT Compounds in a single file.
+//!
+//! An archive begins with a fixed-size header, containing the magic number, the [`Version`] of the archive format,
+//!  and the location of the index. Records are stored after the header, and the index, which maps each key
+//!  to the location of its record, is stored after the records.
+//! All fields of the header and index are stored in Big Endian byte order.
+//!
+//! Records are read on demand. Records are written to the first free region that is large enough, or to the end of the archive.
+//! The index is only written by [`Archive::flush`], and the header is updated after the new index is written,
+//!  so records which are added or moved only become visible to [`Archive::open`] once the archive is flushed.
+//!
+//! The archive never overwrites a region which the index in storage refers to. A record which was written since the last flush
+//!  is updated in place if it fits in the space it occupies, but a flushed record is written to a new region,
+//!  and the region it occupied is only reused once a flush has replaced the index.
+//! The storage is [synced](ArchiveStorage::sync) before and after the header is updated,
+//!  so an interrupted flush or [`Archive::compact`] leaves the archive as it was either before or after it.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::File,
+    io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write},
+};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder};
+
+use crate::{
+    data::{
+        ByteOrder, DataInputStream, DataOutputStream, DeserializeCopy, OutOfRange, Serializeable,
+    },
+    nbt::compound::NbtCompound,
+    uuid::UUID,
+    version::Version,
+};
+
+pub mod consts {
+    //! Constant values for archives
+
+    use crate::version::Version;
+
+    ///
+    /// The current version of the archive format
+    pub const ARCHIVE_VERSION: Version = Version::from_encoded(0x0000);
+
+    ///
+    /// The magic number for an archive: "\xADNBA" or [AD 4E 42 41]
+    pub const ARCHIVE_MAGIC: [u8; 4] = [0xAD, 0x4E, 0x42, 0x41];
+
+    ///
+    /// The size of the archive header, in bytes
+    pub const HEADER_LEN: u64 = 18;
+
+    pub(crate) const FLAG_COMPRESSED: u8 = 0x01;
+}
+
+///
+/// A storage medium for an Archive, such as a File
+pub trait ArchiveStorage: Read + Write + Seek {
+    ///
+    /// Truncates or extends the storage to the given length
+    fn set_len(&mut self, len: u64) -> std::io::Result<()>;
+    ///
+    /// Flushes the storage, and waits until everything written to it is durable.
+    /// By default, the storage is only flushed
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.flush()
+    }
+}
+
+impl ArchiveStorage for File {
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.sync_data()
+    }
+}
+
+impl ArchiveStorage for Cursor<Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        self.get_mut().resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+impl<S: ArchiveStorage + ?Sized> ArchiveStorage for &mut S {
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        S::set_len(self, len)
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        S::sync(self)
+    }
+}
+
+///
+/// The key of a record in an Archive
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RecordKey {
+    ///
+    /// A record identified by a name
+    Name(String),
+    ///
+    /// A record identified by a UUID
+    Uuid(UUID),
+}
+
+impl From<String> for RecordKey {
+    fn from(s: String) -> Self {
+        Self::Name(s)
+    }
+}
+
+impl From<&str> for RecordKey {
+    fn from(s: &str) -> Self {
+        Self::Name(s.to_string())
+    }
+}
+
+impl From<UUID> for RecordKey {
+    fn from(u: UUID) -> Self {
+        Self::Uuid(u)
+    }
+}
+
+impl Display for RecordKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Name(name) => f.write_str(name),
+            Self::Uuid(uuid) => uuid.fmt(f),
+        }
+    }
+}
+
+///
+/// How a record is compressed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compression {
+    ///
+    /// The record is stored uncompressed
+    None,
+    ///
+    /// The record is compressed with zlib
+    Zlib,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Entry {
+    offset: u64,
+    capacity: u32,
+    len: u32,
+    flags: u8,
+    // Whether the index in storage refers to the record, so that it must not be overwritten
+    flushed: bool,
+}
+
+///
+/// An indexed archive of NBT Compounds
+pub struct Archive<S> {
+    storage: S,
+    version: Version,
+    index: HashMap<RecordKey, Entry>,
+    index_region: Option<(u64, u64)>,
+    free: Vec<(u64, u64)>,
+    // Regions which the index in storage refers to, but the archive no longer uses, which are freed by the next flush
+    retired: Vec<(u64, u64)>,
+    end: u64,
+    dirty: bool,
+}
+
+impl<S: ArchiveStorage> Archive<S> {
+    ///
+    /// Creates a new, empty, archive in storage, discarding any existing content
+    pub fn create(mut storage: S) -> std::io::Result<Self> {
+        storage.set_len(0)?;
+        let mut archive = Self {
+            storage,
+            version: consts::ARCHIVE_VERSION,
+            index: HashMap::new(),
+            index_region: None,
+            free: Vec::new(),
+            retired: Vec::new(),
+            end: consts::HEADER_LEN,
+            dirty: true,
+        };
+        archive.write_header(0, 0)?;
+        archive.flush()?;
+        Ok(archive)
+    }
+
+    ///
+    /// Opens an existing archive
+    pub fn open(mut storage: S) -> std::io::Result<Self> {
+        let storage_len = storage.seek(SeekFrom::End(0))?;
+        storage.seek(SeekFrom::Start(0))?;
+        let mut input = DataInputStream::new(&mut storage, ByteOrder::BigEndian);
+        let magic = <[u8; 4]>::deserialize_copy(&mut input)?;
+        if magic != consts::ARCHIVE_MAGIC {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Invalid magic (not an archive)",
+            ));
+        }
+        let version = Version::deserialize_copy(&mut input)?;
+        if consts::ARCHIVE_VERSION < version {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Version {} is not implemetented", version),
+            ));
+        }
+        let index_offset = u64::deserialize_copy(&mut input)?;
+        let index_len = u32::deserialize_copy(&mut input)? as u64;
+
+        let past_end = || {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Archive refers to data past the end of its storage",
+            )
+        };
+        let index_end = index_offset.checked_add(index_len).ok_or_else(past_end)?;
+        if storage_len < index_end {
+            return Err(past_end());
+        }
+
+        let mut index = HashMap::new();
+        if index_len != 0 {
+            let mut bytes = vec![0u8; index_len as usize];
+            storage.seek(SeekFrom::Start(index_offset))?;
+            storage.read_exact(&mut bytes)?;
+            let mut input = DataInputStream::new(&bytes[..], ByteOrder::BigEndian);
+            let count = u32::deserialize_copy(&mut input)?;
+            for _ in 0..count {
+                let key = match u8::deserialize_copy(&mut input)? {
+                    0 => RecordKey::Name(String::deserialize_copy(&mut input)?),
+                    1 => RecordKey::Uuid(UUID::deserialize_copy(&mut input)?),
+                    k => {
+                        return Err(std::io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Invalid record key kind {}", k),
+                        ))
+                    }
+                };
+                let entry = Entry {
+                    offset: u64::deserialize_copy(&mut input)?,
+                    capacity: u32::deserialize_copy(&mut input)?,
+                    len: u32::deserialize_copy(&mut input)?,
+                    flags: u8::deserialize_copy(&mut input)?,
+                    flushed: true,
+                };
+                if entry.capacity < entry.len {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Record {} is larger than its capacity", key),
+                    ));
+                }
+                index.insert(key, entry);
+            }
+        }
+
+        let index_region = if index_len != 0 {
+            Some((index_offset, index_len))
+        } else {
+            None
+        };
+        let mut regions = index
+            .values()
+            .map(|e| (e.offset, e.capacity as u64))
+            .chain(index_region)
+            .collect::<Vec<_>>();
+        regions.sort_unstable();
+        let mut free = Vec::new();
+        let mut end = consts::HEADER_LEN;
+        for (offset, len) in regions {
+            if offset < end {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Overlapping records in archive",
+                ));
+            }
+            if end < offset {
+                free.push((end, offset - end));
+            }
+            end = offset.checked_add(len).ok_or_else(past_end)?;
+        }
+        // Every record is read in full when it is accessed, so none may extend past the storage
+        if storage_len < end {
+            return Err(past_end());
+        }
+
+        Ok(Self {
+            storage,
+            version,
+            index,
+            index_region,
+            free,
+            retired: Vec::new(),
+            end,
+            dirty: false,
+        })
+    }
+
+    ///
+    /// Returns the version of the archive format
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    ///
+    /// Returns the number of records in the archive
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    ///
+    /// Checks if the archive contains no records
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    ///
+    /// Checks if the archive contains a record with the given key
+    pub fn contains(&self, key: &RecordKey) -> bool {
+        self.index.contains_key(key)
+    }
+
+    ///
+    /// Returns an iterator over the keys of the records in the archive, in an unspecified order
+    pub fn keys(&self) -> impl Iterator<Item = &RecordKey> {
+        self.index.keys()
+    }
+
+    ///
+    /// Returns the number of bytes of the archive which are not used by any record or the index
+    pub fn free_space(&self) -> u64 {
+        self.free
+            .iter()
+            .chain(&self.retired)
+            .map(|(_, len)| len)
+            .sum::<u64>()
+            + self
+                .index
+                .values()
+                .map(|e| (e.capacity - e.len) as u64)
+                .sum::<u64>()
+    }
+
+    ///
+    /// Reads the record with the given key
+    pub fn read(&mut self, key: &RecordKey) -> std::io::Result<Option<NbtCompound>> {
+        let entry = match self.index.get(key) {
+            Some(entry) => *entry,
+            None => return Ok(None),
+        };
+        // Records are checked to be within the storage when the archive is opened
+        let mut bytes = vec![0u8; entry.len as usize];
+        self.storage.seek(SeekFrom::Start(entry.offset))?;
+        self.storage.read_exact(&mut bytes)?;
+        let compound = if (entry.flags & consts::FLAG_COMPRESSED) != 0 {
+            let mut input =
+                DataInputStream::new(ZlibDecoder::new(&bytes[..]), ByteOrder::BigEndian);
+            NbtCompound::deserialize_copy(&mut input)?
+        } else {
+            NbtCompound::deserialize_copy(&mut DataInputStream::new(
+                &bytes[..],
+                ByteOrder::BigEndian,
+            ))?
+        };
+        Ok(Some(compound))
+    }
+
+    ///
+    /// Writes a record with the given key, replacing any existing record with that key
+    pub fn write<K: Into<RecordKey>>(
+        &mut self,
+        key: K,
+        value: &NbtCompound,
+        compression: Compression,
+    ) -> std::io::Result<()> {
+        let key = key.into();
+        let mut flags = 0;
+        let bytes = match compression {
+            Compression::None => {
+                let mut output = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+                value.serialize(&mut output)?;
+                output.into_inner()
+            }
+            Compression::Zlib => {
+                flags |= consts::FLAG_COMPRESSED;
+                let mut output = DataOutputStream::new(
+                    ZlibEncoder::new(Vec::new(), flate2::Compression::default()),
+                    ByteOrder::BigEndian,
+                );
+                value.serialize(&mut output)?;
+                output.into_inner().finish()?
+            }
+        };
+        if bytes.len() > (u32::MAX as usize) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                OutOfRange(bytes.len()),
+            ));
+        }
+        let len = bytes.len() as u32;
+
+        let (offset, capacity) = match self.index.get(&key).copied() {
+            Some(entry) if !entry.flushed && len <= entry.capacity => {
+                (entry.offset, entry.capacity)
+            }
+            Some(entry) => {
+                self.discard(entry);
+                (self.allocate(len as u64), len)
+            }
+            None => (self.allocate(len as u64), len),
+        };
+        self.storage.seek(SeekFrom::Start(offset))?;
+        self.storage.write_all(&bytes)?;
+        self.index.insert(
+            key,
+            Entry {
+                offset,
+                capacity,
+                len,
+                flags,
+                flushed: false,
+            },
+        );
+        self.dirty = true;
+        Ok(())
+    }
+
+    ///
+    /// Removes the record with the given key. Returns true if a record was removed
+    pub fn remove(&mut self, key: &RecordKey) -> bool {
+        if let Some(entry) = self.index.remove(key) {
+            self.discard(entry);
+            self.dirty = true;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Frees the region of a record which is no longer used, once the index in storage no longer refers to it
+    fn discard(&mut self, entry: Entry) {
+        let region = (entry.offset, entry.capacity as u64);
+        if entry.flushed {
+            self.retired.push(region);
+        } else {
+            self.release(region);
+        }
+    }
+
+    fn allocate(&mut self, len: u64) -> u64 {
+        if len == 0 {
+            return self.end;
+        }
+        if let Some(pos) = self.free.iter().position(|(_, free)| len <= *free) {
+            let (offset, free) = self.free[pos];
+            if free == len {
+                self.free.remove(pos);
+            } else {
+                self.free[pos] = (offset + len, free - len);
+            }
+            offset
+        } else {
+            let offset = self.end;
+            self.end += len;
+            offset
+        }
+    }
+
+    fn release(&mut self, (offset, len): (u64, u64)) {
+        if len == 0 {
+            return;
+        }
+        let pos = self.free.partition_point(|(o, _)| *o < offset);
+        self.free.insert(pos, (offset, len));
+        if pos + 1 < self.free.len() && offset + len == self.free[pos + 1].0 {
+            self.free[pos].1 += self.free[pos + 1].1;
+            self.free.remove(pos + 1);
+        }
+        if pos > 0 && self.free[pos - 1].0 + self.free[pos - 1].1 == offset {
+            self.free[pos - 1].1 += self.free[pos].1;
+            self.free.remove(pos);
+        }
+        if let Some(&(offset, len)) = self.free.last() {
+            if offset + len == self.end {
+                self.free.pop();
+                self.end = offset;
+            }
+        }
+    }
+
+    fn write_header(&mut self, index_offset: u64, index_len: u32) -> std::io::Result<()> {
+        self.storage.seek(SeekFrom::Start(0))?;
+        let mut output = DataOutputStream::new(&mut self.storage, ByteOrder::BigEndian);
+        consts::ARCHIVE_MAGIC.serialize(&mut output)?;
+        self.version.serialize(&mut output)?;
+        index_offset.serialize(&mut output)?;
+        index_len.serialize(&mut output)
+    }
+
+    fn index_bytes(&self) -> std::io::Result<Vec<u8>> {
+        let mut output = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+        (self.index.len() as u32).serialize(&mut output)?;
+        let mut entries = self.index.iter().collect::<Vec<_>>();
+        entries.sort_unstable_by_key(|(_, e)| e.offset);
+        for (key, entry) in entries {
+            match key {
+                RecordKey::Name(name) => {
+                    0u8.serialize(&mut output)?;
+                    name.serialize(&mut output)?;
+                }
+                RecordKey::Uuid(uuid) => {
+                    1u8.serialize(&mut output)?;
+                    uuid.serialize(&mut output)?;
+                }
+            }
+            entry.offset.serialize(&mut output)?;
+            entry.capacity.serialize(&mut output)?;
+            entry.len.serialize(&mut output)?;
+            entry.flags.serialize(&mut output)?;
+        }
+        let bytes = output.into_inner();
+        if bytes.len() > (u32::MAX as usize) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                OutOfRange(bytes.len()),
+            ));
+        }
+        Ok(bytes)
+    }
+
+    // The index, and the records it refers to, are durable before the header refers to it
+    fn write_index(&mut self, offset: u64, bytes: &[u8]) -> std::io::Result<()> {
+        self.storage.seek(SeekFrom::Start(offset))?;
+        self.storage.write_all(bytes)?;
+        self.storage.sync()?;
+        self.write_header(offset, bytes.len() as u32)?;
+        self.storage.sync()
+    }
+
+    fn copy_record(&mut self, from: u64, to: u64, len: u32) -> std::io::Result<()> {
+        let mut bytes = vec![0u8; len as usize];
+        self.storage.seek(SeekFrom::Start(from))?;
+        self.storage.read_exact(&mut bytes)?;
+        self.storage.seek(SeekFrom::Start(to))?;
+        self.storage.write_all(&bytes)
+    }
+
+    ///
+    /// Writes the index and header of the archive, making all changes since the last flush visible to [`Archive::open`]
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        if !self.dirty {
+            return self.storage.flush();
+        }
+        let bytes = self.index_bytes()?;
+        let offset = self.allocate(bytes.len() as u64);
+        self.write_index(offset, &bytes)?;
+
+        // The header no longer refers to the old index, or to the records which were replaced
+        let retired = std::mem::take(&mut self.retired);
+        for region in retired
+            .into_iter()
+            .chain(self.index_region.replace((offset, bytes.len() as u64)))
+        {
+            self.release(region);
+        }
+        for entry in self.index.values_mut() {
+            entry.flushed = true;
+        }
+        self.storage.set_len(self.end)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    ///
+    /// Moves all records to the start of the archive, removing free space between and within records,
+    ///  and then flushes the archive.
+    ///
+    /// The records are first copied, packed, after the end of the archive, and the header is switched to an index of the copies.
+    /// Only then are they moved to the start of the archive, which the header no longer refers to,
+    ///  so the archive in storage remains readable if compaction is interrupted at any point
+    pub fn compact(&mut self) -> std::io::Result<()> {
+        let mut keys = self
+            .index
+            .iter()
+            .map(|(k, e)| (e.offset, k.clone()))
+            .collect::<Vec<_>>();
+        keys.sort_unstable_by_key(|(offset, _)| *offset);
+        let keys = keys.into_iter().map(|(_, k)| k).collect::<Vec<_>>();
+
+        // Everything up to the end of the archive may be referred to by the current header
+        let staged = self.end;
+        let mut cursor = staged;
+        for key in &keys {
+            let mut entry = self.index[key];
+            self.copy_record(entry.offset, cursor, entry.len)?;
+            entry.offset = cursor;
+            entry.capacity = entry.len;
+            entry.flushed = true;
+            cursor += entry.len as u64;
+            self.index.insert(key.clone(), entry);
+        }
+        let bytes = self.index_bytes()?;
+        self.write_index(cursor, &bytes)?;
+        let mut live_index = (cursor, bytes.len() as u64);
+
+        // The packed records are no larger than the region they were copied from, so they fit before the copies
+        let mut cursor = consts::HEADER_LEN;
+        for key in &keys {
+            let mut entry = self.index[key];
+            self.copy_record(entry.offset, cursor, entry.len)?;
+            entry.offset = cursor;
+            cursor += entry.len as u64;
+            self.index.insert(key.clone(), entry);
+        }
+        let bytes = self.index_bytes()?;
+        let len = bytes.len() as u64;
+        if staged < cursor + len {
+            // The index would overwrite the copies, so it first moves past the index which refers to them
+            let offset = live_index.0 + live_index.1;
+            self.write_index(offset, &bytes)?;
+            live_index = (offset, len);
+        }
+        debug_assert!(cursor + len <= live_index.0);
+        self.write_index(cursor, &bytes)?;
+
+        self.free.clear();
+        self.retired.clear();
+        self.index_region = Some((cursor, len));
+        self.end = cursor + len;
+        self.storage.set_len(self.end)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    ///
+    /// Flushes the archive, and returns the underlying storage
+    pub fn into_inner(mut self) -> std::io::Result<S> {
+        self.flush()?;
+        Ok(self.storage)
+    }
+}
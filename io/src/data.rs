@@ -47,15 +47,13 @@ pub trait DataInput: Read {
     /// Reads exactly bytes.len() bytes into bytes.
     /// Returns an error if an End of File prevents reading the entire array.
     fn read_fully(&mut self, bytes: &mut [u8]) -> std::io::Result<()> {
-        let len = <Self as Read>::read(self, bytes)?;
-        if len != bytes.len() {
-            Err(std::io::Error::new(
-                ErrorKind::UnexpectedEof,
-                "Unexpected EOF in read_fully",
-            ))
-        } else {
-            Ok(())
-        }
+        <Self as Read>::read_exact(self, bytes).map_err(|e| {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                std::io::Error::new(ErrorKind::UnexpectedEof, "Unexpected EOF in read_fully")
+            } else {
+                e
+            }
+        })
     }
     /// Reads a single byte, and returns it, or an error if a byte cannot be read
     fn read_byte(&mut self) -> std::io::Result<u8> {
@@ -69,7 +67,7 @@ pub trait DataInput: Read {
     fn set_byte_order(&mut self, order: ByteOrder);
 }
 
-impl<R: DataInput> DataInput for &mut R {
+impl<R: DataInput + ?Sized> DataInput for &mut R {
     fn byte_order(&self) -> ByteOrder {
         R::byte_order(self)
     }
@@ -230,12 +228,43 @@ impl<T: DeserializeCopy, const N: usize> DeserializeCopy for [T; N] {
     }
 }
 
+///
+/// Reads `len` values from input with `read`.
+/// The length of a sequence is read from the stream before the sequence itself, and may be far larger than what actually follows,
+///  so it is not trusted for the allocation: the capacity reserved up front is bounded, and the Vec only grows as values are read
+pub fn read_sequence<R, T, F>(input: &mut R, len: usize, mut read: F) -> std::io::Result<Vec<T>>
+where
+    R: DataInput + ?Sized,
+    F: FnMut(&mut R) -> std::io::Result<T>,
+{
+    let mut values = Vec::with_capacity(len.min(4096));
+    for _ in 0..len {
+        values.push(read(input)?);
+    }
+    Ok(values)
+}
+
+///
+/// Reads exactly `len` bytes from input into a new Vec.
+/// As with [`read_sequence`], the Vec only grows as the bytes are read, rather than trusting `len` for the allocation
+pub fn read_bytes<R: DataInput + ?Sized>(input: &mut R, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    input.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(std::io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "Unexpected EOF in read_bytes",
+        ));
+    }
+    Ok(bytes)
+}
+
 /// A Trait for types that can perform binary IO Writes
 pub trait DataOutput: Write {
     ///
     /// Writes all of `bytes` to the underlying stream or returns an error
     fn write_bytes(&mut self, bytes: &[u8]) -> std::io::Result<()> {
-        Ok(self.write_all(bytes)?)
+        self.write_all(bytes)
     }
     ///
     /// Writes byte to the underlying stream or returns an error
@@ -250,6 +279,24 @@ pub trait DataOutput: Write {
     fn set_byte_order(&mut self, order: ByteOrder);
 }
 
+impl<W: DataOutput + ?Sized> DataOutput for &mut W {
+    fn write_bytes(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        W::write_bytes(self, bytes)
+    }
+
+    fn write_byte(&mut self, byte: u8) -> std::io::Result<()> {
+        W::write_byte(self, byte)
+    }
+
+    fn byte_order(&self) -> ByteOrder {
+        W::byte_order(self)
+    }
+
+    fn set_byte_order(&mut self, order: ByteOrder) {
+        W::set_byte_order(self, order)
+    }
+}
+
 ///
 /// A type that can serialize types according to LCS4
 pub struct DataOutputStream<W: ?Sized> {
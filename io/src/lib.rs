@@ -19,3 +19,6 @@ pub mod nbt;
 
 #[cfg(feature = "shade")]
 pub mod shade;
+
+#[cfg(feature = "archive")]
+pub mod archive;
@@ -4,21 +4,41 @@
 //!
 
 use core::panic;
+use std::io::ErrorKind;
 
 use crate::data::{DeserializeCopy, Deserializeable, Serializeable};
 
+///
+/// How the length of NBT arrays is encoded.
+/// The Serializeable and Deserializeable impls of NBT types always use [`ArrayEncoding::Prefixed`],
+///  ShadeFile selects the encoding by the version of the file
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ArrayEncoding {
+    ///
+    /// Arrays are prefixed by their length, as an Int
+    Prefixed,
+    ///
+    /// Arrays are written as their elements alone, as in Shade files before 1.5.
+    /// Such arrays could only be read back as empty arrays, so only empty arrays can be written
+    Legacy,
+}
+
 pub mod array {
     //!
     //! Types for NBT_Tag*Array
     use std::{
+        io::ErrorKind,
         mem::ManuallyDrop,
         ops::{Deref, DerefMut, Index, IndexMut},
         ptr,
         slice::{self, SliceIndex},
     };
+
+    use super::ArrayEncoding;
+    use crate::data::{read_sequence, DeserializeCopy, Deserializeable, OutOfRange, Serializeable};
     ///
     /// A type which can store a dynamic, fixed-size array of T
-    #[derive(Clone, Debug)]
+    #[derive(Clone, Debug, PartialEq)]
     pub struct NbtArray<T> {
         inner: Box<[T]>,
     }
@@ -160,12 +180,12 @@ pub mod array {
     impl<T> NbtArray<T> {
         ///
         /// Returns an iterator of references to the array elements
-        pub fn iter(&self) -> Iter<T> {
+        pub fn iter(&self) -> Iter<'_, T> {
             Iter(self.inner.iter())
         }
         ///
         /// Returns an iterator of mut references to the array elements
-        pub fn iter_mut(&mut self) -> IterMut<T> {
+        pub fn iter_mut(&mut self) -> IterMut<'_, T> {
             IterMut(self.inner.iter_mut())
         }
     }
@@ -189,6 +209,73 @@ pub mod array {
             self.iter_mut()
         }
     }
+
+    impl<T: Serializeable> NbtArray<T> {
+        pub(crate) fn write_with<W: crate::data::DataOutput + ?Sized>(
+            &self,
+            output: &mut W,
+            encoding: ArrayEncoding,
+        ) -> std::io::Result<()> {
+            let len = self.inner.len();
+            match encoding {
+                ArrayEncoding::Legacy if len == 0 => Ok(()),
+                ArrayEncoding::Legacy => Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Non-empty arrays cannot be written to Shade files before version 1.5",
+                )),
+                ArrayEncoding::Prefixed => {
+                    if len > (i32::MAX as usize) {
+                        return Err(std::io::Error::new(ErrorKind::InvalidData, OutOfRange(len)));
+                    }
+                    (len as i32).serialize(output)?;
+                    self.inner.serialize(output)
+                }
+            }
+        }
+    }
+
+    impl<T: DeserializeCopy> NbtArray<T> {
+        pub(crate) fn read_with<R: crate::data::DataInput + ?Sized>(
+            input: &mut R,
+            encoding: ArrayEncoding,
+        ) -> std::io::Result<Self> {
+            if encoding == ArrayEncoding::Legacy {
+                return Ok(Self::default());
+            }
+            let len = i32::deserialize_copy(input)?;
+            if len < 0 {
+                return Err(std::io::Error::new(ErrorKind::InvalidData, OutOfRange(len)));
+            }
+            Ok(read_sequence(input, len as usize, T::deserialize_copy)?.into())
+        }
+    }
+
+    impl<T: Serializeable> Serializeable for NbtArray<T> {
+        fn serialize<W: crate::data::DataOutput + ?Sized>(
+            &self,
+            output: &mut W,
+        ) -> std::io::Result<()> {
+            self.write_with(output, ArrayEncoding::Prefixed)
+        }
+    }
+
+    impl<T: DeserializeCopy> Deserializeable for NbtArray<T> {
+        fn deserialize<R: crate::data::DataInput + ?Sized>(
+            &mut self,
+            input: &mut R,
+        ) -> std::io::Result<()> {
+            *self = Self::read_with(input, ArrayEncoding::Prefixed)?;
+            Ok(())
+        }
+    }
+
+    impl<T: DeserializeCopy> DeserializeCopy for NbtArray<T> {
+        fn deserialize_copy<R: crate::data::DataInput + ?Sized>(
+            input: &mut R,
+        ) -> std::io::Result<Self> {
+            Self::read_with(input, ArrayEncoding::Prefixed)
+        }
+    }
 }
 
 fake_enum! {
@@ -263,14 +350,14 @@ impl DeserializeCopy for TagType {
 pub mod list {
     use std::{fmt::Display, io::ErrorKind};
 
-    use crate::data::{DeserializeCopy, Deserializeable, OutOfRange, Serializeable};
+    use crate::data::{read_sequence, DeserializeCopy, Deserializeable, OutOfRange, Serializeable};
 
-    use super::{NbtTag, TagType};
+    use super::{ArrayEncoding, NbtTag, TagType};
 
     ///
     /// A homogenous list of NBT Tags.
     /// Each element in the List has the same tag
-    #[derive(Clone, Default, Debug)]
+    #[derive(Clone, Default, Debug, PartialEq)]
     pub struct NbtList {
         tag: TagType,
         elements: Vec<NbtTag>,
@@ -336,12 +423,51 @@ pub mod list {
                 })
             }
         }
+
+        ///
+        /// Gets the type of the elements of the list, or End if the list is empty
+        pub fn element_type(&self) -> TagType {
+            self.tag
+        }
+
+        ///
+        /// Returns the number of elements in the list
+        pub fn len(&self) -> usize {
+            self.elements.len()
+        }
+
+        ///
+        /// Checks if the list is empty
+        pub fn is_empty(&self) -> bool {
+            self.elements.is_empty()
+        }
+
+        ///
+        /// Returns the element at index, if any
+        pub fn get(&self, index: usize) -> Option<&NbtTag> {
+            self.elements.get(index)
+        }
+
+        ///
+        /// Returns an iterator over the elements of the list
+        pub fn iter(&self) -> std::slice::Iter<'_, NbtTag> {
+            self.elements.iter()
+        }
     }
 
-    impl Serializeable for NbtList {
-        fn serialize<W: crate::data::DataOutput + ?Sized>(
+    impl<'a> IntoIterator for &'a NbtList {
+        type Item = &'a NbtTag;
+        type IntoIter = std::slice::Iter<'a, NbtTag>;
+        fn into_iter(self) -> Self::IntoIter {
+            self.elements.iter()
+        }
+    }
+
+    impl NbtList {
+        pub(crate) fn write_with<W: crate::data::DataOutput + ?Sized>(
             &self,
             output: &mut W,
+            encoding: ArrayEncoding,
         ) -> std::io::Result<()> {
             self.tag.serialize(output)?;
             let len = self.elements.len();
@@ -350,28 +476,41 @@ pub mod list {
             }
             (len as i32).serialize(output)?;
             for t in &self.elements {
-                t.serialize(output)?;
+                t.write_with(output, encoding)?;
             }
             Ok(())
         }
-    }
-    impl Deserializeable for NbtList {
-        fn deserialize<R: crate::data::DataInput + ?Sized>(
-            &mut self,
+
+        pub(crate) fn read_with<R: crate::data::DataInput + ?Sized>(
             input: &mut R,
-        ) -> std::io::Result<()> {
+            encoding: ArrayEncoding,
+        ) -> std::io::Result<Self> {
             let tt = TagType::deserialize_copy(input)?;
             let len = i32::deserialize_copy(input)?;
             if len < 0 {
                 return Err(std::io::Error::new(ErrorKind::InvalidData, OutOfRange(len)));
             }
-            let mut items = Vec::with_capacity(len as usize);
-            items.resize_with(len as usize, || NbtTag::default_for_tag(tt));
-            for i in &mut items {
-                i.deserialize(input)?;
-            }
-            self.tag = tt;
-            self.elements = items;
+            let elements = read_sequence(input, len as usize, |input| {
+                NbtTag::read_with(tt, input, encoding)
+            })?;
+            Ok(Self { tag: tt, elements })
+        }
+    }
+
+    impl Serializeable for NbtList {
+        fn serialize<W: crate::data::DataOutput + ?Sized>(
+            &self,
+            output: &mut W,
+        ) -> std::io::Result<()> {
+            self.write_with(output, ArrayEncoding::Prefixed)
+        }
+    }
+    impl Deserializeable for NbtList {
+        fn deserialize<R: crate::data::DataInput + ?Sized>(
+            &mut self,
+            input: &mut R,
+        ) -> std::io::Result<()> {
+            *self = Self::read_with(input, ArrayEncoding::Prefixed)?;
             Ok(())
         }
     }
@@ -379,20 +518,7 @@ pub mod list {
         fn deserialize_copy<R: crate::data::DataInput + ?Sized>(
             input: &mut R,
         ) -> std::io::Result<Self> {
-            let tt = TagType::deserialize_copy(input)?;
-            let len = i32::deserialize_copy(input)?;
-            if len < 0 {
-                return Err(std::io::Error::new(ErrorKind::InvalidData, OutOfRange(len)));
-            }
-            let mut items = Vec::with_capacity(len as usize);
-            items.resize_with(len as usize, || NbtTag::default_for_tag(tt));
-            for i in &mut items {
-                i.deserialize(input)?;
-            }
-            Ok(Self {
-                tag: tt,
-                elements: items,
-            })
+            Self::read_with(input, ArrayEncoding::Prefixed)
         }
     }
 }
@@ -404,11 +530,11 @@ pub mod compound {
 
     use crate::data::{DeserializeCopy, Deserializeable, Serializeable};
 
-    use super::{NbtTag, TagType};
+    use super::{ArrayEncoding, NbtTag, TagType};
 
     ///
     /// A Compound NBT Tag, containing multiple, named, unordered, NBT Tags
-    #[derive(Clone, Debug, Default)]
+    #[derive(Clone, Debug, Default, PartialEq)]
     pub struct NbtCompound {
         inner: HashMap<String, NbtTag>,
     }
@@ -439,6 +565,57 @@ pub mod compound {
         pub fn insert(&mut self, name: String, value: NbtTag) -> Option<NbtTag> {
             self.inner.insert(name, value)
         }
+
+        ///
+        /// Removes the element of the Compound with the given Name, and returns it
+        pub fn remove<S: AsRef<str> + ?Sized>(&mut self, st: &S) -> Option<NbtTag> {
+            self.inner.remove(st.as_ref())
+        }
+
+        ///
+        /// Checks if the Compound contains an element with the given Name
+        pub fn contains_key<S: AsRef<str> + ?Sized>(&self, st: &S) -> bool {
+            self.inner.contains_key(st.as_ref())
+        }
+
+        ///
+        /// Returns the number of elements in the Compound
+        pub fn len(&self) -> usize {
+            self.inner.len()
+        }
+
+        ///
+        /// Checks if the Compound has no elements
+        pub fn is_empty(&self) -> bool {
+            self.inner.is_empty()
+        }
+
+        ///
+        /// Returns an iterator over the names and elements of the Compound, in an unspecified order
+        pub fn iter(&self) -> Iter<'_> {
+            Iter(self.inner.iter())
+        }
+    }
+
+    ///
+    /// Iterator over the names and elements of an NbtCompound
+    pub struct Iter<'a>(std::collections::hash_map::Iter<'a, String, NbtTag>);
+
+    impl<'a> Iterator for Iter<'a> {
+        type Item = (&'a String, &'a NbtTag);
+        fn next(&mut self) -> Option<Self::Item> {
+            self.0.next()
+        }
+    }
+
+    impl<'a> IntoIterator for &'a NbtCompound {
+        type Item = (&'a String, &'a NbtTag);
+
+        type IntoIter = Iter<'a>;
+
+        fn into_iter(self) -> Self::IntoIter {
+            self.iter()
+        }
     }
 
     impl<S: AsRef<str>> Index<S> for NbtCompound {
@@ -449,10 +626,11 @@ pub mod compound {
         }
     }
 
-    impl Serializeable for NbtCompound {
-        fn serialize<W: crate::data::DataOutput + ?Sized>(
+    impl NbtCompound {
+        pub(crate) fn write_with<W: crate::data::DataOutput + ?Sized>(
             &self,
             output: &mut W,
+            encoding: ArrayEncoding,
         ) -> std::io::Result<()> {
             for (k, v) in &self.inner {
                 let ty = v.tag_type();
@@ -464,53 +642,58 @@ pub mod compound {
                 }
                 ty.serialize(output)?;
                 k.serialize(output)?;
-                v.serialize(output)?;
+                v.write_with(output, encoding)?;
             }
             TagType::End.serialize(output)
         }
-    }
 
-    impl Deserializeable for NbtCompound {
-        fn deserialize<R: crate::data::DataInput + ?Sized>(
-            &mut self,
+        pub(crate) fn read_with<R: crate::data::DataInput + ?Sized>(
             input: &mut R,
-        ) -> std::io::Result<()> {
-            self.inner.clear();
+            encoding: ArrayEncoding,
+        ) -> std::io::Result<Self> {
+            let mut inner = HashMap::new();
             loop {
                 let ty = TagType::deserialize_copy(input)?;
                 if ty == TagType::End {
-                    return Ok(());
+                    return Ok(Self { inner });
                 }
                 let name = String::deserialize_copy(input)?;
-                let mut tag = NbtTag::default_for_tag(ty);
-                tag.deserialize(input)?;
-                self.inner.insert(name, tag);
+                inner.insert(name, NbtTag::read_with(ty, input, encoding)?);
             }
         }
     }
 
+    impl Serializeable for NbtCompound {
+        fn serialize<W: crate::data::DataOutput + ?Sized>(
+            &self,
+            output: &mut W,
+        ) -> std::io::Result<()> {
+            self.write_with(output, ArrayEncoding::Prefixed)
+        }
+    }
+
+    impl Deserializeable for NbtCompound {
+        fn deserialize<R: crate::data::DataInput + ?Sized>(
+            &mut self,
+            input: &mut R,
+        ) -> std::io::Result<()> {
+            *self = Self::read_with(input, ArrayEncoding::Prefixed)?;
+            Ok(())
+        }
+    }
+
     impl DeserializeCopy for NbtCompound {
         fn deserialize_copy<R: crate::data::DataInput + ?Sized>(
             input: &mut R,
         ) -> std::io::Result<Self> {
-            let mut inner = HashMap::new();
-            loop {
-                let ty = TagType::deserialize_copy(input)?;
-                if ty == TagType::End {
-                    return Ok(Self { inner });
-                }
-                let name = String::deserialize_copy(input)?;
-                let mut tag = NbtTag::default_for_tag(ty);
-                tag.deserialize(input)?;
-                inner.insert(name, tag);
-            }
+            Self::read_with(input, ArrayEncoding::Prefixed)
         }
     }
 }
 
 ///
 /// An NBT Tag
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum NbtTag {
     ///
     /// The end Tag
@@ -597,6 +780,7 @@ impl NbtTag {
             TagType::Byte => Self::Byte(0),
             TagType::Short => Self::Short(0),
             TagType::Int => Self::Int(0),
+            TagType::Long => Self::Long(0),
             TagType::Float => Self::Float(0.0),
             TagType::Double => Self::Double(0.0),
             TagType::ByteArray => Self::ByteArray(Default::default()),
@@ -613,10 +797,11 @@ impl NbtTag {
     }
 }
 
-impl Serializeable for NbtTag {
-    fn serialize<W: crate::data::DataOutput + ?Sized>(
+impl NbtTag {
+    pub(crate) fn write_with<W: crate::data::DataOutput + ?Sized>(
         &self,
         output: &mut W,
+        encoding: ArrayEncoding,
     ) -> std::io::Result<()> {
         match self {
             NbtTag::End => Ok(()),
@@ -626,17 +811,57 @@ impl Serializeable for NbtTag {
             NbtTag::Long(v) => v.serialize(output),
             NbtTag::Float(v) => v.serialize(output),
             NbtTag::Double(v) => v.serialize(output),
-            NbtTag::ByteArray(v) => v.serialize(output),
+            NbtTag::ByteArray(v) => v.write_with(output, encoding),
             NbtTag::String(v) => v.serialize(output),
-            NbtTag::List(v) => v.serialize(output),
-            NbtTag::Compound(v) => v.serialize(output),
-            NbtTag::IntArray(v) => v.serialize(output),
-            NbtTag::LongArray(v) => v.serialize(output),
-            NbtTag::FloatArray(v) => v.serialize(output),
-            NbtTag::DoubleArray(v) => v.serialize(output),
+            NbtTag::List(v) => v.write_with(output, encoding),
+            NbtTag::Compound(v) => v.write_with(output, encoding),
+            NbtTag::IntArray(v) => v.write_with(output, encoding),
+            NbtTag::LongArray(v) => v.write_with(output, encoding),
+            NbtTag::FloatArray(v) => v.write_with(output, encoding),
+            NbtTag::DoubleArray(v) => v.write_with(output, encoding),
             NbtTag::Uuid(v) => v.serialize(output),
         }
     }
+
+    pub(crate) fn read_with<R: crate::data::DataInput + ?Sized>(
+        ty: TagType,
+        input: &mut R,
+        encoding: ArrayEncoding,
+    ) -> std::io::Result<Self> {
+        Ok(match ty {
+            TagType::End => Self::End,
+            TagType::Byte => Self::Byte(DeserializeCopy::deserialize_copy(input)?),
+            TagType::Short => Self::Short(DeserializeCopy::deserialize_copy(input)?),
+            TagType::Int => Self::Int(DeserializeCopy::deserialize_copy(input)?),
+            TagType::Long => Self::Long(DeserializeCopy::deserialize_copy(input)?),
+            TagType::Float => Self::Float(DeserializeCopy::deserialize_copy(input)?),
+            TagType::Double => Self::Double(DeserializeCopy::deserialize_copy(input)?),
+            TagType::ByteArray => Self::ByteArray(array::NbtArray::read_with(input, encoding)?),
+            TagType::String => Self::String(DeserializeCopy::deserialize_copy(input)?),
+            TagType::List => Self::List(list::NbtList::read_with(input, encoding)?),
+            TagType::Compound => Self::Compound(compound::NbtCompound::read_with(input, encoding)?),
+            TagType::IntArray => Self::IntArray(array::NbtArray::read_with(input, encoding)?),
+            TagType::LongArray => Self::LongArray(array::NbtArray::read_with(input, encoding)?),
+            TagType::FloatArray => Self::FloatArray(array::NbtArray::read_with(input, encoding)?),
+            TagType::DoubleArray => Self::DoubleArray(array::NbtArray::read_with(input, encoding)?),
+            TagType::Uuid => Self::Uuid(DeserializeCopy::deserialize_copy(input)?),
+            _ => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid tag type {:?}", ty),
+                ))
+            }
+        })
+    }
+}
+
+impl Serializeable for NbtTag {
+    fn serialize<W: crate::data::DataOutput + ?Sized>(
+        &self,
+        output: &mut W,
+    ) -> std::io::Result<()> {
+        self.write_with(output, ArrayEncoding::Prefixed)
+    }
 }
 
 impl Deserializeable for NbtTag {
@@ -644,23 +869,7 @@ impl Deserializeable for NbtTag {
         &mut self,
         output: &mut W,
     ) -> std::io::Result<()> {
-        match self {
-            NbtTag::End => Ok(()),
-            NbtTag::Byte(v) => v.deserialize(output),
-            NbtTag::Short(v) => v.deserialize(output),
-            NbtTag::Int(v) => v.deserialize(output),
-            NbtTag::Long(v) => v.deserialize(output),
-            NbtTag::Float(v) => v.deserialize(output),
-            NbtTag::Double(v) => v.deserialize(output),
-            NbtTag::ByteArray(v) => v.deserialize(output),
-            NbtTag::String(v) => v.deserialize(output),
-            NbtTag::List(v) => v.deserialize(output),
-            NbtTag::Compound(v) => v.deserialize(output),
-            NbtTag::IntArray(v) => v.deserialize(output),
-            NbtTag::LongArray(v) => v.deserialize(output),
-            NbtTag::FloatArray(v) => v.deserialize(output),
-            NbtTag::DoubleArray(v) => v.deserialize(output),
-            NbtTag::Uuid(v) => v.deserialize(output),
-        }
+        *self = Self::read_with(self.tag_type(), output, ArrayEncoding::Prefixed)?;
+        Ok(())
     }
 }
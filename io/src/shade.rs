@@ -6,18 +6,24 @@ use std::{
     ops::{Deref, DerefMut},
 };
 
-use openssl::symm::Cipher;
-use zeroize::Zeroizing;
-
+#[cfg(feature = "crypto_shade")]
+use crate::data::{DataInput, DataOutput};
 use crate::{
-    data::{
-        ByteOrder, DataInput, DataInputStream, DataOutput, DataOutputStream, DeserializeCopy,
-        Deserializeable, OutOfRange, Serializeable,
-    },
-    nbt::compound::NbtCompound,
+    data::{ByteOrder, DeserializeCopy, Deserializeable, Serializeable},
+    nbt::{compound::NbtCompound, ArrayEncoding},
     version::Version,
 };
 
+#[cfg(feature = "crypto_shade")]
+pub mod crypto;
+
+pub mod migration;
+
+pub mod save;
+
+#[cfg(feature = "signed_shade")]
+pub mod signed;
+
 #[derive(Clone, Debug)]
 /// The header of a ShadeNBT File
 pub struct ShadeFile {
@@ -33,14 +39,23 @@ pub mod consts {
     use crate::version::Version;
 
     /// The current version of the Shade file format
-    pub const SHADE_VERSION: Version = Version::from_encoded(0x0004);
+    pub const SHADE_VERSION: Version = Version::from_encoded(0x0005);
 
     pub(crate) const SHADE_FLAGS_VERSION: Version = Version::from_encoded(0x0002);
 
+    /// The first version in which NBT arrays are prefixed by their length.
+    /// Earlier versions wrote arrays as their elements alone, which could not be read back
+    pub(crate) const SHADE_ARRAY_LENGTH_VERSION: Version = Version::from_encoded(0x0005);
+
     pub(crate) const SHADE_FLAGS_MASK: u8 = 0xA0;
 
     pub(crate) const SHADE_FLAGS_ACCEPTED_MASK: u8 = 0xE0;
 
+    ///
+    /// The flag set on CryptoShade files which are encrypted with a key rather than a password.
+    /// The UUID of the key immediately follows the flags.
+    pub const CRYPTO_KEYED_FLAG: u8 = 0x10;
+
     ///
     /// The magic number for a ShadeNBT file: "\xADNBT" or [AD 4E 42 54]
     pub const SHADE_MAGIC: [u8; 4] = [0xAD, 0x4E, 0x42, 0x54];
@@ -48,6 +63,20 @@ pub mod consts {
     ///
     /// The magic number for a CryptoShade file: "\xECNBT" or [EC 4E 42 54]
     pub const CRYPTO_MAGIC: [u8; 4] = [0xEC, 0x4E, 0x42, 0x54];
+
+    ///
+    /// The magic number for a Signed Shade file: "\xD5NBT" or [D5 4E 42 54]
+    pub const SIGNED_MAGIC: [u8; 4] = [0xD5, 0x4E, 0x42, 0x54];
+}
+
+// Files from before arrays were length-prefixed are read with empty arrays, as they always have been.
+// Writing a non-empty array at such a version is an error, rather than producing an unreadable file
+pub(crate) fn array_encoding(version: Version) -> ArrayEncoding {
+    if version < consts::SHADE_ARRAY_LENGTH_VERSION {
+        ArrayEncoding::Legacy
+    } else {
+        ArrayEncoding::Prefixed
+    }
 }
 
 impl ShadeFile {
@@ -104,7 +133,7 @@ impl ShadeFile {
         } else {
             Self {
                 magic: consts::SHADE_MAGIC,
-                version: version,
+                version,
                 flags: if byte_order == ByteOrder::LittleEndian {
                     0x80
                 } else {
@@ -137,78 +166,7 @@ impl ShadeFile {
         passwd: &[u8],
         input: &mut R,
     ) -> std::io::Result<Self> {
-        let magic = <[u8; 4]>::deserialize_copy(input)?;
-        if magic != consts::SHADE_MAGIC {
-            return Err(std::io::Error::new(
-                ErrorKind::InvalidData,
-                "Invalid magic (not a shade file)",
-            ));
-        }
-        let version = Version::deserialize_copy(input)?;
-        if consts::SHADE_VERSION < version {
-            return Err(std::io::Error::new(
-                ErrorKind::InvalidData,
-                format!("Version {} is not implemetented", version),
-            ));
-        }
-        let mut flags;
-        if consts::SHADE_FLAGS_VERSION < version {
-            flags = u8::deserialize_copy(input)?;
-            if (flags & !consts::SHADE_FLAGS_ACCEPTED_MASK) != 0 {
-                return Err(std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    "Invalid flags in mask",
-                ));
-            }
-            flags &= consts::SHADE_FLAGS_MASK;
-        } else {
-            flags = 0;
-        }
-
-        if flags & 0x80 == 0 {
-            input.set_byte_order(ByteOrder::LittleEndian)
-        } else {
-            input.set_byte_order(ByteOrder::BigEndian)
-        }
-
-        let _ = u16::deserialize_copy(input)?;
-        let salt = <[u8; 32]>::deserialize_copy(input)?;
-        let iv = <[u8; 16]>::deserialize_copy(input)?;
-        let check = <[u8; 32]>::deserialize_copy(input)?;
-        let mut check_input = Zeroizing::new(Vec::with_capacity(passwd.len() + 8));
-        check_input.extend_from_slice(passwd);
-        check_input.extend_from_slice(&salt[..8]);
-        let mut check_output = Zeroizing::new(openssl::sha::sha256(&*check_input));
-        if !openssl::memcmp::eq(&*check_output, &check) {
-            return Err(std::io::Error::new(
-                ErrorKind::Other,
-                "Password Check Failed",
-            ));
-        }
-
-        check_input = Zeroizing::new(Vec::with_capacity(passwd.len() + 32));
-        check_input.extend_from_slice(passwd);
-        check_input.extend_from_slice(&salt);
-        check_output = Zeroizing::new(openssl::sha::sha256(&*check_input));
-
-        let reader =
-            cryptostream::read::Decryptor::new(input, Cipher::aes_256_cbc(), &*check_output, &iv)
-                .map_err(|e| std::io::Error::new(ErrorKind::Other, e))?;
-
-        let mode;
-        if (flags & 0x80) != 0 {
-            mode = ByteOrder::LittleEndian
-        } else {
-            mode = ByteOrder::BigEndian
-        }
-        let mut input = DataInputStream::new(reader, mode);
-        let compound = NbtCompound::deserialize_copy(&mut input)?;
-        Ok(Self {
-            magic,
-            version,
-            flags,
-            compound,
-        })
+        Self::read_encrypted_with(crypto::KeySource::Password(passwd), input)
     }
 
     ///
@@ -219,60 +177,13 @@ impl ShadeFile {
         passwd: &[u8],
         output: &mut W,
     ) -> std::io::Result<()> {
-        consts::CRYPTO_MAGIC.serialize(output)?;
-        self.version.serialize(output)?;
-        if consts::SHADE_FLAGS_VERSION < self.version {
-            self.flags.serialize(output)?;
-        }
-
-        let mut salt = [0u8; 32];
-        openssl::rand::rand_bytes(&mut salt)
-            .map_err(|e| std::io::Error::new(ErrorKind::Other, e))?;
-        let mut iv = [0u8; 16];
-        openssl::rand::rand_bytes(&mut iv).map_err(|e| std::io::Error::new(ErrorKind::Other, e))?;
-        let mut check_input = Zeroizing::new(Vec::with_capacity(passwd.len() + 8));
-        check_input.extend_from_slice(passwd);
-        check_input.extend_from_slice(&salt[..8]);
-        let check = openssl::sha::sha256(&*check_input);
-
-        check_input = Zeroizing::new(Vec::with_capacity(passwd.len() + 32));
-        check_input.extend_from_slice(passwd);
-        check_input.extend_from_slice(&salt);
-        let check_output = Zeroizing::new(openssl::sha::sha256(&*check_input));
-        let order;
-        if self.flags & 0x80 == 0 {
-            order = ByteOrder::LittleEndian
-        } else {
-            order = ByteOrder::BigEndian
-        }
-        let mut out_vec = Vec::<u8>::new();
-        {
-            let mut encryptor = cryptostream::write::Encryptor::new(
-                &mut out_vec,
-                Cipher::aes_256_cbc(),
-                &*check_output,
-                &iv,
-            )
-            .map_err(|e| std::io::Error::new(ErrorKind::Other, e))?;
-            let mut output = DataOutputStream::new(&mut encryptor, order);
-            self.compound.serialize(&mut output)?;
-            encryptor
-                .finish()
-                .map_err(|e| std::io::Error::new(ErrorKind::Other, e))?;
-        }
-        let num_blocks = out_vec.len() / 16;
-        if num_blocks > (u16::MAX as usize) {
-            return Err(std::io::Error::new(
-                ErrorKind::InvalidData,
-                OutOfRange(num_blocks),
-            ));
-        }
+        self.write_encrypted_with(crypto::KeySource::Password(passwd), output)
+    }
+}
 
-        (num_blocks as u16).serialize(output)?;
-        salt.serialize(output)?;
-        iv.serialize(output)?;
-        check.serialize(output)?;
-        output.write_all(&out_vec)
+impl Default for ShadeFile {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -297,8 +208,7 @@ impl Serializeable for ShadeFile {
     ) -> std::io::Result<()> {
         #[cfg(feature = "crypto_shade")]
         if self.magic == consts::CRYPTO_MAGIC {
-            return Err(std::io::Error::new(
-                ErrorKind::Other,
+            return Err(std::io::Error::other(
                 "Cannot serialize a CryptoShade file as a ShadeNBT file",
             ));
         }
@@ -312,7 +222,8 @@ impl Serializeable for ShadeFile {
         } else {
             output.set_byte_order(ByteOrder::BigEndian)
         }
-        self.compound.serialize(output)
+        self.compound
+            .write_with(output, array_encoding(self.version))
     }
 }
 
@@ -350,7 +261,8 @@ impl Deserializeable for ShadeFile {
         } else {
             output.set_byte_order(ByteOrder::BigEndian)
         }
-        self.compound.deserialize(output)
+        self.compound = NbtCompound::read_with(output, array_encoding(self.version))?;
+        Ok(())
     }
 }
 
@@ -392,7 +304,7 @@ impl DeserializeCopy for ShadeFile {
             input.set_byte_order(ByteOrder::BigEndian)
         }
 
-        let compound = NbtCompound::deserialize_copy(input)?;
+        let compound = NbtCompound::read_with(input, array_encoding(version))?;
 
         Ok(Self {
             magic,
@@ -0,0 +1,340 @@
+//!
+//! Key management for CryptoShade files.
+//!
+//! CryptoShade files can be encrypted with a password, with a raw 256-bit key,
+//!  or with a key obtained from a [`KeyProvider`].
+//! Files encrypted with a key (rather than a password) set the [`CRYPTO_KEYED_FLAG`] flag,
+//!  and store the UUID of the key in the header, so that the key can be found again when the file is read.
+//!
+//!
+//! As with ShadeNBT files, everything after the flags is written in little endian byte order unless the 0x80 flag is set,
+//!  so files written by earlier versions of this crate remain readable.
+//!
+//! [`CRYPTO_KEYED_FLAG`]: super::consts::CRYPTO_KEYED_FLAG
+
+use std::{collections::HashMap, io::ErrorKind};
+
+use openssl::symm::Cipher;
+use zeroize::Zeroizing;
+
+use crate::{
+    data::{
+        ByteOrder, DataInput, DataInputStream, DataOutput, DataOutputStream, DeserializeCopy,
+        OutOfRange, Serializeable,
+    },
+    nbt::compound::NbtCompound,
+    uuid::UUID,
+    version::Version,
+};
+
+use super::{consts, ShadeFile};
+
+///
+/// A 256-bit key used to encrypt CryptoShade files
+pub type RawKey = Zeroizing<[u8; 32]>;
+
+///
+/// A source of keys for CryptoShade files, such as a keystore
+pub trait KeyProvider {
+    ///
+    /// Returns the key with the given id, or None if the key is not known
+    fn key(&self, key_id: UUID) -> Option<RawKey>;
+
+    ///
+    /// Returns the id and value of the key that new files should be encrypted with
+    fn current_key(&self) -> std::io::Result<(UUID, RawKey)>;
+}
+
+impl<K: KeyProvider + ?Sized> KeyProvider for &K {
+    fn key(&self, key_id: UUID) -> Option<RawKey> {
+        K::key(self, key_id)
+    }
+
+    fn current_key(&self) -> std::io::Result<(UUID, RawKey)> {
+        K::current_key(self)
+    }
+}
+
+///
+/// A simple in-memory KeyProvider.
+/// New files are encrypted with the most recently added key, which allows keys to be rotated
+///  while files encrypted with older keys remain readable.
+#[derive(Default)]
+pub struct KeyRing {
+    keys: HashMap<UUID, RawKey>,
+    current: Option<UUID>,
+}
+
+impl KeyRing {
+    ///
+    /// Creates a new, empty, KeyRing
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Adds a key to the KeyRing, and makes it the current key
+    pub fn insert(&mut self, key_id: UUID, key: RawKey) {
+        self.keys.insert(key_id, key);
+        self.current = Some(key_id);
+    }
+
+    ///
+    /// Removes a key from the KeyRing. Files encrypted with that key can no longer be read using this KeyRing
+    pub fn remove(&mut self, key_id: UUID) -> Option<RawKey> {
+        if self.current == Some(key_id) {
+            self.current = None;
+        }
+        self.keys.remove(&key_id)
+    }
+
+    ///
+    /// Generates a random key, adds it to the KeyRing, and makes it the current key
+    pub fn generate(&mut self, key_id: UUID) -> std::io::Result<()> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        openssl::rand::rand_bytes(&mut *key).map_err(std::io::Error::other)?;
+        self.insert(key_id, key);
+        Ok(())
+    }
+}
+
+impl KeyProvider for KeyRing {
+    fn key(&self, key_id: UUID) -> Option<RawKey> {
+        self.keys.get(&key_id).cloned()
+    }
+
+    fn current_key(&self) -> std::io::Result<(UUID, RawKey)> {
+        self.current
+            .and_then(|id| self.key(id).map(|key| (id, key)))
+            .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "No current key"))
+    }
+}
+
+///
+/// The key material used to encrypt or decrypt a CryptoShade file
+#[derive(Clone, Copy)]
+pub enum KeySource<'a> {
+    ///
+    /// A password. Files encrypted with a password have no key id
+    Password(&'a [u8]),
+    ///
+    /// A raw 256-bit key, with the id stored in the file header
+    Key(UUID, &'a [u8; 32]),
+    ///
+    /// A KeyProvider. When writing, the current key of the provider is used.
+    /// When reading, the key named in the file header is used
+    Provider(&'a dyn KeyProvider),
+}
+
+enum Secret<'a> {
+    Borrowed(&'a [u8]),
+    Owned(RawKey),
+}
+
+impl Secret<'_> {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Secret::Borrowed(b) => b,
+            Secret::Owned(k) => &**k,
+        }
+    }
+}
+
+// Matches the Serializeable impl of ShadeFile, which CryptoShade files have always followed
+fn stream_order(flags: u8) -> ByteOrder {
+    if flags & 0x80 == 0 {
+        ByteOrder::LittleEndian
+    } else {
+        ByteOrder::BigEndian
+    }
+}
+
+fn derive(secret: &[u8], salt: &[u8]) -> Zeroizing<[u8; 32]> {
+    let mut input = Zeroizing::new(Vec::with_capacity(secret.len() + salt.len()));
+    input.extend_from_slice(secret);
+    input.extend_from_slice(salt);
+    Zeroizing::new(openssl::sha::sha256(&input))
+}
+
+impl ShadeFile {
+    ///
+    /// Reads and decrypts a CryptoShade file with the given key source
+    pub fn read_encrypted_with<R: DataInput + ?Sized>(
+        source: KeySource,
+        input: &mut R,
+    ) -> std::io::Result<Self> {
+        let magic = <[u8; 4]>::deserialize_copy(input)?;
+        if magic != consts::CRYPTO_MAGIC {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Invalid magic (not a cryptoshade file)",
+            ));
+        }
+        let version = Version::deserialize_copy(input)?;
+        if consts::SHADE_VERSION < version {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Version {} is not implemetented", version),
+            ));
+        }
+        let mut flags;
+        if consts::SHADE_FLAGS_VERSION < version {
+            flags = u8::deserialize_copy(input)?;
+            if (flags & !(consts::SHADE_FLAGS_ACCEPTED_MASK | consts::CRYPTO_KEYED_FLAG)) != 0 {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Invalid flags in mask",
+                ));
+            }
+        } else {
+            flags = 0;
+        }
+
+        let order = stream_order(flags);
+        input.set_byte_order(order);
+
+        let key_id = if (flags & consts::CRYPTO_KEYED_FLAG) != 0 {
+            Some(UUID::deserialize_copy(input)?)
+        } else {
+            None
+        };
+        flags &= consts::SHADE_FLAGS_MASK;
+
+        let secret = match (source, key_id) {
+            (KeySource::Password(passwd), None) => Secret::Borrowed(passwd),
+            (KeySource::Key(id, key), Some(key_id)) if id == key_id => Secret::Borrowed(key),
+            (KeySource::Key(_, _), Some(key_id)) => {
+                return Err(std::io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("File is encrypted with key {}", key_id),
+                ))
+            }
+            (KeySource::Provider(provider), Some(key_id)) => {
+                Secret::Owned(provider.key(key_id).ok_or_else(|| {
+                    std::io::Error::new(ErrorKind::NotFound, format!("Unknown key {}", key_id))
+                })?)
+            }
+            (KeySource::Password(_), Some(_)) => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "File is encrypted with a key, not a password",
+                ))
+            }
+            (_, None) => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "File is encrypted with a password, not a key",
+                ))
+            }
+        };
+        let secret = secret.as_bytes();
+
+        let _ = u16::deserialize_copy(input)?;
+        let salt = <[u8; 32]>::deserialize_copy(input)?;
+        let iv = <[u8; 16]>::deserialize_copy(input)?;
+        let check = <[u8; 32]>::deserialize_copy(input)?;
+        if !openssl::memcmp::eq(&*derive(secret, &salt[..8]), &check) {
+            return Err(std::io::Error::other("Password Check Failed"));
+        }
+
+        let key = derive(secret, &salt);
+        let reader = cryptostream::read::Decryptor::new(input, Cipher::aes_256_cbc(), &*key, &iv)
+            .map_err(std::io::Error::other)?;
+
+        let mut input = DataInputStream::new(reader, order);
+        let compound = NbtCompound::read_with(&mut input, super::array_encoding(version))?;
+        Ok(Self {
+            magic: consts::SHADE_MAGIC,
+            version,
+            flags,
+            compound,
+        })
+    }
+
+    ///
+    /// Writes an encrypted CryptoShade file with the given key source
+    pub fn write_encrypted_with<W: DataOutput + ?Sized>(
+        &self,
+        source: KeySource,
+        output: &mut W,
+    ) -> std::io::Result<()> {
+        let (key_id, secret) = match source {
+            KeySource::Password(passwd) => (None, Secret::Borrowed(passwd)),
+            KeySource::Key(id, key) => (Some(id), Secret::Borrowed(key)),
+            KeySource::Provider(provider) => {
+                let (id, key) = provider.current_key()?;
+                (Some(id), Secret::Owned(key))
+            }
+        };
+        let secret = secret.as_bytes();
+        let mut flags = self.flags;
+        if key_id.is_some() {
+            if self.version <= consts::SHADE_FLAGS_VERSION {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Shade Version {} does not support key encryption",
+                        self.version
+                    ),
+                ));
+            }
+            flags |= consts::CRYPTO_KEYED_FLAG;
+        }
+
+        consts::CRYPTO_MAGIC.serialize(output)?;
+        self.version.serialize(output)?;
+        if consts::SHADE_FLAGS_VERSION < self.version {
+            flags.serialize(output)?;
+        }
+        let order = stream_order(flags);
+        output.set_byte_order(order);
+        key_id.serialize(output)?;
+
+        let mut salt = [0u8; 32];
+        openssl::rand::rand_bytes(&mut salt).map_err(std::io::Error::other)?;
+        let mut iv = [0u8; 16];
+        openssl::rand::rand_bytes(&mut iv).map_err(std::io::Error::other)?;
+        let check = derive(secret, &salt[..8]);
+        let key = derive(secret, &salt);
+
+        let mut out_vec = Vec::<u8>::new();
+        {
+            let mut encryptor = cryptostream::write::Encryptor::new(
+                &mut out_vec,
+                Cipher::aes_256_cbc(),
+                &*key,
+                &iv,
+            )
+            .map_err(std::io::Error::other)?;
+            let mut output = DataOutputStream::new(&mut encryptor, order);
+            self.compound
+                .write_with(&mut output, super::array_encoding(self.version))?;
+            encryptor.finish().map_err(std::io::Error::other)?;
+        }
+        let num_blocks = out_vec.len() / 16;
+        if num_blocks > (u16::MAX as usize) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                OutOfRange(num_blocks),
+            ));
+        }
+
+        (num_blocks as u16).serialize(output)?;
+        salt.serialize(output)?;
+        iv.serialize(output)?;
+        (*check).serialize(output)?;
+        output.write_all(&out_vec)
+    }
+
+    ///
+    /// Re-encrypts a CryptoShade file under a new key source, without returning the decrypted compound to the caller.
+    /// This can be used to rotate the key of a file, or to convert a password-encrypted file into a key-encrypted one.
+    pub fn rekey<R: DataInput + ?Sized, W: DataOutput + ?Sized>(
+        old: KeySource,
+        new: KeySource,
+        input: &mut R,
+        output: &mut W,
+    ) -> std::io::Result<()> {
+        Self::read_encrypted_with(old, input)?.write_encrypted_with(new, output)
+    }
+}
@@ -0,0 +1,421 @@
+//!
+//! Upgrading old ShadeNBT files.
+//!
+//! A [`Migrations`] registry holds two chains of transformations over the root compound of a file:
+//! * Format migrations, keyed by the Shade [`Version`] of the file, which upgrade files towards [`SHADE_VERSION`].
+//! * Data migrations, keyed by the application-defined `DataVersion` element of the root compound,
+//!   which upgrade the schema of the game data stored in the file.
+//!
+//! Format migrations are applied first, followed by data migrations, in order of increasing version.
+//! Each chain must be unbroken: a file is only upgraded by the migration registered from its exact version,
+//!  and a file that no migration starts from is rejected rather than skipped ahead.
+//! Files which are already up to date are left untouched, including their `DataVersion`.
+//! Files should be stamped with the current data version by [`Migrations::stamp`] whenever they are saved.
+//! A file without a `DataVersion` is only upgraded from data version 0 if it predates data versions,
+//!  as set by [`Migrations::set_legacy_before`], and is otherwise assumed to already be at the current data version.
+//! [`Migrations::plan`] performs a dry run, and reports what a migration would change without modifying the file.
+//!
+//! [`SHADE_VERSION`]: super::consts::SHADE_VERSION
+
+use std::{
+    collections::BTreeMap,
+    fmt::{Debug, Display},
+    io::ErrorKind,
+};
+
+use crate::{
+    data::{DataInput, DeserializeCopy},
+    nbt::{compound::NbtCompound, NbtTag},
+    version::Version,
+};
+
+use super::{consts, ShadeFile};
+
+///
+/// The name of the element of the root compound which stores the application data version
+pub const DATA_VERSION_KEY: &str = "DataVersion";
+
+///
+/// A transformation applied to the root compound of a file
+pub type MigrationFn = Box<dyn Fn(&mut NbtCompound) -> std::io::Result<()> + Send + Sync>;
+
+struct Migration<V> {
+    to: V,
+    description: String,
+    apply: MigrationFn,
+}
+
+///
+/// A single migration that was (or would be) applied to a file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MigrationStep {
+    ///
+    /// A migration between two Shade format versions
+    Format {
+        ///
+        /// The version of the file before the migration
+        from: Version,
+        ///
+        /// The version of the file after the migration
+        to: Version,
+        ///
+        /// The description given when the migration was registered
+        description: String,
+    },
+    ///
+    /// A migration between two application data versions
+    Data {
+        ///
+        /// The data version of the file before the migration
+        from: i32,
+        ///
+        /// The data version of the file after the migration
+        to: i32,
+        ///
+        /// The description given when the migration was registered
+        description: String,
+    },
+}
+
+impl Display for MigrationStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Format {
+                from,
+                to,
+                description,
+            } => f.write_fmt(format_args!("format {} -> {}: {}", from, to, description)),
+            Self::Data {
+                from,
+                to,
+                description,
+            } => f.write_fmt(format_args!("data {} -> {}: {}", from, to, description)),
+        }
+    }
+}
+
+///
+/// A change made to an element of the root compound by a migration
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    ///
+    /// The element at the given path was added
+    Added(String),
+    ///
+    /// The element at the given path was removed
+    Removed(String),
+    ///
+    /// The element at the given path was modified
+    Modified(String),
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Added(path) => f.write_fmt(format_args!("+ {}", path)),
+            Self::Removed(path) => f.write_fmt(format_args!("- {}", path)),
+            Self::Modified(path) => f.write_fmt(format_args!("~ {}", path)),
+        }
+    }
+}
+
+///
+/// The result of migrating a file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    ///
+    /// The migrations applied, in order
+    pub steps: Vec<MigrationStep>,
+    ///
+    /// The elements changed by the migrations.
+    /// Paths into nested compounds are separated by `.`
+    pub changes: Vec<Change>,
+}
+
+impl MigrationReport {
+    ///
+    /// Checks if no migrations were applied
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+fn diff(path: &str, old: &NbtCompound, new: &NbtCompound, changes: &mut Vec<Change>) {
+    let name = |k: &str| {
+        if path.is_empty() {
+            k.to_string()
+        } else {
+            format!("{}.{}", path, k)
+        }
+    };
+    let mut keys = old.iter().map(|(k, _)| k).collect::<Vec<_>>();
+    keys.extend(new.iter().map(|(k, _)| k).filter(|k| !old.contains_key(*k)));
+    keys.sort();
+    for k in keys {
+        match (old.get(k), new.get(k)) {
+            (Some(NbtTag::Compound(a)), Some(NbtTag::Compound(b))) => diff(&name(k), a, b, changes),
+            (Some(a), Some(b)) if a != b => changes.push(Change::Modified(name(k))),
+            (Some(_), None) => changes.push(Change::Removed(name(k))),
+            (None, Some(_)) => changes.push(Change::Added(name(k))),
+            _ => {}
+        }
+    }
+}
+
+///
+/// A registry of migrations for ShadeNBT files
+pub struct Migrations {
+    format: BTreeMap<Version, Migration<Version>>,
+    data: BTreeMap<i32, Migration<i32>>,
+    data_version: Option<i32>,
+    legacy_before: Option<Version>,
+}
+
+impl Debug for Migrations {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Migrations")
+            .field("format", &self.format.keys().collect::<Vec<_>>())
+            .field("data", &self.data.keys().collect::<Vec<_>>())
+            .field("data_version", &self.data_version)
+            .field("legacy_before", &self.legacy_before)
+            .finish()
+    }
+}
+
+impl Default for Migrations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Migrations {
+    ///
+    /// Creates a new registry with no migrations, which does not check the data version of files
+    pub fn new() -> Self {
+        Self {
+            format: BTreeMap::new(),
+            data: BTreeMap::new(),
+            data_version: None,
+            legacy_before: None,
+        }
+    }
+
+    ///
+    /// Creates a new registry with no migrations, which upgrades files to the given data version
+    pub fn with_data_version(data_version: i32) -> Self {
+        Self {
+            data_version: Some(data_version),
+            ..Self::new()
+        }
+    }
+
+    ///
+    /// Returns the data version files are upgraded to, if any
+    pub fn data_version(&self) -> Option<i32> {
+        self.data_version
+    }
+
+    ///
+    /// Returns the Shade version before which files without a `DataVersion` predate data versions, if any
+    pub fn legacy_before(&self) -> Option<Version> {
+        self.legacy_before
+    }
+
+    ///
+    /// Sets the first Shade version whose files are stamped with their data version.
+    /// Files of older versions without a `DataVersion` predate data versions, and are upgraded from data version 0
+    pub fn set_legacy_before(&mut self, version: Version) {
+        self.legacy_before = Some(version);
+    }
+
+    ///
+    /// Sets the `DataVersion` of the file to the data version files are upgraded to, if any
+    pub fn stamp(&self, file: &mut ShadeFile) {
+        if let Some(version) = self.data_version {
+            file.compound
+                .insert(DATA_VERSION_KEY.to_string(), NbtTag::Int(version));
+        }
+    }
+
+    ///
+    /// Registers a migration from the `from` Shade version to the `to` Shade version.
+    /// Panics if `to` is not newer than `from`, if `to` is not supported, or if a migration from `from` is already registered
+    pub fn register_format<F>(&mut self, from: Version, to: Version, description: &str, f: F)
+    where
+        F: Fn(&mut NbtCompound) -> std::io::Result<()> + Send + Sync + 'static,
+    {
+        if to <= from {
+            panic!("Format migration from {} to {} does not upgrade", from, to)
+        } else if consts::SHADE_VERSION < to {
+            panic!("Shade Version {} is not implemented", to)
+        } else if self.format.contains_key(&from) {
+            panic!("Format migration from {} is already registered", from)
+        }
+        self.format.insert(
+            from,
+            Migration {
+                to,
+                description: description.to_string(),
+                apply: Box::new(f),
+            },
+        );
+    }
+
+    ///
+    /// Registers a migration from the `from` data version to the `to` data version.
+    /// Panics if `to` is not newer than `from`, or if a migration from `from` is already registered
+    pub fn register_data<F>(&mut self, from: i32, to: i32, description: &str, f: F)
+    where
+        F: Fn(&mut NbtCompound) -> std::io::Result<()> + Send + Sync + 'static,
+    {
+        if to <= from {
+            panic!("Data migration from {} to {} does not upgrade", from, to)
+        } else if self.data.contains_key(&from) {
+            panic!("Data migration from {} is already registered", from)
+        }
+        self.data.insert(
+            from,
+            Migration {
+                to,
+                description: description.to_string(),
+                apply: Box::new(f),
+            },
+        );
+    }
+
+    // Unstamped files which do not predate data versions were written by the current application
+    fn file_data_version(
+        &self,
+        file: &ShadeFile,
+        version: Version,
+        target: i32,
+    ) -> std::io::Result<i32> {
+        match file.compound.get(DATA_VERSION_KEY) {
+            None if matches!(self.legacy_before, Some(before) if version < before) => Ok(0),
+            None => Ok(target),
+            Some(NbtTag::Int(v)) => Ok(*v),
+            Some(tag) => Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} must be an Int, got {:?}",
+                    DATA_VERSION_KEY,
+                    tag.tag_type()
+                ),
+            )),
+        }
+    }
+
+    fn apply(&self, file: &mut ShadeFile) -> std::io::Result<Vec<MigrationStep>> {
+        let mut steps = Vec::new();
+
+        // Files are upgraded to the newest version any format migration produces
+        let target = self.format.values().map(|m| m.to).max();
+        let original = file.version;
+        let mut version = file.version;
+        while let Some(target) = target.filter(|target| version < *target) {
+            let migration = self.format.get(&version).ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "No migration from Shade version {} towards {}",
+                        version, target
+                    ),
+                )
+            })?;
+            (migration.apply)(&mut file.compound)?;
+            steps.push(MigrationStep::Format {
+                from: version,
+                to: migration.to,
+                description: migration.description.clone(),
+            });
+            version = migration.to;
+        }
+        file.version = version;
+
+        if let Some(target) = self.data_version {
+            let mut current = self.file_data_version(file, original, target)?;
+            if target < current {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Data version {} is newer than the supported version {}",
+                        current, target
+                    ),
+                ));
+            }
+            let from = current;
+            while current < target {
+                let migration = self.data.get(&current).ok_or_else(|| {
+                    std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("No migration from data version {}", current),
+                    )
+                })?;
+                if target < migration.to {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "Migration from data version {} overshoots the supported version {}",
+                            current, target
+                        ),
+                    ));
+                }
+                (migration.apply)(&mut file.compound)?;
+                steps.push(MigrationStep::Data {
+                    from: current,
+                    to: migration.to,
+                    description: migration.description.clone(),
+                });
+                current = migration.to;
+            }
+            if from != current {
+                file.compound
+                    .insert(DATA_VERSION_KEY.to_string(), NbtTag::Int(current));
+            }
+        }
+        Ok(steps)
+    }
+
+    ///
+    /// Upgrades the file in place, and returns the migrations which were applied and the changes they made.
+    /// If an error occurs, the file is left unmodified.
+    pub fn migrate(&self, file: &mut ShadeFile) -> std::io::Result<MigrationReport> {
+        let mut migrated = file.clone();
+        let report = self.plan_into(file, &mut migrated)?;
+        *file = migrated;
+        Ok(report)
+    }
+
+    ///
+    /// Performs a dry run of [`Migrations::migrate`], and reports what would change without modifying the file
+    pub fn plan(&self, file: &ShadeFile) -> std::io::Result<MigrationReport> {
+        let mut migrated = file.clone();
+        self.plan_into(file, &mut migrated)
+    }
+
+    fn plan_into(
+        &self,
+        file: &ShadeFile,
+        migrated: &mut ShadeFile,
+    ) -> std::io::Result<MigrationReport> {
+        let steps = self.apply(migrated)?;
+        let mut changes = Vec::new();
+        if !steps.is_empty() {
+            diff("", &file.compound, &migrated.compound, &mut changes);
+        }
+        Ok(MigrationReport { steps, changes })
+    }
+}
+
+impl ShadeFile {
+    ///
+    /// Reads a ShadeNBT file, and upgrades it with the given migrations
+    pub fn read_migrated<R: DataInput + ?Sized>(
+        migrations: &Migrations,
+        input: &mut R,
+    ) -> std::io::Result<(Self, MigrationReport)> {
+        let mut file = Self::deserialize_copy(input)?;
+        let report = migrations.migrate(&mut file)?;
+        Ok((file, report))
+    }
+}
@@ -0,0 +1,378 @@
+//!
+//! Crash-safe saving of ShadeNBT files.
+//!
+//! A [`SaveFile`] never writes to its destination directly.
+//! The file is first written in full to a temporary file in the same directory, which is synced to disk,
+//!  and is then atomically renamed over the destination. The previous contents of the destination are kept
+//!  as rotating backups (`<name>.1` being the newest), and loading falls back to the newest backup that can be read
+//!  if the destination is missing or corrupt.
+//!
+//! A save proceeds in the following order, and each step only starts once the previous one has completed:
+//! 1. The new contents are written to a temporary file with a unique name, and synced to disk.
+//!    Nothing else is touched until this succeeds, so a failed or interrupted write leaves the existing files intact.
+//! 2. The backups are rotated, oldest first, each with a single rename, so that `<name>.n` becomes `<name>.n+1`
+//!    and the oldest backup is dropped.
+//! 3. The destination is copied to another temporary file, which is synced and renamed to `<name>.1`.
+//! 4. The temporary file is renamed over the destination.
+//!
+//! The directory is synced after every rename. The rotation as a whole is not atomic, but every rename is,
+//!  so each file on disk is always a complete save, and the destination is replaced without ever being missing.
+//! A crash part way through a save may leave the new save in its temporary file, or the previous save in both the
+//!  destination and `<name>.1`. Temporary files left by other processes are removed by [`SaveFile::load_with`].
+//! Concurrent saves to the same destination do not interfere with each other's temporary files,
+//!  but their renames may interleave, so the destination holds whichever save was renamed last,
+//!  and backups may be rotated out by saves that are not the newest.
+//!
+//! A SaveFile can be given [`Migrations`], in which case every file it saves is stamped with the current data version,
+//!  and every file it loads is upgraded once it has been read.
+
+use std::{
+    borrow::Cow,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+#[cfg(feature = "crypto_shade")]
+use super::crypto::KeySource;
+use super::{
+    migration::{MigrationReport, Migrations},
+    ShadeFile,
+};
+use crate::data::{
+    ByteOrder, DataInput, DataInputStream, DataOutput, DataOutputStream, DeserializeCopy,
+    Serializeable,
+};
+
+///
+/// Where a loaded save was read from
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SaveSlot {
+    ///
+    /// The save was read from the destination file
+    Primary,
+    ///
+    /// The save was read from the nth backup, where 1 is the newest backup
+    Backup(usize),
+}
+
+///
+/// A value loaded from a SaveFile
+#[derive(Debug)]
+pub struct Loaded<T> {
+    ///
+    /// The loaded value
+    pub value: T,
+    ///
+    /// The file the value was loaded from
+    pub slot: SaveSlot,
+    ///
+    /// The errors encountered reading newer files before the value was loaded, newest first
+    pub errors: Vec<(SaveSlot, std::io::Error)>,
+    ///
+    /// The migrations applied to the value once it was read
+    pub migration: MigrationReport,
+}
+
+impl<T> Loaded<T> {
+    ///
+    /// Checks if the value was loaded from a backup, rather than the destination file
+    pub fn is_recovered(&self) -> bool {
+        self.slot != SaveSlot::Primary
+    }
+}
+
+///
+/// A destination for ShadeNBT files, which is written atomically and keeps backups of previous saves
+#[derive(Clone, Debug)]
+pub struct SaveFile {
+    path: PathBuf,
+    backups: usize,
+    migrations: Option<Arc<Migrations>>,
+}
+
+impl SaveFile {
+    ///
+    /// Creates a SaveFile which writes to path, and keeps no backups
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self::with_backups(path, 0)
+    }
+
+    ///
+    /// Creates a SaveFile which writes to path, and keeps the given number of backups
+    pub fn with_backups<P: Into<PathBuf>>(path: P, backups: usize) -> Self {
+        Self {
+            path: path.into(),
+            backups,
+            migrations: None,
+        }
+    }
+
+    ///
+    /// Upgrades files with the given migrations when they are loaded, and stamps their data version when they are saved
+    pub fn with_migrations(mut self, migrations: Arc<Migrations>) -> Self {
+        self.migrations = Some(migrations);
+        self
+    }
+
+    ///
+    /// Returns the path of the destination file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    ///
+    /// Returns the number of backups kept
+    pub fn backups(&self) -> usize {
+        self.backups
+    }
+
+    ///
+    /// Returns the migrations files are upgraded with, if any
+    pub fn migrations(&self) -> Option<&Migrations> {
+        self.migrations.as_deref()
+    }
+
+    fn with_suffix(&self, suffix: &str) -> PathBuf {
+        let mut name = self
+            .path
+            .file_name()
+            .map(OsString::from)
+            .unwrap_or_default();
+        name.push(suffix);
+        self.path.with_file_name(name)
+    }
+
+    ///
+    /// Returns the path of the nth backup, where 1 is the newest backup
+    pub fn backup_path(&self, n: usize) -> PathBuf {
+        self.with_suffix(&format!(".{}", n))
+    }
+
+    // Creates a temporary file next to the destination, which no other save in any process is using
+    fn create_temp(&self) -> std::io::Result<(PathBuf, File)> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        loop {
+            let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+            let path = self.with_suffix(&format!(".{}-{}.tmp", std::process::id(), n));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((path, file)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn slot_path(&self, slot: SaveSlot) -> PathBuf {
+        match slot {
+            SaveSlot::Primary => self.path.clone(),
+            SaveSlot::Backup(n) => self.backup_path(n),
+        }
+    }
+
+    ///
+    /// Writes the output of `write` to the destination atomically, rotating the existing backups.
+    /// If `write` fails, nothing on disk is modified. See the [module documentation](self) for the order of each step.
+    pub fn save_with<F>(&self, write: F) -> std::io::Result<()>
+    where
+        F: FnOnce(&mut dyn DataOutput) -> std::io::Result<()>,
+    {
+        let mut output = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+        write(&mut output)?;
+        let bytes = output.into_inner();
+
+        let (temp, mut file) = self.create_temp()?;
+        let result = file
+            .write_all(&bytes)
+            .and_then(|_| file.sync_all())
+            .and_then(|_| {
+                drop(file);
+                self.rotate()
+            })
+            .and_then(|_| self.rename(&temp, &self.path));
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        fs::rename(from, to)?;
+        self.sync_dir()
+    }
+
+    // A file may be missing because a concurrent save has already moved it, or because nothing was saved yet
+    fn rotate(&self) -> std::io::Result<()> {
+        if self.backups == 0 {
+            return Ok(());
+        }
+        for n in (1..self.backups).rev() {
+            match self.rename(&self.backup_path(n), &self.backup_path(n + 1)) {
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                r => r?,
+            }
+        }
+
+        let (temp, mut file) = self.create_temp()?;
+        let result = File::open(&self.path)
+            .and_then(|mut dest| std::io::copy(&mut dest, &mut file))
+            .and_then(|_| file.sync_all())
+            .and_then(|_| {
+                drop(file);
+                self.rename(&temp, &self.backup_path(1))
+            });
+        match result {
+            Err(e) => {
+                let _ = fs::remove_file(&temp);
+                if e.kind() == ErrorKind::NotFound {
+                    Ok(())
+                } else {
+                    Err(e)
+                }
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+
+    // Temporary files are named `<name>.<pid>-<n>.tmp`. A process which is still saving removes its own
+    //  temporary files, so only those of other processes, which crashed part way through a save, are removed
+    fn remove_stale_temps(&self) {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let prefix = format!(
+            "{}.",
+            self.path.file_name().unwrap_or_default().to_string_lossy()
+        );
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let pid = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(&prefix))
+                .and_then(|name| name.strip_suffix(".tmp"))
+                .and_then(|name| name.split_once('-'))
+                .filter(|(_, n)| n.parse::<u64>().is_ok())
+                .and_then(|(pid, _)| pid.parse::<u32>().ok());
+            if matches!(pid, Some(pid) if pid != std::process::id()) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self) -> std::io::Result<()> {
+        match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+            _ => File::open(".")?.sync_all(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn sync_dir(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    ///
+    /// Reads the destination with `read`, falling back to the newest backup which can be read if that fails.
+    /// Returns the error from the destination file if no file can be read.
+    /// Temporary files left behind by saves in other processes which did not complete are removed.
+    pub fn load_with<T, F>(&self, mut read: F) -> std::io::Result<Loaded<T>>
+    where
+        F: FnMut(&mut dyn DataInput) -> std::io::Result<T>,
+    {
+        self.remove_stale_temps();
+        let mut errors = Vec::new();
+        let slots =
+            std::iter::once(SaveSlot::Primary).chain((1..=self.backups).map(SaveSlot::Backup));
+        for slot in slots {
+            let result = fs::read(self.slot_path(slot)).and_then(|bytes| {
+                read(&mut DataInputStream::new(&bytes[..], ByteOrder::BigEndian))
+            });
+            match result {
+                Ok(value) => {
+                    return Ok(Loaded {
+                        value,
+                        slot,
+                        errors,
+                        migration: MigrationReport::default(),
+                    })
+                }
+                Err(e) => errors.push((slot, e)),
+            }
+        }
+        let (_, primary) = errors.swap_remove(0);
+        if primary.kind() == ErrorKind::NotFound {
+            Err(primary)
+        } else {
+            Err(std::io::Error::new(
+                primary.kind(),
+                format!(
+                    "Failed to load {} or any backup: {}",
+                    self.path.display(),
+                    primary
+                ),
+            ))
+        }
+    }
+
+    fn stamped<'a>(&self, file: &'a ShadeFile) -> Cow<'a, ShadeFile> {
+        match &self.migrations {
+            Some(migrations) if migrations.data_version().is_some() => {
+                let mut file = file.clone();
+                migrations.stamp(&mut file);
+                Cow::Owned(file)
+            }
+            _ => Cow::Borrowed(file),
+        }
+    }
+
+    // A file which cannot be migrated is not replaced by an older backup, which could discard newer data
+    fn migrated(&self, mut loaded: Loaded<ShadeFile>) -> std::io::Result<Loaded<ShadeFile>> {
+        if let Some(migrations) = &self.migrations {
+            loaded.migration = migrations.migrate(&mut loaded.value)?;
+        }
+        Ok(loaded)
+    }
+
+    ///
+    /// Atomically saves a ShadeNBT file
+    pub fn save(&self, file: &ShadeFile) -> std::io::Result<()> {
+        let file = self.stamped(file);
+        self.save_with(|output| file.serialize(output))
+    }
+
+    ///
+    /// Loads a ShadeNBT file, falling back to backups if necessary, and upgrades it with the migrations of this SaveFile
+    pub fn load(&self) -> std::io::Result<Loaded<ShadeFile>> {
+        self.load_with(|input| ShadeFile::deserialize_copy(input))
+            .and_then(|loaded| self.migrated(loaded))
+    }
+
+    ///
+    /// Atomically saves a CryptoShade file with the given key source
+    #[cfg(feature = "crypto_shade")]
+    pub fn save_encrypted(&self, file: &ShadeFile, source: KeySource) -> std::io::Result<()> {
+        let file = self.stamped(file);
+        self.save_with(|output| file.write_encrypted_with(source, output))
+    }
+
+    ///
+    /// Loads a CryptoShade file with the given key source, falling back to backups if necessary,
+    ///  and upgrades it with the migrations of this SaveFile
+    #[cfg(feature = "crypto_shade")]
+    pub fn load_encrypted(&self, source: KeySource) -> std::io::Result<Loaded<ShadeFile>> {
+        self.load_with(|input| ShadeFile::read_encrypted_with(source, input))
+            .and_then(|loaded| self.migrated(loaded))
+    }
+}
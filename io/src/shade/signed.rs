@@ -0,0 +1,227 @@
+//!
+//! Support for Signed ShadeNBT files.
+//!
+//! A Signed Shade file has the same header as a ShadeNBT file (with the magic [`SIGNED_MAGIC`]),
+//!  followed by the UUID of the signing key, an Ed25519 signature, and the length-prefixed body.
+//! The signature covers the header (including the key id) and the serialized body,
+//!  so any modification to the version, flags, key id, or compound is detected by [`ShadeFile::verify`].
+//!
+//! Signing and verification are performed entirely locally. Verifying keys are obtained from a [`KeyStore`].
+//!
+//! [`SIGNED_MAGIC`]: super::consts::SIGNED_MAGIC
+
+use std::{collections::HashMap, fmt::Display, io::ErrorKind};
+
+pub use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+
+use ed25519_dalek::Signer;
+
+use crate::{
+    data::{
+        read_bytes, ByteOrder, DataInput, DataInputStream, DataOutput, DataOutputStream,
+        DeserializeCopy, OutOfRange, Serializeable,
+    },
+    nbt::compound::NbtCompound,
+    uuid::UUID,
+    version::Version,
+};
+
+use super::{consts, ShadeFile};
+
+///
+/// A source of public keys used to verify Signed Shade files
+pub trait KeyStore {
+    ///
+    /// Returns the verifying key with the given id, or None if the key is not known
+    fn verifying_key(&self, key_id: UUID) -> Option<VerifyingKey>;
+}
+
+impl<K: KeyStore + ?Sized> KeyStore for &K {
+    fn verifying_key(&self, key_id: UUID) -> Option<VerifyingKey> {
+        K::verifying_key(self, key_id)
+    }
+}
+
+impl KeyStore for HashMap<UUID, VerifyingKey> {
+    fn verifying_key(&self, key_id: UUID) -> Option<VerifyingKey> {
+        self.get(&key_id).copied()
+    }
+}
+
+impl KeyStore for (UUID, VerifyingKey) {
+    fn verifying_key(&self, key_id: UUID) -> Option<VerifyingKey> {
+        if self.0 == key_id {
+            Some(self.1)
+        } else {
+            None
+        }
+    }
+}
+
+///
+/// The Error returned when a Signed Shade file cannot be read or verified
+#[derive(Debug)]
+pub enum VerifyError {
+    ///
+    /// The file could not be read, or is not a well-formed Signed Shade file
+    Io(std::io::Error),
+    ///
+    /// The file was signed by a key which is not known to the [`KeyStore`]
+    UnknownKey(UUID),
+    ///
+    /// The signature does not match the contents of the file.
+    /// The file has been modified since it was signed, or was not signed by the claimed key
+    BadSignature(UUID),
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => e.fmt(f),
+            Self::UnknownKey(id) => f.write_fmt(format_args!("Unknown signing key {}", id)),
+            Self::BadSignature(id) => {
+                f.write_fmt(format_args!("Signature verification failed for key {}", id))
+            }
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for VerifyError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<VerifyError> for std::io::Error {
+    fn from(e: VerifyError) -> Self {
+        match e {
+            VerifyError::Io(e) => e,
+            e => std::io::Error::new(ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+fn header_bytes(version: Version, flags: u8, key_id: UUID) -> std::io::Result<Vec<u8>> {
+    let mut header = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+    consts::SIGNED_MAGIC.serialize(&mut header)?;
+    version.serialize(&mut header)?;
+    if consts::SHADE_FLAGS_VERSION < version {
+        flags.serialize(&mut header)?;
+    }
+    key_id.serialize(&mut header)?;
+    Ok(header.into_inner())
+}
+
+impl ShadeFile {
+    ///
+    /// Writes a Signed Shade file, signed with `key`.
+    /// `key_id` is stored in the file, and is used by [`ShadeFile::verify`] to find the matching verifying key.
+    pub fn sign<W: DataOutput + ?Sized>(
+        &self,
+        key_id: UUID,
+        key: &SigningKey,
+        output: &mut W,
+    ) -> std::io::Result<()> {
+        let header = header_bytes(self.version, self.flags, key_id)?;
+        let mut body = DataOutputStream::new(Vec::new(), self.byte_order());
+        self.compound
+            .write_with(&mut body, super::array_encoding(self.version))?;
+        let body = body.into_inner();
+        if body.len() > (u32::MAX as usize) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                OutOfRange(body.len()),
+            ));
+        }
+
+        let mut message = Vec::with_capacity(header.len() + body.len());
+        message.extend_from_slice(&header);
+        message.extend_from_slice(&body);
+        let signature = key.sign(&message);
+
+        output.set_byte_order(ByteOrder::BigEndian);
+        output.write_bytes(&header)?;
+        signature.to_bytes().serialize(output)?;
+        (body.len() as u32).serialize(output)?;
+        output.write_bytes(&body)
+    }
+
+    ///
+    /// Reads a Signed Shade file, and verifies its signature with the key named in the file.
+    /// The compound is only parsed after the signature has been verified.
+    pub fn verify<K: KeyStore + ?Sized, R: DataInput + ?Sized>(
+        keys: &K,
+        input: &mut R,
+    ) -> Result<Self, VerifyError> {
+        Self::verify_with_key_id(keys, input).map(|(_, file)| file)
+    }
+
+    ///
+    /// Reads and verifies a Signed Shade file as with [`ShadeFile::verify`], and also returns the id of the key which signed it
+    pub fn verify_with_key_id<K: KeyStore + ?Sized, R: DataInput + ?Sized>(
+        keys: &K,
+        input: &mut R,
+    ) -> Result<(UUID, Self), VerifyError> {
+        input.set_byte_order(ByteOrder::BigEndian);
+        let magic = <[u8; 4]>::deserialize_copy(input)?;
+        if magic != consts::SIGNED_MAGIC {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Invalid magic (not a signed shade file)",
+            )
+            .into());
+        }
+        let version = Version::deserialize_copy(input)?;
+        if consts::SHADE_VERSION < version {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Version {} is not implemetented", version),
+            )
+            .into());
+        }
+        let mut flags;
+        if consts::SHADE_FLAGS_VERSION < version {
+            flags = u8::deserialize_copy(input)?;
+            if (flags & !consts::SHADE_FLAGS_ACCEPTED_MASK) != 0 {
+                return Err(
+                    std::io::Error::new(ErrorKind::InvalidData, "Invalid flags in mask").into(),
+                );
+            }
+        } else {
+            flags = 0;
+        }
+        let key_id = UUID::deserialize_copy(input)?;
+        let signature = Signature::from_bytes(&<[u8; 64]>::deserialize_copy(input)?);
+        let len = u32::deserialize_copy(input)? as usize;
+
+        let key = keys
+            .verifying_key(key_id)
+            .ok_or(VerifyError::UnknownKey(key_id))?;
+
+        let mut message = header_bytes(version, flags, key_id)?;
+        let header_len = message.len();
+        message.append(&mut read_bytes(input, len)?);
+        key.verify_strict(&message, &signature)
+            .map_err(|_| VerifyError::BadSignature(key_id))?;
+
+        flags &= consts::SHADE_FLAGS_MASK;
+        let mut file = Self {
+            magic: consts::SHADE_MAGIC,
+            version,
+            flags,
+            compound: NbtCompound::new(),
+        };
+        let mut body = DataInputStream::new(&message[header_len..], file.byte_order());
+        file.compound = NbtCompound::read_with(&mut body, super::array_encoding(version))?;
+        Ok((key_id, file))
+    }
+}
@@ -44,7 +44,7 @@ impl UUID {
         use rand::prelude::*;
         let (mut high, mut low) = thread_rng().gen();
         high = (high & !0xF000) | 0x4000;
-        low = low & !0x8000000000000000;
+        low &= !0x8000000000000000;
         UUID { low, high }
     }
 
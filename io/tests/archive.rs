@@ -0,0 +1,263 @@
+#![cfg(feature = "archive")]
+
+use std::{
+    cell::RefCell,
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    rc::Rc,
+};
+
+use binary_io::{
+    archive::{Archive, ArchiveStorage, Compression, RecordKey},
+    nbt::{compound::NbtCompound, NbtTag},
+    uuid::UUID,
+};
+
+fn record(n: i32, padding: usize) -> NbtCompound {
+    let mut compound = NbtCompound::new();
+    compound.insert("N".to_string(), NbtTag::Int(n));
+    compound.insert(
+        "Padding".to_string(),
+        NbtTag::ByteArray(vec![0xAA; padding].into()),
+    );
+    compound
+}
+
+fn key(n: i32) -> RecordKey {
+    RecordKey::Name(format!("record{}", n))
+}
+
+// Storage which can be inspected while an archive holds it, and which fails every write once a number of
+// writes have succeeded, as if the process stopped
+#[derive(Clone)]
+struct Shared {
+    inner: Rc<RefCell<Cursor<Vec<u8>>>>,
+    writes: usize,
+}
+
+impl Shared {
+    fn new(bytes: Vec<u8>, writes: usize) -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(Cursor::new(bytes))),
+            writes,
+        }
+    }
+
+    fn contents(&self) -> Vec<u8> {
+        self.inner.borrow().get_ref().clone()
+    }
+
+    fn interrupt(&mut self) -> std::io::Result<()> {
+        if self.writes == 0 {
+            return Err(std::io::Error::other("Interrupted"));
+        }
+        self.writes -= 1;
+        Ok(())
+    }
+}
+
+impl Read for Shared {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.borrow_mut().read(buf)
+    }
+}
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.interrupt()?;
+        self.inner.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Shared {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.borrow_mut().seek(pos)
+    }
+}
+
+impl ArchiveStorage for Shared {
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        self.interrupt()?;
+        self.inner.borrow_mut().set_len(len)
+    }
+}
+
+// An archive with free space between records, left by records that grew or were removed
+fn fragmented() -> Vec<u8> {
+    let mut archive = Archive::create(Cursor::new(Vec::new())).unwrap();
+    for n in 0..6 {
+        archive
+            .write(key(n), &record(n, 64), Compression::None)
+            .unwrap();
+    }
+    archive.flush().unwrap();
+    archive.remove(&key(1));
+    archive
+        .write(key(2), &record(2, 256), Compression::None)
+        .unwrap();
+    archive
+        .write(key(4), &record(4, 8), Compression::Zlib)
+        .unwrap();
+    archive.into_inner().unwrap().into_inner()
+}
+
+fn assert_records(bytes: Vec<u8>) {
+    let mut archive = Archive::open(Cursor::new(bytes)).unwrap();
+    assert_eq!(archive.len(), 5);
+    assert!(!archive.contains(&key(1)));
+    for (n, padding) in [(0, 64), (2, 256), (3, 64), (4, 8), (5, 64)] {
+        assert_eq!(archive.read(&key(n)).unwrap(), Some(record(n, padding)));
+    }
+}
+
+#[test]
+fn records_round_trip_through_storage() {
+    let storage = Shared::new(Vec::new(), usize::MAX);
+    let mut archive = Archive::create(storage.clone()).unwrap();
+    let player = UUID::new(1, 1);
+    archive
+        .write(player, &record(1, 16), Compression::Zlib)
+        .unwrap();
+    archive
+        .write("world", &record(2, 0), Compression::None)
+        .unwrap();
+    assert_eq!(archive.read(&player.into()).unwrap(), Some(record(1, 16)));
+
+    // Records are only visible to open once the archive is flushed
+    assert!(Archive::open(Cursor::new(storage.contents()))
+        .unwrap()
+        .is_empty());
+    archive.flush().unwrap();
+    let mut reopened = Archive::open(Cursor::new(storage.contents())).unwrap();
+    assert_eq!(reopened.len(), 2);
+    assert_eq!(reopened.read(&player.into()).unwrap(), Some(record(1, 16)));
+    assert_eq!(
+        reopened.read(&RecordKey::from("world")).unwrap(),
+        Some(record(2, 0))
+    );
+    assert_eq!(reopened.read(&RecordKey::from("missing")).unwrap(), None);
+}
+
+#[test]
+fn records_are_rewritten_in_place_or_moved() {
+    let mut archive = Archive::create(Cursor::new(Vec::new())).unwrap();
+    archive
+        .write(key(0), &record(0, 64), Compression::None)
+        .unwrap();
+    archive
+        .write(key(1), &record(1, 64), Compression::None)
+        .unwrap();
+    archive.flush().unwrap();
+    let len = archive.into_inner().unwrap().into_inner().len();
+
+    let mut archive = Archive::create(Cursor::new(Vec::new())).unwrap();
+    archive
+        .write(key(0), &record(0, 64), Compression::None)
+        .unwrap();
+    archive
+        .write(key(1), &record(1, 64), Compression::None)
+        .unwrap();
+    // A smaller record fits in the space of the old one
+    archive
+        .write(key(0), &record(0, 32), Compression::None)
+        .unwrap();
+    assert_eq!(archive.free_space(), 32);
+    // A larger record is moved, leaving free space behind it
+    archive
+        .write(key(0), &record(0, 128), Compression::None)
+        .unwrap();
+    assert!(archive.free_space() > 64);
+    let bytes = archive.into_inner().unwrap().into_inner();
+    assert!(bytes.len() > len);
+
+    let mut archive = Archive::open(Cursor::new(bytes)).unwrap();
+    assert_eq!(archive.read(&key(0)).unwrap(), Some(record(0, 128)));
+    assert_eq!(archive.read(&key(1)).unwrap(), Some(record(1, 64)));
+}
+
+#[test]
+fn compaction_removes_free_space() {
+    let bytes = fragmented();
+    let len = bytes.len();
+    let mut archive = Archive::open(Cursor::new(bytes)).unwrap();
+    assert!(archive.free_space() > 0);
+    archive.compact().unwrap();
+    assert_eq!(archive.free_space(), 0);
+    let bytes = archive.into_inner().unwrap().into_inner();
+    assert!(bytes.len() < len);
+    assert_records(bytes);
+}
+
+#[test]
+fn interrupted_compaction_leaves_a_readable_archive() {
+    let original = fragmented();
+    for writes in 0.. {
+        let storage = Shared::new(original.clone(), writes);
+        let mut archive = Archive::open(storage.clone()).unwrap();
+        let result = archive.compact();
+        // Whatever was written before the interruption, the archive still holds every record
+        assert_records(storage.contents());
+        if result.is_ok() {
+            assert!(storage.contents().len() < original.len());
+            break;
+        }
+    }
+}
+
+#[test]
+fn interrupted_flush_leaves_the_old_or_new_records() {
+    let original = fragmented();
+    for writes in 0.. {
+        let storage = Shared::new(original.clone(), writes);
+        let mut archive = Archive::open(storage.clone()).unwrap();
+        // Both fit in the space the flushed records occupy, but must not overwrite them
+        let result = archive
+            .write(key(0), &record(10, 64), Compression::None)
+            .and_then(|_| archive.write(key(3), &record(13, 8), Compression::None))
+            .and_then(|_| archive.flush());
+        let mut reopened = Archive::open(Cursor::new(storage.contents())).unwrap();
+        let records = (
+            reopened.read(&key(0)).unwrap().unwrap(),
+            reopened.read(&key(3)).unwrap().unwrap(),
+        );
+        if records != (record(10, 64), record(13, 8)) {
+            assert!(result.is_err());
+            assert_records(storage.contents());
+        }
+        if result.is_ok() {
+            break;
+        }
+    }
+}
+
+#[test]
+fn corrupt_archives_are_rejected() {
+    let mut bytes = fragmented();
+    bytes[0] = 0;
+    assert!(Archive::open(Cursor::new(bytes)).is_err());
+
+    // A header which points past the end of the storage
+    let mut bytes = fragmented();
+    bytes[6..14].copy_from_slice(&u64::MAX.to_be_bytes());
+    assert!(Archive::open(Cursor::new(bytes)).is_err());
+
+    let mut bytes = fragmented();
+    bytes.truncate(10);
+    assert!(Archive::open(Cursor::new(bytes)).is_err());
+
+    // A record whose capacity and length extend past the end of the storage
+    let mut archive = Archive::create(Cursor::new(Vec::new())).unwrap();
+    archive
+        .write(UUID::new(1, 1), &record(1, 0), Compression::None)
+        .unwrap();
+    let mut bytes = archive.into_inner().unwrap().into_inner();
+    let mut index = [0; 8];
+    index.copy_from_slice(&bytes[6..14]);
+    // They follow the count of records, and the key and offset of the record
+    let capacity = u64::from_be_bytes(index) as usize + 4 + 17 + 8;
+    bytes[capacity..capacity + 8].copy_from_slice(&[0xFF; 8]);
+    assert!(Archive::open(Cursor::new(bytes)).is_err());
+}
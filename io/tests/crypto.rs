@@ -0,0 +1,183 @@
+#![cfg(feature = "crypto_shade")]
+
+use std::io::ErrorKind;
+
+use binary_io::{
+    data::{ByteOrder, DataInputStream, DataOutputStream, Serializeable},
+    nbt::NbtTag,
+    shade::{
+        consts,
+        crypto::{KeyRing, KeySource},
+        ShadeFile,
+    },
+    uuid::UUID,
+};
+use zeroize::Zeroizing;
+
+const OLD_KEY: UUID = UUID::new(1, 1);
+const NEW_KEY: UUID = UUID::new(1, 2);
+
+fn file(order: ByteOrder) -> ShadeFile {
+    let mut file = ShadeFile::with_byte_order(order);
+    file.insert("Name".to_string(), NbtTag::String("Misty".to_string()));
+    file.insert("Money".to_string(), NbtTag::Int(0x0102_0304));
+    file
+}
+
+fn write(file: &ShadeFile, source: KeySource) -> std::io::Result<Vec<u8>> {
+    let mut out = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+    file.write_encrypted_with(source, &mut out)?;
+    Ok(out.into_inner())
+}
+
+fn read(bytes: &[u8], source: KeySource) -> std::io::Result<ShadeFile> {
+    ShadeFile::read_encrypted_with(
+        source,
+        &mut DataInputStream::new(bytes, ByteOrder::BigEndian),
+    )
+}
+
+// The decrypted file is a plain ShadeNBT file, with the same contents as the one that was encrypted
+fn assert_same(decrypted: &ShadeFile, original: &ShadeFile) {
+    assert_eq!(**decrypted, **original);
+    assert_eq!(decrypted.byte_order(), original.byte_order());
+    assert_eq!(decrypted.version(), original.version());
+    let mut out = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+    decrypted.serialize(&mut out).unwrap();
+    assert_eq!(out.into_inner()[..4], consts::SHADE_MAGIC);
+}
+
+fn ring() -> KeyRing {
+    let mut ring = KeyRing::new();
+    ring.insert(OLD_KEY, Zeroizing::new([1; 32]));
+    ring.insert(NEW_KEY, Zeroizing::new([2; 32]));
+    ring
+}
+
+#[test]
+fn password_files_round_trip_in_both_byte_orders() {
+    for order in [ByteOrder::BigEndian, ByteOrder::LittleEndian] {
+        let original = file(order);
+        let bytes = write(&original, KeySource::Password(b"hunter2")).unwrap();
+        assert_eq!(bytes[..4], consts::CRYPTO_MAGIC);
+        let decrypted = read(&bytes, KeySource::Password(b"hunter2")).unwrap();
+        assert_same(&decrypted, &original);
+
+        let mut legacy = Vec::new();
+        ShadeFile::read_encrypted(b"hunter2", &mut DataInputStream::new(&*bytes, order))
+            .unwrap()
+            .write_encrypted(b"hunter2", &mut DataOutputStream::new(&mut legacy, order))
+            .unwrap();
+        assert_same(
+            &read(&legacy, KeySource::Password(b"hunter2")).unwrap(),
+            &original,
+        );
+    }
+}
+
+// Written by the original password-only implementation, which stores the compound in little endian
+// when the 0x80 flag is clear
+const LEGACY_FILE: &str = "ec4e425400040000021d586cf03504135ad4e99a14fc3447ffe3b45d97800c5b903950490dbe4639\
+                           6951b0abb7b697b6c69a5f596837038e4912e270e0648ca757ceaa87187530d2b8428a6f45cf49\
+                           f5bb089c8d5bea49135b7a95e2ab1b80d7a10f9cade1d33f5f9e773b5183ac795100dce599f8f8\
+                           92ed27";
+
+#[test]
+fn files_from_the_password_only_format_are_readable() {
+    let bytes = (0..LEGACY_FILE.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&LEGACY_FILE[i..i + 2], 16).unwrap())
+        .collect::<Vec<_>>();
+    let file = read(&bytes, KeySource::Password(b"hunter2")).unwrap();
+    assert_eq!(file.get("Name"), Some(&NbtTag::String("Misty".to_string())));
+    assert_eq!(file.get("Badges"), Some(&NbtTag::Int(2)));
+}
+
+#[test]
+fn wrong_passwords_are_rejected() {
+    let bytes = write(&file(ByteOrder::BigEndian), KeySource::Password(b"hunter2")).unwrap();
+    assert!(read(&bytes, KeySource::Password(b"hunter3")).is_err());
+    let e = read(&bytes, KeySource::Key(OLD_KEY, &[1; 32])).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn raw_key_files_round_trip() {
+    let original = file(ByteOrder::LittleEndian);
+    let bytes = write(&original, KeySource::Key(OLD_KEY, &[1; 32])).unwrap();
+    let decrypted = read(&bytes, KeySource::Key(OLD_KEY, &[1; 32])).unwrap();
+    assert_same(&decrypted, &original);
+
+    // The key id is checked before the key itself
+    let e = read(&bytes, KeySource::Key(NEW_KEY, &[1; 32])).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+    assert!(read(&bytes, KeySource::Key(OLD_KEY, &[2; 32])).is_err());
+    let e = read(&bytes, KeySource::Password(b"hunter2")).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn provider_files_use_the_current_key_and_read_any_known_key() {
+    let original = file(ByteOrder::BigEndian);
+    let old = write(&original, KeySource::Key(OLD_KEY, &[1; 32])).unwrap();
+    let ring = ring();
+    let new = write(&original, KeySource::Provider(&ring)).unwrap();
+
+    assert_same(&read(&old, KeySource::Provider(&ring)).unwrap(), &original);
+    assert_same(&read(&new, KeySource::Provider(&ring)).unwrap(), &original);
+    // Only the current key can read files written with the provider
+    assert!(read(&new, KeySource::Key(NEW_KEY, &[2; 32])).is_ok());
+    assert!(read(&new, KeySource::Key(OLD_KEY, &[1; 32])).is_err());
+}
+
+#[test]
+fn unknown_keys_are_rejected() {
+    let mut ring = ring();
+    let bytes = write(
+        &file(ByteOrder::BigEndian),
+        KeySource::Key(OLD_KEY, &[1; 32]),
+    )
+    .unwrap();
+    ring.remove(OLD_KEY);
+    let e = read(&bytes, KeySource::Provider(&ring)).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+
+    // A provider without a current key cannot write files
+    ring.remove(NEW_KEY);
+    let e = write(&file(ByteOrder::BigEndian), KeySource::Provider(&ring)).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn rekey_moves_files_to_a_new_key() {
+    let original = file(ByteOrder::LittleEndian);
+    let bytes = write(&original, KeySource::Password(b"hunter2")).unwrap();
+    let ring = ring();
+
+    let mut out = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+    ShadeFile::rekey(
+        KeySource::Password(b"hunter2"),
+        KeySource::Provider(&ring),
+        &mut DataInputStream::new(&*bytes, ByteOrder::BigEndian),
+        &mut out,
+    )
+    .unwrap();
+    let rekeyed = out.into_inner();
+
+    assert_same(
+        &read(&rekeyed, KeySource::Key(NEW_KEY, &[2; 32])).unwrap(),
+        &original,
+    );
+    assert!(read(&rekeyed, KeySource::Password(b"hunter2")).is_err());
+
+    // A failed rekey writes nothing
+    let mut out = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+    assert!(ShadeFile::rekey(
+        KeySource::Password(b"wrong"),
+        KeySource::Provider(&ring),
+        &mut DataInputStream::new(&*bytes, ByteOrder::BigEndian),
+        &mut out,
+    )
+    .is_err());
+    assert!(out.into_inner().is_empty());
+}
@@ -0,0 +1,163 @@
+#![cfg(feature = "shade")]
+
+use std::io::ErrorKind;
+
+use binary_io::{
+    nbt::{compound::NbtCompound, NbtTag},
+    shade::{
+        consts::SHADE_VERSION,
+        migration::{Change, MigrationStep, Migrations, DATA_VERSION_KEY},
+        ShadeFile,
+    },
+    version::Version,
+};
+
+const V1_1: Version = Version::from_encoded(0x0001);
+const V1_2: Version = Version::from_encoded(0x0002);
+const V1_3: Version = Version::from_encoded(0x0003);
+
+fn file(version: Version) -> ShadeFile {
+    let mut file = ShadeFile::with_version(version);
+    file.insert("Money".to_string(), NbtTag::Int(300));
+    file
+}
+
+// Adds an element, so the migration shows up in the compound
+fn mark(name: &'static str) -> impl Fn(&mut NbtCompound) -> std::io::Result<()> {
+    move |compound| {
+        compound.insert(name.to_string(), NbtTag::Byte(1));
+        Ok(())
+    }
+}
+
+fn format_chain() -> Migrations {
+    let mut migrations = Migrations::new();
+    migrations.register_format(V1_1, V1_2, "add flags", mark("From1_1"));
+    migrations.register_format(V1_2, V1_3, "nothing", mark("From1_2"));
+    migrations.register_format(V1_3, SHADE_VERSION, "nothing", mark("From1_3"));
+    migrations
+}
+
+#[test]
+fn format_migrations_are_applied_in_order() {
+    let mut old = file(V1_1);
+    let report = format_chain().migrate(&mut old).unwrap();
+    assert_eq!(old.version(), SHADE_VERSION);
+    let froms = report
+        .steps
+        .iter()
+        .map(|step| match step {
+            MigrationStep::Format { from, .. } => *from,
+            step => panic!("Unexpected step {}", step),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(froms, [V1_1, V1_2, V1_3]);
+    assert_eq!(
+        report.changes,
+        [
+            Change::Added("From1_1".to_string()),
+            Change::Added("From1_2".to_string()),
+            Change::Added("From1_3".to_string()),
+        ]
+    );
+
+    // Files part way along the chain only take the remaining steps
+    let mut newer = file(V1_3);
+    let report = format_chain().migrate(&mut newer).unwrap();
+    assert_eq!(report.steps.len(), 1);
+    assert_eq!(newer.get("From1_2"), None);
+}
+
+#[test]
+fn gaps_in_the_format_chain_are_rejected() {
+    let mut migrations = Migrations::new();
+    migrations.register_format(V1_1, V1_2, "add flags", mark("From1_1"));
+    migrations.register_format(V1_3, SHADE_VERSION, "nothing", mark("From1_3"));
+
+    let mut old = file(V1_1);
+    let e = migrations.migrate(&mut old).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    // A failed migration leaves the file unmodified
+    assert_eq!(old.version(), V1_1);
+    assert_eq!(old.get("From1_1"), None);
+    assert!(migrations.plan(&file(V1_2)).is_err());
+}
+
+#[test]
+fn up_to_date_files_are_not_modified() {
+    let mut current = file(SHADE_VERSION);
+    let report = format_chain().migrate(&mut current).unwrap();
+    assert!(report.is_empty());
+    assert!(report.changes.is_empty());
+    assert_eq!(current.get("From1_3"), None);
+
+    // The data version is only written when a data migration ran
+    let mut data = Migrations::with_data_version(0);
+    data.register_data(0, 1, "unused", mark("Data0"));
+    let mut unversioned = file(SHADE_VERSION);
+    assert!(data.migrate(&mut unversioned).unwrap().is_empty());
+    assert_eq!(unversioned.get(DATA_VERSION_KEY), None);
+}
+
+#[test]
+fn data_migrations_follow_format_migrations() {
+    let mut migrations = Migrations::with_data_version(3);
+    migrations.register_format(V1_3, SHADE_VERSION, "nothing", mark("From1_3"));
+    migrations.register_data(1, 2, "rename money", |compound| {
+        let money = compound.remove("Money").unwrap();
+        compound.insert("Pokedollars".to_string(), money);
+        Ok(())
+    });
+    migrations.register_data(2, 3, "add badges", mark("Badges"));
+
+    let mut old = file(V1_3);
+    old.insert(DATA_VERSION_KEY.to_string(), NbtTag::Int(1));
+    let plan = migrations.plan(&old).unwrap();
+    assert_eq!(old.get("Money"), Some(&NbtTag::Int(300)));
+
+    let report = migrations.migrate(&mut old).unwrap();
+    assert_eq!(report, plan);
+    assert_eq!(
+        report.steps.last(),
+        Some(&MigrationStep::Data {
+            from: 2,
+            to: 3,
+            description: "add badges".to_string()
+        })
+    );
+    assert_eq!(report.steps.len(), 3);
+    assert_eq!(old.get("Pokedollars"), Some(&NbtTag::Int(300)));
+    assert_eq!(old.get(DATA_VERSION_KEY), Some(&NbtTag::Int(3)));
+
+    // Files without a data version are assumed to be current, unless they predate data versions
+    assert!(migrations.plan(&file(SHADE_VERSION)).unwrap().is_empty());
+    // Files from a newer application are rejected
+    let mut newer = file(SHADE_VERSION);
+    newer.insert(DATA_VERSION_KEY.to_string(), NbtTag::Int(4));
+    assert!(migrations.plan(&newer).is_err());
+}
+
+#[test]
+fn unstamped_files_are_legacy_only_before_stamping() {
+    let mut migrations = Migrations::with_data_version(1);
+    migrations.register_format(V1_3, SHADE_VERSION, "nothing", mark("From1_3"));
+    migrations.register_data(0, 1, "add badges", mark("Badges"));
+    migrations.set_legacy_before(SHADE_VERSION);
+
+    let mut legacy = file(V1_3);
+    let report = migrations.migrate(&mut legacy).unwrap();
+    assert_eq!(report.steps.len(), 2);
+    assert_eq!(legacy.get("Badges"), Some(&NbtTag::Byte(1)));
+    assert_eq!(legacy.get(DATA_VERSION_KEY), Some(&NbtTag::Int(1)));
+
+    let mut current = file(SHADE_VERSION);
+    assert!(migrations.migrate(&mut current).unwrap().is_empty());
+    assert_eq!(current.get("Badges"), None);
+
+    // Stamped files are migrated from their data version, whatever their Shade version
+    let mut stamped = file(V1_3);
+    migrations.stamp(&mut stamped);
+    assert_eq!(stamped.get(DATA_VERSION_KEY), Some(&NbtTag::Int(1)));
+    migrations.migrate(&mut stamped).unwrap();
+    assert_eq!(stamped.get("Badges"), None);
+}
@@ -0,0 +1,122 @@
+use std::io::ErrorKind;
+
+use binary_io::{
+    data::{ByteOrder, DataInputStream, DataOutputStream, DeserializeCopy, Serializeable},
+    nbt::{compound::NbtCompound, list::NbtList, NbtTag},
+};
+
+fn write<T: Serializeable>(value: &T) -> Vec<u8> {
+    let mut out = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+    value.serialize(&mut out).unwrap();
+    out.into_inner()
+}
+
+fn read<T: DeserializeCopy>(bytes: &[u8]) -> std::io::Result<T> {
+    T::deserialize_copy(&mut DataInputStream::new(bytes, ByteOrder::BigEndian))
+}
+
+#[test]
+fn arrays_are_prefixed_by_their_length() {
+    let tag = NbtTag::IntArray(vec![1, 2, 3].into());
+    assert_eq!(
+        write(&tag),
+        [0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]
+    );
+    assert_eq!(write(&NbtTag::ByteArray(Vec::new().into())), [0, 0, 0, 0]);
+}
+
+#[test]
+fn tags_after_arrays_are_read() {
+    let mut compound = NbtCompound::new();
+    compound.insert("Bytes".to_string(), NbtTag::ByteArray(vec![1, 2].into()));
+    compound.insert("Ints".to_string(), NbtTag::IntArray(vec![-1].into()));
+    compound.insert("Longs".to_string(), NbtTag::LongArray(vec![1 << 40].into()));
+    compound.insert("Floats".to_string(), NbtTag::FloatArray(vec![0.5].into()));
+    compound.insert(
+        "Doubles".to_string(),
+        NbtTag::DoubleArray(vec![0.25].into()),
+    );
+    compound.insert("Long".to_string(), NbtTag::Long(-(1 << 40)));
+    let mut list = NbtList::new();
+    list.insert(NbtTag::IntArray(vec![7, 8].into())).unwrap();
+    list.insert(NbtTag::IntArray(Vec::new().into())).unwrap();
+    compound.insert("List".to_string(), NbtTag::List(list));
+    let mut nested = NbtCompound::new();
+    nested.insert("Longs".to_string(), NbtTag::LongArray(vec![3].into()));
+    compound.insert("Nested".to_string(), NbtTag::Compound(nested));
+
+    assert_eq!(read::<NbtCompound>(&write(&compound)).unwrap(), compound);
+}
+
+#[test]
+fn truncated_arrays_are_rejected() {
+    // An array claiming i32::MAX elements, followed by only one
+    let bytes = [0x7F, 0xFF, 0xFF, 0xFF, 0, 0, 0, 1];
+    let mut tag = NbtTag::IntArray(Default::default());
+    let e = binary_io::data::Deserializeable::deserialize(
+        &mut tag,
+        &mut DataInputStream::new(&bytes[..], ByteOrder::BigEndian),
+    )
+    .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+
+    let e = read::<NbtList>(&[3, 0xFF, 0xFF, 0xFF, 0xFF]).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn unknown_tag_types_are_rejected() {
+    // A compound holding a tag of type 0x7F named "A"
+    let e = read::<NbtCompound>(&[0x7F, 0, 1, b'A', 0]).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+}
+
+#[cfg(feature = "shade")]
+mod shade {
+    use binary_io::{
+        data::{ByteOrder, DataOutputStream, Serializeable},
+        nbt::NbtTag,
+        shade::{consts::SHADE_VERSION, ShadeFile},
+        version::Version,
+    };
+
+    use super::read;
+
+    // Shade 1.4 files wrote arrays as their elements alone
+    const V1_4: Version = Version::from_encoded(0x0004);
+
+    #[test]
+    fn arrays_in_old_shade_files_are_read_as_empty() {
+        #[rustfmt::skip]
+        let bytes = [
+            0xAD, 0x4E, 0x42, 0x54, 0x00, 0x04, 0x80,
+            7, 0, 1, b'A',
+            3, 0, 1, b'B', 0, 0, 0, 42,
+            0,
+        ];
+        let file = read::<ShadeFile>(&bytes).unwrap();
+        assert_eq!(file.version(), V1_4);
+        assert_eq!(file.get("A"), Some(&NbtTag::ByteArray(Vec::new().into())));
+        assert_eq!(file.get("B"), Some(&NbtTag::Int(42)));
+
+        // Written back at its own version, the empty array is still written without a length
+        let written = super::write(&file);
+        assert_eq!(written.len(), bytes.len());
+        assert_eq!(*read::<ShadeFile>(&written).unwrap(), *file);
+    }
+
+    #[test]
+    fn non_empty_arrays_cannot_be_written_to_old_shade_files() {
+        let mut file = ShadeFile::with_version(V1_4);
+        file.insert("A".to_string(), NbtTag::IntArray(vec![1].into()));
+        let mut out = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+        assert!(file.serialize(&mut out).is_err());
+
+        let mut file = ShadeFile::new();
+        assert_eq!(file.version(), SHADE_VERSION);
+        file.insert("A".to_string(), NbtTag::IntArray(vec![1].into()));
+        file.insert("B".to_string(), NbtTag::Int(42));
+        let read = read::<ShadeFile>(&super::write(&file)).unwrap();
+        assert_eq!(*read, *file);
+    }
+}
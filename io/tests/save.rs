@@ -0,0 +1,247 @@
+#![cfg(feature = "shade")]
+
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use binary_io::{
+    nbt::NbtTag,
+    shade::{
+        consts::SHADE_VERSION,
+        migration::{Migrations, DATA_VERSION_KEY},
+        save::{SaveFile, SaveSlot},
+        ShadeFile,
+    },
+    version::Version,
+};
+
+// A directory, removed when dropped
+struct Dir(PathBuf);
+
+impl Dir {
+    fn new(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("binary-io-save-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    fn save_file(&self, backups: usize) -> SaveFile {
+        SaveFile::with_backups(self.0.join("player.shade"), backups)
+    }
+
+    fn files(&self) -> Vec<String> {
+        let mut files = fs::read_dir(&self.0)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+}
+
+impl Drop for Dir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn file(n: i32) -> ShadeFile {
+    let mut file = ShadeFile::new();
+    file.insert("Save".to_string(), NbtTag::Int(n));
+    file
+}
+
+fn saved(path: &Path) -> Option<i32> {
+    let file = SaveFile::new(path).load().ok()?.value;
+    match file.get("Save") {
+        Some(NbtTag::Int(n)) => Some(*n),
+        _ => None,
+    }
+}
+
+#[test]
+fn backups_are_rotated_newest_first() {
+    let dir = Dir::new("rotation");
+    let save = dir.save_file(2);
+    for n in 1..=4 {
+        save.save(&file(n)).unwrap();
+    }
+    assert_eq!(saved(save.path()), Some(4));
+    assert_eq!(saved(&save.backup_path(1)), Some(3));
+    assert_eq!(saved(&save.backup_path(2)), Some(2));
+    assert_eq!(
+        dir.files(),
+        ["player.shade", "player.shade.1", "player.shade.2"]
+    );
+
+    let loaded = save.load().unwrap();
+    assert_eq!(loaded.slot, SaveSlot::Primary);
+    assert!(!loaded.is_recovered());
+    assert!(loaded.errors.is_empty());
+}
+
+#[test]
+fn interrupted_writes_leave_the_previous_save() {
+    let dir = Dir::new("interrupted");
+    let save = dir.save_file(1);
+    save.save(&file(1)).unwrap();
+
+    let e = save
+        .save_with(|output| {
+            output.write_all(&[0xAD, 0x4E])?;
+            Err(std::io::Error::other("Interrupted"))
+        })
+        .unwrap_err();
+    assert_eq!(e.to_string(), "Interrupted");
+    // Neither the destination nor the backups were touched, and no temporary file was left behind
+    assert_eq!(dir.files(), ["player.shade"]);
+    assert_eq!(saved(save.path()), Some(1));
+}
+
+#[test]
+fn corrupt_or_missing_saves_are_recovered_from_backups() {
+    let dir = Dir::new("recovery");
+    let save = dir.save_file(2);
+    for n in 1..=3 {
+        save.save(&file(n)).unwrap();
+    }
+
+    // A torn write of the destination
+    fs::write(save.path(), [0xAD, 0x4E, 0x42]).unwrap();
+    let loaded = save.load().unwrap();
+    assert_eq!(loaded.slot, SaveSlot::Backup(1));
+    assert!(loaded.is_recovered());
+    assert_eq!(loaded.errors.len(), 1);
+    assert_eq!(loaded.value.get("Save"), Some(&NbtTag::Int(2)));
+
+    // A destination which was lost, and a newest backup which was truncated
+    fs::remove_file(save.path()).unwrap();
+    fs::write(save.backup_path(1), b"").unwrap();
+    let loaded = save.load().unwrap();
+    assert_eq!(loaded.slot, SaveSlot::Backup(2));
+    assert_eq!(loaded.errors.len(), 2);
+    assert_eq!(loaded.errors[0].1.kind(), ErrorKind::NotFound);
+
+    // Saving again replaces the missing destination
+    save.save(&file(4)).unwrap();
+    assert_eq!(saved(save.path()), Some(4));
+    assert_eq!(save.load().unwrap().slot, SaveSlot::Primary);
+}
+
+#[test]
+fn unrecoverable_saves_report_the_destination_error() {
+    let dir = Dir::new("unrecoverable");
+    let save = dir.save_file(1);
+    assert_eq!(save.load().unwrap_err().kind(), ErrorKind::NotFound);
+
+    fs::write(save.path(), b"not a shade file").unwrap();
+    let e = save.load().unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    assert!(e.to_string().contains("player.shade"), "{}", e);
+}
+
+#[test]
+fn concurrent_saves_use_separate_temporary_files() {
+    let dir = Dir::new("concurrent");
+    let save = Arc::new(dir.save_file(3));
+    save.save(&file(0)).unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+    let watcher = {
+        let (save, done) = (save.clone(), done.clone());
+        // The destination is replaced by a rename, so it never goes missing
+        std::thread::spawn(move || {
+            while !done.load(Ordering::Relaxed) {
+                assert!(save.path().exists());
+            }
+        })
+    };
+    let threads = (0..8)
+        .map(|n| {
+            let save = save.clone();
+            std::thread::spawn(move || {
+                for i in 0..10 {
+                    save.save(&file(n * 10 + i)).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    done.store(true, Ordering::Relaxed);
+    watcher.join().unwrap();
+
+    // Every file left is a complete save, though interleaved rotations may have dropped a backup
+    assert!(saved(save.path()).is_some());
+    for name in dir.files() {
+        assert!(!name.ends_with(".tmp"), "{}", name);
+        assert!(saved(&dir.0.join(&name)).is_some(), "{}", name);
+    }
+}
+
+#[test]
+fn migrations_stamp_saves_and_upgrade_loads() {
+    let dir = Dir::new("migrations");
+    let mut migrations = Migrations::with_data_version(1);
+    migrations.register_data(0, 1, "double saves", |compound| {
+        if let Some(NbtTag::Int(n)) = compound.get_mut("Save") {
+            *n *= 2;
+        }
+        Ok(())
+    });
+    migrations.set_legacy_before(SHADE_VERSION);
+    let save = dir.save_file(0).with_migrations(Arc::new(migrations));
+
+    save.save(&file(3)).unwrap();
+    let stamped = SaveFile::new(save.path()).load().unwrap().value;
+    assert_eq!(stamped.get(DATA_VERSION_KEY), Some(&NbtTag::Int(1)));
+    let loaded = save.load().unwrap();
+    assert!(loaded.migration.is_empty());
+    assert_eq!(loaded.value.get("Save"), Some(&NbtTag::Int(3)));
+
+    // A file saved before data versions were stamped
+    let mut legacy = ShadeFile::with_version(Version::from_encoded(0x0003));
+    legacy.insert("Save".to_string(), NbtTag::Int(3));
+    SaveFile::new(save.path()).save(&legacy).unwrap();
+    let loaded = save.load().unwrap();
+    assert_eq!(loaded.migration.steps.len(), 1);
+    assert_eq!(loaded.value.get("Save"), Some(&NbtTag::Int(6)));
+    assert_eq!(loaded.value.get(DATA_VERSION_KEY), Some(&NbtTag::Int(1)));
+}
+
+#[test]
+fn loading_removes_temporary_files_left_by_other_processes() {
+    let dir = Dir::new("stale");
+    let save = dir.save_file(1);
+    save.save(&file(1)).unwrap();
+    save.save(&file(2)).unwrap();
+
+    // A process which crashed part way through a save, and a save in progress in this process
+    let stale = format!("player.shade.{}-0.tmp", std::process::id().wrapping_add(1));
+    let saving = format!("player.shade.{}-7.tmp", std::process::id());
+    fs::write(dir.0.join(&stale), [0xAD, 0x4E]).unwrap();
+    fs::write(dir.0.join(&saving), [0xAD, 0x4E]).unwrap();
+    fs::write(dir.0.join("player.shade.notes.tmp"), b"").unwrap();
+
+    assert_eq!(
+        save.load().unwrap().value.get("Save"),
+        Some(&NbtTag::Int(2))
+    );
+    let mut expected = vec![
+        "player.shade".to_string(),
+        "player.shade.1".to_string(),
+        "player.shade.notes.tmp".to_string(),
+        saving,
+    ];
+    expected.sort();
+    assert_eq!(dir.files(), expected);
+    assert_eq!(saved(&save.backup_path(1)), Some(1));
+}
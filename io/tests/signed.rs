@@ -0,0 +1,103 @@
+#![cfg(feature = "signed_shade")]
+
+use std::{collections::HashMap, io::ErrorKind};
+
+use binary_io::{
+    data::{ByteOrder, DataInputStream, DataOutputStream},
+    nbt::NbtTag,
+    shade::{
+        signed::{SigningKey, VerifyError, VerifyingKey},
+        ShadeFile,
+    },
+    uuid::UUID,
+};
+
+const KEY_ID: UUID = UUID::new(1, 1);
+
+// The body length follows the magic, version, flags, key id and signature
+const LENGTH_OFFSET: usize = 4 + 2 + 1 + 16 + 64;
+
+fn key(seed: u8) -> SigningKey {
+    SigningKey::from_bytes(&[seed; 32])
+}
+
+fn file() -> ShadeFile {
+    let mut file = ShadeFile::new();
+    file.insert("Name".to_string(), NbtTag::String("Ash".to_string()));
+    file.insert("Badges".to_string(), NbtTag::Int(8));
+    file
+}
+
+fn sign(file: &ShadeFile, key: &SigningKey) -> Vec<u8> {
+    let mut out = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+    file.sign(KEY_ID, key, &mut out).unwrap();
+    out.into_inner()
+}
+
+fn verify(
+    keys: &HashMap<UUID, VerifyingKey>,
+    bytes: &[u8],
+) -> Result<(UUID, ShadeFile), VerifyError> {
+    ShadeFile::verify_with_key_id(keys, &mut DataInputStream::new(bytes, ByteOrder::BigEndian))
+}
+
+fn keys(key: &SigningKey) -> HashMap<UUID, VerifyingKey> {
+    [(KEY_ID, key.verifying_key())].iter().copied().collect()
+}
+
+#[test]
+fn signed_files_are_verified_with_the_named_key() {
+    let key = key(1);
+    let bytes = sign(&file(), &key);
+    let (id, verified) = verify(&keys(&key), &bytes).unwrap();
+    assert_eq!(id, KEY_ID);
+    assert_eq!(
+        verified.get("Name"),
+        Some(&NbtTag::String("Ash".to_string()))
+    );
+    assert_eq!(verified.get("Badges"), Some(&NbtTag::Int(8)));
+    assert_eq!(verified.version(), file().version());
+}
+
+#[test]
+fn modified_files_are_rejected() {
+    let key = key(1);
+    let bytes = sign(&file(), &key);
+    // Every byte after the magic is covered by the signature, including the key id and the signature itself
+    for i in 4..bytes.len() {
+        let mut tampered = bytes.clone();
+        tampered[i] ^= 0x01;
+        assert!(verify(&keys(&key), &tampered).is_err(), "byte {}", i);
+    }
+    // A signature by another key is not accepted under this key's id
+    let forged = sign(&file(), &self::key(2));
+    match verify(&keys(&key), &forged) {
+        Err(VerifyError::BadSignature(id)) => assert_eq!(id, KEY_ID),
+        r => panic!("Expected a bad signature, got {:?}", r.map(|(id, _)| id)),
+    }
+}
+
+#[test]
+fn files_signed_by_unknown_keys_are_rejected() {
+    let bytes = sign(&file(), &key(1));
+    match verify(&HashMap::new(), &bytes) {
+        Err(VerifyError::UnknownKey(id)) => assert_eq!(id, KEY_ID),
+        r => panic!("Expected an unknown key, got {:?}", r.map(|(id, _)| id)),
+    }
+    let e: std::io::Error = verify(&HashMap::new(), &bytes).unwrap_err().into();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn bodies_longer_than_the_file_are_rejected() {
+    let key = key(1);
+    let mut bytes = sign(&file(), &key);
+    bytes[LENGTH_OFFSET..LENGTH_OFFSET + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+    match verify(&keys(&key), &bytes) {
+        Err(VerifyError::Io(e)) => assert_eq!(e.kind(), ErrorKind::UnexpectedEof),
+        r => panic!(
+            "Expected the file to end early, got {:?}",
+            r.map(|(id, _)| id)
+        ),
+    }
+}
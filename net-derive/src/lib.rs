@@ -0,0 +1,104 @@
+//!
+//! Derive macros for the net crate.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, quote_spanned};
+use syn::{
+    parse_macro_input, parse_quote, spanned::Spanned, Data, DeriveInput, Fields, GenericParam,
+    Index,
+};
+
+///
+/// Derives `net::hashsum::Hashcode` for a struct.
+///
+/// The hashcode is computed from the fields in declaration order, in the same way as Java's `Objects.hash`:
+///  starting from 1, the hash is multiplied by 31 and the hashcode of each field is added, with wrapping arithmetic.
+/// Fields marked `#[hashcode(skip)]` are not included.
+#[proc_macro_derive(Hashcode, attributes(hashcode))]
+pub fn derive_hashcode(input: TokenStream) -> TokenStream {
+    let mut input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        Data::Enum(data) => {
+            return syn::Error::new(
+                data.enum_token.span(),
+                "Hashcode cannot be derived for enums",
+            )
+            .to_compile_error()
+            .into()
+        }
+        Data::Union(data) => {
+            return syn::Error::new(
+                data.union_token.span(),
+                "Hashcode cannot be derived for unions",
+            )
+            .to_compile_error()
+            .into()
+        }
+    };
+
+    let mut terms = Vec::<TokenStream2>::new();
+    let members = match fields {
+        Fields::Named(named) => named.named.iter().collect::<Vec<_>>(),
+        Fields::Unnamed(unnamed) => unnamed.unnamed.iter().collect(),
+        Fields::Unit => Vec::new(),
+    };
+    for (i, field) in members.into_iter().enumerate() {
+        match is_skipped(&field.attrs) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => return e.to_compile_error().into(),
+        }
+        let member = match &field.ident {
+            Some(ident) => quote!(#ident),
+            None => {
+                let index = Index::from(i);
+                quote!(#index)
+            }
+        };
+        terms.push(quote_spanned! {field.span()=>
+            hash = hash
+                .wrapping_mul(31)
+                .wrapping_add(::net::hashsum::Hashcode::hashcode(&self.#member));
+        });
+    }
+
+    for param in &mut input.generics.params {
+        if let GenericParam::Type(ty) = param {
+            ty.bounds.push(parse_quote!(::net::hashsum::Hashcode));
+        }
+    }
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    quote! {
+        impl #impl_generics ::net::hashsum::Hashcode for #name #ty_generics #where_clause {
+            fn hashcode(&self) -> i32 {
+                #[allow(unused_mut)]
+                let mut hash = 1i32;
+                #(#terms)*
+                hash
+            }
+        }
+    }
+    .into()
+}
+
+fn is_skipped(attrs: &[syn::Attribute]) -> syn::Result<bool> {
+    let mut skip = false;
+    for attr in attrs {
+        if attr.path().is_ident("hashcode") {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("skip") {
+                    skip = true;
+                    Ok(())
+                } else {
+                    Err(meta.error("Unknown hashcode attribute"))
+                }
+            })?;
+        }
+    }
+    Ok(skip)
+}
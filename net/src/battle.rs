@@ -0,0 +1,1300 @@
+//!
+//! Link battles, simulated by the server in lockstep.
+//!
+//! A player challenges another with a [`BattleChallenge`], which the server sends to both players with a commitment to a random seed of its own.
+//! Once the opponent sends a [`BattleAccept`], each player sends a [`TeamPreview`] with their team and a seed of their own.
+//! When both teams are received, the server sends both previews to both players, and starts its [`BattleSimulator`]
+//!  with a [`BattleRng`] seeded by [`combine_seeds`] from the server's seed and both players' seeds.
+//! Each turn, both players send an [`ActionSubmit`]. Once both actions are received, the server simulates the turn,
+//!  and sends a [`TurnResult`] with both actions and the events of the turn.
+//! The battle ends when the simulation decides it, or a player forfeits, disconnects, or does not act in time.
+//! The [`BattleEnd`] reveals the server's seed, so that players and spectators can check it against the commitment,
+//!  and simulate the battle again with a [`BattleRecord`] to verify every turn.
+
+#[cfg(feature = "tcp")]
+use std::sync::Mutex;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::ErrorKind,
+    time::{Duration, Instant},
+};
+
+use binary_io::{
+    data::{DataInput, DataOutput, DeserializeCopy, Deserializeable, Serializeable},
+    nbt::compound::NbtCompound,
+    uuid::UUID,
+};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest as _, Sha256};
+use text::TextComponent;
+
+#[cfg(feature = "tcp")]
+use crate::server::{Handler, Peer};
+use crate::{
+    digest::Digest,
+    packet::{AnyPacket, List, Outgoing, Packet},
+};
+
+///
+/// A random seed, contributed to a battle by the server or a player
+pub type Seed = [u8; 32];
+
+const SEED_CONTEXT: &[u8] = b"pkmcom battle seed";
+
+///
+/// Combines the seed of the server with the seeds of the challenger and the opponent, in that order, into the seed of the battle
+pub fn combine_seeds(server: &Seed, players: [&Seed; 2]) -> Seed {
+    let mut hasher = Sha256::new();
+    hasher.update(SEED_CONTEXT);
+    hasher.update(server);
+    hasher.update(players[0]);
+    hasher.update(players[1]);
+    hasher.finalize().into()
+}
+
+///
+/// The deterministic random number generator used by battle simulations.
+/// The stream is SHA-256 of the seed followed by a 64-bit big endian block counter, so it is the same on every platform
+#[derive(Clone, Debug)]
+pub struct BattleRng {
+    seed: Seed,
+    counter: u64,
+    block: [u8; 32],
+    used: usize,
+}
+
+impl BattleRng {
+    ///
+    /// Creates a generator with the given seed
+    pub fn new(seed: Seed) -> Self {
+        Self {
+            seed,
+            counter: 0,
+            block: [0; 32],
+            used: 32,
+        }
+    }
+
+    ///
+    /// Fills bytes with the next bytes of the stream
+    pub fn fill(&mut self, bytes: &mut [u8]) {
+        for b in bytes {
+            if self.used == self.block.len() {
+                let mut hasher = Sha256::new();
+                hasher.update(self.seed);
+                hasher.update(self.counter.to_be_bytes());
+                self.block = hasher.finalize().into();
+                self.counter += 1;
+                self.used = 0;
+            }
+            *b = self.block[self.used];
+            self.used += 1;
+        }
+    }
+
+    ///
+    /// Returns the next 32 bits of the stream, as a big endian number
+    pub fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.fill(&mut bytes);
+        u32::from_be_bytes(bytes)
+    }
+
+    ///
+    /// Returns the next 64 bits of the stream, as a big endian number
+    pub fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        self.fill(&mut bytes);
+        u64::from_be_bytes(bytes)
+    }
+
+    ///
+    /// Returns a uniformly distributed number less than bound.
+    /// Panics if bound is 0
+    pub fn below(&mut self, bound: u32) -> u32 {
+        assert!(bound != 0, "BattleRng::below called with a bound of 0");
+        // Numbers at or above the largest multiple of bound would favour the smallest results, and are drawn again
+        let limit = (1u64 << 32) - (1u64 << 32) % u64::from(bound);
+        loop {
+            let n = u64::from(self.next_u32());
+            if n < limit {
+                return (n % u64::from(bound)) as u32;
+            }
+        }
+    }
+}
+
+fn invalid_tag(kind: &str, tag: u8) -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Unknown {} tag {}", kind, tag),
+    )
+}
+
+///
+/// What a player does in a turn
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BattleAction {
+    ///
+    /// Uses the move with the given index, of the active Pokémon
+    Move(u8),
+    ///
+    /// Switches the active Pokémon for the Pokémon in the given team slot
+    Switch(u8),
+    ///
+    /// Uses an item on the Pokémon in the given team slot
+    Item {
+        ///
+        /// The item, as a resource location
+        item: String,
+        ///
+        /// The team slot of the Pokémon the item is used on
+        target: u8,
+    },
+    ///
+    /// Runs from the battle
+    Run,
+}
+
+impl Serializeable for BattleAction {
+    fn serialize<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        match self {
+            BattleAction::Move(index) => {
+                0u8.serialize(output)?;
+                index.serialize(output)
+            }
+            BattleAction::Switch(slot) => {
+                1u8.serialize(output)?;
+                slot.serialize(output)
+            }
+            BattleAction::Item { item, target } => {
+                2u8.serialize(output)?;
+                item.serialize(output)?;
+                target.serialize(output)
+            }
+            BattleAction::Run => 3u8.serialize(output),
+        }
+    }
+}
+
+impl Deserializeable for BattleAction {
+    fn deserialize<R: DataInput + ?Sized>(&mut self, input: &mut R) -> std::io::Result<()> {
+        *self = Self::deserialize_copy(input)?;
+        Ok(())
+    }
+}
+
+impl DeserializeCopy for BattleAction {
+    fn deserialize_copy<R: DataInput + ?Sized>(input: &mut R) -> std::io::Result<Self> {
+        match u8::deserialize_copy(input)? {
+            0 => Ok(BattleAction::Move(u8::deserialize_copy(input)?)),
+            1 => Ok(BattleAction::Switch(u8::deserialize_copy(input)?)),
+            2 => Ok(BattleAction::Item {
+                item: String::deserialize_copy(input)?,
+                target: u8::deserialize_copy(input)?,
+            }),
+            3 => Ok(BattleAction::Run),
+            tag => Err(invalid_tag("battle action", tag)),
+        }
+    }
+}
+
+///
+/// Something which happened in a turn, to be shown to the players.
+/// Sides are 0 for the challenger, and 1 for the opponent
+#[derive(Clone, Debug, PartialEq)]
+pub enum BattleEvent {
+    ///
+    /// The active Pokémon of a side used a move
+    Move {
+        ///
+        /// The side of the Pokémon
+        side: u8,
+        ///
+        /// The index of the move
+        index: u8,
+    },
+    ///
+    /// A side switched its active Pokémon
+    Switch {
+        ///
+        /// The side which switched
+        side: u8,
+        ///
+        /// The team slot of the new active Pokémon
+        slot: u8,
+    },
+    ///
+    /// A side used an item
+    Item {
+        ///
+        /// The side which used the item
+        side: u8,
+        ///
+        /// The item, as a resource location
+        item: String,
+    },
+    ///
+    /// The active Pokémon of a side took damage
+    Damage {
+        ///
+        /// The side of the Pokémon
+        side: u8,
+        ///
+        /// The damage taken
+        amount: u16,
+        ///
+        /// The HP remaining
+        remaining: u16,
+    },
+    ///
+    /// The active Pokémon of a side fainted
+    Faint {
+        ///
+        /// The side of the Pokémon
+        side: u8,
+    },
+    ///
+    /// A side ran from the battle
+    Fled {
+        ///
+        /// The side which ran
+        side: u8,
+    },
+    ///
+    /// A message to be shown as is
+    Message(TextComponent),
+    ///
+    /// An event specific to the simulation
+    Custom {
+        ///
+        /// The kind of event, as a resource location
+        kind: String,
+        ///
+        /// The details of the event
+        data: NbtCompound,
+    },
+}
+
+impl Serializeable for BattleEvent {
+    fn serialize<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        match self {
+            BattleEvent::Move { side, index } => {
+                0u8.serialize(output)?;
+                side.serialize(output)?;
+                index.serialize(output)
+            }
+            BattleEvent::Switch { side, slot } => {
+                1u8.serialize(output)?;
+                side.serialize(output)?;
+                slot.serialize(output)
+            }
+            BattleEvent::Item { side, item } => {
+                2u8.serialize(output)?;
+                side.serialize(output)?;
+                item.serialize(output)
+            }
+            BattleEvent::Damage {
+                side,
+                amount,
+                remaining,
+            } => {
+                3u8.serialize(output)?;
+                side.serialize(output)?;
+                amount.serialize(output)?;
+                remaining.serialize(output)
+            }
+            BattleEvent::Faint { side } => {
+                4u8.serialize(output)?;
+                side.serialize(output)
+            }
+            BattleEvent::Fled { side } => {
+                5u8.serialize(output)?;
+                side.serialize(output)
+            }
+            BattleEvent::Message(message) => {
+                6u8.serialize(output)?;
+                message.serialize(output)
+            }
+            BattleEvent::Custom { kind, data } => {
+                7u8.serialize(output)?;
+                kind.serialize(output)?;
+                data.serialize(output)
+            }
+        }
+    }
+}
+
+impl Deserializeable for BattleEvent {
+    fn deserialize<R: DataInput + ?Sized>(&mut self, input: &mut R) -> std::io::Result<()> {
+        *self = Self::deserialize_copy(input)?;
+        Ok(())
+    }
+}
+
+impl DeserializeCopy for BattleEvent {
+    fn deserialize_copy<R: DataInput + ?Sized>(input: &mut R) -> std::io::Result<Self> {
+        match u8::deserialize_copy(input)? {
+            0 => Ok(BattleEvent::Move {
+                side: u8::deserialize_copy(input)?,
+                index: u8::deserialize_copy(input)?,
+            }),
+            1 => Ok(BattleEvent::Switch {
+                side: u8::deserialize_copy(input)?,
+                slot: u8::deserialize_copy(input)?,
+            }),
+            2 => Ok(BattleEvent::Item {
+                side: u8::deserialize_copy(input)?,
+                item: String::deserialize_copy(input)?,
+            }),
+            3 => Ok(BattleEvent::Damage {
+                side: u8::deserialize_copy(input)?,
+                amount: u16::deserialize_copy(input)?,
+                remaining: u16::deserialize_copy(input)?,
+            }),
+            4 => Ok(BattleEvent::Faint {
+                side: u8::deserialize_copy(input)?,
+            }),
+            5 => Ok(BattleEvent::Fled {
+                side: u8::deserialize_copy(input)?,
+            }),
+            6 => Ok(BattleEvent::Message(TextComponent::deserialize_copy(
+                input,
+            )?)),
+            7 => Ok(BattleEvent::Custom {
+                kind: String::deserialize_copy(input)?,
+                data: NbtCompound::deserialize_copy(input)?,
+            }),
+            tag => Err(invalid_tag("battle event", tag)),
+        }
+    }
+}
+
+packet! {
+    ///
+    /// Sent by a client to challenge another player to a battle.
+    /// The server sends it to both players, with the id of the battle and the commitment to its seed
+    pub struct BattleChallenge(0x0040, Bidirectional) {
+        ///
+        /// The id of the battle, assigned by the server. Ignored when sent by a client
+        pub battle: UUID,
+        ///
+        /// The challenger. Ignored when sent by a client
+        pub from: UUID,
+        ///
+        /// The player who is challenged
+        pub to: UUID,
+        ///
+        /// The format of the battle, which is interpreted by the simulation
+        pub format: String,
+        ///
+        /// The SHA-256 digest of the server's seed, which is revealed by the [`BattleEnd`]. Ignored when sent by a client
+        pub commitment: Digest,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by the challenged player to accept a challenge.
+    /// The server sends it to both players
+    pub struct BattleAccept(0x0041, Bidirectional) {
+        ///
+        /// The id of the battle
+        pub battle: UUID,
+        ///
+        /// The player who accepted. Ignored when sent by a client
+        pub from: UUID,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by each player after the challenge is accepted, with their team and seed.
+    /// Once both are received, the server sends both to both players, challenger first
+    pub struct TeamPreview(0x0042, Bidirectional) {
+        ///
+        /// The id of the battle
+        pub battle: UUID,
+        ///
+        /// The player whose team it is. Ignored when sent by a client
+        pub from: UUID,
+        ///
+        /// The team, in order of team slots
+        pub team: List<NbtCompound>,
+        ///
+        /// The player's contribution to the seed of the battle
+        pub seed: Seed,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by each player with their action for a turn
+    pub struct ActionSubmit(0x0043, Serverbound) {
+        ///
+        /// The id of the battle
+        pub battle: UUID,
+        ///
+        /// The turn, starting from 1. Actions for other turns are ignored
+        pub turn: u32,
+        ///
+        /// The action
+        pub action: BattleAction,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by the server once a turn is simulated
+    pub struct TurnResult(0x0044, Clientbound) {
+        ///
+        /// The id of the battle
+        pub battle: UUID,
+        ///
+        /// The turn
+        pub turn: u32,
+        ///
+        /// The actions of both players, challenger first
+        pub actions: List<BattleAction>,
+        ///
+        /// The events of the turn, in order
+        pub events: List<BattleEvent>,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by a player to forfeit a battle, or to decline or withdraw a challenge
+    pub struct BattleForfeit(0x0045, Serverbound) {
+        ///
+        /// The id of the battle
+        pub battle: UUID,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by the server when a battle ends
+    pub struct BattleEnd(0x0046, Clientbound) {
+        ///
+        /// The id of the battle, or NIL if a challenge could not be made
+        pub battle: UUID,
+        ///
+        /// The winner, or NIL if nobody won
+        pub winner: UUID,
+        ///
+        /// How the battle ended, to be shown to the players
+        pub reason: TextComponent,
+        ///
+        /// The server's seed, which matches the commitment in the [`BattleChallenge`]
+        pub seed: Seed,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by a client to watch a battle.
+    /// The server sends every packet of the battle so far, and the rest as they happen
+    pub struct BattleSpectate(0x0047, Serverbound) {
+        ///
+        /// The id of the battle
+        pub battle: UUID,
+    }
+}
+
+///
+/// How a battle was decided by the simulation
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    ///
+    /// The side won, 0 for the challenger and 1 for the opponent
+    Winner(usize),
+    ///
+    /// Nobody won
+    Draw,
+}
+
+///
+/// The result of simulating a turn
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Turn {
+    ///
+    /// The events of the turn, in order
+    pub events: Vec<BattleEvent>,
+    ///
+    /// How the battle was decided, if it ended this turn
+    pub outcome: Option<Outcome>,
+}
+
+///
+/// A battle simulation.
+/// Simulations must be deterministic: given the same teams, actions, and random numbers, they must produce the same events,
+///  so that battles can be verified by [`BattleRecord::verify`]
+pub trait BattleSimulator: Send + Sync + 'static {
+    ///
+    /// The state of a battle
+    type State: Send + 'static;
+
+    ///
+    /// Starts a battle between the teams of the challenger and the opponent, in that order
+    fn start(
+        &self,
+        format: &str,
+        teams: [&[NbtCompound]; 2],
+        rng: &mut BattleRng,
+    ) -> std::io::Result<Self::State>;
+
+    ///
+    /// Simulates a turn, with the actions of the challenger and the opponent, in that order
+    fn turn(
+        &self,
+        state: &mut Self::State,
+        actions: [&BattleAction; 2],
+        rng: &mut BattleRng,
+    ) -> Turn;
+}
+
+///
+/// The limits on battles
+#[derive(Clone, Debug)]
+pub struct BattleConfig {
+    ///
+    /// The most Pokémon a team may have
+    pub max_team_size: usize,
+    ///
+    /// The time a challenged player has to accept, before the challenge is withdrawn
+    pub accept_timeout: Duration,
+    ///
+    /// The time players have to send their team, and their action each turn, before they lose
+    pub turn_timeout: Duration,
+}
+
+impl Default for BattleConfig {
+    fn default() -> Self {
+        Self {
+            max_team_size: 6,
+            accept_timeout: Duration::from_secs(60),
+            turn_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+fn random_seed() -> Seed {
+    let mut seed = [0; 32];
+    OsRng.fill_bytes(&mut seed);
+    seed
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Phase {
+    Challenged,
+    Preview,
+    Turn,
+}
+
+// The packets sent to everyone in a battle, which are sent again to new spectators
+#[derive(Clone)]
+enum Published {
+    Challenge(BattleChallenge),
+    Accept(BattleAccept),
+    Preview(TeamPreview),
+    Result(TurnResult),
+}
+
+impl Published {
+    fn packet(&self) -> Box<dyn AnyPacket> {
+        match self {
+            Published::Challenge(p) => Box::new(p.clone()),
+            Published::Accept(p) => Box::new(p.clone()),
+            Published::Preview(p) => Box::new(p.clone()),
+            Published::Result(p) => Box::new(p.clone()),
+        }
+    }
+}
+
+struct Battle<T> {
+    players: [UUID; 2],
+    format: String,
+    seed: Seed,
+    phase: Phase,
+    teams: [Option<TeamPreview>; 2],
+    actions: [Option<BattleAction>; 2],
+    turn: u32,
+    state: Option<(T, BattleRng)>,
+    deadline: Instant,
+    spectators: Vec<UUID>,
+    history: Vec<Published>,
+}
+
+impl<T> Battle<T> {
+    fn side(&self, player: UUID) -> usize {
+        if self.players[0] == player {
+            0
+        } else {
+            1
+        }
+    }
+
+    fn audience(&self) -> impl Iterator<Item = UUID> + '_ {
+        self.players.iter().chain(&self.spectators).copied()
+    }
+
+    fn publish(&mut self, published: Published) -> Vec<Outgoing> {
+        let outgoing = self
+            .audience()
+            .map(|player| (player, published.packet()))
+            .collect();
+        self.history.push(published);
+        outgoing
+    }
+}
+
+fn send_to<P: Packet>(player: UUID, packet: P) -> Vec<Outgoing> {
+    vec![(player, Box::new(packet))]
+}
+
+fn raw(text: String) -> TextComponent {
+    TextComponent::RawText(text)
+}
+
+///
+/// The battles in progress on a server.
+/// Sessions are independent of connections and time, so that they can be driven by a [`BattleService`], or directly
+pub struct BattleSessions<S: BattleSimulator> {
+    simulator: S,
+    config: BattleConfig,
+    battles: HashMap<UUID, Battle<S::State>>,
+    players: HashMap<UUID, UUID>,
+    next_id: u64,
+    seeds: Box<dyn FnMut() -> Seed + Send>,
+}
+
+impl<S: BattleSimulator> BattleSessions<S> {
+    ///
+    /// Creates a new set of sessions, which simulate battles with simulator
+    pub fn new(simulator: S, config: BattleConfig) -> Self {
+        Self {
+            simulator,
+            config,
+            battles: HashMap::new(),
+            players: HashMap::new(),
+            next_id: 1,
+            seeds: Box::new(random_seed),
+        }
+    }
+
+    ///
+    /// Replaces the operating system's generator as the source of the server's seeds, such as with fixed seeds for tests
+    pub fn with_seeds<F: FnMut() -> Seed + Send + 'static>(mut self, seeds: F) -> Self {
+        self.seeds = Box::new(seeds);
+        self
+    }
+
+    ///
+    /// Returns the simulator
+    pub fn simulator(&self) -> &S {
+        &self.simulator
+    }
+
+    ///
+    /// Returns the battle the player is fighting in, if any
+    pub fn battle_of(&self, player: UUID) -> Option<UUID> {
+        self.players.get(&player).copied()
+    }
+
+    ///
+    /// Returns the number of battles in progress, including challenges which are not yet accepted
+    pub fn len(&self) -> usize {
+        self.battles.len()
+    }
+
+    ///
+    /// Checks if no battles are in progress
+    pub fn is_empty(&self) -> bool {
+        self.battles.is_empty()
+    }
+
+    fn end(&mut self, battle: UUID, winner: Option<usize>, reason: TextComponent) -> Vec<Outgoing> {
+        let battle_state = match self.battles.remove(&battle) {
+            Some(battle) => battle,
+            None => return Vec::new(),
+        };
+        for player in &battle_state.players {
+            self.players.remove(player);
+        }
+        let end = BattleEnd {
+            battle,
+            winner: winner.map_or(UUID::NIL, |side| battle_state.players[side]),
+            reason,
+            seed: battle_state.seed,
+        };
+        battle_state
+            .audience()
+            .map(|player| (player, Box::new(end.clone()) as Box<dyn AnyPacket>))
+            .collect()
+    }
+
+    // Ends a battle because some players did not act in time, or left. The other player wins, if there is exactly one
+    fn default_win(
+        &mut self,
+        battle: UUID,
+        losers: [bool; 2],
+        reason: TextComponent,
+    ) -> Vec<Outgoing> {
+        let winner = match losers {
+            [true, false] => Some(1),
+            [false, true] => Some(0),
+            _ => None,
+        };
+        self.end(battle, winner, reason)
+    }
+
+    fn challenge(
+        &mut self,
+        from: UUID,
+        challenge: &BattleChallenge,
+        now: Instant,
+    ) -> Vec<Outgoing> {
+        let refuse = |reason: String| {
+            send_to(
+                from,
+                BattleEnd {
+                    battle: UUID::NIL,
+                    winner: UUID::NIL,
+                    reason: raw(reason),
+                    seed: [0; 32],
+                },
+            )
+        };
+        if from == challenge.to {
+            return refuse("Players cannot battle themselves".to_string());
+        }
+        for player in [from, challenge.to] {
+            if self.players.contains_key(&player) {
+                return refuse(format!("{} is already battling", player));
+            }
+        }
+        let id = UUID::new(0, self.next_id);
+        self.next_id += 1;
+        let seed = (self.seeds)();
+        let mut battle = Battle {
+            players: [from, challenge.to],
+            format: challenge.format.clone(),
+            seed,
+            phase: Phase::Challenged,
+            teams: [None, None],
+            actions: [None, None],
+            turn: 0,
+            state: None,
+            deadline: now + self.config.accept_timeout,
+            spectators: Vec::new(),
+            history: Vec::new(),
+        };
+        let outgoing = battle.publish(Published::Challenge(BattleChallenge {
+            battle: id,
+            from,
+            to: challenge.to,
+            format: challenge.format.clone(),
+            commitment: Digest::of_bytes(&seed),
+        }));
+        self.players.insert(from, id);
+        self.players.insert(challenge.to, id);
+        self.battles.insert(id, battle);
+        outgoing
+    }
+
+    fn spectate(&mut self, from: UUID, id: UUID) -> Vec<Outgoing> {
+        match self.battles.get_mut(&id) {
+            Some(battle) if !battle.audience().any(|p| p == from) => {
+                battle.spectators.push(from);
+                battle.history.iter().map(|p| (from, p.packet())).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn preview(
+        &mut self,
+        from: UUID,
+        id: UUID,
+        preview: &TeamPreview,
+        now: Instant,
+    ) -> Vec<Outgoing> {
+        let max_team_size = self.config.max_team_size;
+        let battle = self.battles.get_mut(&id).unwrap();
+        let side = battle.side(from);
+        if battle.phase != Phase::Preview || battle.teams[side].is_some() {
+            return Vec::new();
+        }
+        if preview.team.is_empty() || preview.team.len() > max_team_size {
+            let reason = format!(
+                "{} sent a team of {} Pokémon, which must have from 1 to {}",
+                from,
+                preview.team.len(),
+                max_team_size
+            );
+            return self.default_win(id, [side == 0, side == 1], raw(reason));
+        }
+        battle.teams[side] = Some(TeamPreview {
+            battle: id,
+            from,
+            team: preview.team.clone(),
+            seed: preview.seed,
+        });
+        if battle.teams.iter().any(Option::is_none) {
+            return Vec::new();
+        }
+        let teams = [
+            battle.teams[0].clone().unwrap(),
+            battle.teams[1].clone().unwrap(),
+        ];
+        let mut outgoing = Vec::new();
+        for team in &teams {
+            outgoing.extend(battle.publish(Published::Preview(team.clone())));
+        }
+        let mut rng = BattleRng::new(combine_seeds(
+            &battle.seed,
+            [&teams[0].seed, &teams[1].seed],
+        ));
+        match self
+            .simulator
+            .start(&battle.format, [&teams[0].team, &teams[1].team], &mut rng)
+        {
+            Ok(state) => {
+                battle.state = Some((state, rng));
+                battle.phase = Phase::Turn;
+                battle.turn = 1;
+                battle.deadline = now + self.config.turn_timeout;
+            }
+            Err(e) => outgoing.extend(self.end(
+                id,
+                None,
+                raw(format!("The battle could not start: {}", e)),
+            )),
+        }
+        outgoing
+    }
+
+    fn action(
+        &mut self,
+        from: UUID,
+        id: UUID,
+        submit: &ActionSubmit,
+        now: Instant,
+    ) -> Vec<Outgoing> {
+        let battle = self.battles.get_mut(&id).unwrap();
+        let side = battle.side(from);
+        // Actions for other turns, and second actions for a turn, are ignored
+        if battle.phase != Phase::Turn
+            || submit.turn != battle.turn
+            || battle.actions[side].is_some()
+        {
+            return Vec::new();
+        }
+        battle.actions[side] = Some(submit.action.clone());
+        if battle.actions.iter().any(Option::is_none) {
+            return Vec::new();
+        }
+        let actions = [
+            battle.actions[0].take().unwrap(),
+            battle.actions[1].take().unwrap(),
+        ];
+        let (state, rng) = battle.state.as_mut().unwrap();
+        let turn = self.simulator.turn(state, [&actions[0], &actions[1]], rng);
+        let mut outgoing = battle.publish(Published::Result(TurnResult {
+            battle: id,
+            turn: battle.turn,
+            actions: List(actions.to_vec()),
+            events: List(turn.events),
+        }));
+        match turn.outcome {
+            Some(Outcome::Winner(side)) => {
+                let reason = raw(format!("{} won the battle", battle.players[side]));
+                outgoing.extend(self.end(id, Some(side), reason));
+            }
+            Some(Outcome::Draw) => {
+                outgoing.extend(self.end(id, None, raw("The battle ended in a draw".to_string())))
+            }
+            None => {
+                battle.turn += 1;
+                battle.deadline = now + self.config.turn_timeout;
+            }
+        }
+        outgoing
+    }
+
+    ///
+    /// Handles a battle packet sent by a player.
+    /// Packets which are not part of a battle, or which are not expected, are ignored
+    pub fn receive(&mut self, from: UUID, packet: &dyn AnyPacket, now: Instant) -> Vec<Outgoing> {
+        let mut outgoing = self.expire(now);
+        if let Some(challenge) = packet.downcast_ref::<BattleChallenge>() {
+            outgoing.extend(self.challenge(from, challenge, now));
+            return outgoing;
+        }
+        if let Some(spectate) = packet.downcast_ref::<BattleSpectate>() {
+            outgoing.extend(self.spectate(from, spectate.battle));
+            return outgoing;
+        }
+        let id = if let Some(p) = packet.downcast_ref::<BattleAccept>() {
+            p.battle
+        } else if let Some(p) = packet.downcast_ref::<TeamPreview>() {
+            p.battle
+        } else if let Some(p) = packet.downcast_ref::<ActionSubmit>() {
+            p.battle
+        } else if let Some(p) = packet.downcast_ref::<BattleForfeit>() {
+            p.battle
+        } else {
+            return outgoing;
+        };
+        if self.battle_of(from) != Some(id) {
+            return outgoing;
+        }
+        if packet.is::<BattleAccept>() {
+            let turn_timeout = self.config.turn_timeout;
+            let battle = self.battles.get_mut(&id).unwrap();
+            if battle.phase == Phase::Challenged && battle.players[1] == from {
+                battle.phase = Phase::Preview;
+                battle.deadline = now + turn_timeout;
+                outgoing
+                    .extend(battle.publish(Published::Accept(BattleAccept { battle: id, from })));
+            }
+        } else if let Some(preview) = packet.downcast_ref::<TeamPreview>() {
+            outgoing.extend(self.preview(from, id, preview, now));
+        } else if let Some(submit) = packet.downcast_ref::<ActionSubmit>() {
+            outgoing.extend(self.action(from, id, submit, now));
+        } else {
+            outgoing.extend(self.forfeit(id, from, raw(format!("{} forfeited", from))));
+        }
+        outgoing
+    }
+
+    // Withdraws a challenge which was not yet accepted, or the player loses
+    fn forfeit(&mut self, id: UUID, player: UUID, reason: TextComponent) -> Vec<Outgoing> {
+        let battle = &self.battles[&id];
+        if battle.phase == Phase::Challenged {
+            return self.end(id, None, reason);
+        }
+        let side = battle.side(player);
+        self.default_win(id, [side == 0, side == 1], reason)
+    }
+
+    ///
+    /// Ends the battle of a player who disconnected, who loses, and stops sending them battles they were watching
+    pub fn disconnected(&mut self, player: UUID) -> Vec<Outgoing> {
+        for battle in self.battles.values_mut() {
+            battle.spectators.retain(|p| *p != player);
+        }
+        match self.battle_of(player) {
+            Some(id) => self.forfeit(id, player, raw(format!("{} disconnected", player))),
+            None => Vec::new(),
+        }
+    }
+
+    ///
+    /// Ends every battle in which a player has not acted before its deadline, which the players who did not act lose
+    pub fn expire(&mut self, now: Instant) -> Vec<Outgoing> {
+        let expired = self
+            .battles
+            .iter()
+            .filter(|(_, battle)| battle.deadline <= now)
+            .map(|(id, battle)| {
+                let losers = match battle.phase {
+                    Phase::Challenged => [false, false],
+                    Phase::Preview => [battle.teams[0].is_none(), battle.teams[1].is_none()],
+                    Phase::Turn => [battle.actions[0].is_none(), battle.actions[1].is_none()],
+                };
+                (*id, battle.phase, losers)
+            })
+            .collect::<Vec<_>>();
+        let mut outgoing = Vec::new();
+        for (id, phase, losers) in expired {
+            if phase == Phase::Challenged {
+                outgoing.extend(self.end(
+                    id,
+                    None,
+                    raw("The challenge was not accepted in time".to_string()),
+                ));
+            } else {
+                outgoing.extend(self.default_win(id, losers, raw("Timed out".to_string())));
+            }
+        }
+        outgoing
+    }
+}
+
+#[cfg(feature = "tcp")]
+fn is_battle_packet(packet: &dyn AnyPacket) -> bool {
+    packet.is::<BattleChallenge>()
+        || packet.is::<BattleAccept>()
+        || packet.is::<TeamPreview>()
+        || packet.is::<ActionSubmit>()
+        || packet.is::<BattleForfeit>()
+        || packet.is::<BattleSpectate>()
+}
+
+#[cfg(feature = "tcp")]
+///
+/// A [`Handler`] which runs battles between the clients of a server.
+/// Packets which are not part of a battle are ignored.
+/// Battles are expired whenever a packet is received, and on each tick of the server
+pub struct BattleService<S: BattleSimulator> {
+    sessions: Mutex<BattleSessions<S>>,
+    peers: Mutex<HashMap<UUID, Peer>>,
+}
+
+#[cfg(feature = "tcp")]
+impl<S: BattleSimulator> BattleService<S> {
+    ///
+    /// Creates a service which runs the given sessions
+    pub fn new(sessions: BattleSessions<S>) -> Self {
+        Self {
+            sessions: Mutex::new(sessions),
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    ///
+    /// Returns the battle the player is fighting in, if any
+    pub fn battle_of(&self, player: UUID) -> Option<UUID> {
+        self.sessions.lock().unwrap().battle_of(player)
+    }
+
+    fn send(&self, outgoing: Vec<Outgoing>) {
+        let peers = self.peers.lock().unwrap();
+        for (player, packet) in outgoing {
+            if let Some(peer) = peers.get(&player) {
+                let _ = peer.send(&*packet);
+            }
+        }
+    }
+
+    ///
+    /// Handles a packet received from a client.
+    /// Returns false if the packet is not part of a battle, so that other handlers can be tried
+    pub fn handle(&self, peer: &Peer, packet: &dyn AnyPacket) -> bool {
+        if !is_battle_packet(packet) {
+            return false;
+        }
+        let outgoing = {
+            let mut sessions = self.sessions.lock().unwrap();
+            // Checked while the sessions are locked, so that an opponent who disconnects now has the battle ended
+            match packet.downcast_ref::<BattleChallenge>() {
+                Some(challenge) if !self.peers.lock().unwrap().contains_key(&challenge.to) => {
+                    send_to(
+                        peer.client(),
+                        BattleEnd {
+                            battle: UUID::NIL,
+                            winner: UUID::NIL,
+                            reason: raw(format!("{} is not connected", challenge.to)),
+                            seed: [0; 32],
+                        },
+                    )
+                }
+                _ => sessions.receive(peer.client(), packet, Instant::now()),
+            }
+        };
+        self.send(outgoing);
+        true
+    }
+
+    ///
+    /// Ends every battle which has timed out
+    pub fn expire(&self) {
+        let outgoing = self.sessions.lock().unwrap().expire(Instant::now());
+        self.send(outgoing);
+    }
+}
+
+#[cfg(feature = "tcp")]
+impl<S: BattleSimulator> Handler for BattleService<S> {
+    fn connected(&self, peer: &Peer) -> std::io::Result<()> {
+        self.peers
+            .lock()
+            .unwrap()
+            .insert(peer.client(), peer.clone());
+        Ok(())
+    }
+
+    fn packet(&self, peer: &Peer, packet: Box<dyn AnyPacket>) -> std::io::Result<()> {
+        self.handle(peer, &*packet);
+        Ok(())
+    }
+
+    fn disconnected(&self, peer: &Peer, _: Option<&std::io::Error>) {
+        self.peers.lock().unwrap().remove(&peer.client());
+        let outgoing = self.sessions.lock().unwrap().disconnected(peer.client());
+        self.send(outgoing);
+    }
+
+    fn tick(&self) {
+        self.expire();
+    }
+}
+
+///
+/// The Error returned when a battle cannot be verified
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    ///
+    /// The battle has not ended, or did not start
+    Incomplete,
+    ///
+    /// The seed revealed by the server does not match its commitment
+    CommitmentMismatch,
+    ///
+    /// The simulation could not start, for the given reason
+    Start(String),
+    ///
+    /// The events of the turn differ from the simulation
+    Diverged(u32),
+    ///
+    /// The winner differs from the simulation
+    WrongWinner,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::Incomplete => f.write_str("The battle is incomplete"),
+            VerifyError::CommitmentMismatch => {
+                f.write_str("The server's seed does not match its commitment")
+            }
+            VerifyError::Start(reason) => {
+                f.write_fmt(format_args!("The simulation could not start: {}", reason))
+            }
+            VerifyError::Diverged(turn) => {
+                f.write_fmt(format_args!("Turn {} differs from the simulation", turn))
+            }
+            VerifyError::WrongWinner => f.write_str("The winner differs from the simulation"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl From<VerifyError> for std::io::Error {
+    fn from(e: VerifyError) -> Self {
+        std::io::Error::new(ErrorKind::InvalidData, e)
+    }
+}
+
+///
+/// The packets of a battle, as seen by a player or spectator, which can be verified once the battle ends
+#[derive(Clone, Debug, Default)]
+pub struct BattleRecord {
+    challenge: Option<BattleChallenge>,
+    previews: Vec<TeamPreview>,
+    results: Vec<TurnResult>,
+    end: Option<BattleEnd>,
+}
+
+impl BattleRecord {
+    ///
+    /// Creates an empty record
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Records a packet of the battle.
+    /// The first challenge received decides the battle, and packets of other battles are not recorded.
+    /// Returns true if the packet was recorded
+    pub fn receive(&mut self, packet: &dyn AnyPacket) -> bool {
+        if let Some(challenge) = packet.downcast_ref::<BattleChallenge>() {
+            if self.challenge.is_none() {
+                self.challenge = Some(challenge.clone());
+                return true;
+            }
+            return false;
+        }
+        let battle = match &self.challenge {
+            Some(challenge) => challenge.battle,
+            None => return false,
+        };
+        if let Some(preview) = packet.downcast_ref::<TeamPreview>() {
+            if preview.battle == battle {
+                self.previews.push(preview.clone());
+                return true;
+            }
+        } else if let Some(result) = packet.downcast_ref::<TurnResult>() {
+            if result.battle == battle {
+                self.results.push(result.clone());
+                return true;
+            }
+        } else if let Some(end) = packet.downcast_ref::<BattleEnd>() {
+            if end.battle == battle {
+                self.end = Some(end.clone());
+                return true;
+            }
+        }
+        false
+    }
+
+    ///
+    /// Returns the end of the battle, if it has ended
+    pub fn end(&self) -> Option<&BattleEnd> {
+        self.end.as_ref()
+    }
+
+    ///
+    /// Returns the results of the turns received so far
+    pub fn results(&self) -> &[TurnResult] {
+        &self.results
+    }
+
+    ///
+    /// Checks the server's seed against its commitment, and simulates the battle again with simulator,
+    ///  checking that every turn has the same events, and that the simulation decides the same winner.
+    /// Battles which end by a forfeit or timeout are checked up to their last turn
+    pub fn verify<S: BattleSimulator>(&self, simulator: &S) -> Result<(), VerifyError> {
+        let (challenge, end) = match (&self.challenge, &self.end) {
+            (Some(challenge), Some(end)) => (challenge, end),
+            _ => return Err(VerifyError::Incomplete),
+        };
+        if Digest::of_bytes(&end.seed) != challenge.commitment {
+            return Err(VerifyError::CommitmentMismatch);
+        }
+        let players = [challenge.from, challenge.to];
+        let team = |player: UUID| {
+            self.previews
+                .iter()
+                .find(|p| p.from == player)
+                .ok_or(VerifyError::Incomplete)
+        };
+        let teams = [team(players[0])?, team(players[1])?];
+        let mut rng = BattleRng::new(combine_seeds(&end.seed, [&teams[0].seed, &teams[1].seed]));
+        let mut state = simulator
+            .start(
+                &challenge.format,
+                [&teams[0].team, &teams[1].team],
+                &mut rng,
+            )
+            .map_err(|e| VerifyError::Start(e.to_string()))?;
+        let mut outcome = None;
+        for (i, result) in self.results.iter().enumerate() {
+            if result.turn as usize != i + 1 || result.actions.len() != 2 || outcome.is_some() {
+                return Err(VerifyError::Diverged(result.turn));
+            }
+            let turn = simulator.turn(
+                &mut state,
+                [&result.actions[0], &result.actions[1]],
+                &mut rng,
+            );
+            if turn.events != result.events.0 {
+                return Err(VerifyError::Diverged(result.turn));
+            }
+            outcome = turn.outcome;
+        }
+        let winner = match outcome {
+            Some(Outcome::Winner(side)) => players[side],
+            Some(Outcome::Draw) => UUID::NIL,
+            None => return Ok(()),
+        };
+        if winner != end.winner {
+            return Err(VerifyError::WrongWinner);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,546 @@
+//!
+//! Capture, inspection and replay of PkmCom connections.
+//!
+//! A [`Capture`] attached to a [`Connection`] records every frame sent or received on it, including KeepAlives and the handshake,
+//!  with the time since the capture started, and the direction it was sent in.
+//! Clients attach a capture through [`ClientConfig::capture`](crate::client::ClientConfig::capture),
+//!  and servers write a capture of each connection to [`ServerConfig::capture_dir`](crate::server::ServerConfig::capture_dir).
+//!
+//! A capture file is big endian, and starts with the magic `PKCP`, the format version as a u16,
+//!  the side of the connection which recorded it as a u8 (0 for the client and 1 for the server),
+//!  and the time it started as a u64 number of milliseconds since the Unix epoch.
+//! Each frame follows, as the microseconds since the capture started as a u64, the direction as a u8
+//!  (0 for serverbound and 1 for clientbound), the packet id as a u16, and the payload as a u32 length followed by its bytes.
+//! Frames are recorded uncompressed, and after decryption, so captures can always be decoded with the packet registry.
+//!
+//! [`CaptureReader`] reads the frames back, [`pretty_print`] writes them as text, and [`Replay`] feeds the packets of a capture
+//!  to a server [`Handler`] or a client, so that recorded sessions can be used as regression tests.
+
+use std::{
+    fmt::{Debug, Display},
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use binary_io::{uuid::UUID, version::Version};
+
+use crate::{
+    connection::{Connection, Sender, Side, Transport},
+    frame::{Frame, FrameCodec, FrameDecoder},
+    handshake::{Handshake, LoginAccept},
+    packet::{AnyPacket, Direction, Disconnect, KeepAlive, KeepAliveReply, Packet, PacketRegistry},
+    server::{Handler, Peer},
+};
+
+///
+/// The magic at the start of every capture file
+pub const CAPTURE_MAGIC: [u8; 4] = *b"PKCP";
+
+///
+/// The version of the capture format written by [`Capture`]
+pub const CAPTURE_VERSION: u16 = 1;
+
+///
+/// The Error returned when a capture file is malformed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CaptureError {
+    ///
+    /// The file does not start with [`CAPTURE_MAGIC`]
+    BadMagic([u8; 4]),
+    ///
+    /// The file has a format version which cannot be read
+    UnsupportedVersion(u16),
+    ///
+    /// The file records an unknown side of a connection
+    InvalidSide(u8),
+    ///
+    /// A frame was recorded with an unknown direction
+    InvalidDirection(u8),
+}
+
+impl Display for CaptureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CaptureError::BadMagic(magic) => {
+                f.write_fmt(format_args!("Not a capture file (magic {:02x?})", magic))
+            }
+            CaptureError::UnsupportedVersion(version) => {
+                f.write_fmt(format_args!("Unsupported capture version {}", version))
+            }
+            CaptureError::InvalidSide(side) => {
+                f.write_fmt(format_args!("Invalid side {} in capture", side))
+            }
+            CaptureError::InvalidDirection(direction) => {
+                f.write_fmt(format_args!("Invalid direction {} in capture", direction))
+            }
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {}
+
+impl From<CaptureError> for std::io::Error {
+    fn from(e: CaptureError) -> Self {
+        std::io::Error::new(ErrorKind::InvalidData, e)
+    }
+}
+
+///
+/// A frame read from a capture
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CapturedFrame {
+    ///
+    /// The time since the capture started, when the frame was sent or received
+    pub elapsed: Duration,
+    ///
+    /// The direction the frame was sent in, which is either Serverbound or Clientbound
+    pub direction: Direction,
+    ///
+    /// The frame
+    pub frame: Frame,
+}
+
+impl CapturedFrame {
+    ///
+    /// Decodes the packet in the frame
+    pub fn decode(&self, registry: &PacketRegistry) -> std::io::Result<Box<dyn AnyPacket>> {
+        self.frame.decode(registry, self.direction)
+    }
+
+    ///
+    /// Describes the frame on a single line, with the packet decoded by registry if possible, or the start of the payload otherwise
+    pub fn describe(&self, registry: &PacketRegistry) -> String {
+        let prefix = format!(
+            "{:>12.3}ms {:<11} {:#06x}",
+            self.elapsed.as_secs_f64() * 1000.0,
+            self.direction,
+            self.frame.id
+        );
+        match self.decode(registry) {
+            Ok(packet) => format!("{} {:?}", prefix, packet),
+            Err(e) => {
+                let shown = &self.frame.payload[..self.frame.payload.len().min(32)];
+                let ellipsis = if shown.len() < self.frame.payload.len() {
+                    " .."
+                } else {
+                    ""
+                };
+                format!(
+                    "{} <{}> {} bytes: {:02x?}{}",
+                    prefix,
+                    e,
+                    self.frame.payload.len(),
+                    shown,
+                    ellipsis
+                )
+            }
+        }
+    }
+}
+
+fn side_tag(side: Side) -> u8 {
+    match side {
+        Side::Client => 0,
+        Side::Server => 1,
+    }
+}
+
+struct Recorder {
+    output: Box<dyn Write + Send>,
+    started: Instant,
+}
+
+///
+/// Records the frames of a connection to a capture file.
+/// A capture is a handle, which may be cloned and shared between the sending and receiving halves of a connection
+#[derive(Clone)]
+pub struct Capture {
+    recorder: Arc<Mutex<Recorder>>,
+    side: Side,
+}
+
+impl Debug for Capture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Capture").field("side", &self.side).finish()
+    }
+}
+
+impl Capture {
+    ///
+    /// Starts a capture of a connection on the given side, written to output
+    pub fn new<W: Write + Send + 'static>(mut output: W, side: Side) -> std::io::Result<Self> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        output.write_all(&CAPTURE_MAGIC)?;
+        output.write_all(&CAPTURE_VERSION.to_be_bytes())?;
+        output.write_all(&[side_tag(side)])?;
+        output.write_all(&started.to_be_bytes())?;
+        output.flush()?;
+        Ok(Self {
+            recorder: Arc::new(Mutex::new(Recorder {
+                output: Box::new(output),
+                started: Instant::now(),
+            })),
+            side,
+        })
+    }
+
+    ///
+    /// Starts a capture of a connection on the given side, written to a new file at path
+    pub fn create<P: AsRef<Path>>(path: P, side: Side) -> std::io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), side)
+    }
+
+    ///
+    /// Returns the side of the connection being recorded
+    pub fn side(&self) -> Side {
+        self.side
+    }
+
+    ///
+    /// Records a frame sent in direction. The frame is flushed to the output, so that captures survive crashes
+    pub fn record(&self, direction: Direction, frame: &Frame) -> std::io::Result<()> {
+        let direction = match direction {
+            Direction::Serverbound => 0u8,
+            Direction::Clientbound => 1u8,
+            Direction::Bidirectional => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "Frames are captured with the direction they were sent in",
+                ))
+            }
+        };
+        let mut recorder = self.recorder.lock().unwrap();
+        let elapsed = recorder.started.elapsed().as_micros() as u64;
+        let mut record = Vec::with_capacity(8 + 1 + 2 + 4 + frame.payload.len());
+        record.extend_from_slice(&elapsed.to_be_bytes());
+        record.push(direction);
+        record.extend_from_slice(&frame.id.to_be_bytes());
+        record.extend_from_slice(&(frame.payload.len() as u32).to_be_bytes());
+        record.extend_from_slice(&frame.payload);
+        recorder.output.write_all(&record)?;
+        recorder.output.flush()
+    }
+}
+
+///
+/// Reads the frames of a capture file
+pub struct CaptureReader<R> {
+    input: R,
+    side: Side,
+    started: SystemTime,
+}
+
+impl CaptureReader<BufReader<File>> {
+    ///
+    /// Opens the capture file at path
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    ///
+    /// Reads the header of a capture from input
+    pub fn new(mut input: R) -> std::io::Result<Self> {
+        let mut header = [0u8; 4 + 2 + 1 + 8];
+        input.read_exact(&mut header)?;
+        let mut magic = [0u8; 4];
+        magic.copy_from_slice(&header[..4]);
+        if magic != CAPTURE_MAGIC {
+            return Err(CaptureError::BadMagic(magic).into());
+        }
+        let version = u16::from_be_bytes([header[4], header[5]]);
+        if version != CAPTURE_VERSION {
+            return Err(CaptureError::UnsupportedVersion(version).into());
+        }
+        let side = match header[6] {
+            0 => Side::Client,
+            1 => Side::Server,
+            side => return Err(CaptureError::InvalidSide(side).into()),
+        };
+        let mut started = [0u8; 8];
+        started.copy_from_slice(&header[7..]);
+        Ok(Self {
+            input,
+            side,
+            started: UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(started)),
+        })
+    }
+
+    ///
+    /// Returns the side of the connection which recorded the capture
+    pub fn side(&self) -> Side {
+        self.side
+    }
+
+    ///
+    /// Returns the time the capture started
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+
+    ///
+    /// Reads the next frame, or returns None at the end of the capture.
+    /// A capture which ends part way through a frame, such as one which was still being written, is an error
+    pub fn next_frame(&mut self) -> std::io::Result<Option<CapturedFrame>> {
+        let mut header = [0u8; 8 + 1 + 2 + 4];
+        let mut read = 0;
+        while read < header.len() {
+            match self.input.read(&mut header[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let mut elapsed = [0u8; 8];
+        elapsed.copy_from_slice(&header[..8]);
+        let direction = match header[8] {
+            0 => Direction::Serverbound,
+            1 => Direction::Clientbound,
+            direction => return Err(CaptureError::InvalidDirection(direction).into()),
+        };
+        let id = u16::from_be_bytes([header[9], header[10]]);
+        let len = u32::from_be_bytes([header[11], header[12], header[13], header[14]]) as u64;
+        // The payload is read as it arrives, rather than allocated up front from an untrusted length
+        let mut payload = Vec::new();
+        (&mut self.input).take(len).read_to_end(&mut payload)?;
+        if payload.len() as u64 != len {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Some(CapturedFrame {
+            elapsed: Duration::from_micros(u64::from_be_bytes(elapsed)),
+            direction,
+            frame: Frame::new(id, payload),
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = std::io::Result<CapturedFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+///
+/// Writes each frame of a capture to output, on its own line, described by [`CapturedFrame::describe`].
+/// Packets are decoded for the protocol version accepted in the capture, if any
+pub fn pretty_print<R: Read, W: Write + ?Sized>(
+    reader: &mut CaptureReader<R>,
+    registry: &PacketRegistry,
+    output: &mut W,
+) -> std::io::Result<()> {
+    let mut registry = registry.clone();
+    let started = reader
+        .started()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis());
+    writeln!(
+        output,
+        "# Capture of the {} side of a connection, started {}ms after the Unix epoch",
+        match reader.side() {
+            Side::Client => "client",
+            Side::Server => "server",
+        },
+        started
+    )?;
+    while let Some(frame) = reader.next_frame()? {
+        writeln!(output, "{}", frame.describe(&registry))?;
+        if frame.frame.id == LoginAccept::ID {
+            if let Ok(accept) = frame.decode(&registry) {
+                registry.set_version(accept.downcast_ref::<LoginAccept>().unwrap().protocol);
+            }
+        }
+    }
+    Ok(())
+}
+
+// The transport of a replayed connection, which keeps what is sent, and has nothing to receive
+#[derive(Clone, Default)]
+struct Sink(Arc<Mutex<Vec<u8>>>);
+
+impl Read for Sink {
+    fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Sink {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(self.clone())
+    }
+
+    fn set_read_timeout(&self, _: Option<Duration>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+///
+/// The packets of a capture, which can be fed to a server [`Handler`] or a client, in place of a live connection
+pub struct Replay {
+    frames: Vec<CapturedFrame>,
+    registry: PacketRegistry,
+}
+
+impl Replay {
+    ///
+    /// Creates a replay of frames, which decodes packets with registry
+    pub fn new(frames: Vec<CapturedFrame>, registry: PacketRegistry) -> Self {
+        Self { frames, registry }
+    }
+
+    ///
+    /// Reads every frame of a capture, and creates a replay of them
+    pub fn read<R: Read>(
+        reader: CaptureReader<R>,
+        registry: PacketRegistry,
+    ) -> std::io::Result<Self> {
+        Ok(Self::new(reader.collect::<std::io::Result<_>>()?, registry))
+    }
+
+    ///
+    /// Returns the frames of the replay
+    pub fn frames(&self) -> &[CapturedFrame] {
+        &self.frames
+    }
+
+    fn find<P: Packet + Clone>(&self) -> Option<P> {
+        let direction = P::DIRECTION;
+        self.frames
+            .iter()
+            .filter(|frame| frame.frame.id == P::ID && direction.allows(frame.direction))
+            .find_map(|frame| frame.decode(&self.registry).ok())
+            .and_then(|packet| packet.downcast_ref::<P>().cloned())
+    }
+
+    ///
+    /// Returns the UUID of the player the client logged in as, if the capture includes the handshake
+    pub fn client(&self) -> Option<UUID> {
+        self.find::<Handshake>().map(|handshake| handshake.client)
+    }
+
+    ///
+    /// Returns the protocol version the server accepted, if the capture includes the handshake
+    pub fn version(&self) -> Option<Version> {
+        self.find::<LoginAccept>().map(|accept| accept.protocol)
+    }
+
+    ///
+    /// Decodes the packets sent in direction after the handshake, other than KeepAlives and their replies.
+    /// If the capture does not include the handshake, every packet is decoded
+    pub fn packets(&self, direction: Direction) -> std::io::Result<Vec<Box<dyn AnyPacket>>> {
+        let mut registry = self.registry.clone();
+        let start = match self.frames.iter().position(|frame| {
+            frame.frame.id == LoginAccept::ID && frame.direction == Direction::Clientbound
+        }) {
+            Some(accept) => {
+                if let Some(version) = self.version() {
+                    registry.set_version(version);
+                }
+                accept + 1
+            }
+            None => 0,
+        };
+        self.frames[start..]
+            .iter()
+            .filter(|frame| {
+                frame.direction == direction
+                    && frame.frame.id != KeepAlive::ID
+                    && frame.frame.id != KeepAliveReply::ID
+            })
+            .map(|frame| frame.decode(&registry))
+            .collect()
+    }
+
+    fn connection(&self, side: Side) -> std::io::Result<(Connection<Sink>, Sink)> {
+        let sink = Sink::default();
+        let mut conn = Connection::new(sink.clone(), side, self.registry.clone())?;
+        if let Some(version) = self.version() {
+            conn.registry_mut().set_version(version);
+        }
+        Ok((conn, sink))
+    }
+
+    fn sent(conn: &Connection<Sink>, sink: &Sink) -> std::io::Result<Vec<Box<dyn AnyPacket>>> {
+        let mut decoder = FrameDecoder::with_codec(FrameCodec::default());
+        decoder.feed(&sink.0.lock().unwrap());
+        let mut packets = Vec::new();
+        while let Some(packet) = decoder.next_packet(conn.registry(), conn.side().sends())? {
+            packets.push(packet);
+        }
+        Ok(packets)
+    }
+
+    ///
+    /// Feeds the serverbound packets of the capture to handler, as if the client had connected again, until the client disconnected.
+    /// The peer is the player from the handshake, or NIL if the capture does not include it.
+    /// Returns the packets the handler sent to the client
+    pub fn to_server<H: Handler + ?Sized>(
+        &self,
+        handler: &H,
+    ) -> std::io::Result<Vec<Box<dyn AnyPacket>>> {
+        let (conn, sink) = self.connection(Side::Server)?;
+        let peer = Peer::new(
+            self.client().unwrap_or(UUID::NIL),
+            conn.registry().version(),
+            conn.sender().clone(),
+            None,
+        );
+        handler.connected(&peer)?;
+        let mut result = Ok(());
+        for packet in self.packets(Direction::Serverbound)? {
+            if packet.is::<Disconnect>() {
+                break;
+            }
+            result = handler.packet(&peer, packet);
+            if result.is_err() {
+                break;
+            }
+        }
+        handler.disconnected(&peer, result.as_ref().err());
+        result?;
+        Self::sent(&conn, &sink)
+    }
+
+    ///
+    /// Feeds the clientbound packets of the capture to client, as if they were received again, until the server disconnected.
+    /// The client is given a sender for its responses, and may stop the replay by returning an error.
+    /// Returns the packets the client sent to the server
+    pub fn to_client<F>(&self, mut client: F) -> std::io::Result<Vec<Box<dyn AnyPacket>>>
+    where
+        F: FnMut(&Sender, Box<dyn AnyPacket>) -> std::io::Result<()>,
+    {
+        let (conn, sink) = self.connection(Side::Client)?;
+        for packet in self.packets(Direction::Clientbound)? {
+            let disconnect = packet.is::<Disconnect>();
+            client(conn.sender(), packet)?;
+            if disconnect {
+                break;
+            }
+        }
+        Self::sent(&conn, &sink)
+    }
+}
@@ -0,0 +1,459 @@
+//!
+//! Chat between players, formatted by the server.
+//!
+//! Players chat on one of three [`Channel`]s: every player online sees a [`ChatMessage`], the members of the sender's party see a [`PartyChat`],
+//!  and only its recipient sees a [`ChatWhisper`].
+//! The server formats each message into a [`TextComponent`], with the sender's name as given by its [`Roster`],
+//!  and sends it to each recipient in a [`ChatBroadcast`]. Players who sent a [`ChatBlock`] for the sender do not receive it, until they send a [`ChatUnblock`].
+//!
+//! Muted players cannot chat, and every message is first passed to the service's [`ChatFilter`], which may allow, rewrite or reject it.
+//! Players are told why their message was not sent by a [`ChatBroadcast`] from the NIL UUID.
+
+#[cfg(feature = "tcp")]
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use binary_io::uuid::UUID;
+use text::TextComponent;
+#[cfg(feature = "tcp")]
+use text::{Color, Style};
+
+use crate::packet::LongString;
+#[cfg(feature = "tcp")]
+use crate::{
+    packet::{AnyPacket, ChatBroadcast, ChatMessage, Outgoing},
+    server::{Handler, Peer},
+};
+
+packet! {
+    ///
+    /// A chat message sent by a player to one other player
+    pub struct ChatWhisper(0x0012, Serverbound) {
+        ///
+        /// The player the message is for
+        pub to: UUID,
+        ///
+        /// The text typed by the player
+        pub message: LongString,
+    }
+}
+
+packet! {
+    ///
+    /// A chat message sent by a player to the members of their party
+    pub struct PartyChat(0x0013, Serverbound) {
+        ///
+        /// The text typed by the player
+        pub message: LongString,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by a player to stop receiving chat messages from another player
+    pub struct ChatBlock(0x0014, Serverbound) {
+        ///
+        /// The player to block
+        pub player: UUID,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by a player to receive chat messages from a player they blocked again
+    pub struct ChatUnblock(0x0015, Serverbound) {
+        ///
+        /// The player to unblock
+        pub player: UUID,
+    }
+}
+
+///
+/// The players a chat message is sent to
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Channel {
+    ///
+    /// Every player online
+    Global,
+    ///
+    /// The members of the party with the given id
+    Party(UUID),
+    ///
+    /// The player with the given UUID
+    Whisper(UUID),
+}
+
+///
+/// The names of the players known to the server
+pub trait Roster: Send + Sync + 'static {
+    ///
+    /// Returns the name of player, styled as it is shown in chat, or None if the player is unknown.
+    /// Unknown players are shown by their UUID
+    fn name(&self, player: UUID) -> Option<TextComponent>;
+}
+
+///
+/// What a [`ChatFilter`] decides to do with a message
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    ///
+    /// The message is sent as it was typed
+    Allow,
+    ///
+    /// The message is sent with the given text instead
+    Replace(String),
+    ///
+    /// The message is not sent, and the sender is told the reason
+    Reject(TextComponent),
+}
+
+///
+/// Checks chat messages before they are sent, for example to censor words or stop spam
+pub trait ChatFilter: Send + Sync + 'static {
+    ///
+    /// Decides what to do with a message sent by sender on channel
+    fn filter(&self, sender: UUID, channel: Channel, message: &str) -> Verdict;
+}
+
+impl<F: Fn(UUID, Channel, &str) -> Verdict + Send + Sync + 'static> ChatFilter for F {
+    fn filter(&self, sender: UUID, channel: Channel, message: &str) -> Verdict {
+        self(sender, channel, message)
+    }
+}
+
+///
+/// The limits on chat messages
+#[derive(Clone, Debug)]
+pub struct ChatConfig {
+    ///
+    /// The most characters in a message
+    pub max_length: usize,
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self { max_length: 256 }
+    }
+}
+
+#[cfg(feature = "tcp")]
+fn styled(text: &str, color: Color, italics: bool) -> TextComponent {
+    TextComponent::Text {
+        text: text.to_string(),
+        style: Some(Style {
+            color: Some(color),
+            italics,
+            ..Default::default()
+        }),
+    }
+}
+
+#[cfg(feature = "tcp")]
+fn notice(player: UUID, message: TextComponent) -> Outgoing {
+    (
+        player,
+        Box::new(ChatBroadcast {
+            sender: UUID::NIL,
+            message,
+        }),
+    )
+}
+
+#[cfg(feature = "tcp")]
+#[derive(Default)]
+struct Chat {
+    peers: HashMap<UUID, Peer>,
+    parties: HashMap<UUID, UUID>,
+    muted: HashSet<UUID>,
+    blocked: HashMap<UUID, HashSet<UUID>>,
+}
+
+#[cfg(feature = "tcp")]
+impl Chat {
+    fn blocks(&self, player: UUID, sender: UUID) -> bool {
+        self.blocked
+            .get(&player)
+            .is_some_and(|blocked| blocked.contains(&sender))
+    }
+}
+
+#[cfg(feature = "tcp")]
+///
+/// A [`Handler`] which sends chat messages between the clients of a server, naming players with a [`Roster`].
+/// Packets which are not chat messages are ignored
+pub struct ChatService<R> {
+    roster: R,
+    config: ChatConfig,
+    filter: Option<Box<dyn ChatFilter>>,
+    chat: Mutex<Chat>,
+}
+
+#[cfg(feature = "tcp")]
+impl<R: Roster> ChatService<R> {
+    ///
+    /// Creates a service which names players with roster, without a filter
+    pub fn new(roster: R, config: ChatConfig) -> Self {
+        Self {
+            roster,
+            config,
+            filter: None,
+            chat: Mutex::new(Chat::default()),
+        }
+    }
+
+    ///
+    /// Returns the roster players are named with
+    pub fn roster(&self) -> &R {
+        &self.roster
+    }
+
+    ///
+    /// Sets the filter every message is checked with
+    pub fn set_filter<F: ChatFilter>(&mut self, filter: F) {
+        self.filter = Some(Box::new(filter));
+    }
+
+    ///
+    /// Returns the name of player, as shown in chat
+    pub fn name(&self, player: UUID) -> TextComponent {
+        self.roster
+            .name(player)
+            .unwrap_or_else(|| TextComponent::RawText(player.to_string()))
+    }
+
+    ///
+    /// Formats a message sent by sender on channel, as it is shown to recipient
+    pub fn format(
+        &self,
+        sender: UUID,
+        channel: Channel,
+        recipient: UUID,
+        message: &str,
+    ) -> TextComponent {
+        let message = TextComponent::RawText(message.to_string());
+        let group = match channel {
+            Channel::Global => vec![
+                self.name(sender),
+                TextComponent::RawText(": ".to_string()),
+                message,
+            ],
+            Channel::Party(_) => vec![
+                styled("[Party] ", Color::Blue, false),
+                self.name(sender),
+                TextComponent::RawText(": ".to_string()),
+                message,
+            ],
+            // The sender sees who they whispered to
+            Channel::Whisper(to) if recipient == sender => vec![
+                styled("You whisper to ", Color::Grey, true),
+                self.name(to),
+                styled(": ", Color::Grey, true),
+                message,
+            ],
+            Channel::Whisper(_) => vec![
+                self.name(sender),
+                styled(" whispers to you: ", Color::Grey, true),
+                message,
+            ],
+        };
+        TextComponent::ImplicitGroup(group)
+    }
+
+    ///
+    /// Adds player to the party with the given id, leaving their previous party
+    pub fn join_party(&self, player: UUID, party: UUID) {
+        self.chat.lock().unwrap().parties.insert(player, party);
+    }
+
+    ///
+    /// Removes player from their party
+    pub fn leave_party(&self, player: UUID) {
+        self.chat.lock().unwrap().parties.remove(&player);
+    }
+
+    ///
+    /// Returns the id of the party player is a member of, if any
+    pub fn party_of(&self, player: UUID) -> Option<UUID> {
+        self.chat.lock().unwrap().parties.get(&player).copied()
+    }
+
+    ///
+    /// Stops player from chatting, until they are unmuted
+    pub fn mute(&self, player: UUID) {
+        self.chat.lock().unwrap().muted.insert(player);
+    }
+
+    ///
+    /// Allows a muted player to chat again
+    pub fn unmute(&self, player: UUID) {
+        self.chat.lock().unwrap().muted.remove(&player);
+    }
+
+    ///
+    /// Checks if player is muted
+    pub fn is_muted(&self, player: UUID) -> bool {
+        self.chat.lock().unwrap().muted.contains(&player)
+    }
+
+    ///
+    /// Stops player from receiving messages from sender, or allows them again if blocked is false
+    pub fn set_blocked(&self, player: UUID, sender: UUID, blocked: bool) {
+        let mut chat = self.chat.lock().unwrap();
+        if blocked {
+            chat.blocked.entry(player).or_default().insert(sender);
+        } else if let Some(list) = chat.blocked.get_mut(&player) {
+            list.remove(&sender);
+            if list.is_empty() {
+                chat.blocked.remove(&player);
+            }
+        }
+    }
+
+    ///
+    /// Checks if player has blocked messages from sender
+    pub fn is_blocked(&self, player: UUID, sender: UUID) -> bool {
+        self.chat.lock().unwrap().blocks(player, sender)
+    }
+
+    ///
+    /// Sends a message from the server to every player online
+    pub fn announce(&self, message: TextComponent) {
+        let chat = self.chat.lock().unwrap();
+        for peer in chat.peers.values() {
+            let _ = peer.send(&ChatBroadcast {
+                sender: UUID::NIL,
+                message: message.clone(),
+            });
+        }
+    }
+
+    // Players who cannot be sent to have disconnected, and are removed when the server notices
+    fn send(&self, outgoing: Vec<Outgoing>) {
+        let chat = self.chat.lock().unwrap();
+        for (player, packet) in outgoing {
+            if let Some(peer) = chat.peers.get(&player) {
+                let _ = peer.send(&*packet);
+            }
+        }
+    }
+
+    // Returns the packets which send message from sender on channel
+    fn chat(&self, sender: UUID, channel: Channel, message: &str) -> Vec<Outgoing> {
+        let message = message.trim();
+        if message.is_empty() {
+            return Vec::new();
+        }
+        if message.chars().count() > self.config.max_length {
+            return vec![notice(
+                sender,
+                TextComponent::RawText("Your message is too long".to_string()),
+            )];
+        }
+        let recipients = {
+            let chat = self.chat.lock().unwrap();
+            if chat.muted.contains(&sender) {
+                return vec![notice(
+                    sender,
+                    TextComponent::RawText("You are muted".to_string()),
+                )];
+            }
+            let mut recipients = match channel {
+                Channel::Global => chat.peers.keys().copied().collect::<Vec<_>>(),
+                Channel::Party(party) => chat
+                    .peers
+                    .keys()
+                    .filter(|player| chat.parties.get(player) == Some(&party))
+                    .copied()
+                    .collect(),
+                Channel::Whisper(to) if !chat.peers.contains_key(&to) => {
+                    return vec![notice(
+                        sender,
+                        TextComponent::ImplicitGroup(vec![
+                            self.name(to),
+                            TextComponent::RawText(" is not online".to_string()),
+                        ]),
+                    )];
+                }
+                // The sender is not told if they are blocked
+                Channel::Whisper(to) if to != sender => vec![to, sender],
+                Channel::Whisper(to) => vec![to],
+            };
+            recipients.retain(|player| !chat.blocks(*player, sender));
+            recipients
+        };
+        let message = match self
+            .filter
+            .as_ref()
+            .map(|f| f.filter(sender, channel, message))
+        {
+            None | Some(Verdict::Allow) => message.to_string(),
+            Some(Verdict::Replace(message)) => message,
+            Some(Verdict::Reject(reason)) => return vec![notice(sender, reason)],
+        };
+        recipients
+            .into_iter()
+            .map(|player| {
+                (
+                    player,
+                    Box::new(ChatBroadcast {
+                        sender,
+                        message: self.format(sender, channel, player, &message),
+                    }) as Box<dyn AnyPacket>,
+                )
+            })
+            .collect()
+    }
+
+    ///
+    /// Handles a packet received from a client.
+    /// Returns false if the packet is not part of chat, so that other handlers can be tried
+    pub fn handle(&self, peer: &Peer, packet: &dyn AnyPacket) -> bool {
+        let sender = peer.client();
+        let outgoing = if let Some(message) = packet.downcast_ref::<ChatMessage>() {
+            self.chat(sender, Channel::Global, &message.message.0)
+        } else if let Some(message) = packet.downcast_ref::<PartyChat>() {
+            match self.party_of(sender) {
+                Some(party) => self.chat(sender, Channel::Party(party), &message.message.0),
+                None => vec![notice(
+                    sender,
+                    TextComponent::RawText("You are not in a party".to_string()),
+                )],
+            }
+        } else if let Some(message) = packet.downcast_ref::<ChatWhisper>() {
+            self.chat(sender, Channel::Whisper(message.to), &message.message.0)
+        } else if let Some(block) = packet.downcast_ref::<ChatBlock>() {
+            self.set_blocked(sender, block.player, true);
+            Vec::new()
+        } else if let Some(unblock) = packet.downcast_ref::<ChatUnblock>() {
+            self.set_blocked(sender, unblock.player, false);
+            Vec::new()
+        } else {
+            return false;
+        };
+        self.send(outgoing);
+        true
+    }
+}
+
+#[cfg(feature = "tcp")]
+impl<R: Roster> Handler for ChatService<R> {
+    fn connected(&self, peer: &Peer) -> std::io::Result<()> {
+        self.chat
+            .lock()
+            .unwrap()
+            .peers
+            .insert(peer.client(), peer.clone());
+        Ok(())
+    }
+
+    fn packet(&self, peer: &Peer, packet: Box<dyn AnyPacket>) -> std::io::Result<()> {
+        self.handle(peer, &*packet);
+        Ok(())
+    }
+
+    fn disconnected(&self, peer: &Peer, _: Option<&std::io::Error>) {
+        self.chat.lock().unwrap().peers.remove(&peer.client());
+    }
+}
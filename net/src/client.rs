@@ -0,0 +1,265 @@
+//!
+//! The client side of PkmCom, independent of the transport.
+//!
+//! [`login`] runs the handshake on a new connection, and returns the connection in the Play state once the server accepts it.
+//! If the server gave the client a session, [`resume`] continues it on a new connection after the old one is lost.
+
+use std::{fmt::Display, io::ErrorKind, time::Duration};
+
+use binary_io::{uuid::UUID, version::Version};
+use text::TextComponent;
+
+use crate::{
+    capture::Capture,
+    connection::{Connection, Side, Transport},
+    digest::ContentSummary,
+    frame::{FrameCodec, DEFAULT_MAX_FRAME_SIZE},
+    handshake::{ClientEvent, ClientHandshake, ContentHash, LoginReject, State},
+    metrics::{ConnectionMetrics, Metrics},
+    packet::{Disconnect, PacketRegistry, PROTOCOL_VERSION},
+    session::{Resume, ResumeAccept, SessionError},
+};
+
+///
+/// The configuration of a client connection
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    ///
+    /// The UUID of the client's player
+    pub client: UUID,
+    ///
+    /// The hashes of the resource domains loaded by the client
+    pub content: Vec<ContentHash>,
+    ///
+    /// The summary of the client's content, from which the digests of entries are sent when the server requests them
+    pub summary: ContentSummary,
+    ///
+    /// The newest protocol version supported by the client
+    pub protocol: Version,
+    ///
+    /// The time the server has to respond during the handshake
+    pub handshake_timeout: Duration,
+    ///
+    /// The interval between KeepAlives sent while the server is idle
+    pub keepalive_interval: Duration,
+    ///
+    /// The time the server may be idle, including not answering KeepAlives, before the connection is closed
+    pub timeout: Duration,
+    ///
+    /// The maximum size of a frame received from the server, and of a packet once decompressed
+    pub max_frame_size: usize,
+    ///
+    /// Whether the client supports compression, which is enabled if the server also supports it
+    pub compression: bool,
+    ///
+    /// A capture which records every frame of the connection, including the handshake
+    pub capture: Option<Capture>,
+    ///
+    /// The metrics which count the traffic of the connection, and of any connection its session is resumed on
+    pub metrics: Option<Metrics>,
+    ///
+    /// Whether the client asks for a session, which it can [`resume`] if its connection is lost
+    pub resume: bool,
+}
+
+impl ClientConfig {
+    ///
+    /// Creates the default configuration for the given player and content
+    pub fn new(client: UUID, content: Vec<ContentHash>) -> Self {
+        Self {
+            client,
+            content,
+            summary: ContentSummary::new(),
+            protocol: PROTOCOL_VERSION,
+            handshake_timeout: Duration::from_secs(10),
+            keepalive_interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            compression: true,
+            capture: None,
+            metrics: None,
+            resume: true,
+        }
+    }
+
+    // Opens the metrics of a new connection over transport, if the client counts its traffic
+    fn connection_metrics<T: Transport>(&self, transport: &T) -> Option<ConnectionMetrics> {
+        self.metrics.as_ref().map(|metrics| {
+            let conn = metrics.connection(transport.remote_addr());
+            conn.set_client(self.client);
+            conn
+        })
+    }
+}
+
+///
+/// The Error returned when the server does not accept the client
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoginError {
+    ///
+    /// The server rejected the client, for the given reason
+    Rejected(TextComponent),
+    ///
+    /// The server asked the client to synchronize content, but the client cannot do so
+    SyncUnsupported(Vec<String>),
+    ///
+    /// The server closed the connection during the handshake
+    Closed,
+}
+
+impl Display for LoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoginError::Rejected(reason) => {
+                f.write_fmt(format_args!("Rejected by server: {}", reason))
+            }
+            LoginError::SyncUnsupported(domains) => f.write_fmt(format_args!(
+                "Server requested content synchronization for: {}",
+                domains.join(", ")
+            )),
+            LoginError::Closed => f.write_str("Server closed the connection during login"),
+        }
+    }
+}
+
+impl std::error::Error for LoginError {}
+
+impl From<LoginError> for std::io::Error {
+    fn from(e: LoginError) -> Self {
+        let kind = match e {
+            LoginError::Closed => ErrorKind::ConnectionAborted,
+            _ => ErrorKind::ConnectionRefused,
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+
+///
+/// Runs the handshake over transport, with the given configuration.
+/// If the server asks the client to synchronize content, `sync` is called with the domains to synchronize,
+///  and returns the new hashes of the client's content.
+/// Returns the connection in the Play state, with the negotiated protocol version, and the timeouts from the configuration.
+pub fn login_with_sync<T, F>(
+    transport: T,
+    config: &ClientConfig,
+    registry: PacketRegistry,
+    mut sync: F,
+) -> std::io::Result<Connection<T>>
+where
+    T: Transport,
+    F: FnMut(&[String]) -> std::io::Result<Vec<ContentHash>>,
+{
+    let mut conn = Connection::with_codec(
+        transport,
+        Side::Client,
+        registry,
+        FrameCodec::new(config.max_frame_size),
+    )?;
+    conn.set_capture(config.capture.clone());
+    conn.set_metrics(config.connection_metrics(conn.transport()));
+    conn.set_timeouts(Some(config.handshake_timeout), None)?;
+    let mut handshake = ClientHandshake::new(config.client, config.content.iter().cloned());
+    handshake.set_protocol(config.protocol);
+    handshake.set_compression_supported(config.compression);
+    handshake.set_resume_supported(config.resume);
+    conn.send(&handshake.start()?)?;
+    conn.set_state(handshake.state());
+    loop {
+        let packet = conn.receive()?.ok_or(LoginError::Closed)?;
+        let event = handshake.receive(&*packet)?;
+        conn.set_state(handshake.state());
+        match event {
+            ClientEvent::Ignored => {}
+            ClientEvent::Accepted(version) => {
+                conn.registry_mut().set_version(version);
+                conn.set_compression(handshake.compression());
+                conn.set_session(handshake.session().cloned());
+                break;
+            }
+            ClientEvent::Rejected(reason) => {
+                conn.sender().close()?;
+                return Err(LoginError::Rejected(reason).into());
+            }
+            ClientEvent::SyncRequested(domains) => {
+                let content = match sync(&domains) {
+                    Ok(content) => content,
+                    Err(e) => {
+                        let _ = conn
+                            .sender()
+                            .disconnect(TextComponent::RawText(e.to_string()));
+                        return Err(e);
+                    }
+                };
+                conn.send(&handshake.report(content)?)?;
+            }
+            ClientEvent::DigestsRequested(domains) => {
+                for domain in &domains {
+                    conn.send(&handshake.digests(&config.summary, domain)?)?;
+                }
+            }
+        }
+    }
+    debug_assert_eq!(conn.state(), State::Play);
+    conn.set_timeouts(Some(config.timeout), Some(config.keepalive_interval))?;
+    Ok(conn)
+}
+
+///
+/// Runs the handshake over transport, with the given configuration.
+/// Returns an error if the server asks the client to synchronize content
+pub fn login<T: Transport>(
+    transport: T,
+    config: &ClientConfig,
+    registry: PacketRegistry,
+) -> std::io::Result<Connection<T>> {
+    login_with_sync(transport, config, registry, |domains| {
+        Err(LoginError::SyncUnsupported(domains.to_vec()).into())
+    })
+}
+
+///
+/// Resumes the session of conn, whose transport was lost, over a new transport to the same server.
+/// Packets sent on conn or its Sender while the connection was lost are sent once the session is resumed,
+///  and every clone of its Sender sends on the returned connection.
+/// Returns an error of kind ConnectionRefused if the server no longer has the session, in which case the client must log in again
+pub fn resume<T: Transport, U: Transport>(
+    conn: &Connection<T>,
+    transport: U,
+    config: &ClientConfig,
+) -> std::io::Result<Connection<U>> {
+    let session = conn.session().ok_or(SessionError::NoSession)?;
+    let mut resumed = Connection::with_codec(
+        transport,
+        Side::Client,
+        conn.registry().clone(),
+        FrameCodec::new(config.max_frame_size),
+    )?;
+    resumed.set_capture(conn.capture());
+    resumed.set_metrics(config.connection_metrics(resumed.transport()));
+    resumed.set_timeouts(Some(config.handshake_timeout), None)?;
+    resumed.send(&Resume {
+        client: config.client,
+        token: session.token(),
+        received: session.received(),
+    })?;
+    resumed.set_state(State::Login);
+    loop {
+        let packet = resumed.receive()?.ok_or(LoginError::Closed)?;
+        if let Some(accept) = packet.downcast_ref::<ResumeAccept>() {
+            resumed.adopt(conn.sender().clone(), accept.received)?;
+            break;
+        }
+        let reason = match (
+            packet.downcast_ref::<LoginReject>(),
+            packet.downcast_ref::<Disconnect>(),
+        ) {
+            (Some(reject), _) => reject.reason.clone(),
+            (_, Some(disconnect)) => disconnect.reason.clone(),
+            _ => continue,
+        };
+        resumed.sender().close()?;
+        return Err(LoginError::Rejected(reason).into());
+    }
+    resumed.set_timeouts(Some(config.timeout), Some(config.keepalive_interval))?;
+    Ok(resumed)
+}
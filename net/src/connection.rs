@@ -0,0 +1,647 @@
+//!
+//! PkmCom connections over arbitrary stream transports.
+//!
+//! A [`Connection`] owns the receiving half of a [`Transport`], and decodes frames from it into packets,
+//!  checking them against the current [`State`] of the connection.
+//! Packets are sent through a [`Sender`], which may be cloned and shared with other threads.
+//!
+//! Connections transparently answer KeepAlive packets from the peer with a KeepAliveReply. If a keepalive interval is set,
+//!  the connection sends a KeepAlive whenever nothing has been received for that interval,
+//!  and uses the reply to measure the round trip time.
+//!
+//! A [`Capture`] may be attached to a connection, to record every frame sent and received on it,
+//!  and [`ConnectionMetrics`] to count them, along with round trip times, decode errors and the reason the connection ended.
+//!
+//! A connection with a [`Session`] counts and acknowledges the packets it receives, and keeps the packets it sends until they are acknowledged.
+//! If the transport fails, packets sent in the session are kept rather than failing, and are sent again once the session is resumed on a new connection.
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use text::TextComponent;
+
+use crate::{
+    capture::Capture,
+    frame::{Frame, FrameCodec, FrameDecoder},
+    handshake::{HandshakeError, State},
+    metrics::{ConnectionMetrics, DisconnectReason},
+    packet::{
+        AnyPacket, Direction, Disconnect, KeepAlive, KeepAliveReply, PacketError, PacketRegistry,
+    },
+    session::{self, Ack, Session, SessionError},
+};
+
+///
+/// A reliable, ordered byte stream which PkmCom packets can be sent over
+pub trait Transport: Read + Write + Send + 'static {
+    ///
+    /// Creates a new handle to the same stream, which can be used to send while another thread receives
+    fn try_clone(&self) -> std::io::Result<Self>
+    where
+        Self: Sized;
+    ///
+    /// Sets the timeout for reads. Reads which time out return an error of kind WouldBlock or TimedOut
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+    ///
+    /// Closes the stream in both directions. Pending and future reads on every handle return End of File
+    fn shutdown(&self) -> std::io::Result<()>;
+    ///
+    /// Returns the address of the other end of the stream, if it has one
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+#[cfg(feature = "tcp")]
+impl Transport for std::net::TcpStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        std::net::TcpStream::try_clone(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        std::net::TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) -> std::io::Result<()> {
+        match std::net::TcpStream::shutdown(self, std::net::Shutdown::Both) {
+            Err(e) if e.kind() == ErrorKind::NotConnected => Ok(()),
+            r => r,
+        }
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr().ok()
+    }
+}
+
+///
+/// The side of a connection
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Side {
+    ///
+    /// The side which opened the connection, and sends Serverbound packets
+    Client,
+    ///
+    /// The side which accepted the connection, and sends Clientbound packets
+    Server,
+}
+
+impl Side {
+    ///
+    /// Returns the direction of packets sent by this side
+    pub fn sends(self) -> Direction {
+        match self {
+            Side::Client => Direction::Serverbound,
+            Side::Server => Direction::Clientbound,
+        }
+    }
+
+    ///
+    /// Returns the direction of packets received by this side
+    pub fn receives(self) -> Direction {
+        self.sends().reverse()
+    }
+}
+
+pub(crate) fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+struct Shared {
+    writer: Mutex<Box<dyn Transport>>,
+    codec: Mutex<FrameCodec>,
+    side: Side,
+    closed: AtomicBool,
+    nonce: AtomicU64,
+    ping: Mutex<Option<(u64, Instant)>>,
+    rtt: Mutex<Option<Duration>>,
+    capture: Mutex<Option<Capture>>,
+    metrics: Mutex<Option<ConnectionMetrics>>,
+    session: Mutex<Option<Session>>,
+}
+
+///
+/// A handle used to send packets on a connection, which may be shared between threads
+#[derive(Clone)]
+pub struct Sender {
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for Sender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sender")
+            .field("side", &self.shared.side)
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+impl Sender {
+    ///
+    /// Returns the side of the connection packets are sent from
+    pub fn side(&self) -> Side {
+        self.shared.side
+    }
+
+    ///
+    /// Sends a packet as a single frame.
+    /// Returns an error if the packet may not be sent from this side
+    pub fn send(&self, packet: &dyn AnyPacket) -> std::io::Result<()> {
+        let direction = self.shared.side.sends();
+        if !packet.direction().allows(direction) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                PacketError::WrongDirection(packet.id(), direction),
+            ));
+        }
+        if self.is_closed() {
+            return Err(std::io::Error::new(
+                ErrorKind::NotConnected,
+                "Connection is closed",
+            ));
+        }
+        let frame = Frame::from_packet(packet)?;
+        if packet.is::<Disconnect>() {
+            self.record_disconnect(DisconnectReason::Disconnected);
+        }
+        let bytes = self.shared.codec.lock().unwrap().encode(&frame)?;
+        let mut writer = self.shared.writer.lock().unwrap();
+        let session = self.session().filter(|_| session::is_sequenced(frame.id));
+        if let Some(session) = &session {
+            session.record_sent(frame.clone());
+        }
+        match self.write(&mut writer, &bytes, &frame) {
+            // The frame is kept by the session, and is sent again when it is resumed
+            Err(_) if session.is_some() => {
+                let _ = writer.shutdown();
+                Ok(())
+            }
+            r => r,
+        }
+    }
+
+    // Writes an encoded frame, and records it in the capture and metrics, if any.
+    // Called while the writer is locked, so that frames are captured in the order they were sent
+    fn write(
+        &self,
+        writer: &mut Box<dyn Transport>,
+        bytes: &[u8],
+        frame: &Frame,
+    ) -> std::io::Result<()> {
+        writer.write_all(bytes)?;
+        writer.flush()?;
+        if let Some(metrics) = &*self.shared.metrics.lock().unwrap() {
+            metrics.record_sent(frame.id, bytes.len());
+        }
+        match &*self.shared.capture.lock().unwrap() {
+            Some(capture) => capture.record(self.shared.side.sends(), frame),
+            None => Ok(()),
+        }
+    }
+
+    ///
+    /// Sends a KeepAlive, and records the time it was sent, to measure the round trip time when the peer replies
+    pub fn ping(&self) -> std::io::Result<()> {
+        let nonce = self.shared.nonce.fetch_add(1, Ordering::Relaxed);
+        *self.shared.ping.lock().unwrap() = Some((nonce, Instant::now()));
+        self.send(&KeepAlive { nonce })
+    }
+
+    // Records the round trip time if the reply is to the last ping sent by this side.
+    // Replies to older pings are ignored
+    fn pong(&self, reply: &KeepAliveReply) {
+        let mut ping = self.shared.ping.lock().unwrap();
+        if let Some((sent, at)) = *ping {
+            if sent == reply.nonce {
+                let rtt = at.elapsed();
+                *self.shared.rtt.lock().unwrap() = Some(rtt);
+                if let Some(metrics) = &*self.shared.metrics.lock().unwrap() {
+                    metrics.record_rtt(rtt);
+                }
+                *ping = None;
+            }
+        }
+    }
+
+    // Records the reason the connection is ending in its metrics, if any, unless a reason was already recorded
+    fn record_disconnect(&self, reason: DisconnectReason) {
+        if let Some(metrics) = &*self.shared.metrics.lock().unwrap() {
+            metrics.record_disconnect(reason);
+        }
+    }
+
+    // Acknowledges the packets received in the session since the last Ack, if any
+    fn acknowledge(&self) -> std::io::Result<()> {
+        match self.session().and_then(|session| session.pending_ack()) {
+            Some(ack) => self.send(&ack),
+            None => Ok(()),
+        }
+    }
+
+    ///
+    /// Returns the session of the connection, if any
+    pub fn session(&self) -> Option<Session> {
+        self.shared.session.lock().unwrap().clone()
+    }
+
+    // Closes the transport, without closing the connection, so that its session can be resumed
+    pub(crate) fn interrupt(&self) {
+        let _ = self.shared.writer.lock().unwrap().shutdown();
+    }
+
+    ///
+    /// Returns the round trip time measured by the last KeepAlive the peer replied to, if any
+    pub fn rtt(&self) -> Option<Duration> {
+        *self.shared.rtt.lock().unwrap()
+    }
+
+    ///
+    /// Sends a Disconnect with the given reason, and closes the connection
+    pub fn disconnect(&self, reason: TextComponent) -> std::io::Result<()> {
+        let sent = self.send(&Disconnect { reason });
+        self.close()?;
+        sent
+    }
+
+    ///
+    /// Closes the connection without notifying the peer. Its session, if any, can no longer be resumed
+    pub fn close(&self) -> std::io::Result<()> {
+        if let Some(session) = self.session() {
+            session.expire();
+        }
+        self.record_disconnect(DisconnectReason::Closed);
+        if self.shared.closed.swap(true, Ordering::AcqRel) {
+            Ok(())
+        } else {
+            self.shared.writer.lock().unwrap().shutdown()
+        }
+    }
+
+    ///
+    /// Checks if the connection has been closed by this side
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+}
+
+///
+/// A PkmCom connection over a transport
+pub struct Connection<T> {
+    reader: T,
+    decoder: FrameDecoder,
+    registry: PacketRegistry,
+    state: State,
+    sender: Sender,
+    last_received: Instant,
+    keepalive: Option<Duration>,
+    timeout: Option<Duration>,
+    // Kept apart from the sender's, which moves to a new connection when its session is resumed
+    metrics: Option<ConnectionMetrics>,
+}
+
+impl<T: Transport> Connection<T> {
+    ///
+    /// Creates a connection over transport, which decodes packets with registry, and has the default maximum frame size
+    pub fn new(transport: T, side: Side, registry: PacketRegistry) -> std::io::Result<Self> {
+        Self::with_codec(transport, side, registry, FrameCodec::default())
+    }
+
+    ///
+    /// Creates a connection over transport, which decodes packets with registry, and frames them with codec
+    pub fn with_codec(
+        transport: T,
+        side: Side,
+        registry: PacketRegistry,
+        codec: FrameCodec,
+    ) -> std::io::Result<Self> {
+        let writer = transport.try_clone()?;
+        Ok(Self {
+            reader: transport,
+            decoder: FrameDecoder::with_codec(codec),
+            registry,
+            state: State::Handshaking,
+            sender: Sender {
+                shared: Arc::new(Shared {
+                    writer: Mutex::new(Box::new(writer)),
+                    codec: Mutex::new(codec),
+                    side,
+                    closed: AtomicBool::new(false),
+                    nonce: AtomicU64::new(0),
+                    ping: Mutex::new(None),
+                    rtt: Mutex::new(None),
+                    capture: Mutex::new(None),
+                    metrics: Mutex::new(None),
+                    session: Mutex::new(None),
+                }),
+            },
+            last_received: Instant::now(),
+            keepalive: None,
+            timeout: None,
+            metrics: None,
+        })
+    }
+
+    ///
+    /// Returns the side of the connection
+    pub fn side(&self) -> Side {
+        self.sender.side()
+    }
+
+    ///
+    /// Returns the state of the connection, which determines which packets may be received
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    ///
+    /// Sets the state of the connection
+    pub fn set_state(&mut self, state: State) {
+        self.state = state;
+    }
+
+    ///
+    /// Returns the registry used to decode packets
+    pub fn registry(&self) -> &PacketRegistry {
+        &self.registry
+    }
+
+    ///
+    /// Returns the registry used to decode packets, to register packets or set the negotiated version
+    pub fn registry_mut(&mut self) -> &mut PacketRegistry {
+        &mut self.registry
+    }
+
+    ///
+    /// Returns the compression threshold of the connection, or None if compression is disabled
+    pub fn compression(&self) -> Option<usize> {
+        self.decoder.codec().compression()
+    }
+
+    ///
+    /// Enables compression of packets whose id and payload are at least threshold bytes, or disables compression if None.
+    /// The change applies to packets sent after this call, and packets received which have not yet been returned by [`Connection::receive`]
+    pub fn set_compression(&mut self, threshold: Option<usize>) {
+        self.decoder.set_compression(threshold);
+        self.sender
+            .shared
+            .codec
+            .lock()
+            .unwrap()
+            .set_compression(threshold);
+    }
+
+    ///
+    /// Returns the capture recording the connection, if any
+    pub fn capture(&self) -> Option<Capture> {
+        self.sender.shared.capture.lock().unwrap().clone()
+    }
+
+    ///
+    /// Starts recording every frame sent and received on the connection to capture, or stops recording if None
+    pub fn set_capture(&mut self, capture: Option<Capture>) {
+        *self.sender.shared.capture.lock().unwrap() = capture;
+    }
+
+    ///
+    /// Returns the metrics counting the traffic of the connection, if any
+    pub fn metrics(&self) -> Option<&ConnectionMetrics> {
+        self.metrics.as_ref()
+    }
+
+    ///
+    /// Starts counting the traffic of the connection in metrics, or stops counting if None.
+    /// The metrics are closed when the connection is dropped
+    pub fn set_metrics(&mut self, metrics: Option<ConnectionMetrics>) {
+        *self.sender.shared.metrics.lock().unwrap() = metrics.clone();
+        self.metrics = metrics;
+    }
+
+    ///
+    /// Returns the session of the connection, if any
+    pub fn session(&self) -> Option<Session> {
+        self.sender.session()
+    }
+
+    ///
+    /// Starts a session on the connection, which counts the packets sent and received from this point, or ends it if None
+    pub fn set_session(&mut self, session: Option<Session>) {
+        *self.sender.shared.session.lock().unwrap() = session;
+    }
+
+    ///
+    /// Moves sender, and its session, onto the transport of this connection, so that every clone of sender sends on this connection.
+    /// The packets sent in the session which are not among the first `received`, which the peer received, are sent again first.
+    /// The connection then receives in the Play state, with the compression of sender
+    pub(crate) fn adopt(&mut self, sender: Sender, received: u64) -> std::io::Result<()> {
+        if sender.is_closed() {
+            return Err(std::io::Error::new(
+                ErrorKind::NotConnected,
+                "Connection is closed",
+            ));
+        }
+        let session = sender.session().ok_or(SessionError::NoSession)?;
+        let capture = self.capture();
+        let compression = {
+            let mut writer = sender.shared.writer.lock().unwrap();
+            let frames = session.unacknowledged_from(received)?;
+            let _ = writer.shutdown();
+            *writer = Box::new(self.reader.try_clone()?);
+            if capture.is_some() {
+                *sender.shared.capture.lock().unwrap() = capture;
+            }
+            // The metrics of the lost connection were closed with it, and the traffic from here is counted as this connection's
+            *sender.shared.metrics.lock().unwrap() = self.metrics.clone();
+            let codec = *sender.shared.codec.lock().unwrap();
+            for frame in &frames {
+                let written = codec
+                    .encode(frame)
+                    .and_then(|bytes| sender.write(&mut writer, &bytes, frame));
+                // As in Sender::send, the frames are still kept, and are sent again if the session is resumed again
+                if written.is_err() {
+                    let _ = writer.shutdown();
+                    break;
+                }
+            }
+            codec.compression()
+        };
+        self.sender = sender;
+        self.decoder.set_compression(compression);
+        self.state = State::Play;
+        Ok(())
+    }
+
+    ///
+    /// Returns the transport packets are received from
+    pub fn transport(&self) -> &T {
+        &self.reader
+    }
+
+    ///
+    /// Returns a handle to send packets on the connection
+    pub fn sender(&self) -> &Sender {
+        &self.sender
+    }
+
+    ///
+    /// Sends a packet on the connection
+    pub fn send(&self, packet: &dyn AnyPacket) -> std::io::Result<()> {
+        self.sender.send(packet)
+    }
+
+    ///
+    /// Sets how long the connection may be idle before [`Connection::receive`] fails with TimedOut,
+    ///  and the interval between KeepAlives sent while the connection is idle.
+    pub fn set_timeouts(
+        &mut self,
+        timeout: Option<Duration>,
+        keepalive: Option<Duration>,
+    ) -> std::io::Result<()> {
+        self.timeout = timeout;
+        self.keepalive = keepalive;
+        self.last_received = Instant::now();
+        let read_timeout = match (keepalive, timeout) {
+            (Some(k), Some(t)) => Some(k.min(t)),
+            (k, t) => k.or(t),
+        };
+        self.reader.set_read_timeout(read_timeout)
+    }
+
+    ///
+    /// Returns the time since a packet was last received
+    pub fn idle(&self) -> Duration {
+        self.last_received.elapsed()
+    }
+
+    ///
+    /// Receives the next packet, blocking until one arrives.
+    /// Returns None if the peer closes the connection between packets.
+    /// KeepAlive packets are handled by the connection, and are not returned.
+    /// Receiving a Disconnect closes the connection, and the Disconnect is returned.
+    pub fn receive(&mut self) -> std::io::Result<Option<Box<dyn AnyPacket>>> {
+        let received = self.receive_packet();
+        match (&received, &self.metrics) {
+            // Reads which time out without a connection timeout leave the connection open
+            (Err(e), Some(metrics)) if !is_timeout(e) => {
+                // Errors with a more specific reason have already recorded it
+                metrics.record_disconnect(DisconnectReason::Error)
+            }
+            _ => {}
+        }
+        received
+    }
+
+    // Counts a frame or packet which could not be decoded, and returns the error
+    fn decode_error(&self, e: std::io::Error) -> std::io::Error {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_decode_error();
+            metrics.record_disconnect(DisconnectReason::DecodeError);
+        }
+        e
+    }
+
+    fn receive_packet(&mut self) -> std::io::Result<Option<Box<dyn AnyPacket>>> {
+        loop {
+            let buffered = self.decoder.buffered();
+            let next = self.decoder.next_frame();
+            if let Some(frame) = next.map_err(|e| self.decode_error(e))? {
+                self.last_received = Instant::now();
+                if let Some(metrics) = &self.metrics {
+                    metrics.record_received(frame.id, buffered - self.decoder.buffered());
+                }
+                if let Some(capture) = &*self.sender.shared.capture.lock().unwrap() {
+                    capture.record(self.side().receives(), &frame)?;
+                }
+                let packet = frame
+                    .decode(&self.registry, self.side().receives())
+                    .map_err(|e| self.decode_error(e))?;
+                if !self.state.accepts(packet.id()) {
+                    return Err(self.decode_error(
+                        HandshakeError::UnexpectedPacket(packet.id(), self.state).into(),
+                    ));
+                }
+                if let Some(keepalive) = packet.downcast_ref::<KeepAlive>() {
+                    self.sender.send(&KeepAliveReply {
+                        nonce: keepalive.nonce,
+                    })?;
+                    self.sender.acknowledge()?;
+                    continue;
+                }
+                if let Some(reply) = packet.downcast_ref::<KeepAliveReply>() {
+                    self.sender.pong(reply);
+                    self.sender.acknowledge()?;
+                    continue;
+                }
+                let session = self.session();
+                if let Some(ack) = packet.downcast_ref::<Ack>() {
+                    if let Some(session) = session {
+                        session.acknowledge(ack.received)?;
+                    }
+                    continue;
+                }
+                if let Some(session) = session.filter(|_| session::is_sequenced(packet.id())) {
+                    if let Some(ack) = session.record_received() {
+                        self.sender.send(&ack)?;
+                    }
+                }
+                if packet.is::<Disconnect>() {
+                    self.state = State::Closed;
+                    if let Some(metrics) = &self.metrics {
+                        metrics.record_disconnect(DisconnectReason::PeerDisconnected);
+                    }
+                }
+                return Ok(Some(packet));
+            }
+            if self.state == State::Closed && self.sender.is_closed() {
+                return Ok(None);
+            }
+            match self.decoder.fill(&mut self.reader) {
+                Ok(0) => {
+                    self.state = State::Closed;
+                    self.decoder.finish()?;
+                    if let Some(metrics) = &self.metrics {
+                        metrics.record_disconnect(DisconnectReason::Closed);
+                    }
+                    return Ok(None);
+                }
+                Ok(_) => {}
+                Err(e) if is_timeout(&e) => {
+                    if let Some(timeout) = self.timeout {
+                        if timeout <= self.idle() {
+                            if let Some(metrics) = &self.metrics {
+                                metrics.record_disconnect(DisconnectReason::TimedOut);
+                            }
+                            return Err(std::io::Error::new(
+                                ErrorKind::TimedOut,
+                                "Connection timed out",
+                            ));
+                        }
+                    }
+                    match self.keepalive {
+                        Some(_) => {
+                            self.sender.ping()?;
+                            self.sender.acknowledge()?;
+                        }
+                        None if self.timeout.is_none() => return Err(e),
+                        None => {}
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<T> Drop for Connection<T> {
+    fn drop(&mut self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.close();
+        }
+    }
+}
@@ -0,0 +1,642 @@
+//!
+//! Content-addressed digests.
+//!
+//! [`ContentDigest`] computes the SHA-256 [`Digest`] of a value's canonical encoding, so that equal content has the same digest
+//!  on every implementation, independently of how it is stored in memory.
+//! The canonical encoding is the LCS 4 encoding of the value in big endian, with the following changes:
+//! * Every NaN is encoded as the canonical NaN.
+//! * Sequences are prefixed with their length as a u32, and `Option`s are prefixed with a boolean.
+//! * Maps and NBT compounds are encoded with their entries in ascending order of their keys.
+//! * An `NbtTag` is prefixed with its tag type. Elements of lists and entries of compounds are encoded as in NBT.
+//!
+//! The digests of the entries in each resource domain are combined into a Merkle tree by a [`DomainSummary`],
+//!  so that two sides can compare a domain with a single digest (the root), and then find the entries which differ.
+//! A [`ContentSummary`] holds the summaries of every loaded domain.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{Debug, Display},
+    io::{ErrorKind, Write},
+    iter::FromIterator,
+};
+
+use binary_io::{
+    data::{
+        ByteOrder, DataInput, DataOutput, DataOutputStream, DeserializeCopy, Deserializeable,
+        OutOfRange, Serializeable,
+    },
+    nbt::{array::NbtArray, compound::NbtCompound, list::NbtList, NbtTag, TagType},
+    uuid::UUID,
+    version::Version,
+};
+use sha2::{Digest as _, Sha256};
+
+///
+/// The size of a digest, in bytes
+pub const DIGEST_SIZE: usize = 32;
+
+///
+/// A SHA-256 digest
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Digest(pub [u8; DIGEST_SIZE]);
+
+impl Digest {
+    ///
+    /// Computes the digest of bytes
+    pub fn of_bytes(bytes: &[u8]) -> Self {
+        Self(Sha256::digest(bytes).into())
+    }
+
+    ///
+    /// Returns the bytes of the digest
+    pub fn as_bytes(&self) -> &[u8; DIGEST_SIZE] {
+        &self.0
+    }
+}
+
+impl Display for Digest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for b in &self.0 {
+            f.write_fmt(format_args!("{:02x}", b))?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for Digest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("Digest({})", self))
+    }
+}
+
+impl Serializeable for Digest {
+    fn serialize<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        self.0.serialize(output)
+    }
+}
+
+impl Deserializeable for Digest {
+    fn deserialize<R: DataInput + ?Sized>(&mut self, input: &mut R) -> std::io::Result<()> {
+        self.0.deserialize(input)
+    }
+}
+
+impl DeserializeCopy for Digest {
+    fn deserialize_copy<R: DataInput + ?Sized>(input: &mut R) -> std::io::Result<Self> {
+        Ok(Self(<[u8; DIGEST_SIZE]>::deserialize_copy(input)?))
+    }
+}
+
+struct HashWriter(Sha256);
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+///
+/// A type which has a canonical encoding, from which a content-addressed digest is computed
+pub trait ContentDigest {
+    ///
+    /// Writes the canonical encoding of the value to output
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()>;
+
+    ///
+    /// Computes the SHA-256 digest of the canonical encoding.
+    /// Returns an error if the value cannot be encoded, for example because a string is too long
+    fn digest(&self) -> std::io::Result<Digest> {
+        let mut output = DataOutputStream::new(HashWriter(Sha256::new()), ByteOrder::BigEndian);
+        self.write_canonical(&mut output)?;
+        Ok(Digest(output.into_inner().0.finalize().into()))
+    }
+}
+
+fn write_len<W: DataOutput + ?Sized>(len: usize, output: &mut W) -> std::io::Result<()> {
+    if len > u32::MAX as usize {
+        return Err(std::io::Error::new(ErrorKind::InvalidData, OutOfRange(len)));
+    }
+    (len as u32).serialize(output)
+}
+
+fn write_sequence<'a, T: ContentDigest + 'a, I: ExactSizeIterator<Item = &'a T>, W>(
+    iter: I,
+    output: &mut W,
+) -> std::io::Result<()>
+where
+    W: DataOutput + ?Sized,
+{
+    write_len(iter.len(), output)?;
+    for v in iter {
+        v.write_canonical(output)?;
+    }
+    Ok(())
+}
+
+// Encodes each entry separately, and writes them in order of their encoded keys,
+//  which is canonical for keys which do not have a total order
+fn write_unordered_map<'a, K, V, I, W>(iter: I, output: &mut W) -> std::io::Result<()>
+where
+    K: ContentDigest + 'a,
+    V: ContentDigest + 'a,
+    I: IntoIterator<Item = (&'a K, &'a V)>,
+    W: DataOutput + ?Sized,
+{
+    let mut entries = Vec::new();
+    for (k, v) in iter {
+        let mut key = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+        k.write_canonical(&mut key)?;
+        let mut value = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+        v.write_canonical(&mut value)?;
+        entries.push((key.into_inner(), value.into_inner()));
+    }
+    entries.sort();
+    write_len(entries.len(), output)?;
+    for (k, v) in entries {
+        output.write_bytes(&k)?;
+        output.write_bytes(&v)?;
+    }
+    Ok(())
+}
+
+macro_rules! impl_content_digest_for_serializeable {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl ContentDigest for $ty {
+                fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+                    self.serialize(output)
+                }
+            }
+        )*
+    };
+}
+
+impl_content_digest_for_serializeable!(
+    u8, i8, u16, i16, u32, i32, u64, i64, u128, i128, UUID, Version, Digest
+);
+
+impl ContentDigest for bool {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        (*self as u8).serialize(output)
+    }
+}
+
+impl ContentDigest for f32 {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        if self.is_nan() {
+            0x7fc0_0000u32.serialize(output)
+        } else {
+            self.to_bits().serialize(output)
+        }
+    }
+}
+
+impl ContentDigest for f64 {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        if self.is_nan() {
+            0x7ff8_0000_0000_0000u64.serialize(output)
+        } else {
+            self.to_bits().serialize(output)
+        }
+    }
+}
+
+impl ContentDigest for str {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        let size = self.len();
+        if size > u16::MAX as usize {
+            Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                OutOfRange(size),
+            ))
+        } else {
+            (size as u16).serialize(output)?;
+            output.write_bytes(self.as_bytes())
+        }
+    }
+}
+
+impl ContentDigest for String {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        self.as_str().write_canonical(output)
+    }
+}
+
+impl<T: ContentDigest + ?Sized> ContentDigest for &'_ T {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        T::write_canonical(self, output)
+    }
+}
+
+impl<T: ContentDigest + ?Sized> ContentDigest for Box<T> {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        T::write_canonical(self, output)
+    }
+}
+
+impl<T: ContentDigest> ContentDigest for [T] {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        write_sequence(self.iter(), output)
+    }
+}
+
+impl<T: ContentDigest, const N: usize> ContentDigest for [T; N] {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        write_sequence(self.iter(), output)
+    }
+}
+
+impl<T: ContentDigest> ContentDigest for Vec<T> {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        write_sequence(self.iter(), output)
+    }
+}
+
+impl<T: ContentDigest> ContentDigest for Option<T> {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        match self {
+            Some(v) => {
+                true.write_canonical(output)?;
+                v.write_canonical(output)
+            }
+            None => false.write_canonical(output),
+        }
+    }
+}
+
+macro_rules! impl_content_digest_for_tuples {
+    ($(($($name:ident),+)),* $(,)?) => {
+        $(
+            #[allow(non_snake_case)]
+            impl<$($name: ContentDigest),+> ContentDigest for ($($name,)+) {
+                fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+                    let ($($name,)+) = self;
+                    $($name.write_canonical(output)?;)+
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+impl_content_digest_for_tuples! {
+    (A),
+    (A, B),
+    (A, B, C),
+    (A, B, C, D),
+}
+
+impl<K: ContentDigest, V: ContentDigest> ContentDigest for BTreeMap<K, V> {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        write_unordered_map(self, output)
+    }
+}
+
+impl<K: ContentDigest, V: ContentDigest, S> ContentDigest for HashMap<K, V, S> {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        write_unordered_map(self, output)
+    }
+}
+
+impl<T: ContentDigest> ContentDigest for NbtArray<T> {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        let len = self.len();
+        if len > i32::MAX as usize {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, OutOfRange(len)));
+        }
+        (len as i32).serialize(output)?;
+        for v in self.iter() {
+            v.write_canonical(output)?;
+        }
+        Ok(())
+    }
+}
+
+// The payload of a tag, without its type
+fn write_payload<W: DataOutput + ?Sized>(tag: &NbtTag, output: &mut W) -> std::io::Result<()> {
+    match tag {
+        NbtTag::End => Ok(()),
+        NbtTag::Byte(v) => v.write_canonical(output),
+        NbtTag::Short(v) => v.write_canonical(output),
+        NbtTag::Int(v) => v.write_canonical(output),
+        NbtTag::Long(v) => v.write_canonical(output),
+        NbtTag::Float(v) => v.write_canonical(output),
+        NbtTag::Double(v) => v.write_canonical(output),
+        NbtTag::ByteArray(v) => v.write_canonical(output),
+        NbtTag::String(v) => v.write_canonical(output),
+        NbtTag::List(v) => v.write_canonical(output),
+        NbtTag::Compound(v) => v.write_canonical(output),
+        NbtTag::IntArray(v) => v.write_canonical(output),
+        NbtTag::LongArray(v) => v.write_canonical(output),
+        NbtTag::FloatArray(v) => v.write_canonical(output),
+        NbtTag::DoubleArray(v) => v.write_canonical(output),
+        NbtTag::Uuid(v) => v.write_canonical(output),
+    }
+}
+
+impl ContentDigest for NbtList {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        self.element_type().serialize(output)?;
+        let len = self.len();
+        if len > i32::MAX as usize {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, OutOfRange(len)));
+        }
+        (len as i32).serialize(output)?;
+        for tag in self {
+            write_payload(tag, output)?;
+        }
+        Ok(())
+    }
+}
+
+impl ContentDigest for NbtCompound {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        let mut entries = self.iter().collect::<Vec<_>>();
+        entries.sort_by_key(|(name, _)| *name);
+        for (name, tag) in entries {
+            if tag.tag_type() == TagType::End {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Embedded Tag Ends cannot be serialized",
+                ));
+            }
+            tag.tag_type().serialize(output)?;
+            name.write_canonical(output)?;
+            write_payload(tag, output)?;
+        }
+        TagType::End.serialize(output)
+    }
+}
+
+impl ContentDigest for NbtTag {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        self.tag_type().serialize(output)?;
+        write_payload(self, output)
+    }
+}
+
+// Leaves and nodes are hashed with distinct prefixes, so that a leaf can never be mistaken for a node
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+fn leaf_digest(name: &str, digest: &Digest) -> Digest {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update((name.len() as u32).to_be_bytes());
+    hasher.update(name.as_bytes());
+    hasher.update(digest.0);
+    Digest(hasher.finalize().into())
+}
+
+fn node_digest(left: &Digest, right: &Digest) -> Digest {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left.0);
+    hasher.update(right.0);
+    Digest(hasher.finalize().into())
+}
+
+///
+/// Computes the root of a Merkle tree over leaves, in order.
+/// Each level pairs adjacent nodes, and an unpaired node at the end of a level is carried to the next level unchanged.
+/// The root of an empty tree is the digest of no bytes
+pub fn merkle_root<I: IntoIterator<Item = Digest>>(leaves: I) -> Digest {
+    let mut level = leaves.into_iter().collect::<Vec<_>>();
+    if level.is_empty() {
+        return Digest::of_bytes(&[]);
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => node_digest(left, right),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level[0]
+}
+
+///
+/// The name and digest of an entry in a resource domain
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EntryDigest {
+    ///
+    /// The name of the entry
+    pub name: String,
+    ///
+    /// The digest of the entry's content
+    pub digest: Digest,
+}
+
+impl Serializeable for EntryDigest {
+    fn serialize<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        self.name.serialize(output)?;
+        self.digest.serialize(output)
+    }
+}
+
+impl Deserializeable for EntryDigest {
+    fn deserialize<R: DataInput + ?Sized>(&mut self, input: &mut R) -> std::io::Result<()> {
+        self.name.deserialize(input)?;
+        self.digest.deserialize(input)
+    }
+}
+
+impl DeserializeCopy for EntryDigest {
+    fn deserialize_copy<R: DataInput + ?Sized>(input: &mut R) -> std::io::Result<Self> {
+        Ok(Self {
+            name: String::deserialize_copy(input)?,
+            digest: Digest::deserialize_copy(input)?,
+        })
+    }
+}
+
+///
+/// A difference between the entries of a resource domain on two sides
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum EntryDiff {
+    ///
+    /// The entry exists on this side, but not on the other
+    Missing(String),
+    ///
+    /// The entry exists on the other side, but not on this side
+    Unexpected(String),
+    ///
+    /// The entry exists on both sides, with different content
+    Changed(String),
+}
+
+impl EntryDiff {
+    ///
+    /// Returns the name of the entry which differs
+    pub fn name(&self) -> &str {
+        match self {
+            EntryDiff::Missing(name) | EntryDiff::Unexpected(name) | EntryDiff::Changed(name) => {
+                name
+            }
+        }
+    }
+}
+
+impl Display for EntryDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EntryDiff::Missing(name) => f.write_fmt(format_args!("missing {}", name)),
+            EntryDiff::Unexpected(name) => f.write_fmt(format_args!("unexpected {}", name)),
+            EntryDiff::Changed(name) => f.write_fmt(format_args!("changed {}", name)),
+        }
+    }
+}
+
+///
+/// The digests of the entries of a resource domain, combined into a Merkle tree ordered by entry name
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DomainSummary {
+    entries: BTreeMap<String, Digest>,
+}
+
+impl DomainSummary {
+    ///
+    /// Creates an empty summary
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Sets the digest of the entry with the given name, and returns the previous digest, if any
+    pub fn insert(&mut self, name: String, digest: Digest) -> Option<Digest> {
+        self.entries.insert(name, digest)
+    }
+
+    ///
+    /// Computes the digest of value, and adds it as the entry with the given name
+    pub fn insert_value<T: ContentDigest + ?Sized>(
+        &mut self,
+        name: String,
+        value: &T,
+    ) -> std::io::Result<Option<Digest>> {
+        Ok(self.insert(name, value.digest()?))
+    }
+
+    ///
+    /// Returns the digest of the entry with the given name
+    pub fn get(&self, name: &str) -> Option<Digest> {
+        self.entries.get(name).copied()
+    }
+
+    ///
+    /// Returns the number of entries
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    ///
+    /// Checks if the summary has no entries
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    ///
+    /// Returns the entries, in order of their names
+    pub fn entries(&self) -> impl Iterator<Item = EntryDigest> + '_ {
+        self.entries.iter().map(|(name, digest)| EntryDigest {
+            name: name.clone(),
+            digest: *digest,
+        })
+    }
+
+    ///
+    /// Computes the root of the Merkle tree over the entries, in order of their names.
+    /// Two summaries have the same root exactly when they have the same entries
+    pub fn root(&self) -> Digest {
+        merkle_root(
+            self.entries
+                .iter()
+                .map(|(name, digest)| leaf_digest(name, digest)),
+        )
+    }
+
+    ///
+    /// Finds the entries which differ between this summary and the entries of another side, in order of their names
+    pub fn diff<I: IntoIterator<Item = EntryDigest>>(&self, other: I) -> Vec<EntryDiff> {
+        let other = other
+            .into_iter()
+            .map(|e| (e.name, e.digest))
+            .collect::<BTreeMap<_, _>>();
+        let mut diffs = Vec::new();
+        for (name, digest) in &self.entries {
+            match other.get(name) {
+                None => diffs.push(EntryDiff::Missing(name.clone())),
+                Some(d) if d != digest => diffs.push(EntryDiff::Changed(name.clone())),
+                Some(_) => {}
+            }
+        }
+        for name in other.keys() {
+            if !self.entries.contains_key(name) {
+                diffs.push(EntryDiff::Unexpected(name.clone()));
+            }
+        }
+        diffs.sort_by(|a, b| a.name().cmp(b.name()));
+        diffs
+    }
+}
+
+impl FromIterator<EntryDigest> for DomainSummary {
+    fn from_iter<I: IntoIterator<Item = EntryDigest>>(iter: I) -> Self {
+        Self {
+            entries: iter.into_iter().map(|e| (e.name, e.digest)).collect(),
+        }
+    }
+}
+
+///
+/// The summaries of every loaded resource domain
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ContentSummary {
+    domains: BTreeMap<String, DomainSummary>,
+}
+
+impl ContentSummary {
+    ///
+    /// Creates an empty summary
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Sets the digest of an entry in the given domain, and returns the previous digest, if any
+    pub fn insert(&mut self, domain: &str, name: String, digest: Digest) -> Option<Digest> {
+        self.domain_mut(domain).insert(name, digest)
+    }
+
+    ///
+    /// Returns the summary of the given domain
+    pub fn domain(&self, domain: &str) -> Option<&DomainSummary> {
+        self.domains.get(domain)
+    }
+
+    ///
+    /// Returns the summary of the given domain, creating an empty one if it has no entries yet
+    pub fn domain_mut(&mut self, domain: &str) -> &mut DomainSummary {
+        self.domains.entry(domain.to_string()).or_default()
+    }
+
+    ///
+    /// Returns the names and summaries of the domains, in order of their names
+    pub fn domains(&self) -> impl Iterator<Item = (&str, &DomainSummary)> + '_ {
+        self.domains.iter().map(|(name, d)| (name.as_str(), d))
+    }
+
+    ///
+    /// Returns the root of each domain, in order of their names
+    pub fn roots(&self) -> impl Iterator<Item = (&str, Digest)> + '_ {
+        self.domains().map(|(name, d)| (name, d.root()))
+    }
+}
@@ -0,0 +1,544 @@
+//!
+//! Framing of PkmCom packets over byte streams.
+//!
+//! Each packet is sent as a frame, which consists of the length of the rest of the frame as a u32,
+//!  the packet id as a u16, and the payload (the fields of the packet). Frames are always big endian.
+//! The length is checked against a maximum frame size before any of the frame is buffered,
+//!  so a peer cannot cause an arbitrarily large allocation.
+//!
+//! Once compression has been negotiated, the length is followed by the uncompressed size of the packet id and payload, as a u32.
+//! If it is 0, the packet id and payload follow uncompressed. Otherwise they follow compressed with zlib.
+//! Packets are compressed when their id and payload are at least the compression threshold.
+//! The uncompressed size is checked against the maximum frame size before decompressing, and decompression stops there,
+//!  so a small frame cannot expand into an arbitrarily large packet.
+//!
+//! [`FrameCodec`] reads and writes frames over a [`DataInput`] or [`DataOutput`], blocking until a whole frame is available.
+//! [`FrameDecoder`] instead buffers bytes as they arrive, and yields frames once they are complete.
+
+use std::{
+    fmt::Display,
+    io::{ErrorKind, Read, Write},
+};
+
+use binary_io::data::{ByteOrder, DataInput, DataInputStream, DataOutput, DataOutputStream};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+
+use crate::packet::{AnyPacket, Direction, PacketRegistry};
+
+///
+/// The size of the length prefix of a frame
+pub const LENGTH_SIZE: usize = 4;
+
+///
+/// The size of the packet id of a frame
+pub const ID_SIZE: usize = 2;
+
+///
+/// The size of the uncompressed size of a frame, when compression is enabled
+pub const UNCOMPRESSED_SIZE_SIZE: usize = 4;
+
+///
+/// The default maximum size of a frame, excluding the length prefix
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 2 * 1024 * 1024;
+
+///
+/// The default size, of the packet id and payload, at which packets are compressed
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 256;
+
+///
+/// The Error returned when a frame is malformed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    ///
+    /// The length of the frame exceeds the maximum frame size
+    Oversized {
+        ///
+        /// The length of the frame
+        size: usize,
+        ///
+        /// The maximum frame size
+        max: usize,
+    },
+    ///
+    /// The length of the frame is too short to contain a packet id
+    Undersized(usize),
+    ///
+    /// The stream ended before the frame was complete
+    Truncated {
+        ///
+        /// The number of bytes in the complete frame, including the length prefix
+        expected: usize,
+        ///
+        /// The number of bytes of the frame that were received
+        received: usize,
+    },
+    ///
+    /// The packet with the given id did not consume the entire payload of its frame
+    TrailingBytes(u16, usize),
+    ///
+    /// The compressed contents of the frame are not valid zlib data
+    BadCompression,
+    ///
+    /// The compressed contents of the frame do not decompress to the uncompressed size of the frame
+    SizeMismatch {
+        ///
+        /// The uncompressed size given by the frame
+        expected: usize,
+        ///
+        /// The size the contents decompressed to, which is at most one byte more than expected
+        actual: usize,
+    },
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::Oversized { size, max } => f.write_fmt(format_args!(
+                "Frame of {} bytes exceeds the maximum frame size of {} bytes",
+                size, max
+            )),
+            FrameError::Undersized(size) => f.write_fmt(format_args!(
+                "Frame of {} bytes is too short to contain a packet id",
+                size
+            )),
+            FrameError::Truncated { expected, received } => f.write_fmt(format_args!(
+                "Frame truncated after {} of {} bytes",
+                received, expected
+            )),
+            FrameError::TrailingBytes(id, len) => f.write_fmt(format_args!(
+                "Packet {:#06x} left {} bytes of its frame unread",
+                id, len
+            )),
+            FrameError::BadCompression => f.write_str("Frame contains invalid compressed data"),
+            FrameError::SizeMismatch { expected, actual } => f.write_fmt(format_args!(
+                "Frame decompressed to {} bytes, but should be {} bytes",
+                actual, expected
+            )),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<FrameError> for std::io::Error {
+    fn from(e: FrameError) -> Self {
+        let kind = match e {
+            FrameError::Truncated { .. } => ErrorKind::UnexpectedEof,
+            _ => ErrorKind::InvalidData,
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+
+///
+/// A single frame, containing the id and serialized fields of a packet
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Frame {
+    ///
+    /// The id of the packet
+    pub id: u16,
+    ///
+    /// The serialized fields of the packet
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    ///
+    /// Creates a frame from a packet id and payload
+    pub fn new(id: u16, payload: Vec<u8>) -> Self {
+        Self { id, payload }
+    }
+
+    ///
+    /// Serializes a packet into a frame
+    pub fn from_packet(packet: &dyn AnyPacket) -> std::io::Result<Self> {
+        let mut output = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+        packet.write_payload(&mut output)?;
+        Ok(Self::new(packet.id(), output.into_inner()))
+    }
+
+    ///
+    /// Returns the value of the length prefix of the frame, which is the size of the packet id and the payload
+    pub fn size(&self) -> usize {
+        ID_SIZE + self.payload.len()
+    }
+
+    ///
+    /// Decodes the packet in the frame, which was sent in the given direction.
+    /// Returns an error if the packet does not consume the entire payload
+    pub fn decode(
+        &self,
+        registry: &PacketRegistry,
+        direction: Direction,
+    ) -> std::io::Result<Box<dyn AnyPacket>> {
+        let mut input = DataInputStream::new(&self.payload[..], ByteOrder::BigEndian);
+        let packet = registry.decode(self.id, direction, &mut input)?;
+        let remaining = input.into_inner().len();
+        if remaining != 0 {
+            Err(FrameError::TrailingBytes(self.id, remaining).into())
+        } else {
+            Ok(packet)
+        }
+    }
+}
+
+fn read_available<R: Read + ?Sized>(input: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match input.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+///
+/// Reads and writes frames over blocking streams, with a maximum frame size, and optionally compression
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FrameCodec {
+    max_frame_size: usize,
+    compression: Option<usize>,
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl FrameCodec {
+    ///
+    /// Creates a codec which rejects frames longer than max_frame_size bytes, excluding the length prefix
+    pub fn new(max_frame_size: usize) -> Self {
+        Self {
+            max_frame_size,
+            compression: None,
+        }
+    }
+
+    ///
+    /// Returns the maximum frame size, excluding the length prefix
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    ///
+    /// Returns the compression threshold, or None if compression is disabled
+    pub fn compression(&self) -> Option<usize> {
+        self.compression
+    }
+
+    ///
+    /// Enables compression of packets whose id and payload are at least threshold bytes, or disables compression if None.
+    /// Both sides of a stream must enable compression at the same point in the stream
+    pub fn set_compression(&mut self, threshold: Option<usize>) {
+        self.compression = threshold;
+    }
+
+    ///
+    /// Returns this codec with the given compression threshold
+    pub fn with_compression(mut self, threshold: Option<usize>) -> Self {
+        self.set_compression(threshold);
+        self
+    }
+
+    fn header_size(&self) -> usize {
+        if self.compression.is_some() {
+            UNCOMPRESSED_SIZE_SIZE
+        } else {
+            0
+        }
+    }
+
+    fn check_len(&self, size: usize) -> Result<(), FrameError> {
+        if size < self.header_size() + ID_SIZE {
+            Err(FrameError::Undersized(size))
+        } else if size > self.max_frame_size || size > (u32::MAX as usize) {
+            Err(FrameError::Oversized {
+                size,
+                max: self.max_frame_size,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    ///
+    /// Encodes a frame, including its length prefix.
+    /// Returns an error if the frame exceeds the maximum frame size
+    pub fn encode(&self, frame: &Frame) -> std::io::Result<Vec<u8>> {
+        let threshold = match self.compression {
+            Some(threshold) => threshold,
+            None => {
+                let len = frame.size();
+                self.check_len(len)?;
+                let mut bytes = Vec::with_capacity(LENGTH_SIZE + len);
+                bytes.extend_from_slice(&(len as u32).to_be_bytes());
+                bytes.extend_from_slice(&frame.id.to_be_bytes());
+                bytes.extend_from_slice(&frame.payload);
+                return Ok(bytes);
+            }
+        };
+        let size = frame.size();
+        if size > self.max_frame_size {
+            return Err(FrameError::Oversized {
+                size,
+                max: self.max_frame_size,
+            }
+            .into());
+        }
+        let (uncompressed, body) = if size >= threshold {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&frame.id.to_be_bytes())?;
+            encoder.write_all(&frame.payload)?;
+            (size as u32, encoder.finish()?)
+        } else {
+            let mut body = Vec::with_capacity(size);
+            body.extend_from_slice(&frame.id.to_be_bytes());
+            body.extend_from_slice(&frame.payload);
+            (0, body)
+        };
+        let len = UNCOMPRESSED_SIZE_SIZE + body.len();
+        self.check_len(len)?;
+        let mut bytes = Vec::with_capacity(LENGTH_SIZE + len);
+        bytes.extend_from_slice(&(len as u32).to_be_bytes());
+        bytes.extend_from_slice(&uncompressed.to_be_bytes());
+        bytes.extend_from_slice(&body);
+        Ok(bytes)
+    }
+
+    // Decodes the contents of a frame, after the length prefix
+    fn decode_body(&self, mut bytes: Vec<u8>) -> std::io::Result<Frame> {
+        if self.compression.is_some() {
+            let uncompressed =
+                u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+            if uncompressed == 0 {
+                bytes.drain(..UNCOMPRESSED_SIZE_SIZE);
+            } else {
+                if uncompressed < ID_SIZE {
+                    return Err(FrameError::Undersized(uncompressed).into());
+                }
+                if uncompressed > self.max_frame_size {
+                    return Err(FrameError::Oversized {
+                        size: uncompressed,
+                        max: self.max_frame_size,
+                    }
+                    .into());
+                }
+                // Reading one byte past the expected size detects data which decompresses to more than it claims
+                let mut decompressed = Vec::with_capacity(uncompressed);
+                ZlibDecoder::new(&bytes[UNCOMPRESSED_SIZE_SIZE..])
+                    .take(uncompressed as u64 + 1)
+                    .read_to_end(&mut decompressed)
+                    .map_err(|_| FrameError::BadCompression)?;
+                if decompressed.len() != uncompressed {
+                    return Err(FrameError::SizeMismatch {
+                        expected: uncompressed,
+                        actual: decompressed.len(),
+                    }
+                    .into());
+                }
+                bytes = decompressed;
+            }
+        }
+        let payload = bytes.split_off(ID_SIZE);
+        Ok(Frame::new(
+            u16::from_be_bytes([bytes[0], bytes[1]]),
+            payload,
+        ))
+    }
+
+    ///
+    /// Writes a frame to output.
+    /// Returns an error, without writing anything, if the frame exceeds the maximum frame size
+    pub fn write_frame<W: DataOutput + ?Sized>(
+        &self,
+        frame: &Frame,
+        output: &mut W,
+    ) -> std::io::Result<()> {
+        let bytes = self.encode(frame)?;
+        output.write_bytes(&bytes)
+    }
+
+    ///
+    /// Serializes a packet, and writes it to output as a single frame
+    pub fn write_packet<W: DataOutput + ?Sized>(
+        &self,
+        packet: &dyn AnyPacket,
+        output: &mut W,
+    ) -> std::io::Result<()> {
+        self.write_frame(&Frame::from_packet(packet)?, output)
+    }
+
+    ///
+    /// Reads a single frame from input, blocking until it is complete.
+    /// Returns None if the stream ends cleanly before the start of a frame,
+    ///  and an error if it ends part way through a frame.
+    pub fn read_frame<R: DataInput + ?Sized>(
+        &self,
+        input: &mut R,
+    ) -> std::io::Result<Option<Frame>> {
+        let mut prefix = [0u8; LENGTH_SIZE];
+        match read_available(input, &mut prefix)? {
+            0 => return Ok(None),
+            LENGTH_SIZE => {}
+            received => {
+                return Err(FrameError::Truncated {
+                    expected: LENGTH_SIZE + self.header_size() + ID_SIZE,
+                    received,
+                }
+                .into())
+            }
+        }
+        let len = u32::from_be_bytes(prefix) as usize;
+        self.check_len(len)?;
+        let mut bytes = vec![0u8; len];
+        let received = read_available(input, &mut bytes)?;
+        if received != len {
+            return Err(FrameError::Truncated {
+                expected: LENGTH_SIZE + len,
+                received: LENGTH_SIZE + received,
+            }
+            .into());
+        }
+        self.decode_body(bytes).map(Some)
+    }
+
+    ///
+    /// Reads a single frame from input, and decodes the packet in it, which was sent in the given direction.
+    /// Returns None if the stream ends cleanly before the start of a frame
+    pub fn read_packet<R: DataInput + ?Sized>(
+        &self,
+        registry: &PacketRegistry,
+        direction: Direction,
+        input: &mut R,
+    ) -> std::io::Result<Option<Box<dyn AnyPacket>>> {
+        match self.read_frame(input)? {
+            Some(frame) => frame.decode(registry, direction).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+///
+/// Splits a stream of bytes, which may arrive in arbitrary pieces, into frames
+#[derive(Clone, Debug, Default)]
+pub struct FrameDecoder {
+    codec: FrameCodec,
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    ///
+    /// Creates a decoder which rejects frames longer than max_frame_size bytes, excluding the length prefix
+    pub fn new(max_frame_size: usize) -> Self {
+        Self::with_codec(FrameCodec::new(max_frame_size))
+    }
+
+    ///
+    /// Creates a decoder with the maximum frame size and compression of codec
+    pub fn with_codec(codec: FrameCodec) -> Self {
+        Self {
+            codec,
+            buffer: Vec::new(),
+        }
+    }
+
+    ///
+    /// Returns the codec used by the decoder
+    pub fn codec(&self) -> FrameCodec {
+        self.codec
+    }
+
+    ///
+    /// Sets the compression threshold used for frames which have not yet been returned by the decoder
+    pub fn set_compression(&mut self, threshold: Option<usize>) {
+        self.codec.set_compression(threshold)
+    }
+
+    ///
+    /// Returns the number of bytes received which are not yet part of a complete frame
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    ///
+    /// Adds received bytes to the end of the buffer
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes)
+    }
+
+    ///
+    /// Reads whatever bytes are available from input (at most one call to `read`), and adds them to the buffer.
+    /// Returns the number of bytes read, which is 0 at the end of the stream
+    pub fn fill<R: Read + ?Sized>(&mut self, input: &mut R) -> std::io::Result<usize> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            match input.read(&mut chunk) {
+                Ok(n) => {
+                    self.feed(&chunk[..n]);
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    ///
+    /// Removes and returns the first complete frame in the buffer, or None if more bytes are needed.
+    /// Returns an error as soon as the length prefix of an invalid frame is received.
+    pub fn next_frame(&mut self) -> std::io::Result<Option<Frame>> {
+        if self.buffer.len() < LENGTH_SIZE {
+            return Ok(None);
+        }
+        let mut prefix = [0u8; LENGTH_SIZE];
+        prefix.copy_from_slice(&self.buffer[..LENGTH_SIZE]);
+        let len = u32::from_be_bytes(prefix) as usize;
+        self.codec.check_len(len)?;
+        if self.buffer.len() < LENGTH_SIZE + len {
+            return Ok(None);
+        }
+        let rest = self.buffer.split_off(LENGTH_SIZE + len);
+        let mut bytes = std::mem::replace(&mut self.buffer, rest);
+        bytes.drain(..LENGTH_SIZE);
+        self.codec.decode_body(bytes).map(Some)
+    }
+
+    ///
+    /// Removes the first complete frame in the buffer, and decodes the packet in it, which was sent in the given direction
+    pub fn next_packet(
+        &mut self,
+        registry: &PacketRegistry,
+        direction: Direction,
+    ) -> std::io::Result<Option<Box<dyn AnyPacket>>> {
+        match self.next_frame()? {
+            Some(frame) => frame.decode(registry, direction).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    ///
+    /// Checks that the stream ended on a frame boundary.
+    /// Returns an error if a partial frame is buffered
+    pub fn finish(&self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let expected = if self.buffer.len() < LENGTH_SIZE {
+            LENGTH_SIZE + self.codec.header_size() + ID_SIZE
+        } else {
+            let mut prefix = [0u8; LENGTH_SIZE];
+            prefix.copy_from_slice(&self.buffer[..LENGTH_SIZE]);
+            LENGTH_SIZE + u32::from_be_bytes(prefix) as usize
+        };
+        Err(FrameError::Truncated {
+            expected,
+            received: self.buffer.len(),
+        }
+        .into())
+    }
+}
@@ -0,0 +1,830 @@
+//!
+//! The PkmCom login handshake.
+//!
+//! A connection starts in the [`State::Handshaking`] state, where the client sends a [`Handshake`] packet,
+//!  carrying its protocol version, the UUID of its player, and a [`ContentHash`] for each resource domain it has loaded.
+//! Both sides then enter [`State::Login`], where the server compares the versions and content hashes, and either:
+//! * Accepts the client with a [`LoginAccept`], carrying the negotiated protocol version and compression threshold.
+//!   Both sides enter [`State::Play`], and enable compression after the LoginAccept if the threshold is not 0.
+//!   If the client supports it, the LoginAccept also carries the token of a [`Session`], which can be resumed on a new connection.
+//! * Rejects the client with a [`LoginReject`], carrying the reason. Both sides enter [`State::Closed`].
+//! * Asks the client to synchronize the listed domains with a [`ContentSyncRequest`].
+//!   Once it has done so, the client sends a [`ContentReport`] with its new content hashes, which the server checks again.
+//!
+//! Each [`ContentHash`] carries the root of the domain's [`DomainSummary`] as well as its hashcode.
+//! If the server has a [`ContentSummary`] of its content, then before syncing or rejecting a client with mismatched content,
+//!  it sends a [`DigestRequest`] for the domains which differ, and the client answers with [`DomainDigests`] for each of them,
+//!  from which the server finds the entries which differ.
+//!
+//! A client resuming a session sends a [`Resume`] rather than a Handshake, which the server answers with a [`ResumeAccept`] or a [`LoginReject`].
+//! A client asking for the server's status sends a [`StatusRequest`], and both sides enter [`State::Status`] rather than [`State::Login`].
+//!
+//! Packets which are not valid in the current state are rejected with a [`HandshakeError`].
+
+use std::{collections::BTreeMap, fmt::Display, io::ErrorKind, time::Duration};
+
+use binary_io::{
+    data::{DataInput, DataOutput, DeserializeCopy, Deserializeable, Serializeable},
+    uuid::UUID,
+    version::Version,
+};
+use text::TextComponent;
+
+use crate::{
+    digest::{ContentDigest, ContentSummary, Digest, DomainSummary, EntryDiff, EntryDigest},
+    hashsum::Hashcode,
+    packet::{AnyPacket, Disconnect, KeepAlive, KeepAliveReply, List, Packet, PROTOCOL_VERSION},
+    session::{new_token, Resume, ResumeAccept, Session, SessionToken},
+    status::{StatusPing, StatusRequest, StatusResponse},
+};
+
+///
+/// The state of a PkmCom connection
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum State {
+    ///
+    /// The connection has been opened, and the client has not yet sent its [`Handshake`]
+    Handshaking,
+    ///
+    /// The client asked for the server's status, rather than logging in
+    Status,
+    ///
+    /// The server is checking the client's version and content
+    Login,
+    ///
+    /// The client has been accepted, and game packets may be exchanged
+    Play,
+    ///
+    /// The connection has been rejected or disconnected, and no more packets may be exchanged
+    Closed,
+}
+
+impl State {
+    ///
+    /// Checks if the packet with the given id may be exchanged in this state
+    pub fn accepts(self, id: u16) -> bool {
+        let login = [
+            LoginAccept::ID,
+            LoginReject::ID,
+            ContentSyncRequest::ID,
+            ContentReport::ID,
+            DigestRequest::ID,
+            DomainDigests::ID,
+            ResumeAccept::ID,
+        ];
+        let status = [StatusRequest::ID, StatusResponse::ID, StatusPing::ID];
+        match self {
+            State::Handshaking => {
+                id == Handshake::ID || id == Resume::ID || id == StatusRequest::ID
+            }
+            State::Status => {
+                id == StatusResponse::ID || id == StatusPing::ID || id == Disconnect::ID
+            }
+            State::Login => {
+                id == KeepAlive::ID
+                    || id == KeepAliveReply::ID
+                    || id == Disconnect::ID
+                    || login.contains(&id)
+            }
+            State::Play => {
+                id != Handshake::ID
+                    && id != Resume::ID
+                    && !login.contains(&id)
+                    && !status.contains(&id)
+            }
+            State::Closed => false,
+        }
+    }
+}
+
+impl Display for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            State::Handshaking => f.write_str("handshaking"),
+            State::Status => f.write_str("status"),
+            State::Login => f.write_str("login"),
+            State::Play => f.write_str("play"),
+            State::Closed => f.write_str("closed"),
+        }
+    }
+}
+
+///
+/// The Error returned when a packet is received which is not valid in the current state
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HandshakeError {
+    ///
+    /// The packet with the given id may not be received in the given state
+    UnexpectedPacket(u16, State),
+}
+
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::UnexpectedPacket(id, state) => f.write_fmt(format_args!(
+                "Packet {:#06x} is not valid in the {} state",
+                id, state
+            )),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<HandshakeError> for std::io::Error {
+    fn from(e: HandshakeError) -> Self {
+        std::io::Error::new(ErrorKind::InvalidData, e)
+    }
+}
+
+///
+/// The hash of the content of a resource domain (the registries and resources it defines)
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ContentHash {
+    ///
+    /// The name of the resource domain
+    pub domain: String,
+    ///
+    /// The hash of the content, computed with [`Hashcode`]
+    pub hash: i32,
+    ///
+    /// The root of the [`DomainSummary`] of the content
+    pub digest: Digest,
+}
+
+impl ContentHash {
+    ///
+    /// Computes the hash of the content of a domain, which is summarized as a single entry named by the domain
+    pub fn of<T: Hashcode + ContentDigest + ?Sized>(
+        domain: &str,
+        content: &T,
+    ) -> std::io::Result<Self> {
+        let mut summary = DomainSummary::new();
+        summary.insert_value(domain.to_string(), content)?;
+        Ok(Self::with_summary(domain, content.hashcode(), &summary))
+    }
+
+    ///
+    /// Creates the hash of a domain from its hashcode and the summary of its entries
+    pub fn with_summary(domain: &str, hash: i32, summary: &DomainSummary) -> Self {
+        Self {
+            domain: domain.to_string(),
+            hash,
+            digest: summary.root(),
+        }
+    }
+}
+
+impl Serializeable for ContentHash {
+    fn serialize<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        self.domain.serialize(output)?;
+        self.hash.serialize(output)?;
+        self.digest.serialize(output)
+    }
+}
+
+impl Deserializeable for ContentHash {
+    fn deserialize<R: DataInput + ?Sized>(&mut self, input: &mut R) -> std::io::Result<()> {
+        self.domain.deserialize(input)?;
+        self.hash.deserialize(input)?;
+        self.digest.deserialize(input)
+    }
+}
+
+impl DeserializeCopy for ContentHash {
+    fn deserialize_copy<R: DataInput + ?Sized>(input: &mut R) -> std::io::Result<Self> {
+        Ok(Self {
+            domain: String::deserialize_copy(input)?,
+            hash: i32::deserialize_copy(input)?,
+            digest: Digest::deserialize_copy(input)?,
+        })
+    }
+}
+
+///
+/// The flag set in [`Handshake::flags`] by clients which support compression
+pub const COMPRESSION_FLAG: u8 = 0x01;
+
+///
+/// The flag set in [`Handshake::flags`] by clients which support resuming their session
+pub const RESUME_FLAG: u8 = 0x02;
+
+packet! {
+    ///
+    /// The first packet sent by a client on a new connection
+    pub struct Handshake(0x0000, Serverbound) {
+        ///
+        /// The newest protocol version supported by the client
+        pub protocol: Version,
+        ///
+        /// The UUID of the client's player
+        pub client: UUID,
+        ///
+        /// The hashes of the resource domains loaded by the client
+        pub content: List<ContentHash>,
+        ///
+        /// The features supported by the client, such as [`COMPRESSION_FLAG`] and [`RESUME_FLAG`]
+        pub flags: u8,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by the server to accept a client. Both sides enter the Play state
+    pub struct LoginAccept(0x0003, Clientbound) {
+        ///
+        /// The protocol version used for the rest of the connection
+        pub protocol: Version,
+        ///
+        /// The size at which packets sent after this one are compressed, or 0 if compression is disabled
+        pub compression: u32,
+        ///
+        /// The time in milliseconds the server keeps the client's session after its connection is lost, or 0 if it has no session
+        pub session_grace: u32,
+        ///
+        /// The token the client presents to resume its session, which is all zeros if it has no session
+        pub session: SessionToken,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by the server to reject a client, before closing the connection
+    pub struct LoginReject(0x0004, Clientbound) {
+        ///
+        /// The reason the client was rejected, to be shown to the player
+        pub reason: TextComponent,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by the server when the content loaded by the client does not match the server's content
+    pub struct ContentSyncRequest(0x0005, Clientbound) {
+        ///
+        /// The resource domains which are missing or differ on the client
+        pub domains: List<String>,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by the client after synchronizing its content in response to a [`ContentSyncRequest`]
+    pub struct ContentReport(0x0006, Serverbound) {
+        ///
+        /// The hashes of the resource domains loaded by the client
+        pub content: List<ContentHash>,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by the server when the content of the client differs, to find the entries which differ
+    pub struct DigestRequest(0x0007, Clientbound) {
+        ///
+        /// The resource domains which differ on the client
+        pub domains: List<String>,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by the client for each domain in a [`DigestRequest`]
+    pub struct DomainDigests(0x0008, Serverbound) {
+        ///
+        /// The resource domain
+        pub domain: String,
+        ///
+        /// The digests of the entries of the domain loaded by the client, in order of their names
+        pub entries: List<EntryDigest>,
+    }
+}
+
+fn check(state: State, packet: &dyn AnyPacket) -> Result<(), HandshakeError> {
+    if state.accepts(packet.id()) {
+        Ok(())
+    } else {
+        Err(HandshakeError::UnexpectedPacket(packet.id(), state))
+    }
+}
+
+///
+/// The response of the server to a client's content or version
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoginResponse {
+    ///
+    /// The client is accepted
+    Accept(LoginAccept),
+    ///
+    /// The client is rejected
+    Reject(LoginReject),
+    ///
+    /// The client must synchronize its content
+    Sync(ContentSyncRequest),
+    ///
+    /// The client must send the digests of the entries of the domains which differ
+    Digests(DigestRequest),
+}
+
+impl LoginResponse {
+    ///
+    /// Returns the packet to send to the client
+    pub fn packet(&self) -> &dyn AnyPacket {
+        match self {
+            LoginResponse::Accept(p) => p,
+            LoginResponse::Reject(p) => p,
+            LoginResponse::Sync(p) => p,
+            LoginResponse::Digests(p) => p,
+        }
+    }
+}
+
+///
+/// The server side of the handshake
+#[derive(Clone, Debug)]
+pub struct ServerHandshake {
+    state: State,
+    min_version: Version,
+    max_version: Version,
+    content: BTreeMap<String, (i32, Digest)>,
+    summary: Option<ContentSummary>,
+    allow_sync: bool,
+    synced: bool,
+    digests_requested: bool,
+    pending_digests: Vec<String>,
+    differs: Vec<String>,
+    entry_diffs: BTreeMap<String, Vec<EntryDiff>>,
+    client: Option<UUID>,
+    version: Option<Version>,
+    compression_threshold: Option<usize>,
+    client_compression: bool,
+    compression: Option<usize>,
+    session_grace: Option<Duration>,
+    client_resume: bool,
+    session: Option<Session>,
+}
+
+impl ServerHandshake {
+    ///
+    /// Creates the server side of a handshake, which accepts clients whose content matches `content`,
+    ///  and which support the current protocol version
+    pub fn new<I: IntoIterator<Item = ContentHash>>(content: I) -> Self {
+        Self {
+            state: State::Handshaking,
+            min_version: PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            content: content
+                .into_iter()
+                .map(|c| (c.domain, (c.hash, c.digest)))
+                .collect(),
+            summary: None,
+            allow_sync: false,
+            synced: false,
+            digests_requested: false,
+            pending_digests: Vec::new(),
+            differs: Vec::new(),
+            entry_diffs: BTreeMap::new(),
+            client: None,
+            version: None,
+            compression_threshold: None,
+            client_compression: false,
+            compression: None,
+            session_grace: None,
+            client_resume: false,
+            session: None,
+        }
+    }
+
+    ///
+    /// Sets how long the sessions given to clients which support resumption are kept after their connection is lost,
+    ///  or None to not give sessions
+    pub fn set_session_grace(&mut self, grace: Option<Duration>) {
+        self.session_grace = grace;
+    }
+
+    ///
+    /// Returns the session of the client, once it has been accepted with a session
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    ///
+    /// Sets the compression threshold offered to clients which support compression, or None to disable compression
+    pub fn set_compression(&mut self, threshold: Option<usize>) {
+        self.compression_threshold = threshold;
+    }
+
+    ///
+    /// Returns the compression threshold of the connection, once the client has been accepted with compression
+    pub fn compression(&self) -> Option<usize> {
+        self.compression
+    }
+
+    ///
+    /// Sets the range of protocol versions supported by the server.
+    /// Clients are accepted with the newest version supported by both sides
+    pub fn set_versions(&mut self, min: Version, max: Version) {
+        self.min_version = min;
+        self.max_version = max;
+    }
+
+    ///
+    /// Sets whether clients with mismatched content are asked to synchronize it once, rather than being rejected
+    pub fn set_allow_sync(&mut self, allow_sync: bool) {
+        self.allow_sync = allow_sync;
+    }
+
+    ///
+    /// Sets the summary of the server's content.
+    /// If set, clients with mismatched content are asked once for the digests of the entries of the domains which differ
+    pub fn set_summary(&mut self, summary: ContentSummary) {
+        self.summary = Some(summary);
+    }
+
+    ///
+    /// Returns the current state of the connection
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    ///
+    /// Returns the entries which differ in each domain the client sent digests for, from the server's side
+    pub fn entry_diffs(&self) -> &BTreeMap<String, Vec<EntryDiff>> {
+        &self.entry_diffs
+    }
+
+    ///
+    /// Returns the UUID of the client's player, once the Handshake has been received
+    pub fn client(&self) -> Option<UUID> {
+        self.client
+    }
+
+    ///
+    /// Returns the negotiated protocol version, once the client has been accepted
+    pub fn version(&self) -> Option<Version> {
+        self.version
+    }
+
+    ///
+    /// Checks that the packet may be received in the current state
+    pub fn check(&self, packet: &dyn AnyPacket) -> Result<(), HandshakeError> {
+        check(self.state, packet)
+    }
+
+    fn reject(&mut self, reason: String) -> LoginResponse {
+        self.state = State::Closed;
+        LoginResponse::Reject(LoginReject {
+            reason: TextComponent::RawText(reason),
+        })
+    }
+
+    fn respond(&mut self, content: &[ContentHash]) -> LoginResponse {
+        let mut differs = Vec::new();
+        let mut extra = Vec::new();
+        let received = content
+            .iter()
+            .map(|c| (c.domain.as_str(), (c.hash, c.digest)))
+            .collect::<BTreeMap<_, _>>();
+        for (domain, hash) in &self.content {
+            if received.get(domain.as_str()) != Some(hash) {
+                differs.push(domain.clone());
+            }
+        }
+        for domain in received.keys() {
+            if !self.content.contains_key(*domain) {
+                extra.push(domain.to_string());
+            }
+        }
+
+        if !extra.is_empty() {
+            self.reject(format!(
+                "The server does not have the content: {}",
+                extra.join(", ")
+            ))
+        } else if differs.is_empty() {
+            let version = self.version.unwrap();
+            self.state = State::Play;
+            // A threshold of 0 would disable compression, so the smallest threshold is 1
+            self.compression = self
+                .compression_threshold
+                .filter(|_| self.client_compression)
+                .map(|threshold| threshold.clamp(1, u32::MAX as usize));
+            // Likewise, a grace of 0 would mean the client has no session
+            let grace = self
+                .session_grace
+                .filter(|_| self.client_resume)
+                .map(|grace| grace.as_millis().clamp(1, u32::MAX as u128) as u32);
+            self.session =
+                grace.map(|grace| Session::new(new_token(), Duration::from_millis(grace as u64)));
+            LoginResponse::Accept(LoginAccept {
+                protocol: version,
+                compression: self.compression.unwrap_or(0) as u32,
+                session_grace: grace.unwrap_or(0),
+                session: self.session.as_ref().map_or([0; 32], Session::token),
+            })
+        } else if self.summary.is_some() && !self.digests_requested && !self.synced {
+            self.digests_requested = true;
+            self.pending_digests = differs.clone();
+            self.differs = differs.clone();
+            LoginResponse::Digests(DigestRequest {
+                domains: List(differs),
+            })
+        } else {
+            self.mismatched(differs)
+        }
+    }
+
+    fn mismatched(&mut self, differs: Vec<String>) -> LoginResponse {
+        if self.allow_sync && !self.synced {
+            self.synced = true;
+            LoginResponse::Sync(ContentSyncRequest {
+                domains: List(differs),
+            })
+        } else {
+            let domains = differs
+                .iter()
+                .map(|domain| match self.entry_diffs.get(domain) {
+                    Some(diffs) if !diffs.is_empty() => format!(
+                        "{} ({})",
+                        domain,
+                        diffs
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                    _ => domain.clone(),
+                })
+                .collect::<Vec<_>>();
+            self.reject(format!(
+                "The content does not match the server: {}",
+                domains.join(", ")
+            ))
+        }
+    }
+
+    ///
+    /// Processes a packet received from the client, and returns the response to send, if any.
+    /// KeepAlive packets are not answered, and a Disconnect closes the connection
+    pub fn receive(
+        &mut self,
+        packet: &dyn AnyPacket,
+    ) -> Result<Option<LoginResponse>, HandshakeError> {
+        self.check(packet)?;
+        if let Some(handshake) = packet.downcast_ref::<Handshake>() {
+            self.client = Some(handshake.client);
+            self.client_compression = handshake.flags & COMPRESSION_FLAG != 0;
+            self.client_resume = handshake.flags & RESUME_FLAG != 0;
+            self.state = State::Login;
+            let version = handshake.protocol.min(self.max_version);
+            if version < self.min_version {
+                return Ok(Some(self.reject(format!(
+                    "Outdated client: the server requires protocol version {}, but the client supports {}",
+                    self.min_version, handshake.protocol
+                ))));
+            }
+            self.version = Some(version);
+            Ok(Some(self.respond(&handshake.content)))
+        } else if let Some(report) = packet.downcast_ref::<ContentReport>() {
+            if self.synced {
+                Ok(Some(self.respond(&report.content)))
+            } else {
+                Err(HandshakeError::UnexpectedPacket(
+                    ContentReport::ID,
+                    self.state,
+                ))
+            }
+        } else if let Some(digests) = packet.downcast_ref::<DomainDigests>() {
+            let pos = self
+                .pending_digests
+                .iter()
+                .position(|d| *d == digests.domain)
+                .ok_or(HandshakeError::UnexpectedPacket(
+                    DomainDigests::ID,
+                    self.state,
+                ))?;
+            self.pending_digests.remove(pos);
+            let diffs = self
+                .summary
+                .as_ref()
+                .and_then(|s| s.domain(&digests.domain))
+                .cloned()
+                .unwrap_or_default()
+                .diff(digests.entries.iter().cloned());
+            self.entry_diffs.insert(digests.domain.clone(), diffs);
+            if self.pending_digests.is_empty() {
+                let differs = std::mem::take(&mut self.differs);
+                Ok(Some(self.mismatched(differs)))
+            } else {
+                Ok(None)
+            }
+        } else if packet.is::<Disconnect>() {
+            self.state = State::Closed;
+            Ok(None)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+///
+/// The result of the client processing a packet from the server during the handshake
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientEvent {
+    ///
+    /// The packet does not affect the handshake
+    Ignored,
+    ///
+    /// The server accepted the client with the given protocol version
+    Accepted(Version),
+    ///
+    /// The server rejected the client or closed the connection, for the given reason
+    Rejected(TextComponent),
+    ///
+    /// The server asked the client to synchronize the given domains, and then to send a [`ContentReport`]
+    SyncRequested(Vec<String>),
+    ///
+    /// The server asked the client to send [`DomainDigests`] for each of the given domains
+    DigestsRequested(Vec<String>),
+}
+
+///
+/// The client side of the handshake
+#[derive(Clone, Debug)]
+pub struct ClientHandshake {
+    state: State,
+    client: UUID,
+    protocol: Version,
+    content: Vec<ContentHash>,
+    version: Option<Version>,
+    compression_supported: bool,
+    compression: Option<usize>,
+    resume_supported: bool,
+    session: Option<Session>,
+}
+
+impl ClientHandshake {
+    ///
+    /// Creates the client side of a handshake for the given player, with the given loaded content
+    pub fn new<I: IntoIterator<Item = ContentHash>>(client: UUID, content: I) -> Self {
+        Self {
+            state: State::Handshaking,
+            client,
+            protocol: PROTOCOL_VERSION,
+            content: content.into_iter().collect(),
+            version: None,
+            compression_supported: false,
+            compression: None,
+            resume_supported: false,
+            session: None,
+        }
+    }
+
+    ///
+    /// Sets whether the client tells the server it supports resuming its session
+    pub fn set_resume_supported(&mut self, supported: bool) {
+        self.resume_supported = supported;
+    }
+
+    ///
+    /// Returns the session given by the server, once it has accepted the client with a session
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    ///
+    /// Sets whether the client tells the server it supports compression
+    pub fn set_compression_supported(&mut self, supported: bool) {
+        self.compression_supported = supported;
+    }
+
+    ///
+    /// Returns the compression threshold sent by the server, once it has accepted the client with compression
+    pub fn compression(&self) -> Option<usize> {
+        self.compression
+    }
+
+    ///
+    /// Sets the newest protocol version supported by the client
+    pub fn set_protocol(&mut self, protocol: Version) {
+        self.protocol = protocol;
+    }
+
+    ///
+    /// Returns the current state of the connection
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    ///
+    /// Returns the negotiated protocol version, once the server has accepted the client
+    pub fn version(&self) -> Option<Version> {
+        self.version
+    }
+
+    ///
+    /// Checks that the packet may be received in the current state
+    pub fn check(&self, packet: &dyn AnyPacket) -> Result<(), HandshakeError> {
+        check(self.state, packet)
+    }
+
+    ///
+    /// Returns the Handshake packet to send to the server, and enters the Login state.
+    /// Returns an error if the handshake has already been started
+    pub fn start(&mut self) -> Result<Handshake, HandshakeError> {
+        if self.state != State::Handshaking {
+            return Err(HandshakeError::UnexpectedPacket(Handshake::ID, self.state));
+        }
+        self.state = State::Login;
+        Ok(Handshake {
+            protocol: self.protocol,
+            client: self.client,
+            content: List(self.content.clone()),
+            flags: if self.compression_supported {
+                COMPRESSION_FLAG
+            } else {
+                0
+            } | if self.resume_supported {
+                RESUME_FLAG
+            } else {
+                0
+            },
+        })
+    }
+
+    ///
+    /// Returns the ContentReport packet to send to the server after synchronizing content
+    pub fn report<I: IntoIterator<Item = ContentHash>>(
+        &mut self,
+        content: I,
+    ) -> Result<ContentReport, HandshakeError> {
+        if self.state != State::Login {
+            return Err(HandshakeError::UnexpectedPacket(
+                ContentReport::ID,
+                self.state,
+            ));
+        }
+        self.content = content.into_iter().collect();
+        Ok(ContentReport {
+            content: List(self.content.clone()),
+        })
+    }
+
+    ///
+    /// Returns the DomainDigests packet to send to the server for the given domain, from the summary of the client's content.
+    /// A domain which is not in the summary is sent with no entries
+    pub fn digests(
+        &self,
+        summary: &ContentSummary,
+        domain: &str,
+    ) -> Result<DomainDigests, HandshakeError> {
+        if self.state != State::Login {
+            return Err(HandshakeError::UnexpectedPacket(
+                DomainDigests::ID,
+                self.state,
+            ));
+        }
+        Ok(DomainDigests {
+            domain: domain.to_string(),
+            entries: summary
+                .domain(domain)
+                .map(|d| d.entries().collect())
+                .unwrap_or_default(),
+        })
+    }
+
+    ///
+    /// Processes a packet received from the server
+    pub fn receive(&mut self, packet: &dyn AnyPacket) -> Result<ClientEvent, HandshakeError> {
+        if self.state == State::Handshaking {
+            return Err(HandshakeError::UnexpectedPacket(packet.id(), self.state));
+        }
+        self.check(packet)?;
+        if let Some(accept) = packet.downcast_ref::<LoginAccept>() {
+            self.state = State::Play;
+            self.version = Some(accept.protocol);
+            if self.compression_supported && accept.compression != 0 {
+                self.compression = Some(accept.compression as usize);
+            }
+            if self.resume_supported && accept.session_grace != 0 {
+                self.session = Some(Session::new(
+                    accept.session,
+                    Duration::from_millis(accept.session_grace as u64),
+                ));
+            }
+            Ok(ClientEvent::Accepted(accept.protocol))
+        } else if let Some(reject) = packet.downcast_ref::<LoginReject>() {
+            self.state = State::Closed;
+            Ok(ClientEvent::Rejected(reject.reason.clone()))
+        } else if let Some(disconnect) = packet.downcast_ref::<Disconnect>() {
+            self.state = State::Closed;
+            Ok(ClientEvent::Rejected(disconnect.reason.clone()))
+        } else if let Some(sync) = packet.downcast_ref::<ContentSyncRequest>() {
+            Ok(ClientEvent::SyncRequested(sync.domains.0.clone()))
+        } else if let Some(request) = packet.downcast_ref::<DigestRequest>() {
+            Ok(ClientEvent::DigestsRequested(request.domains.0.clone()))
+        } else {
+            Ok(ClientEvent::Ignored)
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//!
+//! A harness for testing server handlers in-process.
+//!
+//! A [`Harness`] runs a [`Server`] and any number of clients over a scheduled [`Network`] of loopback streams.
+//! Packets sent by clients are held until the harness runs, and are then handed to the server one at a time,
+//!  waiting for the server to handle each before the next, in the order they were sent.
+//! Packets received by clients are kept in an inbox for each player, to be checked by [`Harness::expect`] and similar assertions.
+//! Links can be given latency and loss, which are measured on the network's virtual clock, so tests never sleep.
+//!
+//! The server handles packets and ticks on its own threads as usual, so its KeepAlives and timeouts still use real time.
+
+use std::{
+    collections::VecDeque,
+    io::ErrorKind,
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use binary_io::uuid::UUID;
+use text::TextComponent;
+
+use crate::{
+    client::{self, ClientConfig},
+    connection::{Connection, Transport},
+    loopback::{LinkConditions, LoopbackStream, Network},
+    packet::{AnyPacket, Packet, PacketRegistry},
+    server::{Handler, Server},
+};
+
+///
+/// The longest the harness waits for the server to handle a packet, before it assumes the server is stuck and panics
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Client {
+    player: UUID,
+    conn: Connection<LoopbackStream>,
+    inbox: VecDeque<Box<dyn AnyPacket>>,
+    closed: bool,
+}
+
+struct Accepted {
+    stream: LoopbackStream,
+    serving: JoinHandle<std::io::Result<()>>,
+}
+
+///
+/// A server and its clients, connected by an in-memory network
+pub struct Harness<H: Handler> {
+    network: Network,
+    server: Arc<Server<H>>,
+    registry: PacketRegistry,
+    clients: Vec<Client>,
+    accepted: Vec<Accepted>,
+}
+
+impl<H: Handler> Harness<H> {
+    ///
+    /// Creates a harness running server, whose clients decode packets with the server's registry
+    pub fn new(server: Server<H>, registry: PacketRegistry) -> Self {
+        Self::with_seed(server, registry, 0)
+    }
+
+    ///
+    /// Creates a harness running server, whose network decides losses with a generator seeded with seed
+    pub fn with_seed(server: Server<H>, registry: PacketRegistry, seed: u64) -> Self {
+        let server = Arc::new(server);
+        server.spawn_ticker();
+        Self {
+            network: Network::scheduled(seed),
+            server,
+            registry,
+            clients: Vec::new(),
+            accepted: Vec::new(),
+        }
+    }
+
+    ///
+    /// Returns the server
+    pub fn server(&self) -> &Arc<Server<H>> {
+        &self.server
+    }
+
+    ///
+    /// Returns the handler of the server
+    pub fn handler(&self) -> &H {
+        self.server.handler()
+    }
+
+    ///
+    /// Returns the network the clients are connected by
+    pub fn network(&self) -> &Network {
+        &self.network
+    }
+
+    ///
+    /// Returns the time on the network's virtual clock
+    pub fn now(&self) -> Duration {
+        self.network.now()
+    }
+
+    fn client(&mut self, player: UUID) -> &mut Client {
+        match self.clients.iter_mut().find(|c| c.player == player) {
+            Some(client) => client,
+            None => panic!("{} has not joined", player),
+        }
+    }
+
+    // Checks that every connection is either closed, or blocked waiting for a packet which has not been released
+    fn is_idle(&self) -> bool {
+        self.accepted
+            .iter()
+            .all(|a| a.serving.is_finished() || a.stream.is_waiting())
+    }
+
+    fn wait_idle(&self) {
+        let start = Instant::now();
+        while !self.is_idle() {
+            if start.elapsed() > STALL_TIMEOUT {
+                panic!(
+                    "The server did not finish handling a packet within {:?}",
+                    STALL_TIMEOUT
+                );
+            }
+            std::thread::sleep(Duration::from_micros(100));
+        }
+    }
+
+    // Moves the packets which are due to each client's inbox, returning true if any were received
+    fn deliver(&mut self) -> bool {
+        let mut received = false;
+        for client in self.clients.iter_mut().filter(|c| !c.closed) {
+            while !client.closed && client.conn.transport().is_readable() {
+                match client.conn.receive() {
+                    Ok(Some(packet)) => client.inbox.push_back(packet),
+                    Ok(None) => client.closed = true,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => panic!("{} received an invalid packet: {}", client.player, e),
+                }
+                // KeepAlives are answered by the connection, and are not returned
+                received = true;
+            }
+            if !client.closed && client.conn.sender().is_closed() {
+                client.closed = true;
+            }
+        }
+        received
+    }
+
+    ///
+    /// Hands every packet which is due to the server, one at a time, and delivers what it sends to the clients,
+    ///  until nothing else is due at the current time
+    pub fn settle(&mut self) {
+        loop {
+            self.wait_idle();
+            let received = self.deliver();
+            if !self.network.release_next() && !received {
+                break;
+            }
+        }
+    }
+
+    ///
+    /// Moves the virtual clock forward by duration, settling at each delivery time on the way
+    pub fn advance(&mut self, duration: Duration) {
+        let target = self.network.now() + duration;
+        loop {
+            self.settle();
+            match self.network.next_delivery() {
+                Some(at) if at <= target => self.network.advance(at - self.network.now()),
+                _ => break,
+            }
+        }
+        self.network.advance(target - self.network.now());
+        self.settle();
+    }
+
+    ///
+    /// Connects a client with the given configuration, and runs the handshake.
+    /// The server's `connected` handler has run, and its packets are in the client's inbox, once this returns
+    pub fn join_with(&mut self, config: ClientConfig) -> std::io::Result<()> {
+        let (client, accepted) = self.network.pair();
+        self.accepted.push(Accepted {
+            stream: accepted.clone(),
+            serving: self.server.spawn(accepted),
+        });
+        let player = config.client;
+        let registry = self.registry.clone();
+        let login = std::thread::spawn(move || client::login(client, &config, registry));
+        while !login.is_finished() {
+            self.wait_idle();
+            if !self.network.release_next() {
+                std::thread::sleep(Duration::from_micros(100));
+            }
+        }
+        let mut conn = login.join().unwrap()?;
+        // Clients are only read when a packet is due, and never block
+        conn.set_timeouts(None, None)?;
+        conn.transport().set_read_timeout(Some(Duration::ZERO))?;
+        self.clients.push(Client {
+            player,
+            conn,
+            inbox: VecDeque::new(),
+            closed: false,
+        });
+        self.settle();
+        Ok(())
+    }
+
+    ///
+    /// Connects a client for player, with the default configuration.
+    /// Panics if the server does not accept the client
+    pub fn join(&mut self, player: UUID) {
+        if let Err(e) = self.join_with(ClientConfig::new(player, Vec::new())) {
+            panic!("{} could not join: {}", player, e);
+        }
+    }
+
+    ///
+    /// Sets the latency and loss of the player's link, for packets sent after this call
+    pub fn set_conditions(&mut self, player: UUID, conditions: LinkConditions) {
+        let stream = self.client(player).conn.transport().clone();
+        self.network.set_conditions(&stream, conditions);
+    }
+
+    ///
+    /// Sends a packet from the player's client. It is handed to the server by the next call to [`Harness::settle`] or [`Harness::advance`]
+    pub fn send(&mut self, player: UUID, packet: &dyn AnyPacket) -> std::io::Result<()> {
+        self.client(player).conn.send(packet)
+    }
+
+    ///
+    /// Disconnects the player's client with the given reason, and settles
+    pub fn disconnect(&mut self, player: UUID, reason: TextComponent) {
+        let _ = self.client(player).conn.sender().disconnect(reason);
+        self.settle();
+    }
+
+    ///
+    /// Checks if the player's client is still connected
+    pub fn is_connected(&mut self, player: UUID) -> bool {
+        !self.client(player).closed
+    }
+
+    ///
+    /// Removes and returns every packet in the player's inbox
+    pub fn received(&mut self, player: UUID) -> Vec<Box<dyn AnyPacket>> {
+        self.client(player).inbox.drain(..).collect()
+    }
+
+    ///
+    /// Removes the first packet in the player's inbox, and returns it.
+    /// Panics if the inbox is empty, or the first packet is not a P
+    pub fn expect<P: Packet + Clone>(&mut self, player: UUID) -> P {
+        match self.client(player).inbox.pop_front() {
+            Some(packet) => match packet.downcast_ref::<P>() {
+                Some(p) => p.clone(),
+                None => panic!(
+                    "{} expected {}, but received {:?}",
+                    player,
+                    std::any::type_name::<P>(),
+                    packet
+                ),
+            },
+            None => panic!(
+                "{} expected {}, but received nothing",
+                player,
+                std::any::type_name::<P>()
+            ),
+        }
+    }
+
+    ///
+    /// Removes every packet which is a P from the player's inbox, and returns them, leaving the others in order
+    pub fn take<P: Packet + Clone>(&mut self, player: UUID) -> Vec<P> {
+        let inbox = &mut self.client(player).inbox;
+        let mut taken = Vec::new();
+        inbox.retain(|packet| match packet.downcast_ref::<P>() {
+            Some(p) => {
+                taken.push(p.clone());
+                false
+            }
+            None => true,
+        });
+        taken
+    }
+
+    ///
+    /// Panics if the player's inbox is not empty
+    pub fn expect_nothing(&mut self, player: UUID) {
+        let inbox = &self.client(player).inbox;
+        if !inbox.is_empty() {
+            panic!("{} expected nothing, but received {:?}", player, inbox);
+        }
+    }
+}
+
+impl<H: Handler> Drop for Harness<H> {
+    fn drop(&mut self) {
+        // Packets are no longer released once the harness is gone, so deliver everything and let the server threads finish
+        self.network.stop_scheduling();
+        for client in &self.clients {
+            let _ = client.conn.sender().close();
+        }
+    }
+}
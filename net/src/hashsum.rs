@@ -1,17 +1,57 @@
-use std::num::Wrapping;
+//!
+//! Java-compatible hashcodes.
+//!
+//! [`Hashcode`] computes the same 32-bit hash as `hashCode` on the equivalent Java type, so that hashes computed here
+//!  match those computed by the Java reference server bit-for-bit. All arithmetic wraps, as it does in Java.
+//!
+//! The Java equivalents of each type are:
+//! * `bool` is `Boolean`, and integer types are the Java integer type of the same width.
+//!   Unsigned types hash the same bits as the signed type of the same width, except `u8` and `u16`, which are zero-extended.
+//! * `f32` and `f64` are `Float` and `Double`, including the canonical NaN.
+//! * `char` is `Character` for characters in the Basic Multilingual Plane, and the code point as an `Integer` otherwise.
+//! * `str` and `String` are `String`, which hashes UTF-16 code units.
+//! * Slices, arrays, and `Vec` are `List` (or `Arrays.hashCode`), and tuples are `Objects.hash` of their elements.
+//! * `Option` is a nullable reference, where None hashes to 0.
+//! * `HashMap` and `BTreeMap` are `Map`, and `HashSet` and `BTreeSet` are `Set`.
+//! * `UUID` is `java.util.UUID`.
+//! * `NbtTag` hashes the value it contains, as the Java type above. Compounds are `Map`s, and lists and arrays are `List`s.
+//!
+//! Structs can derive Hashcode, which combines the hashcodes of their fields as `Objects.hash` does.
 
-use io::{uuid::UUID, version::Version};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
+use binary_io::{
+    nbt::{array::NbtArray, compound::NbtCompound, list::NbtList, NbtTag},
+    uuid::UUID,
+    version::Version,
+};
+
+pub use net_derive::Hashcode;
+
+///
+/// A type which can compute the same hash as `hashCode` on the equivalent Java type
 pub trait Hashcode {
+    ///
+    /// Computes the hash
     fn hashcode(&self) -> i32;
 }
 
+// Java's List.hashCode and Arrays.hashCode
+fn list_hashcode<I: IntoIterator>(iter: I) -> i32
+where
+    I::Item: Hashcode,
+{
+    iter.into_iter().fold(1i32, |hash, v| {
+        hash.wrapping_mul(31).wrapping_add(v.hashcode())
+    })
+}
+
 impl Hashcode for bool {
     fn hashcode(&self) -> i32 {
         if *self {
-            1337
+            1231
         } else {
-            1331
+            1237
         }
     }
 }
@@ -58,46 +98,87 @@ impl Hashcode for i64 {
     }
 }
 
+impl Hashcode for u64 {
+    fn hashcode(&self) -> i32 {
+        (*self as i64).hashcode()
+    }
+}
+
+impl Hashcode for f32 {
+    fn hashcode(&self) -> i32 {
+        // Float.floatToIntBits collapses every NaN to the canonical NaN
+        if self.is_nan() {
+            0x7fc0_0000
+        } else {
+            self.to_bits() as i32
+        }
+    }
+}
+
+impl Hashcode for f64 {
+    fn hashcode(&self) -> i32 {
+        // Double.doubleToLongBits collapses every NaN to the canonical NaN
+        if self.is_nan() {
+            0x7ff8_0000_0000_0000i64.hashcode()
+        } else {
+            (self.to_bits() as i64).hashcode()
+        }
+    }
+}
+
+impl Hashcode for char {
+    fn hashcode(&self) -> i32 {
+        *self as i32
+    }
+}
+
 impl Hashcode for UUID {
     fn hashcode(&self) -> i32 {
         let (high, low) = self.into_fields();
-        (high as i64)
-            .hashcode()
-            .wrapping_mul(31)
-            .wrapping_add((low as i64).hashcode())
+        ((high ^ low) as i64).hashcode()
     }
 }
 
 impl Hashcode for Version {
     fn hashcode(&self) -> i32 {
-        self.major().get().hashcode() * 31 + self.minor().hashcode()
+        (self.major().get() as i32)
+            .wrapping_mul(31)
+            .wrapping_add(self.minor().hashcode())
     }
 }
 
 impl<T: Hashcode> Hashcode for [T] {
     fn hashcode(&self) -> i32 {
-        let mut wrapping = Wrapping::<i32>(0);
-        for v in self {
-            wrapping *= Wrapping(31);
-            wrapping += Wrapping(v.hashcode());
-        }
-        wrapping.0
+        list_hashcode(self)
+    }
+}
+
+impl<T: Hashcode, const N: usize> Hashcode for [T; N] {
+    fn hashcode(&self) -> i32 {
+        list_hashcode(self)
     }
 }
 
 impl Hashcode for str {
     fn hashcode(&self) -> i32 {
-        self.as_bytes().hashcode()
+        self.encode_utf16()
+            .fold(0i32, |hash, c| hash.wrapping_mul(31).wrapping_add(c as i32))
     }
 }
 
-impl<T: Hashcode> Hashcode for &'_ T {
+impl<T: Hashcode + ?Sized> Hashcode for &'_ T {
     fn hashcode(&self) -> i32 {
         <T as Hashcode>::hashcode(self)
     }
 }
 
-impl<T: Hashcode> Hashcode for &'_ mut T {
+impl<T: Hashcode + ?Sized> Hashcode for &'_ mut T {
+    fn hashcode(&self) -> i32 {
+        <T as Hashcode>::hashcode(self)
+    }
+}
+
+impl<T: Hashcode + ?Sized> Hashcode for Box<T> {
     fn hashcode(&self) -> i32 {
         <T as Hashcode>::hashcode(self)
     }
@@ -121,6 +202,117 @@ impl<T: Hashcode> Hashcode for Option<T> {
 
 impl Hashcode for String {
     fn hashcode(&self) -> i32 {
-        self.as_bytes().hashcode()
+        self.as_str().hashcode()
+    }
+}
+
+macro_rules! impl_hashcode_for_tuples {
+    ($(($($name:ident),+)),* $(,)?) => {
+        $(
+            #[allow(non_snake_case)]
+            impl<$($name: Hashcode),+> Hashcode for ($($name,)+) {
+                fn hashcode(&self) -> i32 {
+                    let ($($name,)+) = self;
+                    let mut hash = 1i32;
+                    $(hash = hash.wrapping_mul(31).wrapping_add($name.hashcode());)+
+                    hash
+                }
+            }
+        )*
+    };
+}
+
+impl_hashcode_for_tuples! {
+    (A),
+    (A, B),
+    (A, B, C),
+    (A, B, C, D),
+    (A, B, C, D, E),
+    (A, B, C, D, E, F),
+    (A, B, C, D, E, F, G),
+    (A, B, C, D, E, F, G, H),
+}
+
+// Java's Map.hashCode: the sum of key.hashCode() ^ value.hashCode() for each entry
+fn map_hashcode<'a, K: Hashcode + 'a, V: Hashcode + 'a, I: IntoIterator<Item = (&'a K, &'a V)>>(
+    iter: I,
+) -> i32 {
+    iter.into_iter().fold(0i32, |hash, (k, v)| {
+        hash.wrapping_add(k.hashcode() ^ v.hashcode())
+    })
+}
+
+// Java's Set.hashCode: the sum of the hashCode of each element
+fn set_hashcode<I: IntoIterator>(iter: I) -> i32
+where
+    I::Item: Hashcode,
+{
+    iter.into_iter()
+        .fold(0i32, |hash, v| hash.wrapping_add(v.hashcode()))
+}
+
+impl<K: Hashcode, V: Hashcode, S> Hashcode for HashMap<K, V, S> {
+    fn hashcode(&self) -> i32 {
+        map_hashcode(self)
+    }
+}
+
+impl<K: Hashcode, V: Hashcode> Hashcode for BTreeMap<K, V> {
+    fn hashcode(&self) -> i32 {
+        map_hashcode(self)
+    }
+}
+
+impl<T: Hashcode, S> Hashcode for HashSet<T, S> {
+    fn hashcode(&self) -> i32 {
+        set_hashcode(self)
+    }
+}
+
+impl<T: Hashcode> Hashcode for BTreeSet<T> {
+    fn hashcode(&self) -> i32 {
+        set_hashcode(self)
+    }
+}
+
+impl<T: Hashcode> Hashcode for NbtArray<T> {
+    fn hashcode(&self) -> i32 {
+        list_hashcode(self.iter())
+    }
+}
+
+impl Hashcode for NbtList {
+    fn hashcode(&self) -> i32 {
+        list_hashcode(self)
+    }
+}
+
+impl Hashcode for NbtCompound {
+    fn hashcode(&self) -> i32 {
+        map_hashcode(self)
+    }
+}
+
+impl Hashcode for NbtTag {
+    fn hashcode(&self) -> i32 {
+        match self {
+            NbtTag::End => 0,
+            // NBT bytes are signed in Java
+            NbtTag::Byte(v) => (*v as i8).hashcode(),
+            NbtTag::Short(v) => v.hashcode(),
+            NbtTag::Int(v) => v.hashcode(),
+            NbtTag::Long(v) => v.hashcode(),
+            NbtTag::Float(v) => v.hashcode(),
+            NbtTag::Double(v) => v.hashcode(),
+            NbtTag::ByteArray(v) => list_hashcode(v.iter().map(|b| *b as i8)),
+            NbtTag::String(v) => v.hashcode(),
+            NbtTag::List(v) => v.hashcode(),
+            NbtTag::Compound(v) => v.hashcode(),
+            NbtTag::IntArray(v) => v.hashcode(),
+            NbtTag::LongArray(v) => v.hashcode(),
+            NbtTag::FloatArray(v) => v.hashcode(),
+            NbtTag::DoubleArray(v) => v.hashcode(),
+            NbtTag::Uuid(v) => v.hashcode(),
+        }
     }
 }
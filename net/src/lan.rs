@@ -0,0 +1,397 @@
+//!
+//! Discovery of games on the local network.
+//!
+//! A host announces its game by periodically sending a [`Beacon`] to a multicast group with a [`LanAnnouncer`].
+//! A [`LanListener`] joins the same group, and keeps a list of the games it has heard from,
+//!  removing games which have not been announced within the expiry time.
+//!
+//! Beacons are sent as a single datagram, containing [`BEACON_MAGIC`] followed by the beacon, encoded according to LCS 4.
+
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use binary_io::{
+    data::{
+        ByteOrder, DataInput, DataInputStream, DataOutput, DataOutputStream, DeserializeCopy,
+        Deserializeable, Serializeable,
+    },
+    uuid::UUID,
+    version::Version,
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use text::TextComponent;
+
+///
+/// The multicast group beacons are sent to by default
+pub const LAN_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 80, 75);
+
+///
+/// The port beacons are sent to by default
+pub const LAN_PORT: u16 = 24_780;
+
+///
+/// The magic number at the start of every beacon datagram
+pub const BEACON_MAGIC: [u8; 4] = *b"PKLN";
+
+///
+/// The maximum size of a beacon datagram
+pub const MAX_BEACON_SIZE: usize = 1024;
+
+///
+/// The announcement of a game on the local network
+#[derive(Clone, Debug, PartialEq)]
+pub struct Beacon {
+    ///
+    /// The name of the game, to be shown to players
+    pub name: TextComponent,
+    ///
+    /// The protocol version of the host
+    pub protocol: Version,
+    ///
+    /// The UUID of the host, which identifies the game
+    pub host: UUID,
+    ///
+    /// The number of players in the game
+    pub players: u16,
+    ///
+    /// The port the host accepts PkmCom connections on
+    pub port: u16,
+}
+
+impl Serializeable for Beacon {
+    fn serialize<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        self.name.serialize(output)?;
+        self.protocol.serialize(output)?;
+        self.host.serialize(output)?;
+        self.players.serialize(output)?;
+        self.port.serialize(output)
+    }
+}
+
+impl Deserializeable for Beacon {
+    fn deserialize<R: DataInput + ?Sized>(&mut self, input: &mut R) -> std::io::Result<()> {
+        *self = Self::deserialize_copy(input)?;
+        Ok(())
+    }
+}
+
+impl DeserializeCopy for Beacon {
+    fn deserialize_copy<R: DataInput + ?Sized>(input: &mut R) -> std::io::Result<Self> {
+        Ok(Self {
+            name: TextComponent::deserialize_copy(input)?,
+            protocol: Version::deserialize_copy(input)?,
+            host: UUID::deserialize_copy(input)?,
+            players: u16::deserialize_copy(input)?,
+            port: u16::deserialize_copy(input)?,
+        })
+    }
+}
+
+impl Beacon {
+    ///
+    /// Encodes the beacon as a datagram.
+    /// Returns an error if the datagram would exceed [`MAX_BEACON_SIZE`]
+    pub fn encode(&self) -> std::io::Result<Vec<u8>> {
+        let mut output = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+        BEACON_MAGIC.serialize(&mut output)?;
+        self.serialize(&mut output)?;
+        let bytes = output.into_inner();
+        if bytes.len() > MAX_BEACON_SIZE {
+            Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Beacon exceeds the maximum datagram size",
+            ))
+        } else {
+            Ok(bytes)
+        }
+    }
+
+    ///
+    /// Decodes a beacon from a datagram
+    pub fn decode(bytes: &[u8]) -> std::io::Result<Self> {
+        let mut input = DataInputStream::new(bytes, ByteOrder::BigEndian);
+        if <[u8; 4]>::deserialize_copy(&mut input)? != BEACON_MAGIC {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Invalid magic (not a PkmCom beacon)",
+            ));
+        }
+        Self::deserialize_copy(&mut input)
+    }
+}
+
+///
+/// The configuration of LAN discovery
+#[derive(Clone, Debug)]
+pub struct LanConfig {
+    ///
+    /// The multicast group and port beacons are sent to
+    pub group: SocketAddrV4,
+    ///
+    /// The address of the interface to send and receive beacons on, or UNSPECIFIED to let the system choose
+    pub interface: Ipv4Addr,
+    ///
+    /// The interval between announcements
+    pub interval: Duration,
+    ///
+    /// The time after which a game that has not been announced is removed
+    pub expiry: Duration,
+    ///
+    /// The multicast TTL of beacons, which limits how many routers they cross
+    pub ttl: u32,
+    ///
+    /// The most games a listener keeps. When another game is discovered, the least recently announced game is removed
+    pub max_games: usize,
+}
+
+impl Default for LanConfig {
+    fn default() -> Self {
+        Self {
+            group: SocketAddrV4::new(LAN_GROUP, LAN_PORT),
+            interface: Ipv4Addr::UNSPECIFIED,
+            interval: Duration::from_millis(1500),
+            expiry: Duration::from_secs(5),
+            ttl: 1,
+            max_games: 256,
+        }
+    }
+}
+
+///
+/// Periodically announces a game on the local network, on a background thread
+pub struct LanAnnouncer {
+    beacon: Arc<Mutex<Beacon>>,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LanAnnouncer {
+    ///
+    /// Starts announcing beacon with the given configuration
+    pub fn start(beacon: Beacon, config: &LanConfig) -> std::io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_multicast_ttl_v4(config.ttl)?;
+        socket.set_multicast_loop_v4(true)?;
+        if !config.interface.is_unspecified() {
+            socket.set_multicast_if_v4(&config.interface)?;
+        }
+        socket.bind(&SocketAddr::from((config.interface, 0)).into())?;
+        let socket = UdpSocket::from(socket);
+        // Check the beacon can be encoded before starting
+        beacon.encode()?;
+
+        let beacon = Arc::new(Mutex::new(beacon));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let beacon = beacon.clone();
+            let stopped = stopped.clone();
+            let group = config.group;
+            let interval = config.interval;
+            std::thread::spawn(move || {
+                while !stopped.load(Ordering::Acquire) {
+                    let bytes = beacon.lock().unwrap().encode();
+                    if let Ok(bytes) = bytes {
+                        let _ = socket.send_to(&bytes, group);
+                    }
+                    std::thread::park_timeout(interval);
+                }
+            })
+        };
+        Ok(Self {
+            beacon,
+            stopped,
+            thread: Some(thread),
+        })
+    }
+
+    ///
+    /// Returns the beacon currently being announced
+    pub fn beacon(&self) -> Beacon {
+        self.beacon.lock().unwrap().clone()
+    }
+
+    ///
+    /// Modifies the beacon, for example to update the player count. The change is sent with the next announcement
+    pub fn update<F: FnOnce(&mut Beacon)>(&self, f: F) {
+        f(&mut self.beacon.lock().unwrap())
+    }
+
+    ///
+    /// Stops announcing the game
+    pub fn stop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for LanAnnouncer {
+    fn drop(&mut self) {
+        self.stop()
+    }
+}
+
+///
+/// A game discovered on the local network
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiscoveredGame {
+    ///
+    /// The last beacon received from the game
+    pub beacon: Beacon,
+    ///
+    /// The address to connect to the game on
+    pub addr: SocketAddr,
+    ///
+    /// The time the last beacon was received
+    pub last_seen: Instant,
+}
+
+struct Games {
+    games: Mutex<HashMap<UUID, DiscoveredGame>>,
+    expiry: Duration,
+    max: usize,
+}
+
+impl Games {
+    fn prune(&self, games: &mut HashMap<UUID, DiscoveredGame>, now: Instant) {
+        games.retain(|_, game| now.saturating_duration_since(game.last_seen) < self.expiry);
+    }
+
+    fn discovered(&self, game: DiscoveredGame) {
+        let mut games = self.games.lock().unwrap();
+        self.prune(&mut games, game.last_seen);
+        if !games.contains_key(&game.beacon.host) && games.len() >= self.max {
+            let oldest = games
+                .values()
+                .min_by_key(|game| game.last_seen)
+                .map(|game| game.beacon.host);
+            if let Some(oldest) = oldest {
+                games.remove(&oldest);
+            }
+        }
+        if self.max > 0 {
+            games.insert(game.beacon.host, game);
+        }
+    }
+
+    fn list(&self, now: Instant) -> Vec<DiscoveredGame> {
+        let mut games = self.games.lock().unwrap();
+        self.prune(&mut games, now);
+        let mut list = games.values().cloned().collect::<Vec<_>>();
+        list.sort_by_key(|game| game.last_seen);
+        list
+    }
+}
+
+///
+/// Listens for beacons on a background thread, and keeps a list of the games that have been announced recently
+pub struct LanListener {
+    games: Arc<Games>,
+    local_addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LanListener {
+    ///
+    /// Joins the multicast group given by the configuration, and starts listening for beacons.
+    /// If the port of the group is 0, an unused port is chosen, which is returned by [`LanListener::local_addr`]
+    pub fn start(config: &LanConfig) -> std::io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, config.group.port())).into())?;
+        socket.join_multicast_v4(config.group.ip(), &config.interface)?;
+        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+        let socket = UdpSocket::from(socket);
+        let local_addr = socket.local_addr()?;
+
+        let games = Arc::new(Games {
+            games: Mutex::new(HashMap::new()),
+            expiry: config.expiry,
+            max: config.max_games,
+        });
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let games = games.clone();
+            let stopped = stopped.clone();
+            std::thread::spawn(move || {
+                let mut buf = [0u8; MAX_BEACON_SIZE];
+                while !stopped.load(Ordering::Acquire) {
+                    match socket.recv_from(&mut buf) {
+                        Ok((len, from)) => {
+                            if let Ok(beacon) = Beacon::decode(&buf[..len]) {
+                                let game = DiscoveredGame {
+                                    addr: SocketAddr::new(from.ip(), beacon.port),
+                                    beacon,
+                                    last_seen: Instant::now(),
+                                };
+                                games.discovered(game);
+                            }
+                        }
+                        Err(e)
+                            if matches!(
+                                e.kind(),
+                                ErrorKind::WouldBlock
+                                    | ErrorKind::TimedOut
+                                    | ErrorKind::Interrupted
+                            ) => {}
+                        Err(_) => std::thread::sleep(Duration::from_millis(100)),
+                    }
+                }
+            })
+        };
+        Ok(Self {
+            games,
+            local_addr,
+            stopped,
+            thread: Some(thread),
+        })
+    }
+
+    ///
+    /// Returns the address the listener is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    ///
+    /// Returns the games which have been announced within the expiry time, least recently announced first
+    pub fn games(&self) -> Vec<DiscoveredGame> {
+        self.games.list(Instant::now())
+    }
+
+    ///
+    /// Returns the games which were announced within the expiry time before `now`, least recently announced first.
+    /// Games which have expired at `now` are removed
+    pub fn games_at(&self, now: Instant) -> Vec<DiscoveredGame> {
+        self.games.list(now)
+    }
+
+    ///
+    /// Stops listening for beacons
+    pub fn stop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for LanListener {
+    fn drop(&mut self) {
+        self.stop()
+    }
+}
@@ -1,3 +1,41 @@
+// Allows the derive macros to refer to this crate as ::net
+extern crate self as net;
 
-pub mod hashsum;
+#[macro_use]
 pub mod packet;
+
+pub mod battle;
+pub mod chat;
+pub mod digest;
+pub mod frame;
+pub mod handshake;
+pub mod hashsum;
+pub mod limit;
+pub mod metrics;
+// Sessions are only kept by connections, which need the tcp feature
+#[cfg_attr(not(feature = "tcp"), allow(dead_code))]
+pub mod session;
+pub mod status;
+pub mod sync;
+pub mod trade;
+
+#[cfg(feature = "tcp")]
+pub mod capture;
+#[cfg(feature = "tcp")]
+pub mod client;
+#[cfg(feature = "tcp")]
+pub mod connection;
+#[cfg(feature = "tcp")]
+pub mod harness;
+#[cfg(feature = "tcp")]
+pub mod loopback;
+#[cfg(feature = "tcp")]
+pub mod server;
+#[cfg(feature = "tcp")]
+pub mod tcp;
+
+#[cfg(feature = "multicast")]
+pub mod lan;
+
+#[cfg(feature = "secure")]
+pub mod secure;
@@ -0,0 +1,406 @@
+//!
+//! Limits on what clients may do to a [`Server`](crate::server::Server), to protect it from clients which misbehave.
+//!
+//! The packets received from each client are limited by a [`TokenBucket`] for each [`Category`] of packets.
+//! Each packet takes a token from the bucket of its category, and tokens are restored at a fixed interval, up to the burst size of the bucket.
+//! A client which sends a packet when the bucket is empty has violated the limit.
+//! The server also limits the connections open at once from each IP address, and the time a client has to complete the handshake.
+//! Each [`Violation`] is reported to the server's [`Handler`](crate::server::Handler), and the client is kicked with the reason for it.
+//!
+//! Limits are measured on a [`Clock`], which can be replaced with a [`ManualClock`] to test them without waiting.
+
+#[cfg(feature = "tcp")]
+use std::net::IpAddr;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::ErrorKind,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use binary_io::uuid::UUID;
+use text::TextComponent;
+
+///
+/// A source of the current time
+pub trait Clock: Send + Sync + 'static {
+    ///
+    /// Returns the current time. The times returned must never decrease
+    fn now(&self) -> Instant;
+}
+
+///
+/// The system's monotonic clock
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+///
+/// A clock which only moves when it is advanced. Clones of a clock share its time
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    start: Instant,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    ///
+    /// Creates a clock, starting at the current time
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Arc::new(Mutex::new(Duration::ZERO)),
+        }
+    }
+
+    ///
+    /// Moves the clock forward by duration
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    ///
+    /// Returns the time the clock has been advanced by since it was created
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+}
+
+///
+/// A category of packets, which share a rate limit
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Category {
+    ///
+    /// Chat messages
+    Chat,
+    ///
+    /// Requests to synchronize content
+    Sync,
+    ///
+    /// Trade offers and their responses
+    Trade,
+    ///
+    /// Battle actions
+    Battle,
+    ///
+    /// Every other packet, including those registered by the game
+    Other,
+}
+
+impl Category {
+    ///
+    /// Returns the category of the packet with the given id
+    pub fn of(id: u16) -> Self {
+        match id {
+            0x0010..=0x001F => Category::Chat,
+            0x0020..=0x002F => Category::Sync,
+            0x0030..=0x003F => Category::Trade,
+            0x0040..=0x004F => Category::Battle,
+            _ => Category::Other,
+        }
+    }
+}
+
+impl Display for Category {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Category::Chat => f.write_str("chat"),
+            Category::Sync => f.write_str("sync"),
+            Category::Trade => f.write_str("trade"),
+            Category::Battle => f.write_str("battle"),
+            Category::Other => f.write_str("other"),
+        }
+    }
+}
+
+///
+/// The size and refill rate of a [`TokenBucket`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Limit {
+    ///
+    /// The most tokens the bucket holds, which is the most packets which may be sent at once
+    pub burst: u32,
+    ///
+    /// The time it takes to restore one token
+    pub interval: Duration,
+}
+
+impl Limit {
+    ///
+    /// Creates a limit of burst packets at once, restoring a token every interval
+    pub const fn new(burst: u32, interval: Duration) -> Self {
+        Self { burst, interval }
+    }
+}
+
+///
+/// A token bucket, which starts full
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    limit: Limit,
+    tokens: u32,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    ///
+    /// Creates a full bucket with the given limit, at the time now
+    pub fn new(limit: Limit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            refilled: now,
+        }
+    }
+
+    ///
+    /// Returns the limit of the bucket
+    pub fn limit(&self) -> Limit {
+        self.limit
+    }
+
+    // Restores the tokens for every whole interval since the bucket was last refilled
+    fn refill(&mut self, now: Instant) {
+        let missing = self.limit.burst - self.tokens;
+        let elapsed = now.saturating_duration_since(self.refilled);
+        let restored = match self.limit.interval.as_nanos() {
+            0 => u128::MAX,
+            interval => elapsed.as_nanos() / interval,
+        };
+        if restored >= missing as u128 {
+            self.tokens = self.limit.burst;
+            self.refilled = now;
+        } else {
+            self.tokens += restored as u32;
+            self.refilled += self.limit.interval * restored as u32;
+        }
+    }
+
+    ///
+    /// Returns the tokens in the bucket at the time now
+    pub fn tokens(&mut self, now: Instant) -> u32 {
+        self.refill(now);
+        self.tokens
+    }
+
+    ///
+    /// Takes a token from the bucket at the time now. Returns false if the bucket is empty
+    pub fn take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+///
+/// The limits applied to each client of a server. The default limits are ones which a well-behaved client should never reach
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimits {
+    ///
+    /// The limit of each category of packets received from a client. Categories without a limit are not limited
+    pub packets: HashMap<Category, Limit>,
+    ///
+    /// The most connections which may be open at once from one IP address, or None for no limit
+    pub connections_per_ip: Option<u32>,
+}
+
+impl RateLimits {
+    ///
+    /// Returns limits which never limit a client
+    pub fn unlimited() -> Self {
+        Self {
+            packets: HashMap::new(),
+            connections_per_ip: None,
+        }
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        let packets = [
+            (Category::Chat, Limit::new(8, Duration::from_millis(500))),
+            (Category::Sync, Limit::new(64, Duration::from_millis(10))),
+            (Category::Trade, Limit::new(16, Duration::from_millis(100))),
+            (Category::Battle, Limit::new(16, Duration::from_millis(100))),
+            (Category::Other, Limit::new(128, Duration::from_millis(5))),
+        ];
+        Self {
+            packets: packets.iter().copied().collect(),
+            connections_per_ip: Some(4),
+        }
+    }
+}
+
+///
+/// The token buckets of one client
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    buckets: HashMap<Category, TokenBucket>,
+}
+
+impl RateLimiter {
+    ///
+    /// Creates a full bucket for each limited category, at the time now
+    pub fn new(limits: &RateLimits, now: Instant) -> Self {
+        Self {
+            buckets: limits
+                .packets
+                .iter()
+                .map(|(category, limit)| (*category, TokenBucket::new(*limit, now)))
+                .collect(),
+        }
+    }
+
+    ///
+    /// Takes a token for a packet with the given id, received at the time now.
+    /// Returns the category of the packet if its bucket is empty
+    pub fn check(&mut self, id: u16, now: Instant) -> Result<(), Category> {
+        let category = Category::of(id);
+        let allowed = match self.buckets.get_mut(&category) {
+            Some(bucket) => bucket.take(now),
+            None => true,
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(category)
+        }
+    }
+}
+
+///
+/// The limit a client violated
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ViolationKind {
+    ///
+    /// The client sent packets of a category faster than its limit
+    RateLimited(Category),
+    ///
+    /// The client's IP address already has as many connections open as are allowed
+    TooManyConnections,
+    ///
+    /// The client did not complete the handshake in time
+    HandshakeTimeout,
+}
+
+impl Display for ViolationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ViolationKind::RateLimited(category) => {
+                f.write_fmt(format_args!("Sending {} packets too quickly", category))
+            }
+            ViolationKind::TooManyConnections => {
+                f.write_str("Too many connections from your address")
+            }
+            ViolationKind::HandshakeTimeout => f.write_str("Took too long to log in"),
+        }
+    }
+}
+
+///
+/// A limit violated by a client, which is kicked for it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    ///
+    /// The limit which was violated
+    pub kind: ViolationKind,
+    ///
+    /// The player UUID of the client, if it completed the handshake
+    pub client: Option<UUID>,
+    ///
+    /// The address of the client, if the transport has one
+    pub address: Option<SocketAddr>,
+}
+
+impl Violation {
+    ///
+    /// Returns the reason the client is kicked with
+    pub fn reason(&self) -> TextComponent {
+        TextComponent::RawText(self.kind.to_string())
+    }
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl std::error::Error for Violation {}
+
+impl From<Violation> for std::io::Error {
+    fn from(v: Violation) -> Self {
+        let kind = match v.kind {
+            ViolationKind::RateLimited(_) => ErrorKind::Other,
+            ViolationKind::TooManyConnections => ErrorKind::ConnectionRefused,
+            ViolationKind::HandshakeTimeout => ErrorKind::TimedOut,
+        };
+        std::io::Error::new(kind, v)
+    }
+}
+
+// The number of connections open from each IP address
+#[cfg(feature = "tcp")]
+#[derive(Debug, Default)]
+pub(crate) struct Connections {
+    open: Mutex<HashMap<IpAddr, u32>>,
+}
+
+#[cfg(feature = "tcp")]
+impl Connections {
+    // Counts a connection from ip, unless max connections from it are already open
+    pub(crate) fn open(&self, ip: IpAddr, max: u32) -> Option<OpenConnection<'_>> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.get(&ip).copied().unwrap_or(0);
+        if count >= max {
+            return None;
+        }
+        open.insert(ip, count + 1);
+        Some(OpenConnection {
+            connections: self,
+            ip,
+        })
+    }
+}
+
+// A connection counted by Connections, until it is dropped
+#[cfg(feature = "tcp")]
+pub(crate) struct OpenConnection<'a> {
+    connections: &'a Connections,
+    ip: IpAddr,
+}
+
+#[cfg(feature = "tcp")]
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        let mut open = self.connections.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
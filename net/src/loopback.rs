@@ -0,0 +1,404 @@
+//!
+//! PkmCom over in-memory streams, for tests and for servers and clients in the same process.
+//!
+//! [`pair`] creates two connected [`LoopbackStream`]s, which behave like the two ends of a TCP connection.
+//! [`connect`] serves one end with a [`Server`] on a new thread, and logs in on the other, like `tcp::connect`,
+//!  and [`resume`] likewise resumes the session of a connection which was lost.
+//!
+//! Streams are links in a [`Network`], which has a virtual clock. Each write is sent as a message,
+//!  which is delivered once the clock reaches its delivery time, and may be lost, according to the [`LinkConditions`] of its link.
+//! Connections write each frame at once, so frames are delayed and lost whole.
+//! A scheduled network also holds the messages sent to the accepting end of each link until [`Network::release_next`] is called,
+//!  so that the order a server handles packets from several clients in does not depend on its threads.
+//! The [`harness`](crate::harness) drives a scheduled network for tests.
+
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind, Read, Write},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    client::{self, ClientConfig},
+    connection::{Connection, Transport},
+    packet::PacketRegistry,
+    server::{Handler, Server},
+};
+
+///
+/// The conditions of both directions of a link
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct LinkConditions {
+    ///
+    /// The time between a write and its delivery
+    pub latency: Duration,
+    ///
+    /// The probability that a write is lost, from 0 to 1
+    pub loss: f64,
+}
+
+struct Message {
+    deliver_at: Duration,
+    bytes: Vec<u8>,
+    released: bool,
+    seq: u64,
+}
+
+#[derive(Default)]
+struct Channel {
+    messages: VecDeque<Message>,
+    // The number of bytes of the first message which have been read
+    read: usize,
+    closed: bool,
+    gated: bool,
+    waiting: bool,
+    conditions: LinkConditions,
+    rng: u64,
+}
+
+impl Channel {
+    fn available(&self, now: Duration) -> bool {
+        self.messages
+            .front()
+            .is_some_and(|m| m.deliver_at <= now && (m.released || !self.gated))
+    }
+
+    // splitmix64, so that losses only depend on the seed of the network and the order of writes on the link
+    fn next_random(&mut self) -> f64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+struct State {
+    now: Duration,
+    channels: Vec<Channel>,
+    seed: u64,
+    seq: u64,
+    scheduled: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+///
+/// A set of in-memory links, which share a virtual clock
+#[derive(Clone)]
+pub struct Network {
+    shared: Arc<Shared>,
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl Network {
+    fn with_scheduling(seed: u64, scheduled: bool) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    now: Duration::ZERO,
+                    channels: Vec::new(),
+                    seed,
+                    seq: 0,
+                    scheduled,
+                }),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    ///
+    /// Creates a network, in which messages are delivered as soon as the clock reaches their delivery time.
+    /// Losses are decided by a generator seeded with seed
+    pub fn new(seed: u64) -> Self {
+        Self::with_scheduling(seed, false)
+    }
+
+    ///
+    /// Creates a network, in which messages to the accepting end of each link are also held until they are released
+    pub fn scheduled(seed: u64) -> Self {
+        Self::with_scheduling(seed, true)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.shared.state.lock().unwrap()
+    }
+
+    ///
+    /// Creates a link, returning the connecting end and the accepting end
+    pub fn pair(&self) -> (LoopbackStream, LoopbackStream) {
+        let mut state = self.lock();
+        let first = state.channels.len();
+        for i in 0..2 {
+            let rng = state.seed ^ ((first + i) as u64).wrapping_mul(0x2545_f491_4f6c_dd1d);
+            let gated = state.scheduled && i == 0;
+            state.channels.push(Channel {
+                gated,
+                rng,
+                ..Default::default()
+            });
+        }
+        let stream = |incoming, outgoing| LoopbackStream {
+            network: self.clone(),
+            incoming,
+            outgoing,
+            timeout: Default::default(),
+        };
+        (stream(first + 1, first), stream(first, first + 1))
+    }
+
+    ///
+    /// Returns the time on the virtual clock
+    pub fn now(&self) -> Duration {
+        self.lock().now
+    }
+
+    ///
+    /// Moves the virtual clock forward by duration, delivering every message due by then
+    pub fn advance(&self, duration: Duration) {
+        self.lock().now += duration;
+        self.shared.changed.notify_all();
+    }
+
+    ///
+    /// Returns the earliest delivery time of a message which is not yet due, if any
+    pub fn next_delivery(&self) -> Option<Duration> {
+        let state = self.lock();
+        state
+            .channels
+            .iter()
+            .filter_map(|c| c.messages.front())
+            .map(|m| m.deliver_at)
+            .filter(|t| *t > state.now)
+            .min()
+    }
+
+    ///
+    /// Sets the conditions of the link of stream, which apply to messages written after this call
+    pub fn set_conditions(&self, stream: &LoopbackStream, conditions: LinkConditions) {
+        let mut state = self.lock();
+        state.channels[stream.incoming].conditions = conditions;
+        state.channels[stream.outgoing].conditions = conditions;
+    }
+
+    ///
+    /// Releases the held message which is due first, by delivery time and then by the order it was written in.
+    /// Returns false if no held message is due
+    pub fn release_next(&self) -> bool {
+        let mut state = self.lock();
+        let now = state.now;
+        let next = state
+            .channels
+            .iter_mut()
+            .filter(|c| c.gated)
+            .filter_map(|c| c.messages.iter_mut().find(|m| !m.released))
+            .filter(|m| m.deliver_at <= now)
+            .min_by_key(|m| (m.deliver_at, m.seq));
+        match next {
+            Some(message) => {
+                message.released = true;
+                drop(state);
+                self.shared.changed.notify_all();
+                true
+            }
+            None => false,
+        }
+    }
+
+    ///
+    /// Stops holding messages, so that every message is delivered once it is due
+    pub fn stop_scheduling(&self) {
+        let mut state = self.lock();
+        state.scheduled = false;
+        for channel in &mut state.channels {
+            channel.gated = false;
+        }
+        drop(state);
+        self.shared.changed.notify_all();
+    }
+}
+
+///
+/// One end of an in-memory link.
+/// Clones share the same end of the link, like the handles returned by `TcpStream::try_clone`
+#[derive(Clone)]
+pub struct LoopbackStream {
+    network: Network,
+    incoming: usize,
+    outgoing: usize,
+    timeout: Arc<Mutex<Option<Duration>>>,
+}
+
+impl LoopbackStream {
+    ///
+    /// Returns the network of the stream
+    pub fn network(&self) -> &Network {
+        &self.network
+    }
+
+    ///
+    /// Checks if reading from the stream would not block, because a message is due or the stream has reached End of File
+    pub fn is_readable(&self) -> bool {
+        let state = self.network.lock();
+        let channel = &state.channels[self.incoming];
+        channel.available(state.now) || (channel.closed && channel.messages.is_empty())
+    }
+
+    ///
+    /// Checks if a thread is blocked reading from the stream, with nothing due to be read
+    pub fn is_waiting(&self) -> bool {
+        let state = self.network.lock();
+        let channel = &state.channels[self.incoming];
+        channel.waiting && !channel.available(state.now)
+    }
+}
+
+impl Read for LoopbackStream {
+    ///
+    /// Reads the next message, or as much of it as fits in buf.
+    /// A read timeout of zero does not block
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let timeout = *self.timeout.lock().unwrap();
+        let start = Instant::now();
+        let mut state = self.network.lock();
+        loop {
+            let now = state.now;
+            let channel = &mut state.channels[self.incoming];
+            if channel.available(now) {
+                channel.waiting = false;
+                let message = channel.messages.front().unwrap();
+                let len = buf.len().min(message.bytes.len() - channel.read);
+                buf[..len].copy_from_slice(&message.bytes[channel.read..channel.read + len]);
+                channel.read += len;
+                if channel.read == message.bytes.len() {
+                    channel.messages.pop_front();
+                    channel.read = 0;
+                }
+                return Ok(len);
+            }
+            if channel.closed && channel.messages.is_empty() {
+                channel.waiting = false;
+                return Ok(0);
+            }
+            channel.waiting = true;
+            state = match timeout {
+                Some(timeout) => {
+                    let elapsed = start.elapsed();
+                    if elapsed >= timeout {
+                        state.channels[self.incoming].waiting = false;
+                        return Err(ErrorKind::WouldBlock.into());
+                    }
+                    self.network
+                        .shared
+                        .changed
+                        .wait_timeout(state, timeout - elapsed)
+                        .unwrap()
+                        .0
+                }
+                None => self.network.shared.changed.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+impl Write for LoopbackStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut state = self.network.lock();
+        let now = state.now;
+        let seq = state.seq;
+        state.seq += 1;
+        let channel = &mut state.channels[self.outgoing];
+        if channel.closed {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+        let conditions = channel.conditions;
+        if conditions.loss > 0.0 && channel.next_random() < conditions.loss {
+            return Ok(buf.len());
+        }
+        // Messages are delivered in order, so none is due before the one written before it
+        let deliver_at = channel
+            .messages
+            .back()
+            .map_or(now, |m| m.deliver_at)
+            .max(now + conditions.latency);
+        channel.messages.push_back(Message {
+            deliver_at,
+            bytes: buf.to_vec(),
+            released: false,
+            seq,
+        });
+        drop(state);
+        self.network.shared.changed.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for LoopbackStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(self.clone())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        *self.timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    ///
+    /// Closes both directions. Messages to this end which were not yet read are discarded,
+    ///  and messages to the other end can still be read before it reaches End of File
+    fn shutdown(&self) -> std::io::Result<()> {
+        let mut state = self.network.lock();
+        let incoming = &mut state.channels[self.incoming];
+        incoming.closed = true;
+        incoming.messages.clear();
+        incoming.read = 0;
+        state.channels[self.outgoing].closed = true;
+        drop(state);
+        self.network.shared.changed.notify_all();
+        Ok(())
+    }
+}
+
+///
+/// Creates two connected streams, on a network of their own which delivers every message immediately
+pub fn pair() -> (LoopbackStream, LoopbackStream) {
+    Network::default().pair()
+}
+
+///
+/// Connects to server over a new pair of streams, serving the other end on a new thread, and runs the handshake
+pub fn connect<H: Handler>(
+    server: &Arc<Server<H>>,
+    config: &ClientConfig,
+    registry: PacketRegistry,
+) -> std::io::Result<Connection<LoopbackStream>> {
+    let (client, accepted) = pair();
+    server.spawn(accepted);
+    client::login(client, config, registry)
+}
+
+///
+/// Connects to server over a new pair of streams, serving the other end on a new thread, and resumes the session of conn
+pub fn resume<H: Handler, T: Transport>(
+    server: &Arc<Server<H>>,
+    conn: &Connection<T>,
+    config: &ClientConfig,
+) -> std::io::Result<Connection<LoopbackStream>> {
+    let (client, accepted) = pair();
+    server.spawn(accepted);
+    client::resume(conn, client, config)
+}
@@ -0,0 +1,769 @@
+//!
+//! Counters of the traffic on PkmCom connections, for monitoring servers and clients.
+//!
+//! A [`Metrics`] collects the counters of every connection opened with it. Each connection is given a [`ConnectionMetrics`],
+//!  which counts the packets and bytes sent and received for each packet id, the round trip times measured by KeepAlives,
+//!  and the frames and packets which could not be decoded. When the connection ends, the reason it ended is counted,
+//!  and its counters are added to the totals of the connections which have closed.
+//! Servers count their connections with [`ServerConfig::metrics`](crate::server::ServerConfig::metrics),
+//!  and clients with [`ClientConfig::metrics`](crate::client::ClientConfig::metrics).
+//!
+//! Bytes are counted as frames on the connection, including the length prefix and after compression, but not the overhead of the transport,
+//!  such as the encryption of a secure session.
+//!
+//! [`Metrics::snapshot`] returns a consistent view of the counters, which can be written in the Prometheus text exposition format.
+//! With the `prometheus` feature, a [`PrometheusEndpoint`] serves that exposition over HTTP, to be scraped by a local Prometheus server.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{Display, Write},
+    net::SocketAddr,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use binary_io::uuid::UUID;
+
+use crate::limit::ViolationKind;
+
+///
+/// The reason a connection ended
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DisconnectReason {
+    ///
+    /// This side sent a Disconnect, for example to kick a client or to leave a server
+    Disconnected,
+    ///
+    /// The peer sent a Disconnect
+    PeerDisconnected,
+    ///
+    /// The connection was closed by either side without a Disconnect
+    Closed,
+    ///
+    /// Nothing was received from the peer within the timeout
+    TimedOut,
+    ///
+    /// The peer sent a frame or packet which could not be decoded
+    DecodeError,
+    ///
+    /// The transport failed
+    Error,
+    ///
+    /// The client was kicked by the server for violating a limit
+    Violation(ViolationKind),
+}
+
+impl DisconnectReason {
+    ///
+    /// Returns the name of the reason, as used in the label of a metric
+    pub fn label(&self) -> &'static str {
+        match self {
+            DisconnectReason::Disconnected => "disconnected",
+            DisconnectReason::PeerDisconnected => "peer_disconnected",
+            DisconnectReason::Closed => "closed",
+            DisconnectReason::TimedOut => "timed_out",
+            DisconnectReason::DecodeError => "decode_error",
+            DisconnectReason::Error => "error",
+            DisconnectReason::Violation(ViolationKind::RateLimited(_)) => "rate_limited",
+            DisconnectReason::Violation(ViolationKind::TooManyConnections) => {
+                "too_many_connections"
+            }
+            DisconnectReason::Violation(ViolationKind::HandshakeTimeout) => "handshake_timeout",
+        }
+    }
+}
+
+impl Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+///
+/// The packets and bytes sent and received with one packet id, or in total
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PacketCounts {
+    ///
+    /// The packets received
+    pub packets_received: u64,
+    ///
+    /// The bytes of the packets received
+    pub bytes_received: u64,
+    ///
+    /// The packets sent
+    pub packets_sent: u64,
+    ///
+    /// The bytes of the packets sent
+    pub bytes_sent: u64,
+}
+
+impl PacketCounts {
+    fn add(&mut self, other: &PacketCounts) {
+        self.packets_received += other.packets_received;
+        self.bytes_received += other.bytes_received;
+        self.packets_sent += other.packets_sent;
+        self.bytes_sent += other.bytes_sent;
+    }
+}
+
+///
+/// The counters of one connection, or of many connections added together
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Counters {
+    ///
+    /// The packets and bytes sent and received, by packet id
+    pub packets: BTreeMap<u16, PacketCounts>,
+    ///
+    /// The frames and packets received which could not be decoded
+    pub decode_errors: u64,
+    ///
+    /// The number of round trip times measured
+    pub rtt_samples: u64,
+    ///
+    /// The sum of the round trip times measured
+    pub rtt_total: Duration,
+}
+
+impl Counters {
+    ///
+    /// Returns the packets and bytes sent and received with every packet id
+    pub fn total(&self) -> PacketCounts {
+        let mut total = PacketCounts::default();
+        for counts in self.packets.values() {
+            total.add(counts);
+        }
+        total
+    }
+
+    ///
+    /// Returns the mean of the round trip times measured, if any were
+    pub fn mean_rtt(&self) -> Option<Duration> {
+        match self.rtt_samples {
+            0 => None,
+            n => Some(Duration::from_nanos(
+                (self.rtt_total.as_nanos() / n as u128) as u64,
+            )),
+        }
+    }
+
+    fn add(&mut self, other: &Counters) {
+        for (id, counts) in &other.packets {
+            self.packets.entry(*id).or_default().add(counts);
+        }
+        self.decode_errors += other.decode_errors;
+        self.rtt_samples += other.rtt_samples;
+        self.rtt_total += other.rtt_total;
+    }
+}
+
+///
+/// The counters of one connection at one point in time
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionSnapshot {
+    ///
+    /// The number of the connection, which is unique among the connections of its [`Metrics`]
+    pub id: u64,
+    ///
+    /// The player UUID of the client, once it is known
+    pub client: Option<UUID>,
+    ///
+    /// The address of the peer, if the transport has one
+    pub address: Option<SocketAddr>,
+    ///
+    /// The time since the connection was opened
+    pub age: Duration,
+    ///
+    /// The last round trip time measured, if any
+    pub rtt: Option<Duration>,
+    ///
+    /// The counters of the connection
+    pub counters: Counters,
+    ///
+    /// The reason the connection ended, if it has
+    pub disconnect: Option<DisconnectReason>,
+}
+
+///
+/// The counters of every connection of a [`Metrics`] at one point in time
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    ///
+    /// The connections which are open, in the order they were opened
+    pub connections: Vec<ConnectionSnapshot>,
+    ///
+    /// The counters of every connection ever opened, including those which are open
+    pub totals: Counters,
+    ///
+    /// The number of connections ever opened
+    pub opened: u64,
+    ///
+    /// The number of connections which ended for each reason
+    pub disconnects: HashMap<DisconnectReason, u64>,
+}
+
+fn seconds(d: Duration) -> f64 {
+    d.as_secs_f64()
+}
+
+// Writes the HELP and TYPE lines of a metric
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+impl MetricsSnapshot {
+    ///
+    /// Returns the number of open connections
+    pub fn open(&self) -> usize {
+        self.connections.len()
+    }
+
+    ///
+    /// Writes the snapshot in the Prometheus text exposition format, version 0.0.4.
+    /// Packets are counted by id, and connections are labelled by their number and the client's UUID
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        header(
+            &mut out,
+            "pkmcom_connections_open",
+            "gauge",
+            "Connections currently open",
+        );
+        let _ = writeln!(out, "pkmcom_connections_open {}", self.open());
+        header(
+            &mut out,
+            "pkmcom_connections_opened_total",
+            "counter",
+            "Connections opened",
+        );
+        let _ = writeln!(out, "pkmcom_connections_opened_total {}", self.opened);
+
+        header(
+            &mut out,
+            "pkmcom_packets_total",
+            "counter",
+            "Packets sent and received, by packet id",
+        );
+        for (id, counts) in &self.totals.packets {
+            let _ = writeln!(
+                out,
+                "pkmcom_packets_total{{id=\"0x{:04X}\",direction=\"received\"}} {}",
+                id, counts.packets_received
+            );
+            let _ = writeln!(
+                out,
+                "pkmcom_packets_total{{id=\"0x{:04X}\",direction=\"sent\"}} {}",
+                id, counts.packets_sent
+            );
+        }
+        header(
+            &mut out,
+            "pkmcom_bytes_total",
+            "counter",
+            "Bytes of the frames sent and received, by packet id",
+        );
+        for (id, counts) in &self.totals.packets {
+            let _ = writeln!(
+                out,
+                "pkmcom_bytes_total{{id=\"0x{:04X}\",direction=\"received\"}} {}",
+                id, counts.bytes_received
+            );
+            let _ = writeln!(
+                out,
+                "pkmcom_bytes_total{{id=\"0x{:04X}\",direction=\"sent\"}} {}",
+                id, counts.bytes_sent
+            );
+        }
+        header(
+            &mut out,
+            "pkmcom_decode_errors_total",
+            "counter",
+            "Frames and packets received which could not be decoded",
+        );
+        let _ = writeln!(
+            out,
+            "pkmcom_decode_errors_total {}",
+            self.totals.decode_errors
+        );
+        header(
+            &mut out,
+            "pkmcom_rtt_seconds",
+            "summary",
+            "Round trip times measured by KeepAlives",
+        );
+        let _ = writeln!(
+            out,
+            "pkmcom_rtt_seconds_sum {}",
+            seconds(self.totals.rtt_total)
+        );
+        let _ = writeln!(out, "pkmcom_rtt_seconds_count {}", self.totals.rtt_samples);
+
+        header(
+            &mut out,
+            "pkmcom_disconnects_total",
+            "counter",
+            "Connections which ended, by reason",
+        );
+        // Reasons which share a label, such as rate limits of different categories, are added together
+        let mut disconnects = BTreeMap::new();
+        for (reason, count) in &self.disconnects {
+            *disconnects.entry(reason.label()).or_insert(0) += count;
+        }
+        for (reason, count) in disconnects {
+            let _ = writeln!(
+                out,
+                "pkmcom_disconnects_total{{reason=\"{}\"}} {}",
+                reason, count
+            );
+        }
+
+        header(
+            &mut out,
+            "pkmcom_connection_packets_total",
+            "counter",
+            "Packets sent and received on each open connection",
+        );
+        for conn in &self.connections {
+            let total = conn.counters.total();
+            let labels = conn.labels();
+            let _ = writeln!(
+                out,
+                "pkmcom_connection_packets_total{{{},direction=\"received\"}} {}",
+                labels, total.packets_received
+            );
+            let _ = writeln!(
+                out,
+                "pkmcom_connection_packets_total{{{},direction=\"sent\"}} {}",
+                labels, total.packets_sent
+            );
+        }
+        header(
+            &mut out,
+            "pkmcom_connection_bytes_total",
+            "counter",
+            "Bytes sent and received on each open connection",
+        );
+        for conn in &self.connections {
+            let total = conn.counters.total();
+            let labels = conn.labels();
+            let _ = writeln!(
+                out,
+                "pkmcom_connection_bytes_total{{{},direction=\"received\"}} {}",
+                labels, total.bytes_received
+            );
+            let _ = writeln!(
+                out,
+                "pkmcom_connection_bytes_total{{{},direction=\"sent\"}} {}",
+                labels, total.bytes_sent
+            );
+        }
+        header(
+            &mut out,
+            "pkmcom_connection_decode_errors_total",
+            "counter",
+            "Frames and packets received on each open connection which could not be decoded",
+        );
+        for conn in &self.connections {
+            let _ = writeln!(
+                out,
+                "pkmcom_connection_decode_errors_total{{{}}} {}",
+                conn.labels(),
+                conn.counters.decode_errors
+            );
+        }
+        header(
+            &mut out,
+            "pkmcom_connection_rtt_seconds",
+            "gauge",
+            "The last round trip time measured on each open connection",
+        );
+        for conn in &self.connections {
+            if let Some(rtt) = conn.rtt {
+                let _ = writeln!(
+                    out,
+                    "pkmcom_connection_rtt_seconds{{{}}} {}",
+                    conn.labels(),
+                    seconds(rtt)
+                );
+            }
+        }
+        out
+    }
+}
+
+impl ConnectionSnapshot {
+    fn labels(&self) -> String {
+        match self.client {
+            Some(client) => format!("connection=\"{}\",client=\"{}\"", self.id, client),
+            None => format!("connection=\"{}\"", self.id),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    opened: u64,
+    open: BTreeMap<u64, Weak<ConnectionInner>>,
+    // The counters of the connections which have closed
+    closed: Counters,
+    disconnects: HashMap<DisconnectReason, u64>,
+}
+
+///
+/// The counters of every connection opened with it. Clones share the same counters
+#[derive(Clone, Default)]
+pub struct Metrics {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl std::fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let registry = self.registry.lock().unwrap();
+        f.debug_struct("Metrics")
+            .field("opened", &registry.opened)
+            .field("open", &registry.open.len())
+            .finish()
+    }
+}
+
+impl Metrics {
+    ///
+    /// Creates metrics with no connections
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Opens the counters of a new connection to the given address
+    pub fn connection(&self, address: Option<SocketAddr>) -> ConnectionMetrics {
+        let mut registry = self.registry.lock().unwrap();
+        registry.opened += 1;
+        let inner = Arc::new(ConnectionInner {
+            id: registry.opened,
+            address,
+            opened: Instant::now(),
+            state: Mutex::new(ConnectionState::default()),
+            registry: self.registry.clone(),
+        });
+        registry.open.insert(inner.id, Arc::downgrade(&inner));
+        ConnectionMetrics { inner }
+    }
+
+    ///
+    /// Returns the counters of every connection
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let registry = self.registry.lock().unwrap();
+        // Dropped once the registry is unlocked, as dropping the last handle to a connection closes it
+        let open = registry
+            .open
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect::<Vec<_>>();
+        let mut totals = registry.closed.clone();
+        let connections = open
+            .iter()
+            .map(|inner| {
+                let snapshot = inner.snapshot(&inner.state.lock().unwrap());
+                totals.add(&snapshot.counters);
+                snapshot
+            })
+            .collect();
+        let snapshot = MetricsSnapshot {
+            connections,
+            totals,
+            opened: registry.opened,
+            disconnects: registry.disconnects.clone(),
+        };
+        drop(registry);
+        drop(open);
+        snapshot
+    }
+}
+
+#[derive(Default)]
+struct ConnectionState {
+    client: Option<UUID>,
+    counters: Counters,
+    rtt: Option<Duration>,
+    disconnect: Option<DisconnectReason>,
+    closed: bool,
+}
+
+struct ConnectionInner {
+    id: u64,
+    address: Option<SocketAddr>,
+    opened: Instant,
+    state: Mutex<ConnectionState>,
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl ConnectionInner {
+    fn snapshot(&self, state: &ConnectionState) -> ConnectionSnapshot {
+        ConnectionSnapshot {
+            id: self.id,
+            client: state.client,
+            address: self.address,
+            age: self.opened.elapsed(),
+            rtt: state.rtt,
+            counters: state.counters.clone(),
+            disconnect: state.disconnect,
+        }
+    }
+
+    // Adds the counters to the totals of the closed connections, and counts the reason the connection ended.
+    // The registry is always locked before the state, so that snapshots never count a connection twice
+    fn close(&self, registry: &mut Registry, state: &mut ConnectionState) {
+        if state.closed {
+            return;
+        }
+        state.closed = true;
+        let reason = *state.disconnect.get_or_insert(DisconnectReason::Closed);
+        registry.open.remove(&self.id);
+        registry.closed.add(&state.counters);
+        *registry.disconnects.entry(reason).or_insert(0) += 1;
+    }
+}
+
+impl Drop for ConnectionInner {
+    fn drop(&mut self) {
+        let registry = self.registry.clone();
+        let mut registry = registry.lock().unwrap();
+        let mut state = std::mem::take(self.state.get_mut().unwrap());
+        self.close(&mut registry, &mut state);
+    }
+}
+
+///
+/// The counters of one connection. Clones share the same counters.
+/// The connection is closed when [`ConnectionMetrics::close`] is called, or every clone is dropped
+#[derive(Clone)]
+pub struct ConnectionMetrics {
+    inner: Arc<ConnectionInner>,
+}
+
+impl std::fmt::Debug for ConnectionMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionMetrics")
+            .field("id", &self.inner.id)
+            .field("address", &self.inner.address)
+            .finish()
+    }
+}
+
+impl ConnectionMetrics {
+    fn update<F: FnOnce(&mut ConnectionState)>(&self, f: F) {
+        let mut state = self.inner.state.lock().unwrap();
+        // A closed connection's counters are already part of the totals
+        if !state.closed {
+            f(&mut state)
+        }
+    }
+
+    ///
+    /// Returns the number of the connection
+    pub fn id(&self) -> u64 {
+        self.inner.id
+    }
+
+    ///
+    /// Records the player UUID of the client, once it is known
+    pub fn set_client(&self, client: UUID) {
+        self.update(|state| state.client = Some(client))
+    }
+
+    ///
+    /// Counts a frame of size bytes received with the given packet id
+    pub fn record_received(&self, id: u16, size: usize) {
+        self.update(|state| {
+            let counts = state.counters.packets.entry(id).or_default();
+            counts.packets_received += 1;
+            counts.bytes_received += size as u64;
+        })
+    }
+
+    ///
+    /// Counts a frame of size bytes sent with the given packet id
+    pub fn record_sent(&self, id: u16, size: usize) {
+        self.update(|state| {
+            let counts = state.counters.packets.entry(id).or_default();
+            counts.packets_sent += 1;
+            counts.bytes_sent += size as u64;
+        })
+    }
+
+    ///
+    /// Records a round trip time measured by a KeepAlive
+    pub fn record_rtt(&self, rtt: Duration) {
+        self.update(|state| {
+            state.rtt = Some(rtt);
+            state.counters.rtt_samples += 1;
+            state.counters.rtt_total += rtt;
+        })
+    }
+
+    ///
+    /// Counts a frame or packet received which could not be decoded
+    pub fn record_decode_error(&self) {
+        self.update(|state| state.counters.decode_errors += 1)
+    }
+
+    ///
+    /// Records the reason the connection is ending, unless a reason was already recorded
+    pub fn record_disconnect(&self, reason: DisconnectReason) {
+        self.update(|state| {
+            state.disconnect.get_or_insert(reason);
+        })
+    }
+
+    ///
+    /// Records the reason the connection is ending, replacing any reason already recorded.
+    /// Used when the reason is known better than the connection can tell, such as when a client violates a limit
+    pub fn override_disconnect(&self, reason: DisconnectReason) {
+        self.update(|state| state.disconnect = Some(reason))
+    }
+
+    ///
+    /// Closes the connection, counting the reason it ended, which is [`DisconnectReason::Closed`] if none was recorded.
+    /// Nothing more is counted once the connection is closed
+    pub fn close(&self) {
+        let mut registry = self.inner.registry.lock().unwrap();
+        let mut state = self.inner.state.lock().unwrap();
+        self.inner.close(&mut registry, &mut state);
+    }
+
+    ///
+    /// Returns the counters of the connection
+    pub fn snapshot(&self) -> ConnectionSnapshot {
+        self.inner.snapshot(&self.inner.state.lock().unwrap())
+    }
+}
+
+#[cfg(feature = "prometheus")]
+pub use self::endpoint::PrometheusEndpoint;
+
+#[cfg(feature = "prometheus")]
+mod endpoint {
+    use std::{
+        io::{ErrorKind, Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread::JoinHandle,
+        time::Duration,
+    };
+
+    use super::Metrics;
+
+    // The most bytes read of a request, which is only ever a short GET
+    const MAX_REQUEST_SIZE: usize = 8192;
+
+    // The time a scraper has to send its request
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+    ///
+    /// An HTTP server which serves the Prometheus exposition of a [`Metrics`] at `/metrics`.
+    /// Requests are answered one at a time, so it should only be reachable by a local Prometheus server
+    pub struct PrometheusEndpoint {
+        addr: SocketAddr,
+        stopped: Arc<AtomicBool>,
+        accept: Option<JoinHandle<()>>,
+    }
+
+    impl PrometheusEndpoint {
+        ///
+        /// Binds to addr, and starts serving the metrics on a new thread
+        pub fn bind<A: ToSocketAddrs>(addr: A, metrics: Metrics) -> std::io::Result<Self> {
+            let listener = TcpListener::bind(addr)?;
+            let addr = listener.local_addr()?;
+            let stopped = Arc::new(AtomicBool::new(false));
+            let accept = {
+                let stopped = stopped.clone();
+                std::thread::spawn(move || {
+                    for stream in listener.incoming() {
+                        if stopped.load(Ordering::Acquire) {
+                            break;
+                        }
+                        match stream {
+                            Ok(mut stream) => {
+                                let _ = respond(&mut stream, &metrics);
+                            }
+                            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                            Err(_) => std::thread::sleep(Duration::from_millis(10)),
+                        }
+                    }
+                })
+            };
+            Ok(Self {
+                addr,
+                stopped,
+                accept: Some(accept),
+            })
+        }
+
+        ///
+        /// Returns the address the endpoint is listening on
+        pub fn local_addr(&self) -> SocketAddr {
+            self.addr
+        }
+
+        ///
+        /// Stops serving the metrics
+        pub fn stop(&mut self) {
+            if !self.stopped.swap(true, Ordering::AcqRel) {
+                // Wake the accept thread, which is blocked in accept
+                let _ = TcpStream::connect_timeout(&self.addr, Duration::from_secs(1));
+            }
+            if let Some(accept) = self.accept.take() {
+                let _ = accept.join();
+            }
+        }
+    }
+
+    impl Drop for PrometheusEndpoint {
+        fn drop(&mut self) {
+            self.stop();
+        }
+    }
+
+    // Reads the request line and headers, up to the blank line which ends them
+    fn read_request(stream: &mut TcpStream) -> std::io::Result<String> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut request = Vec::new();
+        let mut chunk = [0u8; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            if request.len() >= MAX_REQUEST_SIZE {
+                break;
+            }
+            match stream.read(&mut chunk)? {
+                0 => break,
+                n => request.extend_from_slice(&chunk[..n]),
+            }
+        }
+        Ok(String::from_utf8_lossy(&request).into_owned())
+    }
+
+    fn respond(stream: &mut TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+        let request = read_request(stream)?;
+        let mut words = request.lines().next().unwrap_or("").split_whitespace();
+        let (method, path) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+        let (status, body) = match (method, path) {
+            ("GET", "/metrics") | ("HEAD", "/metrics") => {
+                ("200 OK", metrics.snapshot().to_prometheus())
+            }
+            ("GET", _) | ("HEAD", _) => ("404 Not Found", "Not Found\n".to_string()),
+            _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_string()),
+        };
+        let mut response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            body.len()
+        );
+        if method != "HEAD" {
+            response.push_str(&body);
+        }
+        stream.write_all(response.as_bytes())?;
+        stream.flush()
+    }
+}
@@ -1,3 +1,636 @@
+//!
+//! The PkmCom packet model.
+//!
+//! Every packet has a numeric id, a direction, and the protocol version it was introduced in.
+//! On the wire, a packet is its id as a u16, followed by its fields, encoded according to LCS 4.
+//! The [`PacketRegistry`] maps ids back to decoders, so packets can be read without knowing their type in advance.
+
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt::{Debug, Display},
+    io::{ErrorKind, Read},
+    iter::FromIterator,
+    ops::{Deref, DerefMut},
+};
+
+use binary_io::{
+    data::{DataInput, DataOutput, DeserializeCopy, Deserializeable, OutOfRange, Serializeable},
+    uuid::UUID,
+    version::Version,
+};
+use text::TextComponent;
+
+use crate::{
+    battle::{
+        ActionSubmit, BattleAccept, BattleChallenge, BattleEnd, BattleForfeit, BattleSpectate,
+        TeamPreview, TurnResult,
+    },
+    chat::{ChatBlock, ChatUnblock, ChatWhisper, PartyChat},
+    handshake::{
+        ContentReport, ContentSyncRequest, DigestRequest, DomainDigests, Handshake, LoginAccept,
+        LoginReject,
+    },
+    session::{Ack, Resume, ResumeAccept},
+    status::{StatusPing, StatusRequest, StatusResponse},
+    sync::{RegistryEntries, RegistrySyncAck, RegistrySyncEnd, RegistrySyncStart},
+    trade::{TradeAbort, TradeCommit, TradeConfirm, TradeCounterOffer, TradeLock, TradeOffer},
+};
+
 ///
-/// A type that
+/// The current version of the PkmCom protocol
+pub const PROTOCOL_VERSION: Version = Version::from_encoded(0x0000);
+
+///
+/// Declares a packet struct, and implements LCS 4 serialization and [`Packet`] for it.
+/// Each field is serialized in declaration order.
+macro_rules! packet {
+    {
+        $(#[$meta:meta])*
+        pub struct $name:ident ($id:expr, $direction:ident $(, $since:expr)?) {
+            $($(#[$fmeta:meta])* pub $field:ident : $ty:ty),* $(,)?
+        }
+    } => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, PartialEq)]
+        pub struct $name {
+            $($(#[$fmeta])* pub $field : $ty),*
+        }
+
+        impl ::binary_io::data::Serializeable for $name {
+            #[allow(unused_variables)]
+            fn serialize<W: ::binary_io::data::DataOutput + ?Sized>(
+                &self,
+                output: &mut W,
+            ) -> std::io::Result<()> {
+                $(::binary_io::data::Serializeable::serialize(&self.$field, output)?;)*
+                Ok(())
+            }
+        }
+
+        impl ::binary_io::data::Deserializeable for $name {
+            #[allow(unused_variables)]
+            fn deserialize<R: ::binary_io::data::DataInput + ?Sized>(
+                &mut self,
+                input: &mut R,
+            ) -> std::io::Result<()> {
+                $(self.$field = <$ty as ::binary_io::data::DeserializeCopy>::deserialize_copy(input)?;)*
+                Ok(())
+            }
+        }
+
+        impl ::binary_io::data::DeserializeCopy for $name {
+            #[allow(unused_variables)]
+            fn deserialize_copy<R: ::binary_io::data::DataInput + ?Sized>(
+                input: &mut R,
+            ) -> std::io::Result<Self> {
+                Ok(Self {
+                    $($field: <$ty as ::binary_io::data::DeserializeCopy>::deserialize_copy(input)?),*
+                })
+            }
+        }
+
+        impl $crate::packet::Packet for $name {
+            const ID: u16 = $id;
+            const DIRECTION: $crate::packet::Direction = $crate::packet::Direction::$direction;
+            $(const SINCE: ::binary_io::version::Version = $since;)?
+        }
+    };
+}
+
+///
+/// A string which is serialized with a 32-bit length, rather than the 16-bit length of an LCS 4 String
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
 pub struct LongString(pub String);
+
+impl Serializeable for LongString {
+    fn serialize<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        let len = self.0.len();
+        if len > (u32::MAX as usize) {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, OutOfRange(len)));
+        }
+        (len as u32).serialize(output)?;
+        output.write_bytes(self.0.as_bytes())
+    }
+}
+
+impl Deserializeable for LongString {
+    fn deserialize<R: DataInput + ?Sized>(&mut self, input: &mut R) -> std::io::Result<()> {
+        *self = Self::deserialize_copy(input)?;
+        Ok(())
+    }
+}
+
+impl DeserializeCopy for LongString {
+    fn deserialize_copy<R: DataInput + ?Sized>(input: &mut R) -> std::io::Result<Self> {
+        let len = u32::deserialize_copy(input)? as usize;
+        let mut bytes = Vec::with_capacity(len.min(4096));
+        (&mut *input).take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != len {
+            return Err(std::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "Unexpected EOF in LongString",
+            ));
+        }
+        String::from_utf8(bytes)
+            .map(Self)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+///
+/// A list which is serialized with a 16-bit length, followed by each element
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct List<T>(pub Vec<T>);
+
+impl<T> Default for List<T> {
+    fn default() -> Self {
+        Self(Vec::new())
+    }
+}
+
+impl<T> Deref for List<T> {
+    type Target = Vec<T>;
+    fn deref(&self) -> &Vec<T> {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for List<T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.0
+    }
+}
+
+impl<T> From<Vec<T>> for List<T> {
+    fn from(v: Vec<T>) -> Self {
+        Self(v)
+    }
+}
+
+impl<T> FromIterator<T> for List<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl<T: Serializeable> Serializeable for List<T> {
+    fn serialize<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        let len = self.0.len();
+        if len > (u16::MAX as usize) {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, OutOfRange(len)));
+        }
+        (len as u16).serialize(output)?;
+        self.0.serialize(output)
+    }
+}
+
+impl<T: DeserializeCopy> Deserializeable for List<T> {
+    fn deserialize<R: DataInput + ?Sized>(&mut self, input: &mut R) -> std::io::Result<()> {
+        *self = Self::deserialize_copy(input)?;
+        Ok(())
+    }
+}
+
+impl<T: DeserializeCopy> DeserializeCopy for List<T> {
+    fn deserialize_copy<R: DataInput + ?Sized>(input: &mut R) -> std::io::Result<Self> {
+        let len = u16::deserialize_copy(input)? as usize;
+        (0..len).map(|_| T::deserialize_copy(input)).collect()
+    }
+}
+
+///
+/// The direction a packet is sent in
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    ///
+    /// Sent from the client to the server
+    Serverbound,
+    ///
+    /// Sent from the server to the client
+    Clientbound,
+    ///
+    /// Sent in either direction
+    Bidirectional,
+}
+
+impl Direction {
+    ///
+    /// Checks if a packet with this direction may be sent in the given direction
+    pub fn allows(self, sent: Direction) -> bool {
+        self == Direction::Bidirectional || self == sent
+    }
+
+    ///
+    /// Returns the direction packets are received in, when they are sent in this direction
+    pub fn reverse(self) -> Direction {
+        match self {
+            Direction::Serverbound => Direction::Clientbound,
+            Direction::Clientbound => Direction::Serverbound,
+            Direction::Bidirectional => Direction::Bidirectional,
+        }
+    }
+}
+
+impl Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::Serverbound => f.write_str("serverbound"),
+            Direction::Clientbound => f.write_str("clientbound"),
+            Direction::Bidirectional => f.write_str("bidirectional"),
+        }
+    }
+}
+
+///
+/// A PkmCom packet type
+pub trait Packet: Serializeable + DeserializeCopy + Debug + Send + 'static {
+    ///
+    /// The numeric id of the packet, unique within a [`PacketRegistry`]
+    const ID: u16;
+    ///
+    /// The direction the packet may be sent in
+    const DIRECTION: Direction;
+    ///
+    /// The protocol version the packet was introduced in
+    const SINCE: Version = Version::V1_0;
+}
+
+///
+/// An object-safe view of a [`Packet`], used for packets whose type is only known at runtime
+pub trait AnyPacket: Debug + Send {
+    ///
+    /// Returns the numeric id of the packet
+    fn id(&self) -> u16;
+    ///
+    /// Returns the direction the packet may be sent in
+    fn direction(&self) -> Direction;
+    ///
+    /// Writes the fields of the packet, without the id
+    fn write_payload(&self, output: &mut dyn DataOutput) -> std::io::Result<()>;
+    ///
+    /// Converts the packet to Any, to allow downcasting to the concrete packet type
+    fn as_any(&self) -> &dyn Any;
+    ///
+    /// Converts the boxed packet to Any, to allow downcasting to the concrete packet type
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<P: Packet> AnyPacket for P {
+    fn id(&self) -> u16 {
+        P::ID
+    }
+
+    fn direction(&self) -> Direction {
+        P::DIRECTION
+    }
+
+    fn write_payload(&self, output: &mut dyn DataOutput) -> std::io::Result<()> {
+        self.serialize(output)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl<'a> dyn AnyPacket + 'a {
+    ///
+    /// Checks if the packet has type P
+    pub fn is<P: Packet>(&self) -> bool {
+        self.as_any().is::<P>()
+    }
+
+    ///
+    /// Returns a reference to the packet as type P, or None if it has a different type
+    pub fn downcast_ref<P: Packet>(&self) -> Option<&P> {
+        self.as_any().downcast_ref()
+    }
+}
+
+impl dyn AnyPacket {
+    ///
+    /// Converts the packet into type P, or returns it unchanged if it has a different type
+    pub fn downcast<P: Packet>(self: Box<Self>) -> Result<Box<P>, Box<dyn AnyPacket>> {
+        if self.is::<P>() {
+            Ok(self.into_any().downcast().unwrap())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+///
+/// A packet to be sent to a client, by the UUID of its player
+pub type Outgoing = (UUID, Box<dyn AnyPacket>);
+
+///
+/// Writes the packet id followed by the packet
+pub fn write_packet<W: DataOutput + ?Sized>(
+    packet: &dyn AnyPacket,
+    output: &mut W,
+) -> std::io::Result<()> {
+    packet.id().serialize(output)?;
+    let mut output = output;
+    packet.write_payload(&mut output)
+}
+
+///
+/// Information about a packet type registered in a [`PacketRegistry`]
+#[derive(Clone, Debug)]
+pub struct PacketInfo {
+    id: u16,
+    name: &'static str,
+    direction: Direction,
+    since: Version,
+    decode: fn(&mut dyn DataInput) -> std::io::Result<Box<dyn AnyPacket>>,
+}
+
+impl PacketInfo {
+    ///
+    /// Returns the id of the packet
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    ///
+    /// Returns the name of the packet type
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    ///
+    /// Returns the direction the packet may be sent in
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    ///
+    /// Returns the protocol version the packet was introduced in
+    pub fn since(&self) -> Version {
+        self.since
+    }
+
+    ///
+    /// Decodes the fields of the packet
+    pub fn decode(&self, input: &mut dyn DataInput) -> std::io::Result<Box<dyn AnyPacket>> {
+        (self.decode)(input)
+    }
+}
+
+fn decode_packet<P: Packet>(input: &mut dyn DataInput) -> std::io::Result<Box<dyn AnyPacket>> {
+    Ok(Box::new(P::deserialize_copy(input)?))
+}
+
+///
+/// The Error returned when a packet cannot be decoded by a [`PacketRegistry`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PacketError {
+    ///
+    /// No packet is registered with the id
+    UnknownId(u16),
+    ///
+    /// The packet may not be sent in the direction it was received in
+    WrongDirection(u16, Direction),
+    ///
+    /// The packet does not exist in the negotiated protocol version
+    Unsupported(u16, Version),
+}
+
+impl Display for PacketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PacketError::UnknownId(id) => {
+                f.write_fmt(format_args!("Unknown packet id {:#06x}", id))
+            }
+            PacketError::WrongDirection(id, dir) => {
+                f.write_fmt(format_args!("Packet {:#06x} cannot be sent {}", id, dir))
+            }
+            PacketError::Unsupported(id, version) => f.write_fmt(format_args!(
+                "Packet {:#06x} is not supported in protocol version {}",
+                id, version
+            )),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+///
+/// A mapping from packet ids to decoders
+#[derive(Clone, Debug)]
+pub struct PacketRegistry {
+    packets: HashMap<u16, PacketInfo>,
+    version: Version,
+}
+
+impl Default for PacketRegistry {
+    fn default() -> Self {
+        Self::pkmcom()
+    }
+}
+
+impl PacketRegistry {
+    ///
+    /// Creates a new registry with no packets, which decodes packets from the given protocol version
+    pub fn new(version: Version) -> Self {
+        Self {
+            packets: HashMap::new(),
+            version,
+        }
+    }
+
+    ///
+    /// Creates a new registry with the base PkmCom packets, for the current protocol version
+    pub fn pkmcom() -> Self {
+        let mut registry = Self::new(PROTOCOL_VERSION);
+        registry.register::<Handshake>();
+        registry.register::<KeepAlive>();
+        registry.register::<KeepAliveReply>();
+        registry.register::<Disconnect>();
+        registry.register::<LoginAccept>();
+        registry.register::<LoginReject>();
+        registry.register::<ContentSyncRequest>();
+        registry.register::<ContentReport>();
+        registry.register::<DigestRequest>();
+        registry.register::<DomainDigests>();
+        registry.register::<Resume>();
+        registry.register::<ResumeAccept>();
+        registry.register::<Ack>();
+        registry.register::<StatusRequest>();
+        registry.register::<StatusResponse>();
+        registry.register::<StatusPing>();
+        registry.register::<ChatMessage>();
+        registry.register::<ChatBroadcast>();
+        registry.register::<ChatWhisper>();
+        registry.register::<PartyChat>();
+        registry.register::<ChatBlock>();
+        registry.register::<ChatUnblock>();
+        registry.register::<RegistrySyncStart>();
+        registry.register::<RegistryEntries>();
+        registry.register::<RegistrySyncEnd>();
+        registry.register::<RegistrySyncAck>();
+        registry.register::<TradeOffer>();
+        registry.register::<TradeCounterOffer>();
+        registry.register::<TradeLock>();
+        registry.register::<TradeConfirm>();
+        registry.register::<TradeCommit>();
+        registry.register::<TradeAbort>();
+        registry.register::<BattleChallenge>();
+        registry.register::<BattleAccept>();
+        registry.register::<TeamPreview>();
+        registry.register::<ActionSubmit>();
+        registry.register::<TurnResult>();
+        registry.register::<BattleForfeit>();
+        registry.register::<BattleEnd>();
+        registry.register::<BattleSpectate>();
+        registry
+    }
+
+    ///
+    /// Returns the protocol version packets are decoded for
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    ///
+    /// Sets the protocol version packets are decoded for, after it has been negotiated
+    pub fn set_version(&mut self, version: Version) {
+        self.version = version
+    }
+
+    ///
+    /// Registers the packet type P.
+    /// Panics if a different packet type is already registered with the same id
+    pub fn register<P: Packet>(&mut self) {
+        let name = std::any::type_name::<P>();
+        if let Some(existing) = self.packets.get(&P::ID) {
+            if existing.name != name {
+                panic!(
+                    "Packet id {:#06x} is already registered to {}",
+                    P::ID,
+                    existing.name
+                )
+            }
+        }
+        self.packets.insert(
+            P::ID,
+            PacketInfo {
+                id: P::ID,
+                name,
+                direction: P::DIRECTION,
+                since: P::SINCE,
+                decode: decode_packet::<P>,
+            },
+        );
+    }
+
+    ///
+    /// Returns information about the packet with the given id
+    pub fn get(&self, id: u16) -> Option<&PacketInfo> {
+        self.packets.get(&id)
+    }
+
+    ///
+    /// Returns an iterator over all registered packets, in an unspecified order
+    pub fn iter(&self) -> impl Iterator<Item = &PacketInfo> {
+        self.packets.values()
+    }
+
+    ///
+    /// Checks that a packet with the given id may be received in the given direction, and returns information about it
+    pub fn check(&self, id: u16, direction: Direction) -> Result<&PacketInfo, PacketError> {
+        let info = self.get(id).ok_or(PacketError::UnknownId(id))?;
+        if !info.direction.allows(direction) {
+            Err(PacketError::WrongDirection(id, direction))
+        } else if self.version < info.since {
+            Err(PacketError::Unsupported(id, self.version))
+        } else {
+            Ok(info)
+        }
+    }
+
+    ///
+    /// Decodes the fields of a packet with the given id, which was sent in the given direction
+    pub fn decode<R: DataInput + ?Sized>(
+        &self,
+        id: u16,
+        direction: Direction,
+        input: &mut R,
+    ) -> std::io::Result<Box<dyn AnyPacket>> {
+        let info = self
+            .check(id, direction)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        let mut input = input;
+        info.decode(&mut input)
+    }
+
+    ///
+    /// Reads a packet id followed by the packet, which was sent in the given direction
+    pub fn read_packet<R: DataInput + ?Sized>(
+        &self,
+        direction: Direction,
+        input: &mut R,
+    ) -> std::io::Result<Box<dyn AnyPacket>> {
+        let id = u16::deserialize_copy(input)?;
+        self.decode(id, direction, input)
+    }
+}
+
+packet! {
+    ///
+    /// Sent periodically to check that the connection is still alive.
+    /// The receiver replies with a [`KeepAliveReply`] with the same nonce
+    pub struct KeepAlive(0x0001, Bidirectional) {
+        ///
+        /// An arbitrary value, echoed by the receiver
+        pub nonce: u64,
+    }
+}
+
+packet! {
+    ///
+    /// Sent in reply to a [`KeepAlive`]. It is not answered
+    pub struct KeepAliveReply(0x000F, Bidirectional) {
+        ///
+        /// The nonce of the KeepAlive
+        pub nonce: u64,
+    }
+}
+
+packet! {
+    ///
+    /// Sent before closing the connection
+    pub struct Disconnect(0x0002, Bidirectional) {
+        ///
+        /// The reason the connection was closed, to be shown to the player
+        pub reason: TextComponent,
+    }
+}
+
+packet! {
+    ///
+    /// A chat message sent by a player
+    pub struct ChatMessage(0x0010, Serverbound) {
+        ///
+        /// The text typed by the player
+        pub message: LongString,
+    }
+}
+
+packet! {
+    ///
+    /// A chat message to be displayed by the client
+    pub struct ChatBroadcast(0x0011, Clientbound) {
+        ///
+        /// The UUID of the player who sent the message, or the NIL UUID for server messages
+        pub sender: UUID,
+        ///
+        /// The formatted message
+        pub message: TextComponent,
+    }
+}
@@ -0,0 +1,610 @@
+//!
+//! Encrypted and authenticated PkmCom sessions.
+//!
+//! A [`SecureTransport`] wraps any [`Transport`], and encrypts everything sent over it.
+//! Before any PkmCom packets are exchanged, the two sides perform a key exchange over the wrapped transport:
+//! * The client sends a hello, containing [`SECURE_MAGIC`], the version of the secure layer, and an ephemeral X25519 public key.
+//! * The server answers with a hello containing its own ephemeral public key, the public key of its long-term [`Identity`],
+//!   and an Ed25519 signature by that identity over both ephemeral keys.
+//! * The client checks the signature, and that the server's identity is one of the keys it pins, if it pins any.
+//!
+//! Each side then derives a key for each direction from the shared secret with HKDF-SHA256.
+//! Data is sent in records, each containing a u32 length, a u64 sequence number, and the data encrypted with ChaCha20-Poly1305.
+//! Sequence numbers start at 0 in each direction and increase by 1 for every record,
+//!  so a record which is replayed, reordered, or dropped is detected by the receiver, and the session fails.
+
+use std::{
+    fmt::{Debug, Display},
+    io::{ErrorKind, Read, Write},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use binary_io::data::{
+    ByteOrder, DataInput, DataInputStream, DataOutput, DataOutputStream, DeserializeCopy,
+    Deserializeable, Serializeable,
+};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use sha2::{Digest as _, Sha256};
+use x25519_dalek::EphemeralSecret;
+
+use crate::connection::{Side, Transport};
+
+///
+/// The magic number at the start of both hellos
+pub const SECURE_MAGIC: [u8; 4] = *b"PKSC";
+
+///
+/// The version of the secure layer
+pub const SECURE_VERSION: u8 = 1;
+
+///
+/// The maximum size of the data in a single record. Larger writes are split into multiple records
+pub const MAX_RECORD_SIZE: usize = 16 * 1024;
+
+// The size of the Poly1305 tag appended to each record
+const TAG_SIZE: usize = 16;
+
+// The size of the length and sequence number before each record
+const HEADER_SIZE: usize = 12;
+
+const SIGNATURE_CONTEXT: &[u8] = b"PkmCom secure session v1";
+
+///
+/// The Error returned when a secure session cannot be established, or a record cannot be received
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SecureError {
+    ///
+    /// The peer's hello does not start with [`SECURE_MAGIC`]
+    BadMagic,
+    ///
+    /// The peer uses an unsupported version of the secure layer
+    UnsupportedVersion(u8),
+    ///
+    /// The server's signature over the key exchange is not valid
+    BadSignature,
+    ///
+    /// The server's identity is not one of the pinned keys
+    UntrustedKey(PublicKey),
+    ///
+    /// The key exchange produced a weak shared secret, because the peer sent a low-order point
+    WeakKey,
+    ///
+    /// A record could not be decrypted, because it was modified or encrypted with a different key
+    Decrypt,
+    ///
+    /// A record was received out of sequence, because it was replayed, reordered, or a previous record was dropped
+    OutOfSequence {
+        ///
+        /// The sequence number of the next record
+        expected: u64,
+        ///
+        /// The sequence number of the received record
+        received: u64,
+    },
+    ///
+    /// A record exceeds the maximum size
+    Oversized(usize),
+    ///
+    /// Every sequence number has been used, so no more records can be sent
+    Exhausted,
+}
+
+impl Display for SecureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecureError::BadMagic => f.write_str("Invalid magic (not a PkmCom secure session)"),
+            SecureError::UnsupportedVersion(v) => {
+                f.write_fmt(format_args!("Unsupported secure session version {}", v))
+            }
+            SecureError::BadSignature => f.write_str("Invalid signature from the server"),
+            SecureError::UntrustedKey(key) => {
+                f.write_fmt(format_args!("Server key {} is not trusted", key))
+            }
+            SecureError::WeakKey => f.write_str("Key exchange produced a weak shared secret"),
+            SecureError::Decrypt => f.write_str("Failed to decrypt record"),
+            SecureError::OutOfSequence { expected, received } => f.write_fmt(format_args!(
+                "Received record {}, expected record {}",
+                received, expected
+            )),
+            SecureError::Oversized(size) => f.write_fmt(format_args!(
+                "Record of {} bytes exceeds the maximum size",
+                size
+            )),
+            SecureError::Exhausted => f.write_str("Sequence numbers exhausted"),
+        }
+    }
+}
+
+impl std::error::Error for SecureError {}
+
+impl From<SecureError> for std::io::Error {
+    fn from(e: SecureError) -> Self {
+        let kind = match e {
+            SecureError::UntrustedKey(_) | SecureError::BadSignature => ErrorKind::PermissionDenied,
+            _ => ErrorKind::InvalidData,
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+
+///
+/// The public key of a server's [`Identity`], which clients may pin
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct PublicKey(pub [u8; 32]);
+
+impl PublicKey {
+    ///
+    /// Returns the bytes of the key
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for b in &self.0 {
+            f.write_fmt(format_args!("{:02x}", b))?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("PublicKey({})", self))
+    }
+}
+
+impl Serializeable for PublicKey {
+    fn serialize<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        self.0.serialize(output)
+    }
+}
+
+impl Deserializeable for PublicKey {
+    fn deserialize<R: DataInput + ?Sized>(&mut self, input: &mut R) -> std::io::Result<()> {
+        self.0.deserialize(input)
+    }
+}
+
+impl DeserializeCopy for PublicKey {
+    fn deserialize_copy<R: DataInput + ?Sized>(input: &mut R) -> std::io::Result<Self> {
+        Ok(Self(<[u8; 32]>::deserialize_copy(input)?))
+    }
+}
+
+///
+/// The long-term identity of a server, which signs the key exchange of each session
+#[derive(Clone)]
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Identity")
+            .field("public_key", &self.public_key())
+            .finish()
+    }
+}
+
+impl Identity {
+    ///
+    /// Generates a new random identity
+    pub fn generate() -> Self {
+        Self {
+            key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    ///
+    /// Creates an identity from its secret key
+    pub fn from_bytes(secret: &[u8; 32]) -> Self {
+        Self {
+            key: SigningKey::from_bytes(secret),
+        }
+    }
+
+    ///
+    /// Returns the secret key of the identity, which should be stored securely
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.key.to_bytes()
+    }
+
+    ///
+    /// Returns the public key of the identity
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.key.verifying_key().to_bytes())
+    }
+}
+
+fn check_hello<R: DataInput + ?Sized>(input: &mut R) -> std::io::Result<()> {
+    if <[u8; 4]>::deserialize_copy(input)? != SECURE_MAGIC {
+        return Err(SecureError::BadMagic.into());
+    }
+    let version = u8::deserialize_copy(input)?;
+    if version != SECURE_VERSION {
+        return Err(SecureError::UnsupportedVersion(version).into());
+    }
+    Ok(())
+}
+
+fn transcript(client: &[u8; 32], server: &[u8; 32], identity: &PublicKey) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(SIGNATURE_CONTEXT);
+    hasher.update(client);
+    hasher.update(server);
+    hasher.update(identity.0);
+    hasher.finalize().into()
+}
+
+// Derives the keys for each direction as (client to server, server to client)
+fn derive_keys(shared: &[u8; 32], transcript: &[u8; 32]) -> (ChaCha20Poly1305, ChaCha20Poly1305) {
+    let hkdf = Hkdf::<Sha256>::new(Some(transcript), shared);
+    let mut serverbound = [0u8; 32];
+    let mut clientbound = [0u8; 32];
+    hkdf.expand(b"pkmcom serverbound", &mut serverbound)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    hkdf.expand(b"pkmcom clientbound", &mut clientbound)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    (
+        ChaCha20Poly1305::new(&serverbound.into()),
+        ChaCha20Poly1305::new(&clientbound.into()),
+    )
+}
+
+fn nonce(seq: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&seq.to_be_bytes());
+    nonce.into()
+}
+
+struct WriteState {
+    cipher: ChaCha20Poly1305,
+    seq: u64,
+}
+
+impl WriteState {
+    fn seal(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        if self.seq == u64::MAX {
+            return Err(SecureError::Exhausted.into());
+        }
+        let seq = self.seq;
+        self.seq += 1;
+        let mut header = [0u8; HEADER_SIZE];
+        header[..4].copy_from_slice(&((data.len() + TAG_SIZE) as u32).to_be_bytes());
+        header[4..].copy_from_slice(&seq.to_be_bytes());
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce(seq),
+                chacha20poly1305::aead::Payload {
+                    msg: data,
+                    aad: &header,
+                },
+            )
+            .map_err(|_| std::io::Error::other("Failed to encrypt record"))?;
+        let mut record = Vec::with_capacity(HEADER_SIZE + ciphertext.len());
+        record.extend_from_slice(&header);
+        record.extend_from_slice(&ciphertext);
+        Ok(record)
+    }
+}
+
+struct ReadState {
+    cipher: ChaCha20Poly1305,
+    seq: u64,
+    // Bytes of records which have been received, but not yet decrypted
+    raw: Vec<u8>,
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl ReadState {
+    // Decrypts the first record in raw, if it has been received completely
+    fn open(&mut self) -> std::io::Result<bool> {
+        if self.raw.len() < HEADER_SIZE {
+            return Ok(false);
+        }
+        let len = u32::from_be_bytes([self.raw[0], self.raw[1], self.raw[2], self.raw[3]]) as usize;
+        if !(TAG_SIZE..=MAX_RECORD_SIZE + TAG_SIZE).contains(&len) {
+            return Err(SecureError::Oversized(len).into());
+        }
+        if self.raw.len() < HEADER_SIZE + len {
+            return Ok(false);
+        }
+        let mut seq = [0u8; 8];
+        seq.copy_from_slice(&self.raw[4..HEADER_SIZE]);
+        let seq = u64::from_be_bytes(seq);
+        if seq != self.seq {
+            return Err(SecureError::OutOfSequence {
+                expected: self.seq,
+                received: seq,
+            }
+            .into());
+        }
+        let plain = self
+            .cipher
+            .decrypt(
+                &nonce(seq),
+                chacha20poly1305::aead::Payload {
+                    msg: &self.raw[HEADER_SIZE..HEADER_SIZE + len],
+                    aad: &self.raw[..HEADER_SIZE],
+                },
+            )
+            .map_err(|_| SecureError::Decrypt)?;
+        self.seq += 1;
+        self.raw.drain(..HEADER_SIZE + len);
+        self.plain = plain;
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+///
+/// A transport which encrypts and authenticates everything sent over another transport
+pub struct SecureTransport<T> {
+    inner: T,
+    side: Side,
+    peer_key: Option<PublicKey>,
+    writer: Arc<Mutex<WriteState>>,
+    reader: Arc<Mutex<ReadState>>,
+}
+
+impl<T> Debug for SecureTransport<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecureTransport")
+            .field("side", &self.side)
+            .field("peer_key", &self.peer_key)
+            .finish()
+    }
+}
+
+impl<T: Transport> SecureTransport<T> {
+    fn new(
+        inner: T,
+        side: Side,
+        peer_key: Option<PublicKey>,
+        send: ChaCha20Poly1305,
+        receive: ChaCha20Poly1305,
+    ) -> Self {
+        Self {
+            inner,
+            side,
+            peer_key,
+            writer: Arc::new(Mutex::new(WriteState {
+                cipher: send,
+                seq: 0,
+            })),
+            reader: Arc::new(Mutex::new(ReadState {
+                cipher: receive,
+                seq: 0,
+                raw: Vec::new(),
+                plain: Vec::new(),
+                pos: 0,
+            })),
+        }
+    }
+
+    ///
+    /// Performs the client side of the key exchange over transport.
+    /// If `pins` is not empty, the server's identity must be one of the keys in it.
+    /// Otherwise any server is accepted, and its key can be checked with [`SecureTransport::peer_key`]
+    pub fn connect(mut transport: T, pins: &[PublicKey]) -> std::io::Result<Self> {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral = x25519_dalek::PublicKey::from(&secret);
+
+        let mut hello = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+        SECURE_MAGIC.serialize(&mut hello)?;
+        SECURE_VERSION.serialize(&mut hello)?;
+        ephemeral.as_bytes().serialize(&mut hello)?;
+        transport.write_all(&hello.into_inner())?;
+        transport.flush()?;
+
+        let mut input = DataInputStream::new(&mut transport, ByteOrder::BigEndian);
+        check_hello(&mut input)?;
+        let server = <[u8; 32]>::deserialize_copy(&mut input)?;
+        let identity = PublicKey::deserialize_copy(&mut input)?;
+        let signature = <[u8; 64]>::deserialize_copy(&mut input)?;
+
+        let transcript = transcript(ephemeral.as_bytes(), &server, &identity);
+        let key = VerifyingKey::from_bytes(&identity.0).map_err(|_| SecureError::BadSignature)?;
+        key.verify(&transcript, &Signature::from_bytes(&signature))
+            .map_err(|_| SecureError::BadSignature)?;
+        if !pins.is_empty() && !pins.contains(&identity) {
+            return Err(SecureError::UntrustedKey(identity).into());
+        }
+
+        let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(server));
+        if !shared.was_contributory() {
+            return Err(SecureError::WeakKey.into());
+        }
+        let (serverbound, clientbound) = derive_keys(shared.as_bytes(), &transcript);
+        Ok(Self::new(
+            transport,
+            Side::Client,
+            Some(identity),
+            serverbound,
+            clientbound,
+        ))
+    }
+
+    ///
+    /// Performs the server side of the key exchange over transport, signing it with identity
+    pub fn accept(transport: T, identity: &Identity) -> std::io::Result<Self> {
+        Self::accept_before(transport, identity, None)
+    }
+
+    ///
+    /// Performs the server side of the key exchange like [`SecureTransport::accept`],
+    ///  but fails with an error of kind TimedOut if the client has not sent its hello within timeout.
+    /// The timeout bounds the whole hello, so a client cannot extend it by sending a byte at a time
+    pub fn accept_within(
+        transport: T,
+        identity: &Identity,
+        timeout: Duration,
+    ) -> std::io::Result<Self> {
+        Self::accept_before(transport, identity, Some(Instant::now() + timeout))
+    }
+
+    fn accept_before(
+        mut transport: T,
+        identity: &Identity,
+        deadline: Option<Instant>,
+    ) -> std::io::Result<Self> {
+        let reader = Before {
+            transport: &mut transport,
+            deadline,
+        };
+        let mut input = DataInputStream::new(reader, ByteOrder::BigEndian);
+        check_hello(&mut input)?;
+        let client = <[u8; 32]>::deserialize_copy(&mut input)?;
+
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral = x25519_dalek::PublicKey::from(&secret);
+        let public = identity.public_key();
+        let transcript = transcript(&client, ephemeral.as_bytes(), &public);
+        let signature = identity.key.sign(&transcript);
+
+        let mut hello = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+        SECURE_MAGIC.serialize(&mut hello)?;
+        SECURE_VERSION.serialize(&mut hello)?;
+        ephemeral.as_bytes().serialize(&mut hello)?;
+        public.serialize(&mut hello)?;
+        signature.to_bytes().serialize(&mut hello)?;
+        transport.write_all(&hello.into_inner())?;
+        transport.flush()?;
+
+        let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(client));
+        if !shared.was_contributory() {
+            return Err(SecureError::WeakKey.into());
+        }
+        let (serverbound, clientbound) = derive_keys(shared.as_bytes(), &transcript);
+        Ok(Self::new(
+            transport,
+            Side::Server,
+            None,
+            clientbound,
+            serverbound,
+        ))
+    }
+
+    ///
+    /// Returns the side of the session
+    pub fn side(&self) -> Side {
+        self.side
+    }
+
+    ///
+    /// Returns the public key of the server's identity, on the client side of the session
+    pub fn peer_key(&self) -> Option<PublicKey> {
+        self.peer_key
+    }
+
+    ///
+    /// Returns the wrapped transport
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+}
+
+// Reads from a transport, giving each read only the time left before the deadline
+struct Before<'a, T> {
+    transport: &'a mut T,
+    deadline: Option<Instant>,
+}
+
+impl<T: Transport> Read for Before<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            self.transport.set_read_timeout(Some(remaining))?;
+        }
+        self.transport.read(buf)
+    }
+}
+
+impl<T: Transport> Read for SecureTransport<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut reader = self.reader.lock().unwrap();
+        loop {
+            if reader.pos < reader.plain.len() {
+                let pos = reader.pos;
+                let len = buf.len().min(reader.plain.len() - pos);
+                buf[..len].copy_from_slice(&reader.plain[pos..pos + len]);
+                reader.pos += len;
+                return Ok(len);
+            }
+            if reader.open()? {
+                continue;
+            }
+            // A timeout leaves any partial record in raw, to be completed by the next read
+            let mut chunk = [0u8; 4096];
+            let len = self.inner.read(&mut chunk)?;
+            if len == 0 {
+                return if reader.raw.is_empty() {
+                    Ok(0)
+                } else {
+                    Err(std::io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Connection closed in the middle of a record",
+                    ))
+                };
+            }
+            reader.raw.extend_from_slice(&chunk[..len]);
+        }
+    }
+}
+
+impl<T: Transport> Write for SecureTransport<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut writer = self.writer.lock().unwrap();
+        for chunk in buf.chunks(MAX_RECORD_SIZE) {
+            let record = writer.seal(chunk)?;
+            self.inner.write_all(&record)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Transport> Transport for SecureTransport<T> {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(Self {
+            inner: self.inner.try_clone()?,
+            side: self.side,
+            peer_key: self.peer_key,
+            writer: self.writer.clone(),
+            reader: self.reader.clone(),
+        })
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn shutdown(&self) -> std::io::Result<()> {
+        self.inner.shutdown()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr()
+    }
+}
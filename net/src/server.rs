@@ -0,0 +1,833 @@
+//!
+//! The server side of PkmCom, independent of the transport.
+//!
+//! A [`Server`] runs the login handshake on each connection it is given, and then dispatches
+//!  the packets received from each client to a [`Handler`], until the client disconnects or times out.
+//! Each connection is served on its own thread, by [`Server::serve`] or [`Server::spawn`].
+//! Connections which ask for the server's [status](crate::status) are answered without logging in.
+//!
+//! If the server gives clients sessions, a client whose connection is lost is not disconnected until its session expires.
+//! Until then, it remains a [`Peer`], packets sent to it are kept, and it may resume its session on a new connection.
+//!
+//! Clients which send packets faster than the server's [limits](crate::limit), open too many connections, or take too long to log in are kicked.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use binary_io::{uuid::UUID, version::Version};
+use text::TextComponent;
+
+use crate::{
+    capture::Capture,
+    connection::{Connection, Sender, Side, Transport},
+    digest::ContentSummary,
+    frame::{FrameCodec, DEFAULT_COMPRESSION_THRESHOLD, DEFAULT_MAX_FRAME_SIZE},
+    handshake::{ContentHash, LoginReject, LoginResponse, ServerHandshake, State},
+    limit::{Clock, Connections, RateLimiter, RateLimits, SystemClock, Violation, ViolationKind},
+    metrics::{DisconnectReason, Metrics},
+    packet::{AnyPacket, Disconnect, List, PacketRegistry, PROTOCOL_VERSION},
+    session::{Resume, ResumeAccept, SessionError},
+    status::{StatusPing, StatusRequest, StatusResponse, MAX_SAMPLE},
+};
+
+#[cfg(feature = "secure")]
+use crate::secure::{Identity, SecureTransport};
+
+///
+/// The configuration of a [`Server`]
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    ///
+    /// The message of the day, sent to clients which ask for the server's status
+    pub motd: TextComponent,
+    ///
+    /// The most clients which may be connected at once
+    pub max_players: u32,
+    ///
+    /// The hashes of the resource domains loaded by the server, which clients must match
+    pub content: Vec<ContentHash>,
+    ///
+    /// The summary of the server's content, used to find the entries which differ on clients with mismatched content
+    pub summary: Option<ContentSummary>,
+    ///
+    /// Whether clients with mismatched content are asked to synchronize it, rather than being rejected
+    pub allow_sync: bool,
+    ///
+    /// The oldest protocol version accepted
+    pub min_version: Version,
+    ///
+    /// The newest protocol version accepted
+    pub max_version: Version,
+    ///
+    /// The time a client has to complete the handshake
+    pub handshake_timeout: Duration,
+    ///
+    /// The interval between calls to [`Handler::tick`], once the server is ticking
+    pub tick_interval: Duration,
+    ///
+    /// The limits on the packets and connections of each client
+    pub limits: RateLimits,
+    ///
+    /// The interval between KeepAlives sent to idle clients
+    pub keepalive_interval: Duration,
+    ///
+    /// The time a client may be idle, including not answering KeepAlives, before it is disconnected
+    pub timeout: Duration,
+    ///
+    /// The maximum size of a frame received from a client, and of a packet once decompressed
+    pub max_frame_size: usize,
+    ///
+    /// The size at which packets are compressed, for clients which support compression, or None to disable compression
+    pub compression_threshold: Option<usize>,
+    ///
+    /// A directory in which a capture of each connection is written, named by the time it was accepted and a counter
+    pub capture_dir: Option<PathBuf>,
+    ///
+    /// How long the session of a client which supports resumption is kept after its connection is lost, or None to not give clients sessions
+    pub session_grace: Option<Duration>,
+    ///
+    /// The metrics which count the traffic of every connection, if any
+    pub metrics: Option<Metrics>,
+    ///
+    /// The identity of the server. If set, every connection is wrapped in an encrypted session signed by it
+    #[cfg(feature = "secure")]
+    pub identity: Option<Identity>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            motd: TextComponent::RawText("A PokemonSMS Server".to_string()),
+            max_players: 100,
+            content: Vec::new(),
+            summary: None,
+            allow_sync: false,
+            min_version: PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            handshake_timeout: Duration::from_secs(10),
+            tick_interval: Duration::from_secs(1),
+            limits: RateLimits::default(),
+            keepalive_interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            compression_threshold: Some(DEFAULT_COMPRESSION_THRESHOLD),
+            capture_dir: None,
+            session_grace: None,
+            metrics: None,
+            #[cfg(feature = "secure")]
+            identity: None,
+        }
+    }
+}
+
+///
+/// A client which has completed the handshake
+#[derive(Clone, Debug)]
+pub struct Peer {
+    client: UUID,
+    version: Version,
+    sender: Sender,
+    address: Option<SocketAddr>,
+}
+
+impl Peer {
+    pub(crate) fn new(
+        client: UUID,
+        version: Version,
+        sender: Sender,
+        address: Option<SocketAddr>,
+    ) -> Self {
+        Self {
+            client,
+            version,
+            sender,
+            address,
+        }
+    }
+
+    ///
+    /// Returns the UUID of the client's player
+    pub fn client(&self) -> UUID {
+        self.client
+    }
+
+    ///
+    /// Returns the negotiated protocol version
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    ///
+    /// Returns the address the client connected from, if the transport has one
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.address
+    }
+
+    ///
+    /// Returns the handle used to send packets to the client
+    pub fn sender(&self) -> &Sender {
+        &self.sender
+    }
+
+    ///
+    /// Sends a packet to the client
+    pub fn send(&self, packet: &dyn AnyPacket) -> std::io::Result<()> {
+        self.sender.send(packet)
+    }
+
+    ///
+    /// Disconnects the client, with the given reason
+    pub fn kick(&self, reason: TextComponent) -> std::io::Result<()> {
+        self.sender.disconnect(reason)
+    }
+}
+
+///
+/// Handles events on the connections of a [`Server`].
+/// Handlers are shared between all connections, and are called from the thread serving each connection
+pub trait Handler: Send + Sync + 'static {
+    ///
+    /// Called when a client completes the handshake.
+    /// If an error is returned, the client is disconnected
+    fn connected(&self, peer: &Peer) -> std::io::Result<()> {
+        let _ = peer;
+        Ok(())
+    }
+
+    ///
+    /// Called for each packet received from a client after the handshake, other than KeepAlive and Disconnect.
+    /// If an error is returned, the client is disconnected
+    fn packet(&self, peer: &Peer, packet: Box<dyn AnyPacket>) -> std::io::Result<()>;
+
+    ///
+    /// Called when a client which completed the handshake disconnects, or when its session expires after its connection was lost.
+    /// `error` is the error which caused the disconnect, or None if the client disconnected cleanly
+    fn disconnected(&self, peer: &Peer, error: Option<&std::io::Error>) {
+        let _ = (peer, error);
+    }
+
+    ///
+    /// Called when a client asks for the server's status, to fill in the sample of player names,
+    ///  or change any other part of the response. At most [`MAX_SAMPLE`] names are sent
+    fn status(&self, status: &mut StatusResponse) {
+        let _ = status;
+    }
+
+    ///
+    /// Called when a client violates one of the server's limits, before it is kicked with [`Violation::reason`]
+    fn violation(&self, violation: &Violation) {
+        let _ = violation;
+    }
+
+    ///
+    /// Called every [`ServerConfig::tick_interval`] while the server is ticking,
+    ///  to handle timeouts which do not depend on packets being received
+    fn tick(&self) {}
+}
+
+impl<H: Handler + ?Sized> Handler for Arc<H> {
+    fn connected(&self, peer: &Peer) -> std::io::Result<()> {
+        H::connected(self, peer)
+    }
+
+    fn packet(&self, peer: &Peer, packet: Box<dyn AnyPacket>) -> std::io::Result<()> {
+        H::packet(self, peer, packet)
+    }
+
+    fn disconnected(&self, peer: &Peer, error: Option<&std::io::Error>) {
+        H::disconnected(self, peer, error)
+    }
+
+    fn status(&self, status: &mut StatusResponse) {
+        H::status(self, status)
+    }
+
+    fn violation(&self, violation: &Violation) {
+        H::violation(self, violation)
+    }
+
+    fn tick(&self) {
+        H::tick(self)
+    }
+}
+
+///
+/// A PkmCom server, which serves connections over any transport
+pub struct Server<H> {
+    config: ServerConfig,
+    registry: PacketRegistry,
+    handler: H,
+    peers: Mutex<HashMap<UUID, Peer>>,
+    joining: Mutex<HashSet<UUID>>,
+    shutdown: AtomicBool,
+    captures: AtomicU64,
+    clock: Arc<dyn Clock>,
+    connections: Connections,
+}
+
+impl<H: Handler> Server<H> {
+    ///
+    /// Creates a server with the given configuration, which decodes packets with registry, and dispatches them to handler
+    pub fn new(config: ServerConfig, registry: PacketRegistry, handler: H) -> Self {
+        Self {
+            config,
+            registry,
+            handler,
+            peers: Mutex::new(HashMap::new()),
+            joining: Mutex::new(HashSet::new()),
+            shutdown: AtomicBool::new(false),
+            captures: AtomicU64::new(0),
+            clock: Arc::new(SystemClock),
+            connections: Connections::default(),
+        }
+    }
+
+    ///
+    /// Sets the clock the server's limits are measured on, which is the system clock by default
+    pub fn set_clock<C: Clock>(&mut self, clock: C) {
+        self.clock = Arc::new(clock);
+    }
+
+    ///
+    /// Returns the configuration of the server
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    ///
+    /// Returns the handler of the server
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    ///
+    /// Returns the clients which have completed the handshake and are still connected,
+    ///  including those whose connection was lost and whose session has not yet expired
+    pub fn peers(&self) -> Vec<Peer> {
+        self.peers.lock().unwrap().values().cloned().collect()
+    }
+
+    ///
+    /// Returns the connected client with the given player UUID
+    pub fn peer(&self, client: UUID) -> Option<Peer> {
+        self.peers.lock().unwrap().get(&client).cloned()
+    }
+
+    ///
+    /// Sends a packet to every connected client.
+    /// Clients which cannot be sent to are skipped
+    pub fn broadcast(&self, packet: &dyn AnyPacket) {
+        for peer in self.peers() {
+            let _ = peer.send(packet);
+        }
+    }
+
+    ///
+    /// Returns the status sent to clients which ask for it
+    pub fn status(&self) -> StatusResponse {
+        let mut status = StatusResponse {
+            motd: self.config.motd.clone(),
+            protocol: self.config.max_version,
+            max_players: self.config.max_players,
+            online_players: self.peers.lock().unwrap().len() as u32,
+            sample: List(Vec::new()),
+            content: self.config.content.iter().cloned().collect(),
+        };
+        self.handler.status(&mut status);
+        status.sample.truncate(MAX_SAMPLE);
+        status
+    }
+
+    ///
+    /// Calls the handler's [`Handler::tick`]
+    pub fn tick(&self) {
+        self.handler.tick()
+    }
+
+    ///
+    /// Checks if the server has been shut down
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::Acquire)
+    }
+
+    ///
+    /// Disconnects every client with the given reason, and stops accepting new connections
+    pub fn shutdown(&self, reason: TextComponent) {
+        self.shutdown.store(true, Ordering::Release);
+        // Kicking a client also expires its session, if it is waiting to be resumed
+        for peer in self.peers() {
+            let _ = peer.kick(reason.clone());
+        }
+    }
+
+    // Reserves a place for a client which is about to be accepted, or returns the reason it is rejected.
+    // Clients are counted from their reservation, so that concurrent logins cannot exceed the limits
+    fn reserve(&self, client: UUID) -> Result<Reservation<'_>, String> {
+        // A new login replaces a session which is waiting to be resumed
+        let detached = self
+            .peer(client)
+            .and_then(|peer| peer.sender().session())
+            .filter(|session| session.is_detached());
+        if let Some(session) = detached {
+            session.end(self.config.handshake_timeout);
+        }
+        let peers = self.peers.lock().unwrap();
+        let mut joining = self.joining.lock().unwrap();
+        if peers.contains_key(&client) || joining.contains(&client) {
+            return Err("A player with the same UUID is already connected".to_string());
+        }
+        if peers.len() + joining.len() >= self.config.max_players as usize {
+            return Err("The server is full".to_string());
+        }
+        joining.insert(client);
+        Ok(Reservation {
+            joining: &self.joining,
+            client,
+        })
+    }
+
+    fn login<T: Transport>(
+        &self,
+        conn: &mut Connection<T>,
+        deadline: Instant,
+    ) -> std::io::Result<Option<Login<'_>>> {
+        let mut handshake = ServerHandshake::new(self.config.content.iter().cloned());
+        handshake.set_versions(self.config.min_version, self.config.max_version);
+        handshake.set_allow_sync(self.config.allow_sync);
+        handshake.set_compression(self.config.compression_threshold);
+        handshake.set_session_grace(self.config.session_grace);
+        if let Some(summary) = &self.config.summary {
+            handshake.set_summary(summary.clone());
+        }
+        let mut reservation = None;
+        while conn.state() != State::Play {
+            let packet = match self.receive_before(conn, deadline)? {
+                Some(packet) => packet,
+                None => return Ok(None),
+            };
+            if let Some(resume) = packet.downcast_ref::<Resume>() {
+                return Ok(self.resume(conn, resume)?.map(Login::Resumed));
+            }
+            if packet.is::<StatusRequest>() {
+                self.answer_status(conn, deadline)?;
+                return Ok(None);
+            }
+            let response = handshake.receive(&*packet)?;
+            if let Some(LoginResponse::Accept(_)) = &response {
+                match self.reserve(handshake.client().unwrap()) {
+                    Ok(reserved) => reservation = Some(reserved),
+                    Err(reason) => {
+                        conn.send(&LoginReject {
+                            reason: TextComponent::RawText(reason),
+                        })?;
+                        conn.sender().close()?;
+                        return Ok(None);
+                    }
+                }
+            }
+            if let Some(response) = response {
+                conn.send(response.packet())?;
+            }
+            conn.set_state(handshake.state());
+            conn.set_compression(handshake.compression());
+            if handshake.state() == State::Closed {
+                conn.sender().close()?;
+                return Ok(None);
+            }
+        }
+        let version = handshake.version().unwrap();
+        conn.registry_mut().set_version(version);
+        conn.set_session(handshake.session().cloned());
+        let peer = Peer::new(
+            handshake.client().unwrap(),
+            version,
+            conn.sender().clone(),
+            conn.transport().remote_addr(),
+        );
+        Ok(Some(Login::Accepted(peer, reservation.unwrap())))
+    }
+
+    // Receives the next packet before the handshake's deadline, or reports that the client took too long
+    fn receive_before<T: Transport>(
+        &self,
+        conn: &mut Connection<T>,
+        deadline: Instant,
+    ) -> std::io::Result<Option<Box<dyn AnyPacket>>> {
+        let remaining = deadline.saturating_duration_since(self.clock.now());
+        if !remaining.is_zero() {
+            conn.set_timeouts(Some(remaining), None)?;
+            match conn.receive() {
+                Ok(Some(_)) if self.clock.now() >= deadline => {}
+                Err(e) if e.kind() == ErrorKind::TimedOut => {}
+                received => return received,
+            }
+        }
+        Err(self.violate(
+            conn,
+            Violation {
+                kind: ViolationKind::HandshakeTimeout,
+                client: None,
+                address: conn.transport().remote_addr(),
+            },
+        ))
+    }
+
+    // Reports a violation to the handler, and returns the error the client is kicked with
+    fn violate<T: Transport>(&self, conn: &Connection<T>, violation: Violation) -> std::io::Error {
+        if let Some(metrics) = conn.metrics() {
+            metrics.override_disconnect(DisconnectReason::Violation(violation.kind));
+        }
+        self.handler.violation(&violation);
+        violation.into()
+    }
+
+    // Reports a violation by a client whose connection is closed before it is secured
+    #[cfg(feature = "secure")]
+    fn violate_insecure(
+        &self,
+        address: Option<SocketAddr>,
+        violation: Violation,
+    ) -> std::io::Error {
+        if let Some(metrics) = &self.config.metrics {
+            metrics
+                .connection(address)
+                .override_disconnect(DisconnectReason::Violation(violation.kind));
+        }
+        self.handler.violation(&violation);
+        violation.into()
+    }
+
+    // Sends the status, and echoes a StatusPing, before closing the connection
+    fn answer_status<T: Transport>(
+        &self,
+        conn: &mut Connection<T>,
+        deadline: Instant,
+    ) -> std::io::Result<()> {
+        conn.set_state(State::Status);
+        conn.send(&self.status())?;
+        while let Some(packet) = self.receive_before(conn, deadline)? {
+            if let Some(ping) = packet.downcast_ref::<StatusPing>() {
+                conn.send(ping)?;
+                break;
+            }
+        }
+        conn.sender().close()
+    }
+
+    // Resumes the session of a peer whose connection was lost, moving it onto conn
+    fn resume<T: Transport>(
+        &self,
+        conn: &mut Connection<T>,
+        resume: &Resume,
+    ) -> std::io::Result<Option<Peer>> {
+        let peer = self.peer(resume.client);
+        let session = peer.as_ref().and_then(|peer| peer.sender().session());
+        let taken = match (&peer, &session) {
+            (Some(peer), Some(session)) if session.matches(&resume.token) => {
+                session.check_resumable(resume.received).and_then(|_| {
+                    session.take_over(self.config.handshake_timeout, || peer.sender().interrupt())
+                })
+            }
+            (Some(_), Some(_)) => Err(SessionError::InvalidToken),
+            _ => Err(SessionError::Expired),
+        };
+        if let Err(e) = taken {
+            let _ = conn.send(&LoginReject {
+                reason: TextComponent::RawText(e.to_string()),
+            });
+            conn.sender().close()?;
+            return Ok(None);
+        }
+        let (peer, session) = (peer.unwrap(), session.unwrap());
+        // The session now belongs to this connection. If it fails from here, the session is lost again, and can still be resumed
+        conn.registry_mut().set_version(peer.version());
+        let resumed = conn
+            .send(&ResumeAccept {
+                received: session.received(),
+            })
+            .and_then(|_| conn.adopt(peer.sender().clone(), resume.received));
+        if resumed.is_err() {
+            let _ = conn.transport().shutdown();
+        }
+        Ok(Some(peer))
+    }
+
+    fn dispatch<T: Transport>(
+        &self,
+        conn: &mut Connection<T>,
+        peer: &Peer,
+        resumed: bool,
+    ) -> Ended {
+        if !resumed {
+            if let Err(e) = self.handler.connected(peer) {
+                return Ended::Closed(Err(e));
+            }
+        }
+        if let Err(e) = conn.set_timeouts(
+            Some(self.config.timeout),
+            Some(self.config.keepalive_interval),
+        ) {
+            return Ended::Closed(Err(e));
+        }
+        let mut limiter = RateLimiter::new(&self.config.limits, self.clock.now());
+        let lost = |e: std::io::Error| {
+            if peer.sender().session().is_some() && is_lost(&e) {
+                Ended::Lost(e)
+            } else {
+                Ended::Closed(Err(e))
+            }
+        };
+        loop {
+            match conn.receive() {
+                Ok(Some(packet)) if packet.is::<Disconnect>() => return Ended::Closed(Ok(())),
+                Ok(Some(packet)) => {
+                    if let Err(category) = limiter.check(packet.id(), self.clock.now()) {
+                        return Ended::Closed(Err(self.violate(
+                            conn,
+                            Violation {
+                                kind: ViolationKind::RateLimited(category),
+                                client: Some(peer.client),
+                                address: peer.address,
+                            },
+                        )));
+                    }
+                    if let Err(e) = self.handler.packet(peer, packet) {
+                        return Ended::Closed(Err(e));
+                    }
+                }
+                // Closed by the server, or by the client without a Disconnect
+                Ok(None) if peer.sender().is_closed() || peer.sender().session().is_none() => {
+                    return Ended::Closed(Ok(()))
+                }
+                Ok(None) => {
+                    return lost(std::io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Connection lost",
+                    ))
+                }
+                Err(e) => return lost(e),
+            }
+        }
+    }
+
+    // Serves a peer until it disconnects, or its connection is lost and its session expires
+    fn run<T: Transport>(
+        &self,
+        conn: &mut Connection<T>,
+        peer: &Peer,
+        resumed: bool,
+    ) -> std::io::Result<()> {
+        let result = match self.dispatch(conn, peer, resumed) {
+            Ended::Closed(result) => result,
+            Ended::Lost(e) => {
+                let session = peer.sender().session().unwrap();
+                if session.await_resume() {
+                    // Another connection is now serving the peer
+                    return Ok(());
+                }
+                Err(e)
+            }
+        };
+        if let Err(e) = &result {
+            let reason = if e.kind() == ErrorKind::TimedOut {
+                "Timed out".to_string()
+            } else {
+                e.to_string()
+            };
+            let _ = peer.kick(TextComponent::RawText(reason));
+        } else {
+            let _ = peer.sender().close();
+        }
+        self.peers.lock().unwrap().remove(&peer.client);
+        self.handler.disconnected(peer, result.as_ref().err());
+        if let Some(session) = peer.sender().session() {
+            session.finish();
+        }
+        result
+    }
+
+    ///
+    /// Serves a connection on the current thread, until the client disconnects.
+    /// If the server has an identity, the connection is first wrapped in a secure session.
+    /// Returns the error that caused the connection to be closed, if any.
+    pub fn serve<T: Transport>(&self, transport: T) -> std::io::Result<()> {
+        // The client must secure its connection and log in before the same deadline
+        let deadline = self.clock.now() + self.config.handshake_timeout;
+        let address = transport.remote_addr();
+        // Counts the connection against the limit for its address, until it is closed
+        let _open = match (address, self.config.limits.connections_per_ip) {
+            (Some(address), Some(max)) => match self.connections.open(address.ip(), max) {
+                Some(open) => Some(open),
+                None => return self.refuse(transport, address),
+            },
+            _ => None,
+        };
+        #[cfg(feature = "secure")]
+        if let Some(identity) = &self.config.identity {
+            let timeout = deadline.saturating_duration_since(self.clock.now());
+            let transport = match SecureTransport::accept_within(transport, identity, timeout) {
+                Ok(transport) => transport,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) if crate::connection::is_timeout(&e) => {
+                    let violation = Violation {
+                        kind: ViolationKind::HandshakeTimeout,
+                        client: None,
+                        address,
+                    };
+                    return Err(self.violate_insecure(address, violation));
+                }
+                Err(e) => return Err(e),
+            };
+            return self.serve_transport(transport, deadline);
+        }
+        self.serve_transport(transport, deadline)
+    }
+
+    // Kicks a client whose address already has as many connections open as are allowed
+    fn refuse<T: Transport>(&self, transport: T, address: SocketAddr) -> std::io::Result<()> {
+        let violation = Violation {
+            kind: ViolationKind::TooManyConnections,
+            client: None,
+            address: Some(address),
+        };
+        // The reason can only be sent once the connection is secured, which the client is not given the chance to do
+        #[cfg(feature = "secure")]
+        if self.config.identity.is_some() {
+            let _ = transport.shutdown();
+            return Err(self.violate_insecure(Some(address), violation));
+        }
+        let conn = self.connection(transport)?;
+        let reason = violation.reason();
+        let e = self.violate(&conn, violation);
+        let _ = conn.sender().disconnect(reason);
+        Err(e)
+    }
+
+    fn connection<T: Transport>(&self, transport: T) -> std::io::Result<Connection<T>> {
+        let mut conn = Connection::with_codec(
+            transport,
+            Side::Server,
+            self.registry.clone(),
+            FrameCodec::new(self.config.max_frame_size),
+        )?;
+        if let Some(metrics) = &self.config.metrics {
+            conn.set_metrics(Some(metrics.connection(conn.transport().remote_addr())));
+        }
+        Ok(conn)
+    }
+
+    fn serve_transport<T: Transport>(
+        &self,
+        transport: T,
+        deadline: Instant,
+    ) -> std::io::Result<()> {
+        let mut conn = self.connection(transport)?;
+        if let Some(dir) = &self.config.capture_dir {
+            let accepted = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis());
+            let n = self.captures.fetch_add(1, Ordering::Relaxed);
+            let path = dir.join(format!("{}-{}.pkmcap", accepted, n));
+            conn.set_capture(Some(Capture::create(path, Side::Server)?));
+        }
+        if self.is_shutdown() {
+            return conn.sender().disconnect(TextComponent::RawText(
+                "The server is shutting down".to_string(),
+            ));
+        }
+        let peer = match self.login(&mut conn, deadline) {
+            Ok(Some(Login::Accepted(peer, reservation))) => {
+                self.peers.lock().unwrap().insert(peer.client, peer.clone());
+                drop(reservation);
+                peer
+            }
+            Ok(Some(Login::Resumed(peer))) => {
+                if let Some(metrics) = conn.metrics() {
+                    metrics.set_client(peer.client);
+                }
+                return self.run(&mut conn, &peer, true);
+            }
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                let _ = conn
+                    .sender()
+                    .disconnect(TextComponent::RawText(e.to_string()));
+                return Err(e);
+            }
+        };
+
+        if let Some(metrics) = conn.metrics() {
+            metrics.set_client(peer.client);
+        }
+        self.run(&mut conn, &peer, false)
+    }
+}
+
+enum Login<'a> {
+    Accepted(Peer, Reservation<'a>),
+    Resumed(Peer),
+}
+
+// A place reserved for a client by Server::reserve, until it is dropped
+struct Reservation<'a> {
+    joining: &'a Mutex<HashSet<UUID>>,
+    client: UUID,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.joining.lock().unwrap().remove(&self.client);
+    }
+}
+
+enum Ended {
+    // The client disconnected, or the connection was closed by the server or by an error
+    Closed(std::io::Result<()>),
+    // The connection was lost, and the client may resume its session
+    Lost(std::io::Error),
+}
+
+fn is_lost(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::NotConnected
+            | ErrorKind::TimedOut
+    )
+}
+
+impl<H: Handler> Server<H> {
+    ///
+    /// Serves a connection on a new thread
+    pub fn spawn<T: Transport>(self: &Arc<Self>, transport: T) -> JoinHandle<std::io::Result<()>> {
+        let server = self.clone();
+        std::thread::spawn(move || server.serve(transport))
+    }
+
+    ///
+    /// Ticks the server every [`ServerConfig::tick_interval`] on a new thread, until it is shut down or dropped
+    pub fn spawn_ticker(self: &Arc<Self>) -> JoinHandle<()> {
+        let server = Arc::downgrade(self);
+        let interval = self.config.tick_interval;
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            match server.upgrade() {
+                Some(server) if !server.is_shutdown() => server.tick(),
+                _ => return,
+            }
+        })
+    }
+}
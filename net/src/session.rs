@@ -0,0 +1,428 @@
+//!
+//! Resumable PkmCom sessions.
+//!
+//! A client which sets [`RESUME_FLAG`](crate::handshake::RESUME_FLAG) in its Handshake may be given a session when it is accepted,
+//!  identified by a random [`SessionToken`] carried in the [`LoginAccept`](crate::handshake::LoginAccept).
+//! If the connection is lost, the server keeps the client's session, and its place in the game, for the grace window sent with the token.
+//! Within that window, the client may open a new connection and send a [`Resume`] instead of a Handshake.
+//! The server answers with a [`ResumeAccept`], and both sides continue in the Play state on the new connection.
+//!
+//! Every packet sent in the Play state, other than KeepAlive, Disconnect and [`Ack`], has a sequence number in its direction,
+//!  which is the number of such packets sent before it. As the transport is ordered, the numbers are not sent,
+//!  but each side counts the packets it has received, and periodically acknowledges them with an [`Ack`].
+//! Each side keeps the packets it has sent which have not been acknowledged, and packets sent while the connection is lost are kept as well.
+//! On resumption, [`Resume`] and [`ResumeAccept`] carry the number of packets each side received,
+//!  and each side sends the packets the other has not received again, before any other packet.
+
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    io::ErrorKind,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use binary_io::uuid::UUID;
+use rand_core::{OsRng, RngCore};
+
+use crate::{
+    frame::Frame,
+    packet::{Disconnect, KeepAlive, KeepAliveReply, Packet},
+};
+
+///
+/// The token which identifies a session, and which a client presents to resume it
+pub type SessionToken = [u8; 32];
+
+///
+/// The number of packets received after which they are acknowledged, even if the connection is busy
+pub const ACK_INTERVAL: u64 = 32;
+
+packet! {
+    ///
+    /// Sent by a client on a new connection, instead of a Handshake, to resume the session of a lost connection
+    pub struct Resume(0x0009, Serverbound) {
+        ///
+        /// The UUID of the client's player
+        pub client: UUID,
+        ///
+        /// The token of the session
+        pub token: SessionToken,
+        ///
+        /// The number of packets the client received from the server in the session
+        pub received: u64,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by the server to accept a [`Resume`]. Both sides enter the Play state,
+    ///  and the packets the other side did not receive are sent again
+    pub struct ResumeAccept(0x000A, Clientbound) {
+        ///
+        /// The number of packets the server received from the client in the session
+        pub received: u64,
+    }
+}
+
+packet! {
+    ///
+    /// Acknowledges the packets received in a session, so that the peer no longer needs to keep them
+    pub struct Ack(0x000B, Bidirectional) {
+        ///
+        /// The number of packets received in the session
+        pub received: u64,
+    }
+}
+
+///
+/// Checks if the packet with the given id is numbered and kept when sent in a session
+pub fn is_sequenced(id: u16) -> bool {
+    id != KeepAlive::ID && id != KeepAliveReply::ID && id != Disconnect::ID && id != Ack::ID
+}
+
+///
+/// The Error returned when a session cannot be resumed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SessionError {
+    ///
+    /// The connection does not have a session
+    NoSession,
+    ///
+    /// The session does not exist, or has expired
+    Expired,
+    ///
+    /// The token does not match the session
+    InvalidToken,
+    ///
+    /// The session is still in use by another connection
+    Busy,
+    ///
+    /// The peer acknowledged the given number of packets, which were not sent, or are no longer kept
+    InvalidAck(u64),
+}
+
+impl Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::NoSession => f.write_str("The connection does not have a session"),
+            SessionError::Expired => f.write_str("The session has expired"),
+            SessionError::InvalidToken => f.write_str("Invalid session token"),
+            SessionError::Busy => f.write_str("The session is in use by another connection"),
+            SessionError::InvalidAck(received) => f.write_fmt(format_args!(
+                "Acknowledgement of {} packets is out of range",
+                received
+            )),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+impl From<SessionError> for std::io::Error {
+    fn from(e: SessionError) -> Self {
+        let kind = match e {
+            SessionError::NoSession => ErrorKind::NotConnected,
+            SessionError::InvalidAck(_) => ErrorKind::InvalidData,
+            _ => ErrorKind::ConnectionRefused,
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+
+// Generates a token from the operating system's random number generator
+pub(crate) fn new_token() -> SessionToken {
+    let mut token = [0; 32];
+    OsRng.fill_bytes(&mut token);
+    token
+}
+
+struct Journal {
+    sent: u64,
+    // The packets sent which have not been acknowledged, the last of which is numbered sent - 1
+    unacked: VecDeque<Frame>,
+    received: u64,
+    acknowledged: u64,
+    // Whether a connection is serving the session, and how many times it has been resumed
+    attached: bool,
+    generation: u64,
+    expired: bool,
+    finished: bool,
+}
+
+impl Journal {
+    fn first_unacked(&self) -> u64 {
+        self.sent - self.unacked.len() as u64
+    }
+
+    fn acknowledge(&mut self, received: u64) -> Result<(), SessionError> {
+        let first = self.first_unacked();
+        if received < first || received > self.sent {
+            return Err(SessionError::InvalidAck(received));
+        }
+        self.unacked.drain(..(received - first) as usize);
+        Ok(())
+    }
+}
+
+struct Shared {
+    token: SessionToken,
+    grace: Duration,
+    journal: Mutex<Journal>,
+    changed: Condvar,
+}
+
+///
+/// The state of a session, shared by every connection which serves it
+#[derive(Clone)]
+pub struct Session {
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let journal = self.lock();
+        f.debug_struct("Session")
+            .field("grace", &self.shared.grace)
+            .field("sent", &journal.sent)
+            .field("received", &journal.received)
+            .field("unacknowledged", &journal.unacked.len())
+            .field("attached", &journal.attached)
+            .finish()
+    }
+}
+
+impl Session {
+    pub(crate) fn new(token: SessionToken, grace: Duration) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                token,
+                grace,
+                journal: Mutex::new(Journal {
+                    sent: 0,
+                    unacked: VecDeque::new(),
+                    received: 0,
+                    acknowledged: 0,
+                    attached: true,
+                    generation: 0,
+                    expired: false,
+                    finished: false,
+                }),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Journal> {
+        self.shared.journal.lock().unwrap()
+    }
+
+    ///
+    /// Returns the token of the session
+    pub fn token(&self) -> SessionToken {
+        self.shared.token
+    }
+
+    ///
+    /// Returns how long the session is kept after its connection is lost
+    pub fn grace(&self) -> Duration {
+        self.shared.grace
+    }
+
+    ///
+    /// Checks if token is the token of the session, in constant time
+    pub fn matches(&self, token: &SessionToken) -> bool {
+        self.shared
+            .token
+            .iter()
+            .zip(token)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+
+    ///
+    /// Returns the number of sequenced packets sent in the session
+    pub fn sent(&self) -> u64 {
+        self.lock().sent
+    }
+
+    ///
+    /// Returns the number of sequenced packets received in the session
+    pub fn received(&self) -> u64 {
+        self.lock().received
+    }
+
+    ///
+    /// Returns the number of packets sent which the peer has not acknowledged
+    pub fn unacknowledged(&self) -> usize {
+        self.lock().unacked.len()
+    }
+
+    ///
+    /// Checks if the session's connection has been lost, and it is waiting to be resumed
+    pub fn is_detached(&self) -> bool {
+        let journal = self.lock();
+        !journal.attached && !journal.expired
+    }
+
+    ///
+    /// Checks if the session has expired, and can no longer be resumed
+    pub fn is_expired(&self) -> bool {
+        self.lock().expired
+    }
+
+    pub(crate) fn record_sent(&self, frame: Frame) {
+        let mut journal = self.lock();
+        journal.sent += 1;
+        journal.unacked.push_back(frame);
+    }
+
+    // Counts a received packet, returning an Ack to send if enough packets have been received since the last
+    pub(crate) fn record_received(&self) -> Option<Ack> {
+        let mut journal = self.lock();
+        journal.received += 1;
+        if journal.received - journal.acknowledged >= ACK_INTERVAL {
+            journal.acknowledged = journal.received;
+            Some(Ack {
+                received: journal.received,
+            })
+        } else {
+            None
+        }
+    }
+
+    // Returns an Ack to send, if any packets have been received since the last
+    pub(crate) fn pending_ack(&self) -> Option<Ack> {
+        let mut journal = self.lock();
+        if journal.received > journal.acknowledged {
+            journal.acknowledged = journal.received;
+            Some(Ack {
+                received: journal.received,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn acknowledge(&self, received: u64) -> Result<(), SessionError> {
+        self.lock().acknowledge(received)
+    }
+
+    // Checks that the peer can be sent every packet after the first received packets
+    pub(crate) fn check_resumable(&self, received: u64) -> Result<(), SessionError> {
+        let journal = self.lock();
+        if received < journal.first_unacked() || received > journal.sent {
+            Err(SessionError::InvalidAck(received))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Acknowledges the first received packets, and returns the rest, to be sent again
+    pub(crate) fn unacknowledged_from(&self, received: u64) -> Result<Vec<Frame>, SessionError> {
+        let mut journal = self.lock();
+        journal.acknowledge(received)?;
+        // The number of packets received was sent in the Resume or ResumeAccept, so they do not need to be acknowledged again
+        journal.acknowledged = journal.received;
+        Ok(journal.unacked.iter().cloned().collect())
+    }
+
+    // Marks the session as lost, and waits for it to be resumed by another connection, or to expire.
+    // Returns true if it was resumed
+    pub(crate) fn await_resume(&self) -> bool {
+        let mut journal = self.lock();
+        let generation = journal.generation;
+        let deadline = Instant::now() + self.shared.grace;
+        journal.attached = false;
+        self.shared.changed.notify_all();
+        loop {
+            if journal.generation != generation {
+                return true;
+            }
+            if journal.expired {
+                return false;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                journal.expired = true;
+                return false;
+            }
+            journal = self
+                .shared
+                .changed
+                .wait_timeout(journal, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    // Attaches the session to a new connection. If it is still attached to another, interrupt is called to close it,
+    //  and the other connection has up to timeout to detach
+    pub(crate) fn take_over<F: FnOnce()>(
+        &self,
+        timeout: Duration,
+        interrupt: F,
+    ) -> Result<(), SessionError> {
+        let mut journal = self.lock();
+        if journal.attached && !journal.expired {
+            drop(journal);
+            interrupt();
+            let deadline = Instant::now() + timeout;
+            journal = self.lock();
+            while journal.attached && !journal.expired {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(SessionError::Busy);
+                }
+                journal = self
+                    .shared
+                    .changed
+                    .wait_timeout(journal, deadline - now)
+                    .unwrap()
+                    .0;
+            }
+        }
+        if journal.expired {
+            return Err(SessionError::Expired);
+        }
+        journal.attached = true;
+        journal.generation += 1;
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    // Ends the session, so that it can no longer be resumed
+    pub(crate) fn expire(&self) {
+        self.lock().expired = true;
+        self.shared.changed.notify_all();
+    }
+
+    // Ends the session, and waits up to timeout for the connection which owns it to finish with it
+    pub(crate) fn end(&self, timeout: Duration) -> bool {
+        let mut journal = self.lock();
+        journal.expired = true;
+        self.shared.changed.notify_all();
+        let deadline = Instant::now() + timeout;
+        while !journal.finished {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            journal = self
+                .shared
+                .changed
+                .wait_timeout(journal, deadline - now)
+                .unwrap()
+                .0;
+        }
+        true
+    }
+
+    // Called once the connection which owns the session has finished with it
+    pub(crate) fn finish(&self) {
+        let mut journal = self.lock();
+        journal.expired = true;
+        journal.finished = true;
+        self.shared.changed.notify_all();
+    }
+}
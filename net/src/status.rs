@@ -0,0 +1,115 @@
+//!
+//! Querying the status of a server without logging in, for server browsers.
+//!
+//! A client sends a [`StatusRequest`] instead of a Handshake, and both sides enter [`State::Status`].
+//! The server answers with a [`StatusResponse`], describing the server, its players, and the content clients need to join.
+//! The client may then send a [`StatusPing`], which the server echoes before closing the connection,
+//!  so that the client can measure the latency to the server.
+
+use std::time::Duration;
+#[cfg(feature = "tcp")]
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use binary_io::version::Version;
+use text::TextComponent;
+
+#[cfg(feature = "tcp")]
+use crate::{
+    client::LoginError,
+    connection::{Connection, Side, Transport},
+    handshake::State,
+    packet::{Disconnect, PacketRegistry},
+};
+use crate::{handshake::ContentHash, packet::List};
+
+///
+/// The most player names sent in the sample of a [`StatusResponse`]
+pub const MAX_SAMPLE: usize = 12;
+
+packet! {
+    ///
+    /// Sent by a client on a new connection, instead of a Handshake, to ask for the server's status
+    pub struct StatusRequest(0x000C, Serverbound) {}
+}
+
+packet! {
+    ///
+    /// Sent by the server in response to a [`StatusRequest`]
+    pub struct StatusResponse(0x000D, Clientbound) {
+        ///
+        /// The message of the day, shown in server browsers
+        pub motd: TextComponent,
+        ///
+        /// The newest protocol version supported by the server
+        pub protocol: Version,
+        ///
+        /// The most players which may be online at once
+        pub max_players: u32,
+        ///
+        /// The number of players online
+        pub online_players: u32,
+        ///
+        /// The names of some of the players online, which is empty if the server does not share them
+        pub sample: List<String>,
+        ///
+        /// The hashes of the resource domains loaded by the server, which clients must match to join
+        pub content: List<ContentHash>,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by a client after receiving a [`StatusResponse`], and echoed by the server, to measure the latency
+    pub struct StatusPing(0x000E, Bidirectional) {
+        ///
+        /// An arbitrary value chosen by the client, which the server echoes
+        pub payload: u64,
+    }
+}
+
+///
+/// The status of a server, as seen by a client
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerStatus {
+    ///
+    /// The server's response
+    pub response: StatusResponse,
+    ///
+    /// The round trip time of a [`StatusPing`]
+    pub latency: Duration,
+}
+
+#[cfg(feature = "tcp")]
+///
+/// Asks the server at the other end of transport for its status, and measures the latency to it.
+/// Each response must arrive within timeout
+pub fn query<T: Transport>(transport: T, timeout: Duration) -> std::io::Result<ServerStatus> {
+    let mut conn = Connection::new(transport, Side::Client, PacketRegistry::pkmcom())?;
+    conn.set_timeouts(Some(timeout), None)?;
+    conn.send(&StatusRequest {})?;
+    conn.set_state(State::Status);
+    let response = loop {
+        let packet = conn.receive()?.ok_or(LoginError::Closed)?;
+        if let Some(response) = packet.downcast_ref::<StatusResponse>() {
+            break response.clone();
+        } else if let Some(disconnect) = packet.downcast_ref::<Disconnect>() {
+            return Err(LoginError::Rejected(disconnect.reason.clone()).into());
+        }
+    };
+    let payload = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    let sent = Instant::now();
+    conn.send(&StatusPing { payload })?;
+    let latency = loop {
+        let packet = conn.receive()?.ok_or(LoginError::Closed)?;
+        if packet
+            .downcast_ref::<StatusPing>()
+            .is_some_and(|ping| ping.payload == payload)
+        {
+            break sent.elapsed();
+        }
+    };
+    conn.sender().close()?;
+    Ok(ServerStatus { response, latency })
+}
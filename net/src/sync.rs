@@ -0,0 +1,545 @@
+//!
+//! Synchronization of registries from the server to the client.
+//!
+//! In multiplayer, the server's registries are authoritative. For each registry, the server sends a [`RegistrySyncStart`],
+//!  then every entry with its numeric id in one or more [`RegistryEntries`], and finally a [`RegistrySyncEnd`] with the digest of the entries.
+//! Entries are sent in order of their ids, which are assigned densely from 0.
+//! The client collects the entries with a [`RegistrySync`], installs them into its own registries before they are locked,
+//!  and acknowledges each registry with a [`RegistrySyncAck`].
+//! If the entries do not match what the server announced, or cannot be installed,
+//!  the client disconnects with the reason given by [`SyncError::reason`].
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt::Display,
+    io::ErrorKind,
+};
+
+#[cfg(feature = "tcp")]
+use binary_io::data::{ByteOrder, DataOutputStream, OutOfRange};
+use binary_io::{
+    data::{DataInput, DataOutput, DeserializeCopy, Deserializeable, Serializeable},
+    nbt::compound::NbtCompound,
+};
+use text::TextComponent;
+
+#[cfg(feature = "tcp")]
+use crate::{
+    connection::{Connection, Sender, Transport},
+    packet::Disconnect,
+};
+use crate::{
+    digest::{ContentDigest, Digest},
+    packet::{AnyPacket, List},
+};
+
+///
+/// The default limit on the encoded size of the entries in a single [`RegistryEntries`] packet
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+///
+/// A registry entry, as sent by the server
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SyncedEntry {
+    ///
+    /// The numeric id of the entry, which is its index in the registry
+    pub id: u32,
+    ///
+    /// The name of the entry, as a resource location
+    pub name: String,
+    ///
+    /// The content of the entry
+    pub data: NbtCompound,
+}
+
+impl Serializeable for SyncedEntry {
+    fn serialize<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        self.id.serialize(output)?;
+        self.name.serialize(output)?;
+        self.data.serialize(output)
+    }
+}
+
+impl Deserializeable for SyncedEntry {
+    fn deserialize<R: DataInput + ?Sized>(&mut self, input: &mut R) -> std::io::Result<()> {
+        self.id.deserialize(input)?;
+        self.name.deserialize(input)?;
+        self.data.deserialize(input)
+    }
+}
+
+impl DeserializeCopy for SyncedEntry {
+    fn deserialize_copy<R: DataInput + ?Sized>(input: &mut R) -> std::io::Result<Self> {
+        Ok(Self {
+            id: u32::deserialize_copy(input)?,
+            name: String::deserialize_copy(input)?,
+            data: NbtCompound::deserialize_copy(input)?,
+        })
+    }
+}
+
+impl ContentDigest for SyncedEntry {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        self.id.write_canonical(output)?;
+        self.name.write_canonical(output)?;
+        self.data.write_canonical(output)
+    }
+}
+
+packet! {
+    ///
+    /// Sent by the server before the entries of a registry
+    pub struct RegistrySyncStart(0x0020, Clientbound) {
+        ///
+        /// The name of the registry
+        pub registry: String,
+        ///
+        /// The number of entries which will be sent
+        pub count: u32,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by the server with some of the entries of the registry being synchronized
+    pub struct RegistryEntries(0x0021, Clientbound) {
+        ///
+        /// The name of the registry
+        pub registry: String,
+        ///
+        /// The entries, in order of their ids
+        pub entries: List<SyncedEntry>,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by the server after every entry of a registry
+    pub struct RegistrySyncEnd(0x0022, Clientbound) {
+        ///
+        /// The name of the registry
+        pub registry: String,
+        ///
+        /// The digest of the entries, in the order they were sent
+        pub digest: Digest,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by the client once the entries of a registry have been installed
+    pub struct RegistrySyncAck(0x0023, Serverbound) {
+        ///
+        /// The name of the registry
+        pub registry: String,
+    }
+}
+
+///
+/// The Error returned when a registry cannot be synchronized
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyncError {
+    ///
+    /// The server sent a registry the client does not have
+    UnknownRegistry(String),
+    ///
+    /// The server sent a registry which was already synchronized, or started another registry before finishing one
+    Duplicate(String),
+    ///
+    /// The server sent entries or finished a registry which it had not started
+    NotStarted(String),
+    ///
+    /// The server sent more entries than it announced
+    TooManyEntries {
+        ///
+        /// The name of the registry
+        registry: String,
+        ///
+        /// The number of entries announced
+        count: u32,
+    },
+    ///
+    /// The server finished a registry without sending every entry it announced
+    MissingEntries {
+        ///
+        /// The name of the registry
+        registry: String,
+        ///
+        /// The number of entries announced
+        expected: u32,
+        ///
+        /// The number of entries received
+        received: u32,
+    },
+    ///
+    /// An entry has an id outside of the announced range
+    InvalidId {
+        ///
+        /// The name of the registry
+        registry: String,
+        ///
+        /// The id of the entry
+        id: u32,
+    },
+    ///
+    /// Two entries have the same id
+    DuplicateId {
+        ///
+        /// The name of the registry
+        registry: String,
+        ///
+        /// The id of the entries
+        id: u32,
+    },
+    ///
+    /// Two entries have the same name
+    DuplicateName {
+        ///
+        /// The name of the registry
+        registry: String,
+        ///
+        /// The name of the entries
+        name: String,
+    },
+    ///
+    /// The digest of the entries received does not match the digest sent by the server
+    DigestMismatch(String),
+    ///
+    /// An entry could not be converted to the type of the client's registry
+    InvalidEntry {
+        ///
+        /// The name of the registry
+        registry: String,
+        ///
+        /// The name of the entry
+        name: String,
+        ///
+        /// Why the entry is invalid
+        reason: String,
+    },
+    ///
+    /// The client's registry was locked before the entries could be installed
+    Locked(String),
+}
+
+impl SyncError {
+    ///
+    /// Returns the reason to disconnect with, to be shown to the player
+    pub fn reason(&self) -> TextComponent {
+        TextComponent::RawText(format!("Registry synchronization failed: {}", self))
+    }
+}
+
+impl Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncError::UnknownRegistry(registry) => {
+                f.write_fmt(format_args!("unknown registry {}", registry))
+            }
+            SyncError::Duplicate(registry) => {
+                f.write_fmt(format_args!("registry {} was sent twice", registry))
+            }
+            SyncError::NotStarted(registry) => {
+                f.write_fmt(format_args!("registry {} was not started", registry))
+            }
+            SyncError::TooManyEntries { registry, count } => f.write_fmt(format_args!(
+                "registry {} has more than the {} entries announced",
+                registry, count
+            )),
+            SyncError::MissingEntries {
+                registry,
+                expected,
+                received,
+            } => f.write_fmt(format_args!(
+                "registry {} has {} entries, expected {}",
+                registry, received, expected
+            )),
+            SyncError::InvalidId { registry, id } => f.write_fmt(format_args!(
+                "id {} is out of range for registry {}",
+                id, registry
+            )),
+            SyncError::DuplicateId { registry, id } => f.write_fmt(format_args!(
+                "id {} is used twice in registry {}",
+                id, registry
+            )),
+            SyncError::DuplicateName { registry, name } => f.write_fmt(format_args!(
+                "{} is sent twice in registry {}",
+                name, registry
+            )),
+            SyncError::DigestMismatch(registry) => f.write_fmt(format_args!(
+                "the entries of registry {} do not match their digest",
+                registry
+            )),
+            SyncError::InvalidEntry {
+                registry,
+                name,
+                reason,
+            } => f.write_fmt(format_args!(
+                "invalid entry {} in registry {}: {}",
+                name, registry, reason
+            )),
+            SyncError::Locked(registry) => {
+                f.write_fmt(format_args!("registry {} is already locked", registry))
+            }
+        }
+    }
+}
+
+impl std::error::Error for SyncError {}
+
+impl From<SyncError> for std::io::Error {
+    fn from(e: SyncError) -> Self {
+        std::io::Error::new(ErrorKind::InvalidData, e)
+    }
+}
+
+///
+/// A registry on the client, into which the entries sent by the server can be installed
+pub trait SyncTarget {
+    ///
+    /// Replaces the entries of the registry with entries, which are in order of their ids, and then locks the registry.
+    /// `registry` is the name the registry was synchronized under, for errors
+    fn install(&self, registry: &str, entries: Vec<SyncedEntry>) -> Result<(), SyncError>;
+}
+
+#[cfg(feature = "tcp")]
+fn encoded_len(entry: &SyncedEntry) -> std::io::Result<usize> {
+    let mut output = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+    entry.serialize(&mut output)?;
+    Ok(output.into_inner().len())
+}
+
+#[cfg(feature = "tcp")]
+///
+/// Sends the entries of a registry, which must be in order of their ids, with the [`DEFAULT_CHUNK_SIZE`]
+pub fn send_registry(
+    sender: &Sender,
+    registry: &str,
+    entries: &[SyncedEntry],
+) -> std::io::Result<()> {
+    send_registry_chunked(sender, registry, entries, DEFAULT_CHUNK_SIZE)
+}
+
+#[cfg(feature = "tcp")]
+///
+/// Sends the entries of a registry, which must be in order of their ids.
+/// Entries are grouped into [`RegistryEntries`] packets of at most `chunk_size` bytes, unless a single entry is larger
+pub fn send_registry_chunked(
+    sender: &Sender,
+    registry: &str,
+    entries: &[SyncedEntry],
+    chunk_size: usize,
+) -> std::io::Result<()> {
+    if entries.len() > u32::MAX as usize {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            OutOfRange(entries.len()),
+        ));
+    }
+    sender.send(&RegistrySyncStart {
+        registry: registry.to_string(),
+        count: entries.len() as u32,
+    })?;
+    let mut chunk = Vec::new();
+    let mut size = 0;
+    for entry in entries {
+        let len = encoded_len(entry)?;
+        if !chunk.is_empty() && (size + len > chunk_size || chunk.len() == u16::MAX as usize) {
+            sender.send(&RegistryEntries {
+                registry: registry.to_string(),
+                entries: List(std::mem::take(&mut chunk)),
+            })?;
+            size = 0;
+        }
+        chunk.push(entry.clone());
+        size += len;
+    }
+    if !chunk.is_empty() {
+        sender.send(&RegistryEntries {
+            registry: registry.to_string(),
+            entries: List(chunk),
+        })?;
+    }
+    sender.send(&RegistrySyncEnd {
+        registry: registry.to_string(),
+        digest: entries.digest()?,
+    })
+}
+
+///
+/// What happened when the client received a packet during registry synchronization
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyncEvent {
+    ///
+    /// The packet is not part of registry synchronization
+    Ignored,
+    ///
+    /// The packet was accepted, and the registry is not yet complete
+    Received,
+    ///
+    /// Every entry of the named registry was received and installed.
+    /// The client should acknowledge it with a [`RegistrySyncAck`]
+    Installed(String),
+}
+
+struct Pending {
+    registry: String,
+    count: u32,
+    entries: Vec<SyncedEntry>,
+    ids: HashSet<u32>,
+    names: HashSet<String>,
+}
+
+///
+/// The client side of registry synchronization, which collects the entries sent by the server,
+///  and installs them into the client's registries
+#[derive(Default)]
+pub struct RegistrySync<'a> {
+    targets: BTreeMap<String, &'a dyn SyncTarget>,
+    pending: Option<Pending>,
+    installed: BTreeSet<String>,
+}
+
+impl<'a> RegistrySync<'a> {
+    ///
+    /// Creates a new synchronization, with no registries
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Adds a registry which the server synchronizes under the given name
+    pub fn add_target<S: Into<String>>(&mut self, registry: S, target: &'a dyn SyncTarget) {
+        self.targets.insert(registry.into(), target);
+    }
+
+    ///
+    /// Returns the names of the registries which have been installed
+    pub fn installed(&self) -> impl Iterator<Item = &str> {
+        self.installed.iter().map(String::as_str)
+    }
+
+    ///
+    /// Checks if every registry has been installed
+    pub fn is_complete(&self) -> bool {
+        self.pending.is_none() && self.installed.len() == self.targets.len()
+    }
+
+    fn pending(&mut self, registry: &str) -> Result<&mut Pending, SyncError> {
+        match &mut self.pending {
+            Some(pending) if pending.registry == registry => Ok(pending),
+            _ => Err(SyncError::NotStarted(registry.to_string())),
+        }
+    }
+
+    ///
+    /// Handles a packet received from the server
+    pub fn receive(&mut self, packet: &dyn AnyPacket) -> Result<SyncEvent, SyncError> {
+        if let Some(start) = packet.downcast_ref::<RegistrySyncStart>() {
+            if !self.targets.contains_key(&start.registry) {
+                return Err(SyncError::UnknownRegistry(start.registry.clone()));
+            }
+            if self.pending.is_some() || self.installed.contains(&start.registry) {
+                return Err(SyncError::Duplicate(start.registry.clone()));
+            }
+            self.pending = Some(Pending {
+                registry: start.registry.clone(),
+                count: start.count,
+                // Don't trust the count for the allocation
+                entries: Vec::with_capacity((start.count as usize).min(4096)),
+                ids: HashSet::new(),
+                names: HashSet::new(),
+            });
+            Ok(SyncEvent::Received)
+        } else if let Some(chunk) = packet.downcast_ref::<RegistryEntries>() {
+            let pending = self.pending(&chunk.registry)?;
+            for entry in chunk.entries.iter() {
+                if pending.entries.len() >= pending.count as usize {
+                    return Err(SyncError::TooManyEntries {
+                        registry: pending.registry.clone(),
+                        count: pending.count,
+                    });
+                }
+                if entry.id >= pending.count {
+                    return Err(SyncError::InvalidId {
+                        registry: pending.registry.clone(),
+                        id: entry.id,
+                    });
+                }
+                if !pending.ids.insert(entry.id) {
+                    return Err(SyncError::DuplicateId {
+                        registry: pending.registry.clone(),
+                        id: entry.id,
+                    });
+                }
+                if !pending.names.insert(entry.name.clone()) {
+                    return Err(SyncError::DuplicateName {
+                        registry: pending.registry.clone(),
+                        name: entry.name.clone(),
+                    });
+                }
+                pending.entries.push(entry.clone());
+            }
+            Ok(SyncEvent::Received)
+        } else if let Some(end) = packet.downcast_ref::<RegistrySyncEnd>() {
+            let pending = self.pending(&end.registry)?;
+            if pending.entries.len() != pending.count as usize {
+                return Err(SyncError::MissingEntries {
+                    registry: pending.registry.clone(),
+                    expected: pending.count,
+                    received: pending.entries.len() as u32,
+                });
+            }
+            // Entries which cannot be digested cannot match
+            if pending.entries.digest().map_or(true, |d| d != end.digest) {
+                return Err(SyncError::DigestMismatch(pending.registry.clone()));
+            }
+            let mut pending = self.pending.take().unwrap();
+            pending.entries.sort_by_key(|e| e.id);
+            self.targets[&pending.registry].install(&pending.registry, pending.entries)?;
+            self.installed.insert(pending.registry.clone());
+            Ok(SyncEvent::Installed(pending.registry))
+        } else {
+            Ok(SyncEvent::Ignored)
+        }
+    }
+
+    #[cfg(feature = "tcp")]
+    ///
+    /// Receives packets on conn until every registry has been installed, acknowledging each registry.
+    /// Returns the other packets received in the meantime, in order.
+    /// If synchronization fails, the client disconnects with the reason, and the error is returned
+    pub fn run<T: Transport>(
+        &mut self,
+        conn: &mut Connection<T>,
+    ) -> std::io::Result<Vec<Box<dyn AnyPacket>>> {
+        let mut other = Vec::new();
+        while !self.is_complete() {
+            let packet = conn.receive()?.ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::ConnectionAborted,
+                    "Server closed the connection during registry synchronization",
+                )
+            })?;
+            if let Some(disconnect) = packet.downcast_ref::<Disconnect>() {
+                return Err(std::io::Error::new(
+                    ErrorKind::ConnectionAborted,
+                    format!(
+                        "Disconnected during registry synchronization: {}",
+                        disconnect.reason
+                    ),
+                ));
+            }
+            match self.receive(&*packet) {
+                Ok(SyncEvent::Ignored) => other.push(packet),
+                Ok(SyncEvent::Received) => {}
+                Ok(SyncEvent::Installed(registry)) => conn.send(&RegistrySyncAck { registry })?,
+                Err(e) => {
+                    let _ = conn.sender().disconnect(e.reason());
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(other)
+    }
+}
@@ -0,0 +1,176 @@
+//!
+//! PkmCom over TCP.
+//!
+//! [`TcpServer`] accepts connections on a listening socket, and serves each of them with a [`Server`] on its own thread.
+//! The server is ticked on another thread while it is bound.
+//! [`connect`] opens a connection to a server, with a connect timeout, and runs the handshake.
+//! [`resume`] opens a new connection to resume the session of a connection which was lost.
+//! [`query`] asks a server for its status, without logging in.
+//! With the `secure` feature, `connect_secure` does the same over an encrypted session.
+
+use std::{
+    io::ErrorKind,
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use text::TextComponent;
+
+use crate::{
+    client::{self, ClientConfig},
+    connection::{Connection, Transport},
+    packet::PacketRegistry,
+    server::{Handler, Server},
+    status::{self, ServerStatus},
+};
+
+#[cfg(feature = "secure")]
+use crate::secure::{PublicKey, SecureTransport};
+
+///
+/// A server which accepts PkmCom connections over TCP
+pub struct TcpServer<H> {
+    server: Arc<Server<H>>,
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    accept: Option<JoinHandle<()>>,
+}
+
+impl<H: Handler> TcpServer<H> {
+    ///
+    /// Binds to addr, and starts accepting connections on a new thread
+    pub fn bind<A: ToSocketAddrs>(addr: A, server: Server<H>) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let server = Arc::new(server);
+        server.spawn_ticker();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let accept = {
+            let server = server.clone();
+            let stopped = stopped.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::Acquire) {
+                        break;
+                    }
+                    match stream {
+                        Ok(stream) => {
+                            let _ = stream.set_nodelay(true);
+                            server.spawn(stream);
+                        }
+                        Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                        Err(_) => std::thread::sleep(Duration::from_millis(10)),
+                    }
+                }
+            })
+        };
+        Ok(Self {
+            server,
+            addr,
+            stopped,
+            accept: Some(accept),
+        })
+    }
+
+    ///
+    /// Returns the address the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    ///
+    /// Returns the server which serves the connections
+    pub fn server(&self) -> &Arc<Server<H>> {
+        &self.server
+    }
+
+    ///
+    /// Stops accepting connections, and disconnects every client with the given reason
+    pub fn shutdown(&mut self, reason: TextComponent) {
+        self.server.shutdown(reason);
+        if !self.stopped.swap(true, Ordering::AcqRel) {
+            // Wake the accept thread, which is blocked in accept
+            let _ = TcpStream::connect_timeout(&self.addr, Duration::from_secs(1));
+        }
+        if let Some(accept) = self.accept.take() {
+            let _ = accept.join();
+        }
+    }
+}
+
+impl<H> Drop for TcpServer<H> {
+    fn drop(&mut self) {
+        if !self.stopped.swap(true, Ordering::AcqRel) {
+            let _ = TcpStream::connect_timeout(&self.addr, Duration::from_secs(1));
+        }
+    }
+}
+
+///
+/// Connects to a server at addr, waiting at most connect_timeout for each address, and runs the handshake
+pub fn connect<A: ToSocketAddrs>(
+    addr: A,
+    connect_timeout: Duration,
+    config: &ClientConfig,
+    registry: PacketRegistry,
+) -> std::io::Result<Connection<TcpStream>> {
+    let stream = open(addr, connect_timeout)?;
+    client::login(stream, config, registry)
+}
+
+///
+/// Connects to the server at addr, waiting at most connect_timeout for each address, and resumes the session of conn
+pub fn resume<A: ToSocketAddrs, T: Transport>(
+    addr: A,
+    connect_timeout: Duration,
+    conn: &Connection<T>,
+    config: &ClientConfig,
+) -> std::io::Result<Connection<TcpStream>> {
+    let stream = open(addr, connect_timeout)?;
+    client::resume(conn, stream, config)
+}
+
+///
+/// Connects to the server at addr, waiting at most timeout for each address and for each response, and asks for its status
+pub fn query<A: ToSocketAddrs>(addr: A, timeout: Duration) -> std::io::Result<ServerStatus> {
+    let stream = open(addr, timeout)?;
+    status::query(stream, timeout)
+}
+
+fn open<A: ToSocketAddrs>(addr: A, connect_timeout: Duration) -> std::io::Result<TcpStream> {
+    let mut error = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, connect_timeout) {
+            Ok(stream) => {
+                stream.set_nodelay(true)?;
+                return Ok(stream);
+            }
+            Err(e) => error = Some(e),
+        }
+    }
+    Err(error.unwrap_or_else(|| {
+        std::io::Error::new(ErrorKind::InvalidInput, "No addresses to connect to")
+    }))
+}
+
+///
+/// Connects to the server at addr, establishes a secure session, and runs the handshake with the given configuration.
+/// If `pins` is not empty, the server's identity must be one of the keys in it
+#[cfg(feature = "secure")]
+pub fn connect_secure<A: ToSocketAddrs>(
+    addr: A,
+    connect_timeout: Duration,
+    pins: &[PublicKey],
+    config: &ClientConfig,
+    registry: PacketRegistry,
+) -> std::io::Result<Connection<SecureTransport<TcpStream>>> {
+    let stream = open(addr, connect_timeout)?;
+    stream.set_read_timeout(Some(config.handshake_timeout))?;
+    let transport = SecureTransport::connect(stream, pins)?;
+    client::login(transport, config, registry)
+}
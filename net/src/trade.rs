@@ -0,0 +1,755 @@
+//!
+//! Trading Pokémon between players, with a two-phase commit on the server.
+//!
+//! A player opens a trade with a [`TradeOffer`] to another player, who answers with a [`TradeCounterOffer`].
+//! Either player may revise their side with further counter-offers, each of which increments the revision of the trade,
+//!  and unlocks both sides.
+//! Once both players send a [`TradeLock`] for the current revision, the server prepares the trade with its [`TradeStore`],
+//!  which checks and reserves the Pokémon of both sides, and asks both players for a final [`TradeConfirm`].
+//! When both players confirm, the store commits the trade, and both players receive a [`TradeCommit`].
+//! If either player aborts or disconnects, or the trade times out, before the commit, the store rolls back the trade,
+//!  and both players receive a [`TradeAbort`] with the reason.
+//!
+//! The store is the authority on which player owns each Pokémon, so a Pokémon is never duplicated or lost,
+//!  as long as its commit transfers every Pokémon of the trade, or none of them.
+
+#[cfg(feature = "tcp")]
+use std::sync::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use binary_io::{
+    data::{DataInput, DataOutput, DeserializeCopy, Deserializeable, Serializeable},
+    nbt::compound::NbtCompound,
+    uuid::UUID,
+};
+use text::TextComponent;
+
+use crate::packet::{AnyPacket, List, Outgoing};
+#[cfg(feature = "tcp")]
+use crate::server::{Handler, Peer};
+
+///
+/// A Pokémon offered in a trade
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TradePokemon {
+    ///
+    /// The UUID of the Pokémon
+    pub id: UUID,
+    ///
+    /// The Pokémon, as stored by its owner
+    pub data: NbtCompound,
+}
+
+impl Serializeable for TradePokemon {
+    fn serialize<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        self.id.serialize(output)?;
+        self.data.serialize(output)
+    }
+}
+
+impl Deserializeable for TradePokemon {
+    fn deserialize<R: DataInput + ?Sized>(&mut self, input: &mut R) -> std::io::Result<()> {
+        self.id.deserialize(input)?;
+        self.data.deserialize(input)
+    }
+}
+
+impl DeserializeCopy for TradePokemon {
+    fn deserialize_copy<R: DataInput + ?Sized>(input: &mut R) -> std::io::Result<Self> {
+        Ok(Self {
+            id: UUID::deserialize_copy(input)?,
+            data: NbtCompound::deserialize_copy(input)?,
+        })
+    }
+}
+
+packet! {
+    ///
+    /// Sent by a client to open a trade with another player.
+    /// The server sends it to both players, with the id of the new trade
+    pub struct TradeOffer(0x0030, Bidirectional) {
+        ///
+        /// The id of the trade, assigned by the server. Ignored when sent by a client
+        pub trade: UUID,
+        ///
+        /// The player who opened the trade. Ignored when sent by a client
+        pub from: UUID,
+        ///
+        /// The player the trade is offered to
+        pub to: UUID,
+        ///
+        /// The Pokémon offered by the player who opened the trade
+        pub pokemon: List<TradePokemon>,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by either player to replace their side of a trade.
+    /// The server sends it to both players, with the new revision of the trade
+    pub struct TradeCounterOffer(0x0031, Bidirectional) {
+        ///
+        /// The id of the trade
+        pub trade: UUID,
+        ///
+        /// The player whose side is replaced. Ignored when sent by a client
+        pub from: UUID,
+        ///
+        /// The revision of the trade after this offer. Ignored when sent by a client
+        pub revision: u32,
+        ///
+        /// The Pokémon offered
+        pub pokemon: List<TradePokemon>,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by a player to accept both sides of a trade as of a revision.
+    /// The server sends it to both players
+    pub struct TradeLock(0x0032, Bidirectional) {
+        ///
+        /// The id of the trade
+        pub trade: UUID,
+        ///
+        /// The player who locked the trade. Ignored when sent by a client
+        pub from: UUID,
+        ///
+        /// The revision which is accepted. Locks of earlier revisions are ignored
+        pub revision: u32,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by the server to both players once the trade is prepared, with a `from` of NIL.
+    /// Each player answers with a TradeConfirm of their own to commit the trade
+    pub struct TradeConfirm(0x0033, Bidirectional) {
+        ///
+        /// The id of the trade
+        pub trade: UUID,
+        ///
+        /// The player who confirmed the trade, or NIL when sent by the server. Ignored when sent by a client
+        pub from: UUID,
+        ///
+        /// The revision which is confirmed
+        pub revision: u32,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by the server to both players once the trade is committed
+    pub struct TradeCommit(0x0034, Clientbound) {
+        ///
+        /// The id of the trade
+        pub trade: UUID,
+    }
+}
+
+packet! {
+    ///
+    /// Sent by a player to cancel a trade, or by the server when a trade is cancelled.
+    /// Nothing is traded
+    pub struct TradeAbort(0x0035, Bidirectional) {
+        ///
+        /// The id of the trade, or NIL if a trade could not be opened
+        pub trade: UUID,
+        ///
+        /// Why the trade was cancelled, to be shown to the players
+        pub reason: TextComponent,
+    }
+}
+
+///
+/// The Error which causes a trade to be cancelled
+#[derive(Clone, Debug, PartialEq)]
+pub enum TradeError {
+    ///
+    /// The trade does not exist, or the player is not part of it
+    UnknownTrade(UUID),
+    ///
+    /// The player is already trading
+    Busy(UUID),
+    ///
+    /// The player is not connected
+    Unavailable(UUID),
+    ///
+    /// A player opened a trade with themselves
+    SelfTrade,
+    ///
+    /// An offer has more Pokémon than allowed
+    TooManyPokemon(usize),
+    ///
+    /// A Pokémon is offered more than once
+    DuplicatePokemon(UUID),
+    ///
+    /// The packet is not allowed in the current phase of the trade
+    WrongPhase,
+    ///
+    /// The player cancelled the trade, for the given reason
+    Cancelled(UUID, TextComponent),
+    ///
+    /// The player disconnected
+    Disconnected(UUID),
+    ///
+    /// The players did not finish the trade in time
+    TimedOut,
+    ///
+    /// The store could not prepare or commit the trade
+    Store(String),
+}
+
+impl TradeError {
+    ///
+    /// Returns the reason sent in the [`TradeAbort`]
+    pub fn reason(&self) -> TextComponent {
+        match self {
+            TradeError::Cancelled(_, reason) => reason.clone(),
+            e => TextComponent::RawText(format!("Trade cancelled: {}", e)),
+        }
+    }
+}
+
+impl Display for TradeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TradeError::UnknownTrade(trade) => f.write_fmt(format_args!("unknown trade {}", trade)),
+            TradeError::Busy(player) => f.write_fmt(format_args!("{} is already trading", player)),
+            TradeError::Unavailable(player) => {
+                f.write_fmt(format_args!("{} is not connected", player))
+            }
+            TradeError::SelfTrade => f.write_str("players cannot trade with themselves"),
+            TradeError::TooManyPokemon(max) => {
+                f.write_fmt(format_args!("at most {} Pokémon may be offered", max))
+            }
+            TradeError::DuplicatePokemon(id) => {
+                f.write_fmt(format_args!("Pokémon {} is offered twice", id))
+            }
+            TradeError::WrongPhase => f.write_str("unexpected trade packet"),
+            TradeError::Cancelled(player, reason) => {
+                f.write_fmt(format_args!("{} cancelled the trade: {}", player, reason))
+            }
+            TradeError::Disconnected(player) => {
+                f.write_fmt(format_args!("{} disconnected", player))
+            }
+            TradeError::TimedOut => f.write_str("timed out"),
+            TradeError::Store(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl std::error::Error for TradeError {}
+
+///
+/// The server's record of which player owns each Pokémon, which takes part in the two-phase commit of trades
+pub trait TradeStore: Send + Sync + 'static {
+    ///
+    /// Checks that owner owns each Pokémon, as offered, and reserves them for trade,
+    ///  so that they cannot be traded or released until the trade is committed or rolled back.
+    /// Called once for each player in the trade
+    fn prepare(&self, trade: UUID, owner: UUID, pokemon: &[TradePokemon]) -> std::io::Result<()>;
+
+    ///
+    /// Gives every Pokémon reserved for trade to the other player in the trade.
+    /// Either every Pokémon is transferred, or, if an error is returned, none are
+    fn commit(&self, trade: UUID) -> std::io::Result<()>;
+
+    ///
+    /// Releases every Pokémon reserved for trade, without transferring them.
+    /// Called for trades which were prepared for any player, including trades for which commit failed
+    fn rollback(&self, trade: UUID);
+}
+
+///
+/// The limits on trades
+#[derive(Clone, Debug)]
+pub struct TradeConfig {
+    ///
+    /// The most Pokémon a player may offer in one trade
+    pub max_pokemon: usize,
+    ///
+    /// The time the players have between offers or locks, before the trade is cancelled
+    pub negotiation_timeout: Duration,
+    ///
+    /// The time the players have to confirm a prepared trade, before it is rolled back
+    pub confirm_timeout: Duration,
+}
+
+impl Default for TradeConfig {
+    fn default() -> Self {
+        Self {
+            max_pokemon: 6,
+            negotiation_timeout: Duration::from_secs(300),
+            confirm_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Phase {
+    Negotiating,
+    // The store has prepared the trade, and the players are asked to confirm
+    Confirming,
+}
+
+struct Session {
+    players: [UUID; 2],
+    offers: [Vec<TradePokemon>; 2],
+    revision: u32,
+    locked: [bool; 2],
+    confirmed: [bool; 2],
+    phase: Phase,
+    deadline: Instant,
+}
+
+impl Session {
+    fn side(&self, player: UUID) -> usize {
+        if self.players[0] == player {
+            0
+        } else {
+            1
+        }
+    }
+}
+
+#[cfg(feature = "tcp")]
+fn is_trade_packet(packet: &dyn AnyPacket) -> bool {
+    packet.is::<TradeOffer>()
+        || packet.is::<TradeCounterOffer>()
+        || packet.is::<TradeLock>()
+        || packet.is::<TradeConfirm>()
+        || packet.is::<TradeAbort>()
+}
+
+fn to_both<P: AnyPacket + Clone + 'static>(session: &Session, packet: P) -> Vec<Outgoing> {
+    vec![
+        (session.players[0], Box::new(packet.clone())),
+        (session.players[1], Box::new(packet)),
+    ]
+}
+
+///
+/// The trades in progress on a server.
+/// Sessions are independent of connections and time, so that they can be driven by a [`TradeService`], or directly
+pub struct TradeSessions {
+    config: TradeConfig,
+    sessions: HashMap<UUID, Session>,
+    players: HashMap<UUID, UUID>,
+    next_id: u64,
+}
+
+impl TradeSessions {
+    ///
+    /// Creates a new set of sessions, with no trades
+    pub fn new(config: TradeConfig) -> Self {
+        Self {
+            config,
+            sessions: HashMap::new(),
+            players: HashMap::new(),
+            next_id: 1,
+        }
+    }
+
+    ///
+    /// Returns the trade the player is part of, if any
+    pub fn trade_of(&self, player: UUID) -> Option<UUID> {
+        self.players.get(&player).copied()
+    }
+
+    ///
+    /// Returns the number of trades in progress
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    ///
+    /// Checks if no trades are in progress
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    fn check_offer(&self, pokemon: &[TradePokemon]) -> Result<(), TradeError> {
+        if pokemon.len() > self.config.max_pokemon {
+            return Err(TradeError::TooManyPokemon(self.config.max_pokemon));
+        }
+        let mut ids = HashSet::new();
+        for p in pokemon {
+            if !ids.insert(p.id) {
+                return Err(TradeError::DuplicatePokemon(p.id));
+            }
+        }
+        Ok(())
+    }
+
+    fn open(
+        &mut self,
+        from: UUID,
+        offer: &TradeOffer,
+        now: Instant,
+    ) -> Result<Vec<Outgoing>, TradeError> {
+        if from == offer.to {
+            return Err(TradeError::SelfTrade);
+        }
+        for player in [from, offer.to] {
+            if self.players.contains_key(&player) {
+                return Err(TradeError::Busy(player));
+            }
+        }
+        self.check_offer(&offer.pokemon)?;
+        // Trade ids only need to be unique on this server
+        let trade = UUID::new(0, self.next_id);
+        self.next_id += 1;
+        let session = Session {
+            players: [from, offer.to],
+            offers: [offer.pokemon.0.clone(), Vec::new()],
+            revision: 0,
+            locked: [false; 2],
+            confirmed: [false; 2],
+            phase: Phase::Negotiating,
+            deadline: now + self.config.negotiation_timeout,
+        };
+        let outgoing = to_both(
+            &session,
+            TradeOffer {
+                trade,
+                from,
+                to: offer.to,
+                pokemon: offer.pokemon.clone(),
+            },
+        );
+        self.players.insert(from, trade);
+        self.players.insert(offer.to, trade);
+        self.sessions.insert(trade, session);
+        Ok(outgoing)
+    }
+
+    // Ends the trade, rolling it back if it was prepared, and tells both players why
+    fn abort<S: TradeStore + ?Sized>(
+        &mut self,
+        store: &S,
+        trade: UUID,
+        error: &TradeError,
+    ) -> Vec<Outgoing> {
+        let session = match self.sessions.remove(&trade) {
+            Some(session) => session,
+            None => return Vec::new(),
+        };
+        for player in &session.players {
+            self.players.remove(player);
+        }
+        if session.phase == Phase::Confirming {
+            store.rollback(trade);
+        }
+        to_both(
+            &session,
+            TradeAbort {
+                trade,
+                reason: error.reason(),
+            },
+        )
+    }
+
+    fn prepare<S: TradeStore + ?Sized>(
+        &mut self,
+        store: &S,
+        trade: UUID,
+        now: Instant,
+    ) -> Result<Vec<Outgoing>, TradeError> {
+        let session = self.sessions.get_mut(&trade).unwrap();
+        // The phase is set first, so the prepare of the first side is rolled back if the second fails
+        session.phase = Phase::Confirming;
+        session.confirmed = [false; 2];
+        session.deadline = now + self.config.confirm_timeout;
+        for side in 0..2 {
+            store
+                .prepare(trade, session.players[side], &session.offers[side])
+                .map_err(|e| TradeError::Store(e.to_string()))?;
+        }
+        Ok(to_both(
+            session,
+            TradeConfirm {
+                trade,
+                from: UUID::NIL,
+                revision: session.revision,
+            },
+        ))
+    }
+
+    fn update<S: TradeStore + ?Sized>(
+        &mut self,
+        store: &S,
+        from: UUID,
+        trade: UUID,
+        packet: &dyn AnyPacket,
+        now: Instant,
+    ) -> Result<Vec<Outgoing>, TradeError> {
+        let negotiation_timeout = self.config.negotiation_timeout;
+        if let Some(counter) = packet.downcast_ref::<TradeCounterOffer>() {
+            self.check_offer(&counter.pokemon)?;
+            let session = self.sessions.get_mut(&trade).unwrap();
+            if session.phase == Phase::Confirming {
+                // Changing an offer after both players locked the trade releases the prepared Pokémon
+                store.rollback(trade);
+                session.phase = Phase::Negotiating;
+            }
+            let side = session.side(from);
+            session.offers[side] = counter.pokemon.0.clone();
+            session.revision += 1;
+            session.locked = [false; 2];
+            session.deadline = now + negotiation_timeout;
+            Ok(to_both(
+                session,
+                TradeCounterOffer {
+                    trade,
+                    from,
+                    revision: session.revision,
+                    pokemon: counter.pokemon.clone(),
+                },
+            ))
+        } else if let Some(lock) = packet.downcast_ref::<TradeLock>() {
+            let session = self.sessions.get_mut(&trade).unwrap();
+            // A lock of an earlier revision crossed a counter-offer, and is ignored
+            if lock.revision != session.revision {
+                return Ok(Vec::new());
+            }
+            // Both sides are already locked once the trade is prepared, so a repeated lock changes nothing
+            if session.phase == Phase::Confirming {
+                return Ok(Vec::new());
+            }
+            let side = session.side(from);
+            session.locked[side] = true;
+            session.deadline = now + negotiation_timeout;
+            let mut outgoing = to_both(
+                session,
+                TradeLock {
+                    trade,
+                    from,
+                    revision: session.revision,
+                },
+            );
+            if session.locked == [true; 2] {
+                outgoing.extend(self.prepare(store, trade, now)?);
+            }
+            Ok(outgoing)
+        } else if let Some(confirm) = packet.downcast_ref::<TradeConfirm>() {
+            let session = self.sessions.get_mut(&trade).unwrap();
+            if confirm.revision != session.revision {
+                return Ok(Vec::new());
+            }
+            if session.phase != Phase::Confirming {
+                return Err(TradeError::WrongPhase);
+            }
+            let side = session.side(from);
+            session.confirmed[side] = true;
+            if session.confirmed != [true; 2] {
+                return Ok(Vec::new());
+            }
+            store
+                .commit(trade)
+                .map_err(|e| TradeError::Store(e.to_string()))?;
+            let session = self.sessions.remove(&trade).unwrap();
+            for player in &session.players {
+                self.players.remove(player);
+            }
+            Ok(to_both(&session, TradeCommit { trade }))
+        } else if let Some(abort) = packet.downcast_ref::<TradeAbort>() {
+            Err(TradeError::Cancelled(from, abort.reason.clone()))
+        } else {
+            Err(TradeError::WrongPhase)
+        }
+    }
+
+    ///
+    /// Handles a trade packet sent by a player.
+    /// Packets which are not part of a trade are ignored.
+    /// If the packet is invalid, the trade is cancelled
+    pub fn receive<S: TradeStore + ?Sized>(
+        &mut self,
+        store: &S,
+        from: UUID,
+        packet: &dyn AnyPacket,
+        now: Instant,
+    ) -> Vec<Outgoing> {
+        let mut outgoing = self.expire(store, now);
+        if let Some(offer) = packet.downcast_ref::<TradeOffer>() {
+            match self.open(from, offer, now) {
+                Ok(packets) => outgoing.extend(packets),
+                Err(e) => outgoing.push((
+                    from,
+                    Box::new(TradeAbort {
+                        trade: UUID::NIL,
+                        reason: e.reason(),
+                    }),
+                )),
+            }
+            return outgoing;
+        }
+        let trade = if let Some(p) = packet.downcast_ref::<TradeCounterOffer>() {
+            p.trade
+        } else if let Some(p) = packet.downcast_ref::<TradeLock>() {
+            p.trade
+        } else if let Some(p) = packet.downcast_ref::<TradeConfirm>() {
+            p.trade
+        } else if let Some(p) = packet.downcast_ref::<TradeAbort>() {
+            p.trade
+        } else {
+            return outgoing;
+        };
+        if self.trade_of(from) != Some(trade) {
+            // An abort may cross the end of the trade, so it is not answered
+            if !packet.is::<TradeAbort>() {
+                outgoing.push((
+                    from,
+                    Box::new(TradeAbort {
+                        trade,
+                        reason: TradeError::UnknownTrade(trade).reason(),
+                    }),
+                ));
+            }
+            return outgoing;
+        }
+        match self.update(store, from, trade, packet, now) {
+            Ok(packets) => outgoing.extend(packets),
+            Err(e) => outgoing.extend(self.abort(store, trade, &e)),
+        }
+        outgoing
+    }
+
+    ///
+    /// Cancels the trade of a player who disconnected, if any
+    pub fn disconnected<S: TradeStore + ?Sized>(
+        &mut self,
+        store: &S,
+        player: UUID,
+    ) -> Vec<Outgoing> {
+        match self.trade_of(player) {
+            Some(trade) => self.abort(store, trade, &TradeError::Disconnected(player)),
+            None => Vec::new(),
+        }
+    }
+
+    ///
+    /// Cancels every trade whose deadline is before now
+    pub fn expire<S: TradeStore + ?Sized>(&mut self, store: &S, now: Instant) -> Vec<Outgoing> {
+        let expired = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.deadline <= now)
+            .map(|(trade, _)| *trade)
+            .collect::<Vec<_>>();
+        expired
+            .into_iter()
+            .flat_map(|trade| self.abort(store, trade, &TradeError::TimedOut))
+            .collect()
+    }
+}
+
+#[cfg(feature = "tcp")]
+///
+/// A [`Handler`] which runs trades between the clients of a server, with a [`TradeStore`].
+/// Packets which are not part of a trade are ignored.
+/// Trades are expired whenever a packet is received, and on each tick of the server
+pub struct TradeService<S> {
+    store: S,
+    sessions: Mutex<TradeSessions>,
+    peers: Mutex<HashMap<UUID, Peer>>,
+}
+
+#[cfg(feature = "tcp")]
+impl<S: TradeStore> TradeService<S> {
+    ///
+    /// Creates a service which runs trades with the given store and limits
+    pub fn new(store: S, config: TradeConfig) -> Self {
+        Self {
+            store,
+            sessions: Mutex::new(TradeSessions::new(config)),
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    ///
+    /// Returns the store trades are committed to
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    ///
+    /// Returns the trade the player is part of, if any
+    pub fn trade_of(&self, player: UUID) -> Option<UUID> {
+        self.sessions.lock().unwrap().trade_of(player)
+    }
+
+    // Players who cannot be sent to have disconnected, and their trades are cancelled when the server notices
+    // Called with the sessions locked, so that each player receives packets in the order the trades changed
+    fn send(&self, outgoing: Vec<Outgoing>) {
+        let peers = self.peers.lock().unwrap();
+        for (player, packet) in outgoing {
+            if let Some(peer) = peers.get(&player) {
+                let _ = peer.send(&*packet);
+            }
+        }
+    }
+
+    ///
+    /// Handles a packet received from a client.
+    /// Returns false if the packet is not part of a trade, so that other handlers can be tried
+    pub fn handle(&self, peer: &Peer, packet: &dyn AnyPacket) -> bool {
+        if !is_trade_packet(packet) {
+            return false;
+        }
+        let mut sessions = self.sessions.lock().unwrap();
+        // Checked while the sessions are locked, so that a partner who disconnects now has the trade cancelled
+        let outgoing = match packet.downcast_ref::<TradeOffer>() {
+            Some(offer) if !self.peers.lock().unwrap().contains_key(&offer.to) => vec![(
+                peer.client(),
+                Box::new(TradeAbort {
+                    trade: UUID::NIL,
+                    reason: TradeError::Unavailable(offer.to).reason(),
+                }) as Box<dyn AnyPacket>,
+            )],
+            _ => sessions.receive(&self.store, peer.client(), packet, Instant::now()),
+        };
+        self.send(outgoing);
+        true
+    }
+
+    ///
+    /// Cancels every trade which has timed out
+    pub fn expire(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let outgoing = sessions.expire(&self.store, Instant::now());
+        self.send(outgoing);
+    }
+}
+
+#[cfg(feature = "tcp")]
+impl<S: TradeStore> Handler for TradeService<S> {
+    fn connected(&self, peer: &Peer) -> std::io::Result<()> {
+        self.peers
+            .lock()
+            .unwrap()
+            .insert(peer.client(), peer.clone());
+        Ok(())
+    }
+
+    fn packet(&self, peer: &Peer, packet: Box<dyn AnyPacket>) -> std::io::Result<()> {
+        self.handle(peer, &*packet);
+        Ok(())
+    }
+
+    fn disconnected(&self, peer: &Peer, _: Option<&std::io::Error>) {
+        self.peers.lock().unwrap().remove(&peer.client());
+        let mut sessions = self.sessions.lock().unwrap();
+        let outgoing = sessions.disconnected(&self.store, peer.client());
+        self.send(outgoing);
+    }
+
+    fn tick(&self) {
+        self.expire();
+    }
+}
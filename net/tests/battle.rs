@@ -0,0 +1,515 @@
+#![cfg(feature = "tcp")]
+
+mod common;
+
+use std::{
+    io::ErrorKind,
+    time::{Duration, Instant},
+};
+
+use binary_io::{
+    nbt::{compound::NbtCompound, NbtTag},
+    uuid::UUID,
+};
+use net::{
+    battle::{
+        combine_seeds, ActionSubmit, BattleAccept, BattleAction, BattleChallenge, BattleConfig,
+        BattleEnd, BattleEvent, BattleForfeit, BattleRecord, BattleRng, BattleService,
+        BattleSessions, BattleSimulator, BattleSpectate, Outcome, Seed, TeamPreview, Turn,
+        TurnResult, VerifyError,
+    },
+    digest::Digest,
+    harness::Harness,
+    packet::{AnyPacket, List, Outgoing, Packet, PacketRegistry},
+    server::{Server, ServerConfig},
+};
+use text::TextComponent;
+
+use common::packets;
+
+const ASH: UUID = UUID::new(1, 1);
+const GARY: UUID = UUID::new(1, 2);
+const BROCK: UUID = UUID::new(1, 3);
+const SERVER_SEED: Seed = [7; 32];
+
+// The first Pokémon of each team attacks the other, for its power plus up to 9 damage
+struct Duel;
+
+fn stat(pokemon: &NbtCompound, name: &str) -> std::io::Result<i32> {
+    match pokemon.get(name) {
+        Some(NbtTag::Int(n)) => Ok(*n),
+        _ => Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("missing {}", name),
+        )),
+    }
+}
+
+impl BattleSimulator for Duel {
+    type State = [(i32, i32); 2];
+
+    fn start(
+        &self,
+        _: &str,
+        teams: [&[NbtCompound]; 2],
+        _: &mut BattleRng,
+    ) -> std::io::Result<Self::State> {
+        let mut state = [(0, 0); 2];
+        for (side, team) in teams.iter().enumerate() {
+            state[side] = (stat(&team[0], "hp")?, stat(&team[0], "power")?);
+        }
+        Ok(state)
+    }
+
+    fn turn(
+        &self,
+        state: &mut Self::State,
+        actions: [&BattleAction; 2],
+        rng: &mut BattleRng,
+    ) -> Turn {
+        let mut turn = Turn::default();
+        for (side, action) in actions.iter().enumerate() {
+            let other = 1 - side;
+            match action {
+                BattleAction::Move(index) => {
+                    turn.events.push(BattleEvent::Move {
+                        side: side as u8,
+                        index: *index,
+                    });
+                    let amount = (state[side].1 + rng.below(10) as i32).min(state[other].0);
+                    state[other].0 -= amount;
+                    turn.events.push(BattleEvent::Damage {
+                        side: other as u8,
+                        amount: amount as u16,
+                        remaining: state[other].0 as u16,
+                    });
+                    if state[other].0 == 0 {
+                        turn.events.push(BattleEvent::Faint { side: other as u8 });
+                        turn.outcome = Some(Outcome::Winner(side));
+                        return turn;
+                    }
+                }
+                BattleAction::Run => {
+                    turn.events.push(BattleEvent::Fled { side: side as u8 });
+                    turn.outcome = Some(Outcome::Winner(other));
+                    return turn;
+                }
+                _ => {}
+            }
+        }
+        turn
+    }
+}
+
+fn team(hp: i32, power: i32) -> List<NbtCompound> {
+    let mut pokemon = NbtCompound::new();
+    pokemon.insert("hp".to_string(), NbtTag::Int(hp));
+    pokemon.insert("power".to_string(), NbtTag::Int(power));
+    List(vec![pokemon])
+}
+
+fn sessions() -> BattleSessions<Duel> {
+    BattleSessions::new(Duel, BattleConfig::default()).with_seeds(|| SERVER_SEED)
+}
+
+fn harness() -> Harness<BattleService<Duel>> {
+    let service = BattleService::new(sessions());
+    let server = Server::new(ServerConfig::default(), PacketRegistry::pkmcom(), service);
+    let mut harness = Harness::new(server, PacketRegistry::pkmcom());
+    for player in [ASH, GARY, BROCK] {
+        harness.join(player);
+    }
+    harness
+}
+
+// Takes the player's next packet, which must be a P, and records it
+fn expect<P: Packet + Clone>(
+    harness: &mut Harness<BattleService<Duel>>,
+    player: UUID,
+    record: &mut BattleRecord,
+) -> P {
+    let packet = harness.expect::<P>(player);
+    record.receive(&packet);
+    packet
+}
+
+fn preview(battle: UUID, team: List<NbtCompound>, seed: u8) -> TeamPreview {
+    TeamPreview {
+        battle,
+        from: UUID::NIL,
+        team,
+        seed: [seed; 32],
+    }
+}
+
+// Challenges Gary, and sends both teams, returning the battle id
+fn start(harness: &mut Harness<BattleService<Duel>>, records: &mut [BattleRecord; 2]) -> UUID {
+    let [ash, gary] = records;
+    harness
+        .send(
+            ASH,
+            &BattleChallenge {
+                battle: UUID::NIL,
+                from: UUID::NIL,
+                to: GARY,
+                format: "singles".to_string(),
+                commitment: Digest::default(),
+            },
+        )
+        .unwrap();
+    harness.settle();
+    let challenge = expect::<BattleChallenge>(harness, ASH, ash);
+    assert_eq!(expect::<BattleChallenge>(harness, GARY, gary), challenge);
+    assert_eq!((challenge.from, challenge.to), (ASH, GARY));
+    assert_eq!(challenge.commitment, Digest::of_bytes(&SERVER_SEED));
+    let battle = challenge.battle;
+
+    harness
+        .send(
+            GARY,
+            &BattleAccept {
+                battle,
+                from: UUID::NIL,
+            },
+        )
+        .unwrap();
+    harness.settle();
+    assert_eq!(expect::<BattleAccept>(harness, ASH, ash).from, GARY);
+    expect::<BattleAccept>(harness, GARY, gary);
+
+    harness
+        .send(ASH, &preview(battle, team(40, 10), 1))
+        .unwrap();
+    harness
+        .send(GARY, &preview(battle, team(25, 8), 2))
+        .unwrap();
+    harness.settle();
+    for (player, record) in [(ASH, ash), (GARY, gary)] {
+        let previews = [
+            expect::<TeamPreview>(harness, player, record),
+            expect::<TeamPreview>(harness, player, record),
+        ];
+        assert_eq!((previews[0].from, previews[1].from), (ASH, GARY));
+        assert_eq!(previews[1].team, team(25, 8));
+    }
+    battle
+}
+
+#[test]
+fn battle_is_simulated_and_verified_by_players_and_spectators() {
+    let mut harness = harness();
+    let mut records = [BattleRecord::new(), BattleRecord::new()];
+    let battle = start(&mut harness, &mut records);
+    assert_eq!(harness.handler().battle_of(ASH), Some(battle));
+
+    let mut spectator = BattleRecord::new();
+    let mut turn = 1;
+    let end = loop {
+        for (player, index) in [(ASH, 0), (GARY, 1)] {
+            harness
+                .send(
+                    player,
+                    &ActionSubmit {
+                        battle,
+                        turn,
+                        action: BattleAction::Move(index),
+                    },
+                )
+                .unwrap();
+        }
+        harness.settle();
+        let result = expect::<TurnResult>(&mut harness, ASH, &mut records[0]);
+        assert_eq!(
+            expect::<TurnResult>(&mut harness, GARY, &mut records[1]),
+            result
+        );
+        assert_eq!(result.turn, turn);
+        assert_eq!(
+            result.actions,
+            List(vec![BattleAction::Move(0), BattleAction::Move(1)])
+        );
+        if turn == 1 {
+            // Brock joins after the first turn, and is sent the battle so far
+            harness.send(BROCK, &BattleSpectate { battle }).unwrap();
+            harness.settle();
+            expect::<BattleChallenge>(&mut harness, BROCK, &mut spectator);
+            expect::<BattleAccept>(&mut harness, BROCK, &mut spectator);
+            expect::<TeamPreview>(&mut harness, BROCK, &mut spectator);
+            expect::<TeamPreview>(&mut harness, BROCK, &mut spectator);
+        }
+        assert_eq!(
+            expect::<TurnResult>(&mut harness, BROCK, &mut spectator),
+            result
+        );
+        if result
+            .events
+            .iter()
+            .any(|event| matches!(event, BattleEvent::Faint { .. }))
+        {
+            let end = expect::<BattleEnd>(&mut harness, ASH, &mut records[0]);
+            assert_eq!(
+                expect::<BattleEnd>(&mut harness, GARY, &mut records[1]),
+                end
+            );
+            assert_eq!(
+                expect::<BattleEnd>(&mut harness, BROCK, &mut spectator),
+                end
+            );
+            break end;
+        }
+        turn += 1;
+    };
+
+    // Ash attacks first, and Gary cannot deal 40 damage in the three turns Ash needs at most
+    assert_eq!(end.winner, ASH);
+    assert_eq!(end.seed, SERVER_SEED);
+    assert_eq!(
+        end.reason,
+        TextComponent::RawText(format!("{} won the battle", ASH))
+    );
+    for record in records.iter().chain([&spectator]) {
+        assert_eq!(record.verify(&Duel), Ok(()));
+    }
+    assert_eq!(harness.handler().battle_of(ASH), None);
+}
+
+// Starts a battle directly on the sessions, returning its id and the packets sent to Ash
+fn started(sessions: &mut BattleSessions<Duel>, now: Instant) -> (UUID, Vec<Outgoing>) {
+    let challenge = BattleChallenge {
+        battle: UUID::NIL,
+        from: UUID::NIL,
+        to: GARY,
+        format: "singles".to_string(),
+        commitment: Digest::default(),
+    };
+    let mut out = sessions.receive(ASH, &challenge, now);
+    let battle = sessions.battle_of(ASH).unwrap();
+    let accept = BattleAccept {
+        battle,
+        from: UUID::NIL,
+    };
+    out.extend(sessions.receive(GARY, &accept, now));
+    out.extend(sessions.receive(ASH, &preview(battle, team(40, 10), 1), now));
+    out.extend(sessions.receive(GARY, &preview(battle, team(25, 8), 2), now));
+    out.retain(|(player, _)| *player == ASH);
+    (battle, out)
+}
+
+// Plays a battle directly on the sessions until it ends, returning the packets sent to Ash
+fn played() -> Vec<Box<dyn AnyPacket>> {
+    let mut sessions = sessions();
+    let now = Instant::now();
+    let (battle, mut out) = started(&mut sessions, now);
+    let mut turn = 1;
+    while !out.iter().any(|(_, packet)| packet.is::<BattleEnd>()) {
+        for player in [ASH, GARY] {
+            let submit = ActionSubmit {
+                battle,
+                turn,
+                action: BattleAction::Move(0),
+            };
+            out.extend(sessions.receive(player, &submit, now));
+        }
+        turn += 1;
+    }
+    out.into_iter()
+        .filter(|(player, packet)| *player == ASH && !packet.is::<BattleAccept>())
+        .map(|(_, packet)| packet)
+        .collect()
+}
+
+fn record(packets: &[Box<dyn AnyPacket>]) -> BattleRecord {
+    let mut record = BattleRecord::new();
+    for packet in packets {
+        assert!(record.receive(&**packet));
+    }
+    record
+}
+
+// Copies the packets which a record keeps
+fn copy(packet: &dyn AnyPacket) -> Box<dyn AnyPacket> {
+    if let Some(p) = packet.downcast_ref::<BattleChallenge>() {
+        Box::new(p.clone())
+    } else if let Some(p) = packet.downcast_ref::<TeamPreview>() {
+        Box::new(p.clone())
+    } else if let Some(p) = packet.downcast_ref::<TurnResult>() {
+        Box::new(p.clone())
+    } else {
+        Box::new(packet.downcast_ref::<BattleEnd>().unwrap().clone())
+    }
+}
+
+#[test]
+fn tampered_battles_fail_verification() {
+    let packets = played();
+    assert_eq!(record(&packets).verify(&Duel), Ok(()));
+
+    // The first turn claims Gary fainted at once
+    let tampered = packets
+        .iter()
+        .map(|packet| match packet.downcast_ref::<TurnResult>() {
+            Some(result) if result.turn == 1 => {
+                let mut result = result.clone();
+                result.events.0[1] = BattleEvent::Damage {
+                    side: 1,
+                    amount: 25,
+                    remaining: 0,
+                };
+                Box::new(result) as Box<dyn AnyPacket>
+            }
+            _ => copy(&**packet),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        record(&tampered).verify(&Duel),
+        Err(VerifyError::Diverged(1))
+    );
+
+    // The server reveals a seed other than the one it committed to
+    let wrong_seed = packets
+        .iter()
+        .map(|packet| match packet.downcast_ref::<BattleEnd>() {
+            Some(end) => Box::new(BattleEnd {
+                seed: [8; 32],
+                ..end.clone()
+            }) as Box<dyn AnyPacket>,
+            None => copy(&**packet),
+        })
+        .collect::<Vec<_>>();
+    let e = record(&wrong_seed).verify(&Duel).unwrap_err();
+    assert_eq!(e, VerifyError::CommitmentMismatch);
+    assert_eq!(std::io::Error::from(e).kind(), ErrorKind::InvalidData);
+
+    assert_eq!(
+        record(&packets[..packets.len() - 1]).verify(&Duel),
+        Err(VerifyError::Incomplete)
+    );
+}
+
+#[test]
+fn players_who_do_not_act_in_time_lose() {
+    let config = BattleConfig::default();
+    let turn_timeout = config.turn_timeout;
+    let mut sessions = BattleSessions::new(Duel, config).with_seeds(|| SERVER_SEED);
+    let now = Instant::now();
+    let (battle, _) = started(&mut sessions, now);
+    let submit = ActionSubmit {
+        battle,
+        turn: 1,
+        action: BattleAction::Move(0),
+    };
+    assert!(sessions.receive(GARY, &submit, now).is_empty());
+    // A second action, or an action for another turn, is ignored
+    assert!(sessions.receive(GARY, &submit, now).is_empty());
+    let late = ActionSubmit { turn: 2, ..submit };
+    assert!(sessions.receive(ASH, &late, now).is_empty());
+
+    assert!(sessions
+        .expire(now + turn_timeout - Duration::from_secs(1))
+        .is_empty());
+    let out = sessions.expire(now + turn_timeout);
+    for player in [ASH, GARY] {
+        assert_eq!(
+            packets::<BattleEnd>(&out, player),
+            vec![BattleEnd {
+                battle,
+                winner: GARY,
+                reason: TextComponent::RawText("Timed out".to_string()),
+                seed: SERVER_SEED,
+            }]
+        );
+    }
+    assert!(sessions.is_empty());
+    assert_eq!(sessions.battle_of(ASH), None);
+}
+
+#[test]
+fn forfeits_and_disconnects_end_battles() {
+    let mut sessions = sessions();
+    let now = Instant::now();
+
+    // Withdrawing a challenge is not a loss
+    let challenge = BattleChallenge {
+        battle: UUID::NIL,
+        from: UUID::NIL,
+        to: GARY,
+        format: "singles".to_string(),
+        commitment: Digest::default(),
+    };
+    sessions.receive(ASH, &challenge, now);
+    let battle = sessions.battle_of(GARY).unwrap();
+    // Only Gary can accept
+    let accept = BattleAccept {
+        battle,
+        from: UUID::NIL,
+    };
+    assert!(sessions.receive(ASH, &accept, now).is_empty());
+    let out = sessions.receive(ASH, &BattleForfeit { battle }, now);
+    assert_eq!(packets::<BattleEnd>(&out, GARY)[0].winner, UUID::NIL);
+    assert!(sessions.is_empty());
+
+    let (battle, _) = started(&mut sessions, now);
+    let out = sessions.receive(GARY, &BattleForfeit { battle }, now);
+    let end = &packets::<BattleEnd>(&out, ASH)[0];
+    assert_eq!(end.winner, ASH);
+    assert_eq!(
+        end.reason,
+        TextComponent::RawText(format!("{} forfeited", GARY))
+    );
+
+    let (battle, _) = started(&mut sessions, now);
+    assert_eq!(
+        sessions
+            .receive(BROCK, &BattleSpectate { battle }, now)
+            .len(),
+        4
+    );
+    let out = sessions.disconnected(ASH);
+    assert_eq!(packets::<BattleEnd>(&out, BROCK)[0].winner, GARY);
+    assert!(sessions.is_empty());
+
+    // Nobody can battle themselves, or two battles at once
+    let to_self = BattleChallenge {
+        to: ASH,
+        ..challenge.clone()
+    };
+    assert_eq!(
+        packets::<BattleEnd>(&sessions.receive(ASH, &to_self, now), ASH)[0].battle,
+        UUID::NIL
+    );
+    sessions.receive(ASH, &challenge, now);
+    let out = sessions.receive(
+        BROCK,
+        &BattleChallenge {
+            to: GARY,
+            ..challenge
+        },
+        now,
+    );
+    assert_eq!(
+        packets::<BattleEnd>(&out, BROCK)[0].reason,
+        TextComponent::RawText(format!("{} is already battling", GARY))
+    );
+}
+
+#[test]
+fn battle_rng_is_deterministic() {
+    let seed = combine_seeds(&SERVER_SEED, [&[1; 32], &[2; 32]]);
+    assert_ne!(seed, combine_seeds(&SERVER_SEED, [&[2; 32], &[1; 32]]));
+    let mut a = BattleRng::new(seed);
+    let mut b = BattleRng::new(seed);
+    let a = (0..100).map(|_| a.below(6)).collect::<Vec<_>>();
+    let b = (0..100).map(|_| b.below(6)).collect::<Vec<_>>();
+    assert_eq!(a, b);
+    assert!(a.iter().all(|n| *n < 6));
+    assert!((0..6).all(|n| a.contains(&n)));
+
+    // Reading in pieces yields the same stream, across block boundaries
+    let mut whole = [0; 40];
+    BattleRng::new(seed).fill(&mut whole);
+    let mut rng = BattleRng::new(seed);
+    let mut pieces = [0; 40];
+    rng.fill(&mut pieces[..30]);
+    rng.fill(&mut pieces[30..]);
+    assert_eq!(whole, pieces);
+}
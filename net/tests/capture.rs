@@ -0,0 +1,233 @@
+#![cfg(feature = "tcp")]
+
+mod common;
+
+use std::{
+    io::{ErrorKind, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use binary_io::uuid::UUID;
+use net::{
+    capture::{pretty_print, Capture, CaptureError, CaptureReader, CapturedFrame, Replay},
+    client::{self, ClientConfig},
+    connection::Side,
+    frame::Frame,
+    handshake::{Handshake, LoginAccept},
+    loopback,
+    packet::{
+        AnyPacket, ChatBroadcast, ChatMessage, Direction, Disconnect, Packet, PacketRegistry,
+    },
+    server::{Server, ServerConfig},
+};
+use text::TextComponent;
+
+use common::{say, Echo};
+
+const ASH: UUID = UUID::new(1, 1);
+
+// A capture output which can be read while the capture is still open
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Buffer {
+    fn bytes(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Logs in to a server running a greeting Echo, chats, and disconnects, returning the capture recorded by the client
+fn chat(server_config: ServerConfig) -> Vec<u8> {
+    let server = Arc::new(Server::new(
+        server_config,
+        PacketRegistry::pkmcom(),
+        Echo::greeting(),
+    ));
+    let (client, transport) = loopback::pair();
+    let serving = server.spawn(transport);
+    let buffer = Buffer::default();
+    let mut config = ClientConfig::new(ASH, Vec::new());
+    config.capture = Some(Capture::new(buffer.clone(), Side::Client).unwrap());
+    let mut conn = client::login(client, &config, PacketRegistry::pkmcom()).unwrap();
+    assert!(conn.capture().is_some());
+    conn.receive().unwrap().unwrap();
+    for message in ["hello", "goodbye"] {
+        conn.send(&say(message)).unwrap();
+        conn.receive().unwrap().unwrap();
+    }
+    conn.sender()
+        .disconnect(TextComponent::RawText("Leaving".to_string()))
+        .unwrap();
+    serving.join().unwrap().unwrap();
+    buffer.bytes()
+}
+
+fn frames(bytes: &[u8]) -> Vec<CapturedFrame> {
+    let reader = CaptureReader::new(bytes).unwrap();
+    assert_eq!(reader.side(), Side::Client);
+    reader.collect::<std::io::Result<_>>().unwrap()
+}
+
+fn ids(packets: &[Box<dyn AnyPacket>]) -> Vec<u16> {
+    packets.iter().map(|packet| packet.id()).collect()
+}
+
+#[test]
+fn client_captures_every_frame_in_order() {
+    let frames = frames(&chat(ServerConfig::default()));
+    let summary = frames
+        .iter()
+        .map(|frame| (frame.direction, frame.frame.id))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        vec![
+            (Direction::Serverbound, Handshake::ID),
+            (Direction::Clientbound, LoginAccept::ID),
+            (Direction::Clientbound, ChatBroadcast::ID),
+            (Direction::Serverbound, ChatMessage::ID),
+            (Direction::Clientbound, ChatBroadcast::ID),
+            (Direction::Serverbound, ChatMessage::ID),
+            (Direction::Clientbound, ChatBroadcast::ID),
+            (Direction::Serverbound, Disconnect::ID),
+        ]
+    );
+    assert!(frames.windows(2).all(|w| w[0].elapsed <= w[1].elapsed));
+    // Frames are captured uncompressed, even though compression was negotiated
+    let registry = PacketRegistry::pkmcom();
+    let accept = frames[1].decode(&registry).unwrap();
+    assert_ne!(accept.downcast_ref::<LoginAccept>().unwrap().compression, 0);
+    assert_eq!(
+        frames[3].decode(&registry).unwrap().downcast_ref(),
+        Some(&say("hello"))
+    );
+}
+
+#[test]
+fn captures_are_pretty_printed() {
+    let bytes = chat(ServerConfig::default());
+    let mut reader = CaptureReader::new(&bytes[..]).unwrap();
+    let mut output = Vec::new();
+    pretty_print(&mut reader, &PacketRegistry::pkmcom(), &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 9);
+    assert!(lines[0].starts_with("# Capture of the client side of a connection"));
+    assert!(lines[1].contains("serverbound 0x0000 Handshake {"));
+    assert!(lines[4].contains("serverbound 0x0010 ChatMessage { message: LongString(\"hello\") }"));
+
+    // Packets which cannot be decoded are shown as bytes
+    let unknown = CapturedFrame {
+        elapsed: Duration::from_micros(1500),
+        direction: Direction::Clientbound,
+        frame: Frame::new(0x7fff, vec![1, 2, 3]),
+    };
+    let line = unknown.describe(&PacketRegistry::pkmcom());
+    assert!(line.starts_with("       1.500ms clientbound 0x7fff <"));
+    assert!(line.ends_with("> 3 bytes: [01, 02, 03]"));
+}
+
+#[test]
+fn replayed_client_gets_the_same_responses() {
+    let bytes = chat(ServerConfig::default());
+    let replay = Replay::read(
+        CaptureReader::new(&bytes[..]).unwrap(),
+        PacketRegistry::pkmcom(),
+    )
+    .unwrap();
+    assert_eq!(replay.client(), Some(ASH));
+
+    let recorded = replay.packets(Direction::Clientbound).unwrap();
+    let replayed = replay.to_server(&Echo::greeting()).unwrap();
+    assert_eq!(ids(&replayed), vec![ChatBroadcast::ID; 3]);
+    for (recorded, replayed) in recorded.iter().zip(&replayed) {
+        assert_eq!(
+            recorded.downcast_ref::<ChatBroadcast>(),
+            replayed.downcast_ref::<ChatBroadcast>()
+        );
+    }
+
+    // The client sees the server's packets, and its responses are collected
+    let mut seen = Vec::new();
+    let sent = replay
+        .to_client(|sender, packet| {
+            seen.push(packet.id());
+            sender.send(&say("ok"))
+        })
+        .unwrap();
+    assert_eq!(seen, vec![ChatBroadcast::ID; 3]);
+    assert_eq!(ids(&sent), vec![ChatMessage::ID; 3]);
+}
+
+#[test]
+fn server_writes_a_capture_of_each_connection() {
+    let dir = std::env::temp_dir().join(format!("pkmcom-capture-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = ServerConfig {
+        capture_dir: Some(dir.clone()),
+        ..Default::default()
+    };
+    let client_capture = chat(config);
+    let files = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    assert_eq!(files.len(), 1);
+    assert!(files[0].to_str().unwrap().ends_with("-0.pkmcap"));
+
+    let reader = CaptureReader::open(&files[0]).unwrap();
+    assert_eq!(reader.side(), Side::Server);
+    let server_frames = reader.collect::<std::io::Result<Vec<_>>>().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    // Both sides saw the same frames
+    let strip = |frames: Vec<CapturedFrame>| {
+        frames
+            .into_iter()
+            .map(|frame| (frame.direction, frame.frame))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(strip(server_frames), strip(frames(&client_capture)));
+}
+
+#[test]
+fn malformed_captures_are_rejected() {
+    let e = CaptureReader::new(&b"PCAP\0\x01\0\0\0\0\0\0\0\0\0"[..])
+        .err()
+        .unwrap();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    assert_eq!(
+        e.get_ref().and_then(|e| e.downcast_ref::<CaptureError>()),
+        Some(&CaptureError::BadMagic(*b"PCAP"))
+    );
+
+    let buffer = Buffer::default();
+    let capture = Capture::new(buffer.clone(), Side::Server).unwrap();
+    capture
+        .record(Direction::Clientbound, &Frame::new(0x0011, vec![0; 16]))
+        .unwrap();
+    assert_eq!(
+        capture
+            .record(Direction::Bidirectional, &Frame::new(0x0011, Vec::new()))
+            .unwrap_err()
+            .kind(),
+        ErrorKind::InvalidInput
+    );
+    let bytes = buffer.bytes();
+    let mut reader = CaptureReader::new(&bytes[..bytes.len() - 1]).unwrap();
+    assert_eq!(
+        reader.next_frame().unwrap_err().kind(),
+        ErrorKind::UnexpectedEof
+    );
+}
@@ -0,0 +1,275 @@
+#![cfg(feature = "tcp")]
+
+mod common;
+
+use std::collections::HashMap;
+
+use binary_io::uuid::UUID;
+use net::{
+    chat::{
+        Channel, ChatBlock, ChatConfig, ChatService, ChatUnblock, ChatWhisper, PartyChat, Roster,
+        Verdict,
+    },
+    harness::Harness,
+    packet::{ChatBroadcast, LongString, PacketRegistry},
+    server::{Server, ServerConfig},
+};
+use text::{Color, Style, TextComponent};
+
+use common::{heard, say};
+
+const ASH: UUID = UUID::new(1, 1);
+const GARY: UUID = UUID::new(1, 2);
+const MISTY: UUID = UUID::new(1, 3);
+const BROCK: UUID = UUID::new(1, 4);
+const PARTY: UUID = UUID::new(2, 1);
+
+struct Names(HashMap<UUID, TextComponent>);
+
+impl Roster for Names {
+    fn name(&self, player: UUID) -> Option<TextComponent> {
+        self.0.get(&player).cloned()
+    }
+}
+
+fn ash() -> TextComponent {
+    TextComponent::Text {
+        text: "Ash".to_string(),
+        style: Some(Style {
+            color: Some(Color::Red),
+            bold: true,
+            ..Default::default()
+        }),
+    }
+}
+
+fn service() -> ChatService<Names> {
+    let mut names = HashMap::new();
+    names.insert(ASH, ash());
+    names.insert(GARY, TextComponent::RawText("Gary".to_string()));
+    names.insert(MISTY, TextComponent::RawText("Misty".to_string()));
+    ChatService::new(Names(names), ChatConfig::default())
+}
+
+fn harness(service: ChatService<Names>) -> Harness<ChatService<Names>> {
+    let server = Server::new(ServerConfig::default(), PacketRegistry::pkmcom(), service);
+    let mut harness = Harness::new(server, PacketRegistry::pkmcom());
+    for player in [ASH, GARY, MISTY] {
+        harness.join(player);
+    }
+    harness
+}
+
+fn text(message: &str) -> LongString {
+    LongString(message.to_string())
+}
+
+#[test]
+fn messages_are_formatted_with_the_senders_name() {
+    let mut harness = harness(service());
+    harness.send(ASH, &say("  hello  ")).unwrap();
+    harness.settle();
+    for player in [ASH, GARY, MISTY] {
+        let broadcast = harness.expect::<ChatBroadcast>(player);
+        assert_eq!(broadcast.sender, ASH);
+        assert_eq!(
+            broadcast.message,
+            TextComponent::ImplicitGroup(vec![
+                ash(),
+                TextComponent::RawText(": ".to_string()),
+                TextComponent::RawText("hello".to_string())
+            ])
+        );
+    }
+
+    // Players the roster does not know are named by their UUID
+    let service = harness.handler();
+    assert_eq!(
+        service
+            .format(BROCK, Channel::Global, ASH, "hi")
+            .to_string(),
+        format!("{}: hi", BROCK)
+    );
+    // Blank messages are not sent
+    harness.send(GARY, &say("   ")).unwrap();
+    harness.settle();
+    harness.expect_nothing(ASH);
+}
+
+#[test]
+fn parties_and_whispers_only_reach_their_recipients() {
+    let mut harness = harness(service());
+    harness.handler().join_party(ASH, PARTY);
+    harness.handler().join_party(MISTY, PARTY);
+    harness
+        .send(
+            MISTY,
+            &PartyChat {
+                message: text("Onix ahead"),
+            },
+        )
+        .unwrap();
+    harness
+        .send(
+            GARY,
+            &PartyChat {
+                message: text("hi"),
+            },
+        )
+        .unwrap();
+    harness.settle();
+    for player in [ASH, MISTY] {
+        assert_eq!(
+            heard(&mut harness, player),
+            vec![(MISTY, "[Party] Misty: Onix ahead".to_string())]
+        );
+    }
+    assert_eq!(
+        heard(&mut harness, GARY),
+        vec![(UUID::NIL, "You are not in a party".to_string())]
+    );
+
+    harness
+        .send(
+            ASH,
+            &ChatWhisper {
+                to: GARY,
+                message: text("smell ya later"),
+            },
+        )
+        .unwrap();
+    harness
+        .send(
+            ASH,
+            &ChatWhisper {
+                to: BROCK,
+                message: text("where are you?"),
+            },
+        )
+        .unwrap();
+    harness.settle();
+    assert_eq!(
+        heard(&mut harness, GARY),
+        vec![(ASH, "Ash whispers to you: smell ya later".to_string())]
+    );
+    assert_eq!(
+        heard(&mut harness, ASH),
+        vec![
+            (ASH, "You whisper to Gary: smell ya later".to_string()),
+            (UUID::NIL, format!("{} is not online", BROCK))
+        ]
+    );
+    harness.expect_nothing(MISTY);
+
+    harness.handler().leave_party(MISTY);
+    assert_eq!(harness.handler().party_of(MISTY), None);
+    assert_eq!(harness.handler().party_of(ASH), Some(PARTY));
+}
+
+#[test]
+fn blocked_and_muted_players_are_not_heard() {
+    let mut harness = harness(service());
+    harness.send(MISTY, &ChatBlock { player: ASH }).unwrap();
+    harness.send(ASH, &say("hi all")).unwrap();
+    harness
+        .send(
+            ASH,
+            &ChatWhisper {
+                to: MISTY,
+                message: text("hi Misty"),
+            },
+        )
+        .unwrap();
+    harness.settle();
+    assert!(harness.handler().is_blocked(MISTY, ASH));
+    harness.expect_nothing(MISTY);
+    assert_eq!(heard(&mut harness, GARY).len(), 1);
+    // Ash is not told that Misty blocked them
+    assert_eq!(
+        heard(&mut harness, ASH),
+        vec![
+            (ASH, "Ash: hi all".to_string()),
+            (ASH, "You whisper to Misty: hi Misty".to_string())
+        ]
+    );
+
+    harness.send(MISTY, &ChatUnblock { player: ASH }).unwrap();
+    harness.send(ASH, &say("hi again")).unwrap();
+    harness.settle();
+    assert_eq!(
+        heard(&mut harness, MISTY),
+        vec![(ASH, "Ash: hi again".to_string())]
+    );
+    for player in [ASH, GARY] {
+        assert_eq!(heard(&mut harness, player).len(), 1);
+    }
+
+    harness.handler().mute(GARY);
+    harness.send(GARY, &say("let me talk")).unwrap();
+    harness.settle();
+    assert_eq!(
+        heard(&mut harness, GARY),
+        vec![(UUID::NIL, "You are muted".to_string())]
+    );
+    for player in [ASH, MISTY] {
+        harness.expect_nothing(player);
+    }
+    harness.handler().unmute(GARY);
+    assert!(!harness.handler().is_muted(GARY));
+}
+
+#[test]
+fn filters_can_rewrite_or_reject_messages() {
+    let mut service = service();
+    service.set_filter(|_: UUID, channel: Channel, message: &str| {
+        if message.contains("buy coins") {
+            Verdict::Reject(TextComponent::RawText("No advertising".to_string()))
+        } else if channel == Channel::Global {
+            Verdict::Replace(message.replace("darn", "****"))
+        } else {
+            Verdict::Allow
+        }
+    });
+    let mut harness = harness(service);
+    harness.send(GARY, &say("darn it")).unwrap();
+    harness.send(GARY, &say("buy coins here")).unwrap();
+    harness
+        .send(
+            GARY,
+            &ChatWhisper {
+                to: ASH,
+                message: text("darn"),
+            },
+        )
+        .unwrap();
+    harness.send(GARY, &say(&"a".repeat(257))).unwrap();
+    harness.settle();
+    assert_eq!(
+        heard(&mut harness, GARY),
+        vec![
+            (GARY, "Gary: **** it".to_string()),
+            (UUID::NIL, "No advertising".to_string()),
+            (GARY, "You whisper to Ash: darn".to_string()),
+            (UUID::NIL, "Your message is too long".to_string())
+        ]
+    );
+    assert_eq!(
+        heard(&mut harness, ASH),
+        vec![
+            (GARY, "Gary: **** it".to_string()),
+            (GARY, "Gary whispers to you: darn".to_string())
+        ]
+    );
+
+    harness
+        .handler()
+        .announce(TextComponent::RawText("Server restarting".to_string()));
+    harness.settle();
+    assert_eq!(
+        heard(&mut harness, MISTY),
+        vec![
+            (GARY, "Gary: **** it".to_string()),
+            (UUID::NIL, "Server restarting".to_string())
+        ]
+    );
+}
@@ -0,0 +1,119 @@
+// Fixtures shared by the integration tests. Each test crate uses only some of them
+#![allow(dead_code)]
+
+#[cfg(feature = "tcp")]
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
+
+use binary_io::uuid::UUID;
+use net::packet::{ChatMessage, LongString, Outgoing, Packet};
+#[cfg(feature = "tcp")]
+use net::{
+    harness::Harness,
+    packet::{AnyPacket, ChatBroadcast},
+    server::{Handler, Peer},
+};
+#[cfg(feature = "tcp")]
+use text::TextComponent;
+
+pub fn say(message: &str) -> ChatMessage {
+    ChatMessage {
+        message: LongString(message.to_string()),
+    }
+}
+
+// Waits for f to hold, for at most 5 seconds
+pub fn eventually<F: FnMut() -> bool>(mut f: F) {
+    let start = Instant::now();
+    while !f() {
+        assert!(start.elapsed() < Duration::from_secs(5), "Timed out");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+// The packets of type P sent to a player
+pub fn packets<P: Packet + Clone>(outgoing: &[Outgoing], to: UUID) -> Vec<P> {
+    outgoing
+        .iter()
+        .filter(|(player, _)| *player == to)
+        .filter_map(|(_, packet)| packet.downcast_ref::<P>().cloned())
+        .collect()
+}
+
+// Broadcasts each message back to its sender, and reports each player who connects or disconnects
+#[cfg(feature = "tcp")]
+pub struct Echo {
+    greet: bool,
+    events: Mutex<mpsc::Sender<String>>,
+}
+
+#[cfg(feature = "tcp")]
+impl Default for Echo {
+    fn default() -> Self {
+        Self::new().0
+    }
+}
+
+#[cfg(feature = "tcp")]
+impl Echo {
+    pub fn new() -> (Self, mpsc::Receiver<String>) {
+        let (events, receiver) = mpsc::channel();
+        let echo = Self {
+            greet: false,
+            events: Mutex::new(events),
+        };
+        (echo, receiver)
+    }
+
+    // Also greets each player as they connect
+    pub fn greeting() -> Self {
+        Self {
+            greet: true,
+            ..Self::default()
+        }
+    }
+
+    fn report(&self, event: String) {
+        let _ = self.events.lock().unwrap().send(event);
+    }
+}
+
+#[cfg(feature = "tcp")]
+impl Handler for Echo {
+    fn connected(&self, peer: &Peer) -> std::io::Result<()> {
+        self.report(format!("connected {}", peer.client()));
+        if self.greet {
+            peer.send(&ChatBroadcast {
+                sender: UUID::NIL,
+                message: TextComponent::RawText(format!("Welcome, {}", peer.client())),
+            })?;
+        }
+        Ok(())
+    }
+
+    fn packet(&self, peer: &Peer, packet: Box<dyn AnyPacket>) -> std::io::Result<()> {
+        let message = packet.downcast::<ChatMessage>().unwrap().message.0;
+        peer.send(&ChatBroadcast {
+            sender: peer.client(),
+            message: TextComponent::RawText(message),
+        })
+    }
+
+    fn disconnected(&self, peer: &Peer, error: Option<&std::io::Error>) {
+        self.report(format!(
+            "disconnected {} {:?}",
+            peer.client(),
+            error.map(|e| e.kind())
+        ));
+    }
+}
+
+// Removes each broadcast in the player's inbox, as its sender and plain text
+#[cfg(feature = "tcp")]
+pub fn heard<H: Handler>(harness: &mut Harness<H>, player: UUID) -> Vec<(UUID, String)> {
+    harness
+        .take::<ChatBroadcast>(player)
+        .into_iter()
+        .map(|b| (b.sender, b.message.to_string()))
+        .collect()
+}
@@ -0,0 +1,192 @@
+#![cfg(feature = "tcp")]
+
+mod common;
+
+use std::io::Write;
+
+use binary_io::data::{ByteOrder, DataInputStream};
+use flate2::{write::ZlibEncoder, Compression};
+use net::{
+    frame::{Frame, FrameCodec, FrameDecoder, FrameError},
+    packet::{ChatMessage, PacketRegistry},
+};
+
+use common::say;
+
+fn codec() -> FrameCodec {
+    FrameCodec::new(4096).with_compression(Some(64))
+}
+
+fn frame_error(e: &std::io::Error) -> Option<&FrameError> {
+    e.get_ref().and_then(|e| e.downcast_ref::<FrameError>())
+}
+
+// A frame with the given uncompressed size, and zlib compressed contents
+fn compressed_frame(uncompressed: u32, contents: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(contents).unwrap();
+    let body = encoder.finish().unwrap();
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&((4 + body.len()) as u32).to_be_bytes());
+    bytes.extend_from_slice(&uncompressed.to_be_bytes());
+    bytes.extend_from_slice(&body);
+    bytes
+}
+
+#[test]
+fn frames_below_threshold_are_not_compressed() {
+    let frame = Frame::new(0x0010, vec![7; 61]);
+    let bytes = codec().encode(&frame).unwrap();
+    // Length, uncompressed size of 0, then the id and payload as they are
+    assert_eq!(&bytes[..8], &[0, 0, 0, 67, 0, 0, 0, 0]);
+    assert_eq!(&bytes[8..10], &[0x00, 0x10]);
+    assert_eq!(&bytes[10..], &frame.payload[..]);
+
+    let mut input = DataInputStream::new(&bytes[..], ByteOrder::BigEndian);
+    assert_eq!(codec().read_frame(&mut input).unwrap(), Some(frame));
+}
+
+#[test]
+fn frames_at_threshold_are_compressed() {
+    let frame = Frame::new(0x0010, vec![7; 62]);
+    let bytes = codec().encode(&frame).unwrap();
+    assert_eq!(&bytes[4..8], &64u32.to_be_bytes());
+    assert!(bytes.len() < 4 + 4 + 64);
+
+    let mut decoder = FrameDecoder::with_codec(codec());
+    decoder.feed(&bytes);
+    assert_eq!(decoder.next_frame().unwrap(), Some(frame));
+    assert_eq!(decoder.buffered(), 0);
+}
+
+#[test]
+fn large_packets_round_trip() {
+    let packet = say(&"hello ".repeat(500));
+    let bytes = codec()
+        .encode(&Frame::from_packet(&packet).unwrap())
+        .unwrap();
+    assert!(bytes.len() < 3000 / 4);
+    let mut decoder = FrameDecoder::with_codec(codec());
+    decoder.feed(&bytes);
+    let received = decoder
+        .next_packet(
+            &PacketRegistry::pkmcom(),
+            net::packet::Direction::Serverbound,
+        )
+        .unwrap()
+        .unwrap();
+    assert_eq!(received.downcast_ref::<ChatMessage>(), Some(&packet));
+}
+
+#[test]
+fn uncompressed_size_above_limit_is_rejected_before_decompressing() {
+    let bytes = compressed_frame(4097, &vec![0u8; 4097]);
+    let mut decoder = FrameDecoder::with_codec(codec());
+    decoder.feed(&bytes);
+    let e = decoder.next_frame().unwrap_err();
+    assert_eq!(
+        frame_error(&e),
+        Some(&FrameError::Oversized {
+            size: 4097,
+            max: 4096
+        })
+    );
+}
+
+#[test]
+fn decompression_bomb_is_rejected() {
+    // Claims 100 bytes, but expands to 1 MiB
+    let bytes = compressed_frame(100, &vec![0u8; 1024 * 1024]);
+    assert!(bytes.len() < 4096);
+    let mut decoder = FrameDecoder::with_codec(codec());
+    decoder.feed(&bytes);
+    let e = decoder.next_frame().unwrap_err();
+    assert_eq!(
+        frame_error(&e),
+        Some(&FrameError::SizeMismatch {
+            expected: 100,
+            actual: 101
+        })
+    );
+}
+
+#[test]
+fn invalid_compressed_data_is_rejected() {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&12u32.to_be_bytes());
+    bytes.extend_from_slice(&100u32.to_be_bytes());
+    bytes.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef, 0, 0, 0, 0]);
+    let mut input = DataInputStream::new(&bytes[..], ByteOrder::BigEndian);
+    let e = codec().read_frame(&mut input).unwrap_err();
+    assert_eq!(frame_error(&e), Some(&FrameError::BadCompression));
+}
+
+#[test]
+fn oversized_packets_are_not_sent() {
+    let frame = Frame::new(0x0010, vec![0; 4095]);
+    let e = codec().encode(&frame).unwrap_err();
+    assert_eq!(
+        frame_error(&e),
+        Some(&FrameError::Oversized {
+            size: 4097,
+            max: 4096
+        })
+    );
+}
+
+#[cfg(feature = "tcp")]
+mod negotiation {
+    use std::time::Duration;
+
+    use binary_io::uuid::UUID;
+    use net::{
+        client::ClientConfig,
+        packet::{ChatBroadcast, PacketRegistry},
+        server::{Server, ServerConfig},
+        tcp::{self, TcpServer},
+    };
+    use text::TextComponent;
+
+    use crate::common::{say, Echo};
+
+    fn echo(server_threshold: Option<usize>, client_compression: bool) -> Option<usize> {
+        let server = Server::new(
+            ServerConfig {
+                compression_threshold: server_threshold,
+                ..Default::default()
+            },
+            PacketRegistry::pkmcom(),
+            Echo::default(),
+        );
+        let server = TcpServer::bind("127.0.0.1:0", server).unwrap();
+        let mut config = ClientConfig::new(UUID::new(1, 1), Vec::new());
+        config.compression = client_compression;
+        let mut conn = tcp::connect(
+            server.local_addr(),
+            Duration::from_secs(5),
+            &config,
+            PacketRegistry::pkmcom(),
+        )
+        .unwrap();
+        for message in ["hi".to_string(), "big ".repeat(10_000)] {
+            conn.send(&say(&message)).unwrap();
+            let reply = conn.receive().unwrap().unwrap();
+            assert_eq!(
+                reply.downcast_ref::<ChatBroadcast>().unwrap().message,
+                TextComponent::RawText(message)
+            );
+        }
+        conn.compression()
+    }
+
+    #[test]
+    fn compression_is_enabled_when_both_sides_support_it() {
+        assert_eq!(echo(Some(128), true), Some(128));
+    }
+
+    #[test]
+    fn compression_is_disabled_unless_both_sides_support_it() {
+        assert_eq!(echo(Some(128), false), None);
+        assert_eq!(echo(None, true), None);
+    }
+}
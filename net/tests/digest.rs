@@ -0,0 +1,155 @@
+use binary_io::{
+    nbt::{compound::NbtCompound, NbtTag},
+    uuid::UUID,
+};
+use net::{
+    digest::{merkle_root, ContentDigest, ContentSummary, Digest, DomainSummary, EntryDiff},
+    handshake::{ClientEvent, ClientHandshake, ContentHash, LoginResponse, ServerHandshake, State},
+};
+use text::TextComponent;
+
+#[test]
+fn digest_is_sha256_of_canonical_encoding() {
+    // The canonical encoding of 1u32 is [0, 0, 0, 1]
+    assert_eq!(1u32.digest().unwrap(), Digest::of_bytes(&[0, 0, 0, 1]));
+    assert_eq!(
+        Digest::of_bytes(&[]).to_string(),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+        "abc".digest().unwrap(),
+        Digest::of_bytes(&[0, 3, b'a', b'b', b'c'])
+    );
+    assert_eq!(f64::NAN.digest().unwrap(), (-f64::NAN).digest().unwrap());
+}
+
+#[test]
+fn compound_digest_is_independent_of_insertion_order() {
+    let names = ["a", "b", "c", "d", "e", "f", "g", "h"];
+    let mut forward = NbtCompound::new();
+    for (i, name) in names.iter().enumerate() {
+        forward.insert(name.to_string(), NbtTag::Int(i as i32));
+    }
+    let mut backward = NbtCompound::new();
+    for (i, name) in names.iter().enumerate().rev() {
+        backward.insert(name.to_string(), NbtTag::Int(i as i32));
+    }
+    let forward = NbtTag::Compound(forward);
+    assert_eq!(
+        forward.digest().unwrap(),
+        NbtTag::Compound(backward).digest().unwrap()
+    );
+    assert_ne!(
+        forward.digest().unwrap(),
+        NbtTag::Compound(NbtCompound::new()).digest().unwrap()
+    );
+    assert_ne!(
+        NbtTag::Int(1).digest().unwrap(),
+        NbtTag::Float(f32::from_bits(1)).digest().unwrap()
+    );
+}
+
+#[test]
+fn merkle_root_depends_on_every_leaf() {
+    let leaves = (0u8..5).map(|i| Digest([i; 32])).collect::<Vec<_>>();
+    let root = merkle_root(leaves.iter().copied());
+    for i in 0..leaves.len() {
+        let mut changed = leaves.clone();
+        changed[i] = Digest([0xff; 32]);
+        assert_ne!(merkle_root(changed), root);
+    }
+    assert_ne!(merkle_root(leaves[..4].iter().copied()), root);
+    assert_eq!(merkle_root(vec![leaves[0]]), leaves[0]);
+}
+
+fn summary(entries: &[(&str, &str)]) -> DomainSummary {
+    let mut summary = DomainSummary::new();
+    for (name, content) in entries {
+        summary.insert_value(name.to_string(), *content).unwrap();
+    }
+    summary
+}
+
+#[test]
+fn domain_summary_finds_differing_entries() {
+    let server = summary(&[
+        ("move/tackle", "40"),
+        ("move/growl", "0"),
+        ("move/ember", "40"),
+    ]);
+    let client = summary(&[
+        ("move/tackle", "50"),
+        ("move/growl", "0"),
+        ("move/bubble", "40"),
+    ]);
+    assert_eq!(server.root(), server.clone().root());
+    assert_ne!(server.root(), client.root());
+    assert_eq!(
+        server.diff(client.entries()),
+        vec![
+            EntryDiff::Unexpected("move/bubble".to_string()),
+            EntryDiff::Missing("move/ember".to_string()),
+            EntryDiff::Changed("move/tackle".to_string()),
+        ]
+    );
+    assert!(server.diff(server.entries()).is_empty());
+}
+
+fn content(summary: &ContentSummary) -> Vec<ContentHash> {
+    summary
+        .domains()
+        .map(|(name, d)| ContentHash::with_summary(name, 0, d))
+        .collect()
+}
+
+#[test]
+fn handshake_pinpoints_differing_entries() {
+    let mut server_summary = ContentSummary::new();
+    *server_summary.domain_mut("pokemon") = summary(&[("species/bulbasaur", "grass")]);
+    *server_summary.domain_mut("moves") = summary(&[("move/tackle", "40"), ("move/growl", "0")]);
+    let mut client_summary = server_summary.clone();
+    *client_summary.domain_mut("moves") = summary(&[("move/tackle", "50"), ("move/growl", "0")]);
+
+    let mut server = ServerHandshake::new(content(&server_summary));
+    server.set_summary(server_summary);
+    let mut client = ClientHandshake::new(UUID::new(1, 2), content(&client_summary));
+
+    let response = server.receive(&client.start().unwrap()).unwrap().unwrap();
+    let request = match &response {
+        LoginResponse::Digests(request) => request,
+        response => panic!("Expected a DigestRequest, got {:?}", response),
+    };
+    assert_eq!(request.domains.0, vec!["moves".to_string()]);
+    let domains = match client.receive(response.packet()).unwrap() {
+        ClientEvent::DigestsRequested(domains) => domains,
+        event => panic!("Expected DigestsRequested, got {:?}", event),
+    };
+    let digests = client.digests(&client_summary, &domains[0]).unwrap();
+    let response = server.receive(&digests).unwrap().unwrap();
+
+    assert_eq!(server.state(), State::Closed);
+    assert_eq!(
+        server.entry_diffs().get("moves"),
+        Some(&vec![EntryDiff::Changed("move/tackle".to_string())])
+    );
+    match client.receive(response.packet()).unwrap() {
+        ClientEvent::Rejected(TextComponent::RawText(reason)) => {
+            assert!(reason.contains("moves (changed move/tackle)"), "{}", reason)
+        }
+        event => panic!("Expected Rejected, got {:?}", event),
+    }
+}
+
+#[test]
+fn unrequested_digests_are_rejected() {
+    let mut server = ServerHandshake::new(vec![ContentHash::of("moves", "base").unwrap()]);
+    server.set_allow_sync(true);
+    let mut client = ClientHandshake::new(
+        UUID::new(1, 2),
+        vec![ContentHash::of("moves", "modded").unwrap()],
+    );
+    let response = server.receive(&client.start().unwrap()).unwrap().unwrap();
+    assert!(matches!(response, LoginResponse::Sync(_)));
+    let digests = client.digests(&ContentSummary::new(), "moves").unwrap();
+    assert!(server.receive(&digests).is_err());
+}
@@ -0,0 +1,199 @@
+use std::io::{ErrorKind, Read};
+
+use binary_io::data::{ByteOrder, DataInputStream};
+use net::{
+    frame::{Frame, FrameCodec, FrameDecoder, FrameError, LENGTH_SIZE},
+    packet::{Direction, KeepAlive, PacketRegistry},
+};
+
+fn frame_error(e: &std::io::Error) -> Option<&FrameError> {
+    e.get_ref().and_then(|e| e.downcast_ref::<FrameError>())
+}
+
+fn frames() -> Vec<Frame> {
+    vec![
+        Frame::new(0x0001, vec![0, 0, 0, 0, 0, 0, 0, 42]),
+        Frame::new(0x0010, Vec::new()),
+        Frame::new(0x0011, vec![0xAA; 300]),
+    ]
+}
+
+fn encoded(codec: FrameCodec) -> Vec<u8> {
+    frames()
+        .iter()
+        .flat_map(|frame| codec.encode(frame).unwrap())
+        .collect()
+}
+
+// A stream which returns at most one byte from each read, and is interrupted before every other read
+struct Trickle<'a> {
+    bytes: &'a [u8],
+    interrupt: bool,
+}
+
+impl<'a> Trickle<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            interrupt: true,
+        }
+    }
+}
+
+impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.interrupt = !self.interrupt;
+        if self.interrupt {
+            return Err(ErrorKind::Interrupted.into());
+        }
+        let n = self.bytes.len().min(buf.len()).min(1);
+        buf[..n].copy_from_slice(&self.bytes[..n]);
+        self.bytes = &self.bytes[n..];
+        Ok(n)
+    }
+}
+
+#[test]
+fn frames_round_trip() {
+    for codec in [
+        FrameCodec::default(),
+        FrameCodec::new(4096).with_compression(Some(64)),
+    ] {
+        let bytes = encoded(codec);
+        let mut input = DataInputStream::new(&bytes[..], ByteOrder::BigEndian);
+        for frame in frames() {
+            assert_eq!(codec.read_frame(&mut input).unwrap(), Some(frame));
+        }
+        assert_eq!(codec.read_frame(&mut input).unwrap(), None);
+    }
+}
+
+#[test]
+fn frames_split_across_reads_are_reassembled() {
+    let bytes = encoded(FrameCodec::default());
+
+    // Byte by byte, as the decoder sees them
+    let mut decoder = FrameDecoder::default();
+    let mut decoded = Vec::new();
+    for byte in &bytes {
+        decoder.feed(std::slice::from_ref(byte));
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            decoded.push(frame);
+        }
+    }
+    assert_eq!(decoded, frames());
+    assert_eq!(decoder.buffered(), 0);
+    decoder.finish().unwrap();
+
+    // Several frames in a single read
+    let mut decoder = FrameDecoder::default();
+    decoder.feed(&bytes);
+    for frame in frames() {
+        assert_eq!(decoder.next_frame().unwrap(), Some(frame));
+    }
+    assert_eq!(decoder.next_frame().unwrap(), None);
+
+    // From a stream which returns short reads
+    let mut decoder = FrameDecoder::default();
+    let mut input = Trickle::new(&bytes);
+    let mut decoded = Vec::new();
+    while decoder.fill(&mut input).unwrap() != 0 {
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            decoded.push(frame);
+        }
+    }
+    assert_eq!(decoded, frames());
+
+    let mut input = DataInputStream::new(Trickle::new(&bytes), ByteOrder::BigEndian);
+    for frame in frames() {
+        assert_eq!(
+            FrameCodec::default().read_frame(&mut input).unwrap(),
+            Some(frame)
+        );
+    }
+    assert_eq!(FrameCodec::default().read_frame(&mut input).unwrap(), None);
+}
+
+#[test]
+fn oversized_frames_are_rejected_from_their_length() {
+    let codec = FrameCodec::new(16);
+    let frame = Frame::new(0x0001, vec![0; 15]);
+    let e = codec.encode(&frame).unwrap_err();
+    assert_eq!(
+        frame_error(&e),
+        Some(&FrameError::Oversized { size: 17, max: 16 })
+    );
+
+    // Only the length prefix is needed to reject the frame, and nothing more is buffered
+    let mut decoder = FrameDecoder::new(16);
+    decoder.feed(&u32::MAX.to_be_bytes());
+    let e = decoder.next_frame().unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    assert_eq!(
+        frame_error(&e),
+        Some(&FrameError::Oversized {
+            size: u32::MAX as usize,
+            max: 16
+        })
+    );
+
+    let bytes = FrameCodec::default().encode(&frame).unwrap();
+    let mut input = DataInputStream::new(&bytes[..], ByteOrder::BigEndian);
+    let e = codec.read_frame(&mut input).unwrap_err();
+    assert_eq!(
+        frame_error(&e),
+        Some(&FrameError::Oversized { size: 17, max: 16 })
+    );
+
+    // Frames too short to hold a packet id
+    let mut decoder = FrameDecoder::default();
+    decoder.feed(&[0, 0, 0, 1, 0]);
+    let e = decoder.next_frame().unwrap_err();
+    assert_eq!(frame_error(&e), Some(&FrameError::Undersized(1)));
+}
+
+#[test]
+fn truncated_frames_are_rejected() {
+    let bytes = FrameCodec::default().encode(&frames()[0]).unwrap();
+    for len in 1..bytes.len() {
+        let mut input = DataInputStream::new(&bytes[..len], ByteOrder::BigEndian);
+        let e = FrameCodec::default().read_frame(&mut input).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        let expected = if len < LENGTH_SIZE { 6 } else { bytes.len() };
+        assert_eq!(
+            frame_error(&e),
+            Some(&FrameError::Truncated {
+                expected,
+                received: len
+            })
+        );
+
+        let mut decoder = FrameDecoder::default();
+        decoder.feed(&bytes[..len]);
+        assert_eq!(decoder.next_frame().unwrap(), None);
+        assert_eq!(decoder.buffered(), len);
+        let e = decoder.finish().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+}
+
+#[test]
+fn packets_must_consume_their_frame() {
+    let registry = PacketRegistry::pkmcom();
+    let frame = Frame::from_packet(&KeepAlive { nonce: 42 }).unwrap();
+    let packet = frame.decode(&registry, Direction::Serverbound).unwrap();
+    assert_eq!(
+        packet.downcast_ref::<KeepAlive>(),
+        Some(&KeepAlive { nonce: 42 })
+    );
+
+    let mut long = frame.clone();
+    long.payload.push(0);
+    let e = long.decode(&registry, Direction::Serverbound).unwrap_err();
+    assert_eq!(frame_error(&e), Some(&FrameError::TrailingBytes(0x0001, 1)));
+
+    let mut short = frame;
+    short.payload.pop();
+    let e = short.decode(&registry, Direction::Serverbound).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+}
@@ -0,0 +1,245 @@
+mod common;
+
+use binary_io::uuid::UUID;
+use net::{
+    digest::ContentSummary,
+    handshake::{
+        ClientEvent, ClientHandshake, ContentHash, ContentReport, DomainDigests, Handshake,
+        HandshakeError, LoginAccept, LoginReject, LoginResponse, ServerHandshake, State,
+    },
+    packet::{ChatMessage, Disconnect, KeepAlive, List, Packet, PROTOCOL_VERSION},
+    session::Resume,
+    status::{StatusRequest, StatusResponse},
+};
+use text::TextComponent;
+
+use common::say;
+
+const PLAYER: UUID = UUID::new(1, 1);
+
+fn content(moves: &str) -> Vec<ContentHash> {
+    vec![ContentHash::of("moves", moves).unwrap()]
+}
+
+fn unexpected<P: Packet>(state: State) -> HandshakeError {
+    HandshakeError::UnexpectedPacket(P::ID, state)
+}
+
+#[test]
+fn states_accept_their_packets() {
+    assert!(State::Handshaking.accepts(Handshake::ID));
+    assert!(State::Handshaking.accepts(Resume::ID));
+    assert!(State::Handshaking.accepts(StatusRequest::ID));
+    assert!(!State::Handshaking.accepts(KeepAlive::ID));
+    assert!(!State::Handshaking.accepts(ChatMessage::ID));
+
+    assert!(State::Status.accepts(StatusResponse::ID));
+    assert!(!State::Status.accepts(Handshake::ID));
+    assert!(!State::Status.accepts(ChatMessage::ID));
+
+    assert!(State::Login.accepts(LoginAccept::ID));
+    assert!(State::Login.accepts(KeepAlive::ID));
+    assert!(!State::Login.accepts(Handshake::ID));
+    assert!(!State::Login.accepts(ChatMessage::ID));
+
+    assert!(State::Play.accepts(ChatMessage::ID));
+    assert!(State::Play.accepts(KeepAlive::ID));
+    assert!(!State::Play.accepts(Handshake::ID));
+    assert!(!State::Play.accepts(LoginAccept::ID));
+    assert!(!State::Play.accepts(StatusRequest::ID));
+
+    for id in [
+        Handshake::ID,
+        KeepAlive::ID,
+        Disconnect::ID,
+        ChatMessage::ID,
+    ] {
+        assert!(!State::Closed.accepts(id));
+    }
+}
+
+#[test]
+fn accepted_clients_advance_to_play() {
+    let mut client = ClientHandshake::new(PLAYER, content("base"));
+    let mut server = ServerHandshake::new(content("base"));
+    assert_eq!(client.state(), State::Handshaking);
+    assert_eq!(server.state(), State::Handshaking);
+
+    let handshake = client.start().unwrap();
+    assert_eq!(client.state(), State::Login);
+    let response = server.receive(&handshake).unwrap().unwrap();
+    assert_eq!(server.state(), State::Play);
+    assert_eq!(server.client(), Some(PLAYER));
+    assert_eq!(server.version(), Some(PROTOCOL_VERSION));
+    assert!(matches!(response, LoginResponse::Accept(_)));
+
+    assert_eq!(
+        client.receive(response.packet()).unwrap(),
+        ClientEvent::Accepted(PROTOCOL_VERSION)
+    );
+    assert_eq!(client.state(), State::Play);
+    assert_eq!(client.version(), Some(PROTOCOL_VERSION));
+
+    // Game packets pass through once both sides are playing
+    assert_eq!(server.receive(&say("Hello")).unwrap(), None);
+    assert_eq!(
+        client.receive(&KeepAlive { nonce: 1 }).unwrap(),
+        ClientEvent::Ignored
+    );
+}
+
+#[test]
+fn packets_in_the_wrong_state_are_rejected() {
+    let mut server = ServerHandshake::new(content("base"));
+    assert_eq!(
+        server.receive(&say("Hello")).unwrap_err(),
+        unexpected::<ChatMessage>(State::Handshaking)
+    );
+    assert_eq!(
+        server.receive(&KeepAlive { nonce: 1 }).unwrap_err(),
+        unexpected::<KeepAlive>(State::Handshaking)
+    );
+    assert_eq!(server.state(), State::Handshaking);
+
+    let mut client = ClientHandshake::new(PLAYER, content("base"));
+    let accept = LoginAccept {
+        protocol: PROTOCOL_VERSION,
+        compression: 0,
+        session_grace: 0,
+        session: [0; 32],
+    };
+    // The client cannot receive anything before it has sent its Handshake
+    assert_eq!(
+        client.receive(&accept).unwrap_err(),
+        unexpected::<LoginAccept>(State::Handshaking)
+    );
+    let handshake = client.start().unwrap();
+    assert_eq!(
+        client.start().unwrap_err(),
+        unexpected::<Handshake>(State::Login)
+    );
+    assert_eq!(
+        client.receive(&say("Hello")).unwrap_err(),
+        unexpected::<ChatMessage>(State::Login)
+    );
+
+    server.receive(&handshake).unwrap();
+    // Neither the handshake nor login packets may be repeated once playing
+    assert_eq!(
+        server.receive(&handshake).unwrap_err(),
+        unexpected::<Handshake>(State::Play)
+    );
+    assert_eq!(
+        server
+            .receive(&ContentReport {
+                content: List(content("base"))
+            })
+            .unwrap_err(),
+        unexpected::<ContentReport>(State::Play)
+    );
+    client.receive(&accept).unwrap();
+    assert_eq!(
+        client.receive(&accept).unwrap_err(),
+        unexpected::<LoginAccept>(State::Play)
+    );
+    assert_eq!(server.state(), State::Play);
+    assert_eq!(client.state(), State::Play);
+}
+
+#[test]
+fn unrequested_login_packets_are_rejected() {
+    let mut server = ServerHandshake::new(content("base"));
+    server.set_allow_sync(true);
+    let mut client = ClientHandshake::new(PLAYER, content("modded"));
+    let response = server.receive(&client.start().unwrap()).unwrap().unwrap();
+    assert_eq!(server.state(), State::Login);
+    assert_eq!(
+        client.receive(response.packet()).unwrap(),
+        ClientEvent::SyncRequested(vec!["moves".to_string()])
+    );
+    assert_eq!(client.state(), State::Login);
+
+    // Digests were not requested, as the server has no summary
+    let digests = DomainDigests {
+        domain: "moves".to_string(),
+        entries: List::default(),
+    };
+    assert_eq!(
+        server.receive(&digests).unwrap_err(),
+        unexpected::<DomainDigests>(State::Login)
+    );
+
+    let report = client.report(content("base")).unwrap();
+    let response = server.receive(&report).unwrap().unwrap();
+    assert!(matches!(response, LoginResponse::Accept(_)));
+    assert_eq!(server.state(), State::Play);
+
+    // Nor is a ContentReport valid until a ContentSyncRequest was sent
+    let mut server = ServerHandshake::new(content("base"));
+    server.set_summary(ContentSummary::new());
+    let response = server
+        .receive(
+            &ClientHandshake::new(PLAYER, content("modded"))
+                .start()
+                .unwrap(),
+        )
+        .unwrap()
+        .unwrap();
+    assert!(matches!(response, LoginResponse::Digests(_)));
+    assert_eq!(
+        server.receive(&report).unwrap_err(),
+        unexpected::<ContentReport>(State::Login)
+    );
+    let other = DomainDigests {
+        domain: "pokemon".to_string(),
+        entries: List::default(),
+    };
+    assert_eq!(
+        server.receive(&other).unwrap_err(),
+        unexpected::<DomainDigests>(State::Login)
+    );
+    assert_eq!(server.state(), State::Login);
+}
+
+#[test]
+fn rejected_and_disconnected_connections_are_closed() {
+    let mut server = ServerHandshake::new(content("base"));
+    let mut client = ClientHandshake::new(PLAYER, content("modded"));
+    let response = server.receive(&client.start().unwrap()).unwrap().unwrap();
+    assert!(matches!(response, LoginResponse::Reject(_)));
+    assert_eq!(server.state(), State::Closed);
+    assert!(matches!(
+        client.receive(response.packet()).unwrap(),
+        ClientEvent::Rejected(_)
+    ));
+    assert_eq!(client.state(), State::Closed);
+    assert_eq!(
+        server.receive(&say("Hello")).unwrap_err(),
+        unexpected::<ChatMessage>(State::Closed)
+    );
+    assert_eq!(
+        client
+            .receive(&LoginReject {
+                reason: TextComponent::RawText("Again".to_string())
+            })
+            .unwrap_err(),
+        unexpected::<LoginReject>(State::Closed)
+    );
+
+    // A Disconnect during login closes the connection
+    let mut server = ServerHandshake::new(content("base"));
+    server.set_allow_sync(true);
+    let mut client = ClientHandshake::new(PLAYER, content("modded"));
+    server.receive(&client.start().unwrap()).unwrap();
+    let disconnect = Disconnect {
+        reason: TextComponent::RawText("Quit".to_string()),
+    };
+    assert_eq!(server.receive(&disconnect).unwrap(), None);
+    assert_eq!(server.state(), State::Closed);
+    assert_eq!(
+        client.receive(&disconnect).unwrap(),
+        ClientEvent::Rejected(disconnect.reason.clone())
+    );
+    assert_eq!(client.state(), State::Closed);
+    assert!(client.report(content("base")).is_err());
+}
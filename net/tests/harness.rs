@@ -0,0 +1,255 @@
+#![cfg(feature = "tcp")]
+
+mod common;
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use binary_io::{uuid::UUID, version::Version};
+use net::{
+    client::ClientConfig,
+    connection::Transport,
+    harness::Harness,
+    loopback::{self, LinkConditions, Network},
+    packet::{AnyPacket, ChatBroadcast, ChatMessage, Disconnect, PacketRegistry},
+    server::{Handler, Peer, Server, ServerConfig},
+};
+use text::TextComponent;
+
+use common::{heard, say};
+
+const ASH: UUID = UUID::new(1, 1);
+const GARY: UUID = UUID::new(1, 2);
+const MISTY: UUID = UUID::new(1, 3);
+
+// Relays every message to every player, in the order the server handles them
+#[derive(Default)]
+struct Relay {
+    peers: Mutex<Vec<Peer>>,
+}
+
+impl Relay {
+    fn broadcast(&self, sender: UUID, message: String) {
+        for peer in self.peers.lock().unwrap().iter() {
+            let _ = peer.send(&ChatBroadcast {
+                sender,
+                message: TextComponent::RawText(message.clone()),
+            });
+        }
+    }
+}
+
+impl Handler for Relay {
+    fn connected(&self, peer: &Peer) -> std::io::Result<()> {
+        self.peers.lock().unwrap().push(peer.clone());
+        Ok(())
+    }
+
+    fn packet(&self, peer: &Peer, packet: Box<dyn AnyPacket>) -> std::io::Result<()> {
+        let message = packet.downcast::<ChatMessage>().unwrap();
+        self.broadcast(peer.client(), message.message.0);
+        Ok(())
+    }
+
+    fn disconnected(&self, peer: &Peer, _: Option<&std::io::Error>) {
+        self.peers
+            .lock()
+            .unwrap()
+            .retain(|p| p.client() != peer.client());
+        self.broadcast(UUID::NIL, format!("{} left", peer.client()));
+    }
+}
+
+fn harness(seed: u64) -> Harness<Relay> {
+    let server = Server::new(
+        ServerConfig::default(),
+        PacketRegistry::pkmcom(),
+        Relay::default(),
+    );
+    let mut harness = Harness::with_seed(server, PacketRegistry::pkmcom(), seed);
+    for player in [ASH, GARY, MISTY] {
+        harness.join(player);
+    }
+    harness
+}
+
+#[test]
+fn packets_are_handled_in_the_order_they_were_sent() {
+    let mut harness = harness(0);
+    // Sent at the same time, so without scheduling the server's threads could handle them in any order
+    for (i, player) in [MISTY, ASH, GARY, ASH].iter().enumerate() {
+        harness.send(*player, &say(&i.to_string())).unwrap();
+    }
+    harness.expect_nothing(ASH);
+    harness.settle();
+    let expected = vec![
+        (MISTY, "0".to_string()),
+        (ASH, "1".to_string()),
+        (GARY, "2".to_string()),
+        (ASH, "3".to_string()),
+    ];
+    for player in [ASH, GARY, MISTY] {
+        assert_eq!(heard(&mut harness, player), expected);
+        harness.expect_nothing(player);
+    }
+}
+
+#[test]
+fn latency_is_measured_on_the_virtual_clock() {
+    let mut harness = harness(0);
+    harness.set_conditions(
+        ASH,
+        LinkConditions {
+            latency: Duration::from_millis(100),
+            loss: 0.0,
+        },
+    );
+    harness.send(ASH, &say("slow")).unwrap();
+    harness.send(GARY, &say("fast")).unwrap();
+    harness.settle();
+    assert_eq!(heard(&mut harness, ASH), Vec::new());
+    assert_eq!(heard(&mut harness, GARY), vec![(GARY, "fast".to_string())]);
+
+    harness.advance(Duration::from_millis(99));
+    harness.expect_nothing(GARY);
+    harness.advance(Duration::from_millis(1));
+    assert_eq!(heard(&mut harness, GARY), vec![(ASH, "slow".to_string())]);
+    // Ash hears the fast message 100ms after it was sent, and the slow one 200ms after
+    assert_eq!(heard(&mut harness, ASH), vec![(GARY, "fast".to_string())]);
+    harness.advance(Duration::from_millis(100));
+    assert_eq!(heard(&mut harness, ASH), vec![(ASH, "slow".to_string())]);
+    assert_eq!(harness.now(), Duration::from_millis(200));
+}
+
+#[test]
+fn losses_are_deterministic_for_a_seed() {
+    let run = |seed| {
+        let mut harness = harness(seed);
+        harness.set_conditions(
+            GARY,
+            LinkConditions {
+                latency: Duration::ZERO,
+                loss: 0.5,
+            },
+        );
+        for i in 0..32 {
+            harness.send(GARY, &say(&i.to_string())).unwrap();
+        }
+        harness.settle();
+        heard(&mut harness, ASH)
+    };
+    let first = run(7);
+    assert_eq!(run(7), first);
+    assert!(!first.is_empty() && first.len() < 32);
+    assert_ne!(run(8), first);
+}
+
+#[test]
+fn disconnects_are_handled() {
+    let mut harness = harness(0);
+    harness.disconnect(GARY, TextComponent::RawText("Bye".to_string()));
+    assert!(!harness.is_connected(GARY));
+    for player in [ASH, MISTY] {
+        assert_eq!(
+            heard(&mut harness, player),
+            vec![(UUID::NIL, format!("{} left", GARY))]
+        );
+    }
+    assert_eq!(harness.server().peers().len(), 2);
+
+    // A kicked client receives the reason, and is then disconnected
+    harness
+        .server()
+        .peer(ASH)
+        .unwrap()
+        .kick(TextComponent::RawText("Kicked".to_string()))
+        .unwrap();
+    harness.settle();
+    assert_eq!(
+        harness.expect::<Disconnect>(ASH).reason,
+        TextComponent::RawText("Kicked".to_string())
+    );
+    assert!(!harness.is_connected(ASH));
+}
+
+#[test]
+fn rejected_clients_do_not_join() {
+    let version = Version::from_encoded(0x0001);
+    let config = ServerConfig {
+        min_version: version,
+        max_version: version,
+        ..Default::default()
+    };
+    let server = Server::new(config, PacketRegistry::pkmcom(), Relay::default());
+    let mut harness = Harness::new(server, PacketRegistry::pkmcom());
+    let mut config = ClientConfig::new(ASH, Vec::new());
+    config.protocol = version;
+    harness.join_with(config).unwrap();
+    let e = harness
+        .join_with(ClientConfig::new(GARY, Vec::new()))
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+    assert_eq!(harness.server().peers().len(), 1);
+}
+
+#[test]
+fn loopback_streams_behave_like_sockets() {
+    let (mut a, mut b) = loopback::pair();
+    a.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 3];
+    assert_eq!(b.read(&mut buf).unwrap(), 3);
+    assert_eq!(&buf, b"hel");
+    assert_eq!(b.read(&mut buf).unwrap(), 2);
+
+    // A zero read timeout does not block
+    b.set_read_timeout(Some(Duration::ZERO)).unwrap();
+    assert_eq!(b.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+    // Data sent before a shutdown can still be read, followed by End of File
+    b.write_all(b"bye").unwrap();
+    b.shutdown().unwrap();
+    assert_eq!(a.write(b"x").unwrap_err().kind(), ErrorKind::BrokenPipe);
+    let mut rest = Vec::new();
+    a.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, b"bye");
+
+    // Messages are not due until the clock reaches their delivery time
+    let network = Network::new(0);
+    let (mut a, mut b) = network.pair();
+    network.set_conditions(
+        &a,
+        LinkConditions {
+            latency: Duration::from_secs(1),
+            loss: 0.0,
+        },
+    );
+    a.write_all(b"late").unwrap();
+    assert!(!b.is_readable());
+    assert_eq!(network.next_delivery(), Some(Duration::from_secs(1)));
+    network.advance(Duration::from_secs(1));
+    assert!(b.is_readable());
+    let mut buf = [0u8; 4];
+    b.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"late");
+}
+
+#[test]
+fn loopback_connects_like_tcp() {
+    let server = Arc::new(Server::new(
+        ServerConfig::default(),
+        PacketRegistry::pkmcom(),
+        Relay::default(),
+    ));
+    let mut conn = loopback::connect(
+        &server,
+        &ClientConfig::new(ASH, Vec::new()),
+        PacketRegistry::pkmcom(),
+    )
+    .unwrap();
+    conn.send(&say("hi")).unwrap();
+    let packet = conn.receive().unwrap().unwrap();
+    assert_eq!(packet.downcast_ref::<ChatBroadcast>().unwrap().sender, ASH);
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use binary_io::{
+    nbt::{compound::NbtCompound, NbtTag},
+    uuid::UUID,
+};
+use net::hashsum::Hashcode;
+
+// Expected values are computed by the equivalent Java expressions
+
+#[test]
+fn primitives_match_java() {
+    assert_eq!(true.hashcode(), 1231);
+    assert_eq!(false.hashcode(), 1237);
+    assert_eq!((-1i64).hashcode(), 0);
+    assert_eq!(0x1_0000_0002i64.hashcode(), 3);
+    assert_eq!(1.0f32.hashcode(), 1065353216);
+    assert_eq!(1.0f64.hashcode(), 1072693248);
+    assert_eq!(f32::NAN.hashcode(), 2143289344);
+    assert_eq!((-f64::NAN).hashcode(), 2146959360);
+    assert_eq!('a'.hashcode(), 97);
+}
+
+#[test]
+fn strings_hash_utf16() {
+    assert_eq!("".hashcode(), 0);
+    assert_eq!("hello".hashcode(), 99162322);
+    assert_eq!("é".hashcode(), 233);
+    // "😀".hashCode()
+    assert_eq!("😀".hashcode(), 1772899);
+    // Overflows, and wraps as Java does
+    assert_eq!(
+        "The quick brown fox jumps over the lazy dog".hashcode(),
+        -609428141
+    );
+}
+
+#[test]
+fn collections_match_java() {
+    // List.of(1, 2, 3).hashCode()
+    assert_eq!(vec![1, 2, 3].hashcode(), 30817);
+    assert_eq!(Vec::<i32>::new().hashcode(), 1);
+    // Objects.hash(1, 2, 3)
+    assert_eq!((1, 2, 3).hashcode(), 30817);
+    // Map.of("a", 1, "b", 2).hashCode()
+    let map = vec![("a".to_string(), 1), ("b".to_string(), 2)]
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    assert_eq!(map.hashcode(), (97 ^ 1) + (98 ^ 2));
+    // new UUID(1, 2).hashCode()
+    assert_eq!(UUID::new(1, 2).hashcode(), 3);
+}
+
+#[test]
+fn nbt_hashes_contained_values() {
+    let mut compound = NbtCompound::new();
+    compound.insert("a".to_string(), NbtTag::Int(1));
+    compound.insert("b".to_string(), NbtTag::Byte(0xff));
+    assert_eq!(NbtTag::Compound(compound).hashcode(), (97 ^ 1) + (98 ^ -1));
+    assert_eq!(NbtTag::String("hello".to_string()).hashcode(), 99162322);
+}
+
+#[derive(Hashcode)]
+struct Named {
+    id: i32,
+    name: String,
+    #[hashcode(skip)]
+    #[allow(dead_code)]
+    cached: Option<i32>,
+}
+
+#[derive(Hashcode)]
+struct Tuple(i32, i32, i32);
+
+#[derive(Hashcode)]
+struct Unit;
+
+#[derive(Hashcode)]
+struct Generic<T> {
+    value: T,
+}
+
+#[test]
+fn derive_matches_objects_hash() {
+    let named = Named {
+        id: 1,
+        name: "hello".to_string(),
+        cached: Some(5),
+    };
+    // Objects.hash(1, "hello")
+    assert_eq!(named.hashcode(), (31 + 1) * 31 + 99162322);
+    assert_eq!(Tuple(1, 2, 3).hashcode(), 30817);
+    assert_eq!(Unit.hashcode(), 1);
+    assert_eq!(Generic { value: true }.hashcode(), 31 + 1231);
+}
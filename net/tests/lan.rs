@@ -0,0 +1,112 @@
+#![cfg(feature = "multicast")]
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use binary_io::uuid::UUID;
+use net::{
+    lan::{Beacon, LanAnnouncer, LanConfig, LanListener, LAN_GROUP},
+    packet::PROTOCOL_VERSION,
+};
+use text::TextComponent;
+
+fn beacon(host: u64, players: u16) -> Beacon {
+    Beacon {
+        name: TextComponent::RawText(format!("Game {}", host)),
+        protocol: PROTOCOL_VERSION,
+        host: UUID::new(host, host),
+        players,
+        port: 3000 + host as u16,
+    }
+}
+
+fn wait_for<F: FnMut() -> bool>(mut f: F) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(5) {
+        if f() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+#[test]
+fn beacon_round_trip() {
+    let beacon = beacon(1, 3);
+    assert_eq!(Beacon::decode(&beacon.encode().unwrap()).unwrap(), beacon);
+    assert!(Beacon::decode(b"nope").is_err());
+}
+
+#[test]
+fn discovers_games_on_loopback() {
+    let mut config = LanConfig {
+        group: SocketAddrV4::new(LAN_GROUP, 0),
+        interface: Ipv4Addr::LOCALHOST,
+        interval: Duration::from_millis(20),
+        expiry: Duration::from_millis(500),
+        ttl: 0,
+        ..Default::default()
+    };
+    let listener = LanListener::start(&config).unwrap();
+    config.group.set_port(listener.local_addr().port());
+
+    let first = LanAnnouncer::start(beacon(1, 0), &config).unwrap();
+    let mut second = LanAnnouncer::start(beacon(2, 5), &config).unwrap();
+    assert!(wait_for(|| listener.games().len() == 2));
+    let game = listener
+        .games()
+        .into_iter()
+        .find(|g| g.beacon.host == UUID::new(2, 2))
+        .unwrap();
+    assert_eq!(game.beacon, beacon(2, 5));
+    assert_eq!(game.addr.port(), 3002);
+    assert!(game.addr.ip().is_loopback());
+
+    first.update(|b| b.players = 4);
+    assert!(wait_for(|| listener
+        .games()
+        .iter()
+        .any(|g| g.beacon == beacon(1, 4))));
+
+    second.stop();
+    let stopped = Instant::now();
+    assert!(wait_for(|| listener.games().len() == 1));
+    assert!(stopped.elapsed() >= Duration::from_millis(400));
+    assert_eq!(
+        listener.games_at(Instant::now() + Duration::from_secs(1)),
+        Vec::new()
+    );
+}
+
+#[test]
+fn keeps_at_most_max_games() {
+    let config = LanConfig {
+        group: SocketAddrV4::new(LAN_GROUP, 0),
+        interface: Ipv4Addr::LOCALHOST,
+        max_games: 2,
+        ..Default::default()
+    };
+    let listener = LanListener::start(&config).unwrap();
+    let to = SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().port());
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+
+    for host in 1..=5 {
+        let bytes = beacon(host, 0).encode().unwrap();
+        socket.send_to(&bytes, to).unwrap();
+        assert!(wait_for(|| listener
+            .games()
+            .iter()
+            .any(|g| g.beacon.host == UUID::new(host, host))));
+        assert!(listener.games().len() <= 2);
+    }
+    // The least recently announced games were removed
+    let hosts = listener
+        .games()
+        .iter()
+        .map(|g| g.beacon.host)
+        .collect::<Vec<_>>();
+    assert_eq!(hosts, vec![UUID::new(4, 4), UUID::new(5, 5)]);
+}
@@ -0,0 +1,241 @@
+#![cfg(feature = "tcp")]
+
+mod common;
+
+use std::{
+    io::ErrorKind,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use binary_io::uuid::UUID;
+use net::{
+    client::ClientConfig,
+    connection::{Connection, Side},
+    handshake::State,
+    limit::{
+        Category, Clock, Limit, ManualClock, RateLimits, TokenBucket, Violation, ViolationKind,
+    },
+    loopback,
+    packet::{AnyPacket, ChatBroadcast, ChatMessage, Disconnect, PacketRegistry},
+    server::{Handler, Peer, Server, ServerConfig},
+    status::{StatusPing, StatusRequest, StatusResponse},
+    tcp::{self, TcpServer},
+};
+use text::TextComponent;
+
+use common::{eventually, say};
+
+const ASH: UUID = UUID::new(1, 1);
+const GARY: UUID = UUID::new(1, 2);
+
+// Echoes messages back to their sender, and records the violations reported
+#[derive(Default)]
+struct Moderator {
+    violations: Mutex<Vec<Violation>>,
+    disconnects: Mutex<u32>,
+}
+
+impl Moderator {
+    fn violations(&self) -> Vec<Violation> {
+        self.violations.lock().unwrap().clone()
+    }
+}
+
+impl Handler for Moderator {
+    fn packet(&self, peer: &Peer, packet: Box<dyn AnyPacket>) -> std::io::Result<()> {
+        let message = packet.downcast::<ChatMessage>().unwrap().message.0;
+        peer.send(&ChatBroadcast {
+            sender: peer.client(),
+            message: TextComponent::RawText(message),
+        })
+    }
+
+    fn disconnected(&self, _: &Peer, _: Option<&std::io::Error>) {
+        *self.disconnects.lock().unwrap() += 1;
+    }
+
+    fn violation(&self, violation: &Violation) {
+        self.violations.lock().unwrap().push(violation.clone());
+    }
+}
+
+fn server(limits: RateLimits, clock: &ManualClock) -> Arc<Server<Moderator>> {
+    let config = ServerConfig {
+        limits,
+        ..Default::default()
+    };
+    let mut server = Server::new(config, PacketRegistry::pkmcom(), Moderator::default());
+    server.set_clock(clock.clone());
+    Arc::new(server)
+}
+
+fn chat_limit(burst: u32, interval: Duration) -> RateLimits {
+    RateLimits {
+        packets: [(Category::Chat, Limit::new(burst, interval))]
+            .iter()
+            .copied()
+            .collect(),
+        connections_per_ip: None,
+    }
+}
+
+#[test]
+fn servers_limit_clients_by_default() {
+    let limits = ServerConfig::default().limits;
+    let categories = [
+        Category::Chat,
+        Category::Sync,
+        Category::Trade,
+        Category::Battle,
+        Category::Other,
+    ];
+    for category in categories.iter() {
+        assert!(limits.packets[category].burst > 0, "{}", category);
+    }
+    assert!(limits.connections_per_ip.is_some());
+}
+
+#[test]
+fn buckets_refill_one_token_per_interval() {
+    let clock = ManualClock::new();
+    let mut bucket = TokenBucket::new(Limit::new(3, Duration::from_secs(1)), clock.now());
+    for _ in 0..3 {
+        assert!(bucket.take(clock.now()));
+    }
+    assert!(!bucket.take(clock.now()));
+
+    clock.advance(Duration::from_millis(1500));
+    assert_eq!(bucket.tokens(clock.now()), 1);
+    assert!(bucket.take(clock.now()));
+    assert!(!bucket.take(clock.now()));
+    // The half interval left over still counts towards the next token
+    clock.advance(Duration::from_millis(500));
+    assert_eq!(bucket.tokens(clock.now()), 1);
+
+    // Buckets never hold more than their burst
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(bucket.tokens(clock.now()), 3);
+    assert_eq!(clock.elapsed(), Duration::from_secs(62));
+}
+
+#[test]
+fn spamming_chat_gets_a_client_kicked() {
+    let clock = ManualClock::new();
+    let server = server(chat_limit(2, Duration::from_secs(1)), &clock);
+    let mut conn = loopback::connect(
+        &server,
+        &ClientConfig::new(ASH, Vec::new()),
+        PacketRegistry::pkmcom(),
+    )
+    .unwrap();
+    for message in ["one", "two"] {
+        conn.send(&say(message)).unwrap();
+        assert!(conn.receive().unwrap().unwrap().is::<ChatBroadcast>());
+    }
+    clock.advance(Duration::from_secs(1));
+    conn.send(&say("three")).unwrap();
+    assert!(conn.receive().unwrap().unwrap().is::<ChatBroadcast>());
+    assert!(server.handler().violations().is_empty());
+
+    conn.send(&say("four")).unwrap();
+    let packet = conn.receive().unwrap().unwrap();
+    assert_eq!(
+        packet.downcast_ref::<Disconnect>().unwrap().reason,
+        TextComponent::RawText("Sending chat packets too quickly".to_string())
+    );
+    assert!(conn.receive().unwrap().is_none());
+    eventually(|| *server.handler().disconnects.lock().unwrap() == 1);
+    assert_eq!(
+        server.handler().violations(),
+        vec![Violation {
+            kind: ViolationKind::RateLimited(Category::Chat),
+            client: Some(ASH),
+            address: None,
+        }]
+    );
+    assert!(server.peers().is_empty());
+}
+
+#[test]
+fn clients_must_log_in_before_the_handshake_timeout() {
+    let clock = ManualClock::new();
+    let server = server(RateLimits::default(), &clock);
+    let (client, accepted) = loopback::pair();
+    let serving = server.spawn(accepted);
+    let mut conn = Connection::new(client, Side::Client, PacketRegistry::pkmcom()).unwrap();
+
+    conn.send(&StatusRequest {}).unwrap();
+    conn.set_state(State::Status);
+    assert!(conn.receive().unwrap().unwrap().is::<StatusResponse>());
+
+    // The ping arrives after the deadline of the exchange
+    clock.advance(server.config().handshake_timeout);
+    conn.send(&StatusPing { payload: 0 }).unwrap();
+    let packet = conn.receive().unwrap().unwrap();
+    assert_eq!(
+        packet.downcast_ref::<Disconnect>().unwrap().reason,
+        TextComponent::RawText("Took too long to log in".to_string())
+    );
+    assert_eq!(
+        serving.join().unwrap().unwrap_err().kind(),
+        ErrorKind::TimedOut
+    );
+    assert_eq!(
+        server.handler().violations()[0].kind,
+        ViolationKind::HandshakeTimeout
+    );
+}
+
+#[test]
+fn connections_are_limited_per_address() {
+    let limits = RateLimits {
+        connections_per_ip: Some(1),
+        ..Default::default()
+    };
+    let config = ServerConfig {
+        limits,
+        ..Default::default()
+    };
+    let server = TcpServer::bind(
+        "127.0.0.1:0",
+        Server::new(config, PacketRegistry::pkmcom(), Moderator::default()),
+    )
+    .unwrap();
+    let connect = |client| {
+        tcp::connect(
+            server.local_addr(),
+            Duration::from_secs(5),
+            &ClientConfig::new(client, Vec::new()),
+            PacketRegistry::pkmcom(),
+        )
+    };
+    let ash = connect(ASH).unwrap();
+    let e = connect(GARY).err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+    assert!(e
+        .to_string()
+        .contains("Too many connections from your address"));
+    let violations = server.server().handler().violations();
+    assert_eq!(violations[0].kind, ViolationKind::TooManyConnections);
+    assert!(violations[0].address.unwrap().ip().is_loopback());
+
+    // Once the first connection is closed, another may be opened
+    ash.sender()
+        .disconnect(TextComponent::RawText("Bye".to_string()))
+        .unwrap();
+    eventually(|| *server.server().handler().disconnects.lock().unwrap() == 1);
+    let start = Instant::now();
+    let _gary = loop {
+        // The connection is counted until the thread serving it ends, just after the handler is told
+        match connect(GARY) {
+            Ok(conn) => break conn,
+            Err(_) => assert!(start.elapsed() < Duration::from_secs(5), "Timed out"),
+        }
+    };
+    eventually(|| server.server().peer(GARY).is_some());
+    assert_eq!(
+        server.server().peer(GARY).unwrap().address().unwrap().ip(),
+        violations[0].address.unwrap().ip()
+    );
+}
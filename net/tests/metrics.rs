@@ -0,0 +1,239 @@
+#![cfg(feature = "tcp")]
+
+mod common;
+
+use std::{io::Write, sync::Arc, time::Duration};
+
+use binary_io::uuid::UUID;
+use net::{
+    client::ClientConfig,
+    connection::{Connection, Side},
+    limit::{Category, Limit, RateLimits, ViolationKind},
+    loopback,
+    metrics::{DisconnectReason, Metrics},
+    packet::{
+        ChatBroadcast, ChatMessage, Disconnect, KeepAlive, KeepAliveReply, Packet, PacketRegistry,
+    },
+    server::{Server, ServerConfig},
+};
+use text::TextComponent;
+
+use common::{eventually, say, Echo};
+
+const ASH: UUID = UUID::new(1, 1);
+
+fn server(metrics: &Metrics, limits: RateLimits, echo: Echo) -> Arc<Server<Echo>> {
+    let config = ServerConfig {
+        limits,
+        metrics: Some(metrics.clone()),
+        ..Default::default()
+    };
+    Arc::new(Server::new(config, PacketRegistry::pkmcom(), echo))
+}
+
+fn client(metrics: &Metrics) -> ClientConfig {
+    ClientConfig {
+        metrics: Some(metrics.clone()),
+        ..ClientConfig::new(ASH, Vec::new())
+    }
+}
+
+#[test]
+fn packets_are_counted_by_id_on_both_sides() {
+    let (server_metrics, client_metrics) = (Metrics::new(), Metrics::new());
+    let server = server(&server_metrics, RateLimits::default(), Echo::default());
+    let mut conn =
+        loopback::connect(&server, &client(&client_metrics), PacketRegistry::pkmcom()).unwrap();
+    for message in ["one", "two"] {
+        conn.send(&say(message)).unwrap();
+        assert!(conn.receive().unwrap().unwrap().is::<ChatBroadcast>());
+    }
+    // Frames are counted once they are written, which may be just after the client receives them
+    eventually(|| server_metrics.snapshot().totals.packets[&ChatBroadcast::ID].packets_sent == 2);
+
+    let snapshot = server_metrics.snapshot();
+    assert_eq!(snapshot.open(), 1);
+    assert_eq!(snapshot.connections[0].client, Some(ASH));
+    let chat = snapshot.connections[0].counters.packets[&ChatMessage::ID];
+    assert_eq!((chat.packets_received, chat.packets_sent), (2, 0));
+    let broadcast = snapshot.connections[0].counters.packets[&ChatBroadcast::ID];
+    assert_eq!((broadcast.packets_received, broadcast.packets_sent), (0, 2));
+
+    // Each side counts the same frames, in opposite directions
+    let sent = client_metrics.snapshot().connections[0].counters.clone();
+    assert_eq!(
+        sent.packets[&ChatMessage::ID].bytes_sent,
+        chat.bytes_received
+    );
+    assert_eq!(
+        sent.packets[&ChatBroadcast::ID].bytes_received,
+        broadcast.bytes_sent
+    );
+    assert_eq!(
+        sent.total().bytes_sent,
+        snapshot.totals.total().bytes_received
+    );
+
+    conn.sender()
+        .disconnect(TextComponent::RawText("Bye".to_string()))
+        .unwrap();
+    drop(conn);
+    eventually(|| server_metrics.snapshot().open() == 0);
+    let closed = server_metrics.snapshot();
+    assert_eq!(closed.opened, 1);
+    assert_eq!(closed.totals.packets[&ChatMessage::ID], chat);
+    assert_eq!(
+        closed.disconnects.get(&DisconnectReason::PeerDisconnected),
+        Some(&1)
+    );
+    let closed = client_metrics.snapshot();
+    assert_eq!(closed.open(), 0);
+    assert_eq!(
+        closed.disconnects.get(&DisconnectReason::Disconnected),
+        Some(&1)
+    );
+    assert_eq!(closed.totals.packets[&Disconnect::ID].packets_sent, 1);
+}
+
+#[test]
+fn round_trip_times_are_measured_by_keepalives() {
+    let metrics = Metrics::new();
+    let server = server(&Metrics::new(), RateLimits::default(), Echo::default());
+    let mut conn = loopback::connect(&server, &client(&metrics), PacketRegistry::pkmcom()).unwrap();
+    assert_eq!(metrics.snapshot().connections[0].rtt, None);
+
+    // The reply to the KeepAlive is handled before the broadcast is returned
+    conn.sender().ping().unwrap();
+    conn.send(&say("ping")).unwrap();
+    assert!(conn.receive().unwrap().unwrap().is::<ChatBroadcast>());
+    let snapshot = metrics.snapshot().connections[0].clone();
+    assert_eq!(snapshot.rtt, conn.sender().rtt());
+    assert!(snapshot.rtt.is_some());
+    assert_eq!(snapshot.counters.rtt_samples, 1);
+    assert_eq!(snapshot.counters.mean_rtt(), snapshot.rtt);
+    let keepalive = snapshot.counters.packets[&KeepAlive::ID];
+    assert_eq!((keepalive.packets_sent, keepalive.packets_received), (1, 0));
+    let reply = snapshot.counters.packets[&KeepAliveReply::ID];
+    assert_eq!((reply.packets_sent, reply.packets_received), (0, 1));
+}
+
+#[test]
+fn undecodable_packets_are_counted() {
+    let metrics = Metrics::new();
+    let (mut client, accepted) = loopback::pair();
+    let mut conn = Connection::new(accepted, Side::Server, PacketRegistry::pkmcom()).unwrap();
+    conn.set_metrics(Some(metrics.connection(None)));
+
+    // A frame of 2 bytes, holding only an unregistered packet id
+    client.write_all(&[0, 0, 0, 2, 0x7F, 0xFF]).unwrap();
+    assert!(conn.receive().is_err());
+    let snapshot = metrics.snapshot().connections[0].clone();
+    assert_eq!(snapshot.counters.decode_errors, 1);
+    assert_eq!(snapshot.counters.packets[&0x7FFF].bytes_received, 6);
+    assert_eq!(snapshot.disconnect, Some(DisconnectReason::DecodeError));
+
+    drop(conn);
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.open(), 0);
+    assert_eq!(snapshot.totals.decode_errors, 1);
+    assert_eq!(
+        snapshot.disconnects.get(&DisconnectReason::DecodeError),
+        Some(&1)
+    );
+}
+
+#[test]
+fn clients_kicked_for_violations_are_counted_by_the_violation() {
+    let metrics = Metrics::new();
+    let limits = RateLimits {
+        packets: [(Category::Chat, Limit::new(1, Duration::from_secs(60)))]
+            .iter()
+            .copied()
+            .collect(),
+        connections_per_ip: None,
+    };
+    let (echo, events) = Echo::new();
+    let server = server(&metrics, limits, echo);
+    let mut conn = loopback::connect(
+        &server,
+        &ClientConfig::new(ASH, Vec::new()),
+        PacketRegistry::pkmcom(),
+    )
+    .unwrap();
+    conn.send(&say("one")).unwrap();
+    assert!(conn.receive().unwrap().unwrap().is::<ChatBroadcast>());
+    conn.send(&say("two")).unwrap();
+    assert!(conn.receive().unwrap().unwrap().is::<Disconnect>());
+
+    eventually(|| events.try_iter().any(|e| e.starts_with("disconnected")));
+    eventually(|| metrics.snapshot().open() == 0);
+    let reason = DisconnectReason::Violation(ViolationKind::RateLimited(Category::Chat));
+    assert_eq!(metrics.snapshot().disconnects.get(&reason), Some(&1));
+    assert_eq!(reason.to_string(), "rate_limited");
+}
+
+#[test]
+fn snapshots_are_written_in_the_prometheus_text_format() {
+    let metrics = Metrics::new();
+    let closed = metrics.connection(None);
+    closed.record_sent(0x0010, 20);
+    closed.record_decode_error();
+    closed.record_disconnect(DisconnectReason::TimedOut);
+    closed.close();
+    // Nothing is counted once a connection is closed
+    closed.record_sent(0x0010, 20);
+
+    let open = metrics.connection(None);
+    open.set_client(ASH);
+    open.record_received(0x0010, 30);
+    open.record_rtt(Duration::from_millis(250));
+
+    let text = metrics.snapshot().to_prometheus();
+    let labels = format!("connection=\"2\",client=\"{}\"", ASH);
+    for line in [
+        "# TYPE pkmcom_connections_open gauge".to_string(),
+        "pkmcom_connections_open 1".to_string(),
+        "pkmcom_connections_opened_total 2".to_string(),
+        "pkmcom_packets_total{id=\"0x0010\",direction=\"received\"} 1".to_string(),
+        "pkmcom_packets_total{id=\"0x0010\",direction=\"sent\"} 1".to_string(),
+        "pkmcom_bytes_total{id=\"0x0010\",direction=\"received\"} 30".to_string(),
+        "pkmcom_bytes_total{id=\"0x0010\",direction=\"sent\"} 20".to_string(),
+        "pkmcom_decode_errors_total 1".to_string(),
+        "pkmcom_rtt_seconds_sum 0.25".to_string(),
+        "pkmcom_rtt_seconds_count 1".to_string(),
+        "pkmcom_disconnects_total{reason=\"timed_out\"} 1".to_string(),
+        format!(
+            "pkmcom_connection_bytes_total{{{},direction=\"received\"}} 30",
+            labels
+        ),
+        format!("pkmcom_connection_rtt_seconds{{{}}} 0.25", labels),
+    ] {
+        assert!(text.lines().any(|l| l == line), "{} in\n{}", line, text);
+    }
+}
+
+#[cfg(feature = "prometheus")]
+#[test]
+fn the_prometheus_endpoint_serves_the_metrics() {
+    use net::metrics::PrometheusEndpoint;
+    use std::{io::Read, net::TcpStream};
+
+    let metrics = Metrics::new();
+    let _open = metrics.connection(None);
+    let endpoint = PrometheusEndpoint::bind("127.0.0.1:0", metrics).unwrap();
+    let request = |request: &str| {
+        let mut stream = TcpStream::connect(endpoint.local_addr()).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let response = request("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+    assert!(response.contains("\r\n\r\n# HELP pkmcom_connections_open"));
+    assert!(response.contains("\npkmcom_connections_open 1\n"));
+    assert!(request("GET /other HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+    assert!(request("POST /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+}
@@ -0,0 +1,176 @@
+use std::{collections::HashSet, io::ErrorKind};
+
+use binary_io::{
+    data::{
+        ByteOrder, DataInput, DataInputStream, DataOutput, DataOutputStream, DeserializeCopy,
+        Deserializeable, Serializeable,
+    },
+    uuid::UUID,
+    version::Version,
+};
+use net::packet::{
+    write_packet, AnyPacket, ChatBroadcast, ChatMessage, Direction, Disconnect, KeepAlive,
+    KeepAliveReply, LongString, Packet, PacketError, PacketRegistry,
+};
+use text::TextComponent;
+
+fn write(packet: &dyn AnyPacket) -> Vec<u8> {
+    let mut out = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+    write_packet(packet, &mut out).unwrap();
+    out.into_inner()
+}
+
+fn read(bytes: &[u8], direction: Direction) -> std::io::Result<Box<dyn AnyPacket>> {
+    let mut input = DataInputStream::new(bytes, ByteOrder::BigEndian);
+    PacketRegistry::pkmcom().read_packet(direction, &mut input)
+}
+
+fn round_trip<P: Packet + PartialEq>(packet: P, direction: Direction) {
+    let bytes = write(&packet);
+    assert_eq!(bytes[..2], P::ID.to_be_bytes());
+    let read = read(&bytes, direction).unwrap();
+    assert_eq!(read.id(), P::ID);
+    assert_eq!(*read.downcast::<P>().unwrap(), packet);
+}
+
+// A packet from a newer protocol version, which reuses the id of KeepAlive
+#[derive(Debug, PartialEq)]
+struct Future(u8);
+
+impl Serializeable for Future {
+    fn serialize<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        self.0.serialize(output)
+    }
+}
+
+impl Deserializeable for Future {
+    fn deserialize<R: DataInput + ?Sized>(&mut self, input: &mut R) -> std::io::Result<()> {
+        self.0.deserialize(input)
+    }
+}
+
+impl DeserializeCopy for Future {
+    fn deserialize_copy<R: DataInput + ?Sized>(input: &mut R) -> std::io::Result<Self> {
+        u8::deserialize_copy(input).map(Self)
+    }
+}
+
+impl Packet for Future {
+    const ID: u16 = KeepAlive::ID;
+    const DIRECTION: Direction = Direction::Clientbound;
+    const SINCE: Version = Version::from_encoded(0x0001);
+}
+
+#[test]
+fn base_packets_round_trip() {
+    for direction in [Direction::Serverbound, Direction::Clientbound] {
+        round_trip(KeepAlive { nonce: u64::MAX }, direction);
+        round_trip(KeepAliveReply { nonce: 1 }, direction);
+        round_trip(
+            Disconnect {
+                reason: TextComponent::RawText("Server closed".to_string()),
+            },
+            direction,
+        );
+    }
+    round_trip(
+        ChatMessage {
+            message: LongString("Hello".to_string()),
+        },
+        Direction::Serverbound,
+    );
+    round_trip(
+        ChatBroadcast {
+            sender: UUID::new(1, 2),
+            message: TextComponent::RawText("<Ash> Hello".to_string()),
+        },
+        Direction::Clientbound,
+    );
+}
+
+#[test]
+fn long_strings_use_a_32_bit_length() {
+    let long = LongString("a".repeat(u16::MAX as usize + 1));
+    let mut out = DataOutputStream::new(Vec::new(), ByteOrder::BigEndian);
+    long.serialize(&mut out).unwrap();
+    let bytes = out.into_inner();
+    assert_eq!(bytes[..4], [0, 1, 0, 0]);
+    assert_eq!(bytes.len(), 4 + long.0.len());
+    let mut input = DataInputStream::new(&bytes[..], ByteOrder::BigEndian);
+    assert_eq!(LongString::deserialize_copy(&mut input).unwrap(), long);
+
+    let mut input = DataInputStream::new(&bytes[..100], ByteOrder::BigEndian);
+    let e = LongString::deserialize_copy(&mut input).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+
+    let bytes = [0, 0, 0, 2, 0xC3, 0x28];
+    let mut input = DataInputStream::new(&bytes[..], ByteOrder::BigEndian);
+    let e = LongString::deserialize_copy(&mut input).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn registry_holds_one_packet_per_id() {
+    let registry = PacketRegistry::pkmcom();
+    let mut ids = HashSet::new();
+    for info in registry.iter() {
+        assert!(ids.insert(info.id()), "{:#06x}", info.id());
+        assert_eq!(registry.get(info.id()).unwrap().name(), info.name());
+        assert!(info.since() <= registry.version());
+    }
+    let info = registry.get(ChatMessage::ID).unwrap();
+    assert_eq!(info.direction(), Direction::Serverbound);
+    assert!(info.name().ends_with("ChatMessage"));
+}
+
+#[test]
+#[should_panic(expected = "already registered")]
+fn conflicting_ids_are_rejected() {
+    PacketRegistry::pkmcom().register::<Future>();
+}
+
+#[test]
+fn packets_are_checked_against_the_registry() {
+    let registry = PacketRegistry::pkmcom();
+    assert_eq!(
+        registry
+            .check(ChatMessage::ID, Direction::Clientbound)
+            .unwrap_err(),
+        PacketError::WrongDirection(ChatMessage::ID, Direction::Clientbound)
+    );
+    let bytes = write(&ChatBroadcast {
+        sender: UUID::new(1, 2),
+        message: TextComponent::RawText("Hello".to_string()),
+    });
+    let e = read(&bytes, Direction::Serverbound).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+    // Packets newer than the negotiated version are rejected
+    let mut registry = PacketRegistry::new(Version::V1_0);
+    registry.register::<Future>();
+    assert_eq!(
+        registry
+            .check(Future::ID, Direction::Clientbound)
+            .unwrap_err(),
+        PacketError::Unsupported(Future::ID, Version::V1_0)
+    );
+    registry.set_version(Future::SINCE);
+    let mut input = DataInputStream::new(&[7][..], ByteOrder::BigEndian);
+    let read = registry
+        .decode(Future::ID, Direction::Clientbound, &mut input)
+        .unwrap();
+    assert_eq!(read.downcast_ref::<Future>(), Some(&Future(7)));
+}
+
+#[test]
+fn unknown_ids_are_rejected() {
+    let registry = PacketRegistry::pkmcom();
+    assert!(registry.get(0x7FFF).is_none());
+    assert_eq!(
+        registry.check(0x7FFF, Direction::Serverbound).unwrap_err(),
+        PacketError::UnknownId(0x7FFF)
+    );
+    let e = read(&[0x7F, 0xFF, 0, 0], Direction::Serverbound).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    assert!(e.get_ref().unwrap().is::<PacketError>());
+}
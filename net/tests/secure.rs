@@ -0,0 +1,271 @@
+#![cfg(feature = "secure")]
+
+mod common;
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use binary_io::uuid::UUID;
+use net::{
+    client::{self, ClientConfig},
+    connection::Transport,
+    loopback::{self, LoopbackStream},
+    packet::{ChatBroadcast, PacketRegistry},
+    secure::{Identity, SecureError, SecureTransport},
+    server::{Server, ServerConfig},
+};
+use text::TextComponent;
+
+use common::{say, Echo};
+
+#[derive(Default)]
+struct Tap {
+    // Everything written to this end of the pipe
+    log: Mutex<Vec<u8>>,
+    // Writes are only logged, and not delivered
+    hold: AtomicBool,
+    // The last byte of the next write is flipped
+    tamper: AtomicBool,
+}
+
+// One end of a loopback pipe, whose writes can be observed and interfered with
+#[derive(Clone)]
+struct Tapped {
+    stream: LoopbackStream,
+    tap: Arc<Tap>,
+}
+
+fn pipe() -> (Tapped, Tapped) {
+    let (a, b) = loopback::pair();
+    let tap = |stream| Tapped {
+        stream,
+        tap: Default::default(),
+    };
+    (tap(a), tap(b))
+}
+
+impl Read for Tapped {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Tapped {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut bytes = buf.to_vec();
+        if self.tap.tamper.swap(false, Ordering::AcqRel) {
+            *bytes.last_mut().unwrap() ^= 1;
+        }
+        self.tap.log.lock().unwrap().extend_from_slice(&bytes);
+        if !self.tap.hold.load(Ordering::Acquire) {
+            self.stream.write_all(&bytes)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for Tapped {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(self.clone())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    fn shutdown(&self) -> std::io::Result<()> {
+        self.stream.shutdown()
+    }
+}
+
+// Establishes a session, returning both sides and the raw client end of the pipe
+fn session(identity: &Identity) -> (SecureTransport<Tapped>, SecureTransport<Tapped>, Tapped) {
+    let (client, server) = pipe();
+    let raw = client.clone();
+    let identity = identity.clone();
+    let accept = std::thread::spawn(move || SecureTransport::accept(server, &identity));
+    let client = SecureTransport::connect(client, &[]).unwrap();
+    let server = accept.join().unwrap().unwrap();
+    raw.tap.log.lock().unwrap().clear();
+    (client, server, raw)
+}
+
+fn secure_error(e: &std::io::Error) -> Option<&SecureError> {
+    e.get_ref().and_then(|e| e.downcast_ref::<SecureError>())
+}
+
+#[test]
+fn data_round_trips_encrypted() {
+    let identity = Identity::generate();
+    let (mut client, mut server, raw) = session(&identity);
+    assert_eq!(client.peer_key(), Some(identity.public_key()));
+
+    // Larger than a record, so it is split
+    let message = (0..40_000u32).map(|i| i as u8).collect::<Vec<_>>();
+    client.write_all(&message).unwrap();
+    let mut received = vec![0u8; message.len()];
+    server.read_exact(&mut received).unwrap();
+    assert_eq!(received, message);
+
+    let log = raw.tap.log.lock().unwrap().clone();
+    assert!(log.len() > message.len());
+    assert!(!log.windows(64).any(|w| w == &message[..64]));
+
+    server.write_all(b"reply").unwrap();
+    let mut reply = [0u8; 5];
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"reply");
+}
+
+#[test]
+fn pinned_key_is_enforced() {
+    let identity = Identity::generate();
+    let other = Identity::generate();
+
+    let (client, server) = pipe();
+    let pinned = identity.clone();
+    let accept = std::thread::spawn(move || SecureTransport::accept(server, &pinned));
+    let transport = SecureTransport::connect(client, &[identity.public_key()]).unwrap();
+    assert_eq!(transport.peer_key(), Some(identity.public_key()));
+    accept.join().unwrap().unwrap();
+
+    let (client, server) = pipe();
+    let accept = std::thread::spawn(move || SecureTransport::accept(server, &identity));
+    let e = SecureTransport::connect(client, &[other.public_key()]).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+    assert!(matches!(
+        secure_error(&e),
+        Some(SecureError::UntrustedKey(_))
+    ));
+    let _ = accept.join();
+}
+
+#[test]
+fn identity_round_trips_through_bytes() {
+    let identity = Identity::generate();
+    let restored = Identity::from_bytes(&identity.to_bytes());
+    assert_eq!(restored.public_key(), identity.public_key());
+}
+
+#[test]
+fn replayed_record_is_rejected() {
+    let (mut client, mut server, raw) = session(&Identity::generate());
+    client.write_all(b"pay 100").unwrap();
+    let mut buf = [0u8; 7];
+    server.read_exact(&mut buf).unwrap();
+
+    let record = raw.tap.log.lock().unwrap().clone();
+    let mut raw = raw;
+    raw.write_all(&record).unwrap();
+    let e = server.read(&mut buf).unwrap_err();
+    assert_eq!(
+        secure_error(&e),
+        Some(&SecureError::OutOfSequence {
+            expected: 1,
+            received: 0
+        })
+    );
+}
+
+#[test]
+fn tampered_record_is_rejected() {
+    let (mut client, mut server, raw) = session(&Identity::generate());
+    raw.tap.tamper.store(true, Ordering::Release);
+    client.write_all(b"pay 100").unwrap();
+    let mut buf = [0u8; 7];
+    let e = server.read(&mut buf).unwrap_err();
+    assert_eq!(secure_error(&e), Some(&SecureError::Decrypt));
+}
+
+#[test]
+fn partial_record_survives_timeout() {
+    let (mut client, mut server, raw) = session(&Identity::generate());
+    raw.tap.hold.store(true, Ordering::Release);
+    client.write_all(b"hello").unwrap();
+    raw.tap.hold.store(false, Ordering::Release);
+    let record = std::mem::take(&mut *raw.tap.log.lock().unwrap());
+
+    let mut raw = raw;
+    raw.write_all(&record[..10]).unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    let mut buf = [0u8; 5];
+    assert_eq!(
+        server.read(&mut buf).unwrap_err().kind(),
+        ErrorKind::WouldBlock
+    );
+    raw.write_all(&record[10..]).unwrap();
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[test]
+fn server_with_identity_serves_secure_sessions() {
+    let identity = Identity::generate();
+    let server = Arc::new(Server::new(
+        ServerConfig {
+            identity: Some(identity.clone()),
+            ..Default::default()
+        },
+        PacketRegistry::pkmcom(),
+        Echo::default(),
+    ));
+    let (client, transport) = pipe();
+    let serving = server.spawn(transport);
+
+    let transport = SecureTransport::connect(client, &[identity.public_key()]).unwrap();
+    let config = ClientConfig::new(UUID::new(1, 1), Vec::new());
+    let mut conn = client::login(transport, &config, PacketRegistry::pkmcom()).unwrap();
+    conn.send(&say("hello")).unwrap();
+    let reply = conn.receive().unwrap().unwrap();
+    assert_eq!(
+        reply.downcast_ref::<ChatBroadcast>(),
+        Some(&ChatBroadcast {
+            sender: config.client,
+            message: TextComponent::RawText("hello".to_string()),
+        })
+    );
+    conn.sender().close().unwrap();
+    serving.join().unwrap().unwrap();
+}
+
+#[test]
+fn hello_sent_a_byte_at_a_time_times_out() {
+    let server = Arc::new(Server::new(
+        ServerConfig {
+            identity: Some(Identity::generate()),
+            handshake_timeout: Duration::from_millis(200),
+            ..Default::default()
+        },
+        PacketRegistry::pkmcom(),
+        Echo::default(),
+    ));
+    let (mut client, transport) = pipe();
+    let start = Instant::now();
+    let serving = server.spawn(transport);
+    // Each byte arrives well within the timeout, but the whole hello takes almost 2 seconds
+    std::thread::spawn(move || {
+        for _ in 0..37 {
+            std::thread::sleep(Duration::from_millis(50));
+            if client.write_all(&[0]).is_err() {
+                break;
+            }
+        }
+    });
+    assert_eq!(
+        serving.join().unwrap().unwrap_err().kind(),
+        ErrorKind::TimedOut
+    );
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
@@ -0,0 +1,298 @@
+#![cfg(feature = "tcp")]
+
+mod common;
+
+use std::{
+    io::ErrorKind,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use binary_io::uuid::UUID;
+use net::{
+    client::ClientConfig,
+    connection::{Connection, Side, Transport},
+    handshake::{LoginReject, State},
+    limit::RateLimits,
+    loopback::{self, LoopbackStream},
+    packet::{AnyPacket, ChatBroadcast, ChatMessage, PacketRegistry},
+    server::{Handler, Peer, Server, ServerConfig},
+    session::{Resume, ACK_INTERVAL},
+};
+use text::TextComponent;
+
+use common::{eventually, say};
+
+const ASH: UUID = UUID::new(1, 1);
+
+#[derive(Clone, Debug, PartialEq)]
+enum Event {
+    Connected,
+    Message(String),
+    Disconnected(Option<ErrorKind>),
+}
+
+// Records what happens to each connection, and echoes messages back to their sender
+#[derive(Default)]
+struct Recorder {
+    events: Mutex<Vec<Event>>,
+}
+
+impl Recorder {
+    fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl Handler for Recorder {
+    fn connected(&self, _: &Peer) -> std::io::Result<()> {
+        self.events.lock().unwrap().push(Event::Connected);
+        Ok(())
+    }
+
+    fn packet(&self, peer: &Peer, packet: Box<dyn AnyPacket>) -> std::io::Result<()> {
+        let message = packet.downcast::<ChatMessage>().unwrap().message.0;
+        self.events
+            .lock()
+            .unwrap()
+            .push(Event::Message(message.clone()));
+        peer.send(&ChatBroadcast {
+            sender: peer.client(),
+            message: TextComponent::RawText(message),
+        })
+    }
+
+    fn disconnected(&self, _: &Peer, error: Option<&std::io::Error>) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(Event::Disconnected(error.map(|e| e.kind())));
+    }
+}
+
+fn server(grace: Option<Duration>) -> Arc<Server<Recorder>> {
+    // Some tests send more messages at once than the default limits allow
+    let config = ServerConfig {
+        session_grace: grace,
+        limits: RateLimits::unlimited(),
+        ..Default::default()
+    };
+    Arc::new(Server::new(
+        config,
+        PacketRegistry::pkmcom(),
+        Recorder::default(),
+    ))
+}
+
+fn connect(server: &Arc<Server<Recorder>>) -> Connection<LoopbackStream> {
+    loopback::connect(
+        server,
+        &ClientConfig::new(ASH, Vec::new()),
+        PacketRegistry::pkmcom(),
+    )
+    .unwrap()
+}
+
+fn hear<T: Transport>(conn: &mut Connection<T>) -> String {
+    let packet = conn.receive().unwrap().unwrap();
+    packet
+        .downcast_ref::<ChatBroadcast>()
+        .unwrap()
+        .message
+        .to_string()
+}
+
+fn is_detached(server: &Server<Recorder>) -> bool {
+    server
+        .peer(ASH)
+        .and_then(|peer| peer.sender().session())
+        .is_some_and(|session| session.is_detached())
+}
+
+#[test]
+fn lost_packets_are_sent_again_on_resume() {
+    let server = server(Some(Duration::from_secs(10)));
+    let mut conn = connect(&server);
+    let session = conn.session().unwrap();
+    assert_eq!(session.grace(), Duration::from_secs(10));
+    conn.send(&say("one")).unwrap();
+    assert_eq!(hear(&mut conn), "one");
+
+    // The packet is never read, as the client's end of the connection is lost
+    let peer = server.peer(ASH).unwrap();
+    peer.send(&ChatBroadcast {
+        sender: UUID::NIL,
+        message: TextComponent::RawText("in flight".to_string()),
+    })
+    .unwrap();
+    conn.transport().shutdown().unwrap();
+    eventually(|| is_detached(&server));
+
+    // Both sides keep the packets they send until the session is resumed
+    peer.send(&ChatBroadcast {
+        sender: UUID::NIL,
+        message: TextComponent::RawText("while you were away".to_string()),
+    })
+    .unwrap();
+    conn.send(&say("two")).unwrap();
+    assert_eq!(session.received(), 1);
+
+    let mut conn = loopback::resume(&server, &conn, &ClientConfig::new(ASH, Vec::new())).unwrap();
+    assert_eq!(hear(&mut conn), "in flight");
+    assert_eq!(hear(&mut conn), "while you were away");
+    assert_eq!(hear(&mut conn), "two");
+    assert_eq!(session.received(), 4);
+    assert!(!is_detached(&server));
+    assert_eq!(
+        server.handler().events(),
+        vec![
+            Event::Connected,
+            Event::Message("one".to_string()),
+            Event::Message("two".to_string())
+        ]
+    );
+
+    conn.sender()
+        .disconnect(TextComponent::RawText("Bye".to_string()))
+        .unwrap();
+    eventually(|| server.handler().events().len() == 4);
+    assert_eq!(
+        server.handler().events().last(),
+        Some(&Event::Disconnected(None))
+    );
+}
+
+#[test]
+fn resuming_takes_over_a_connection_the_server_still_serves() {
+    let server = server(Some(Duration::from_secs(10)));
+    let mut conn = connect(&server);
+    conn.send(&say("one")).unwrap();
+    assert_eq!(hear(&mut conn), "one");
+
+    // The server has not noticed the old connection is gone, and closes it
+    let mut resumed =
+        loopback::resume(&server, &conn, &ClientConfig::new(ASH, Vec::new())).unwrap();
+    assert!(conn.receive().unwrap().is_none());
+    resumed.send(&say("two")).unwrap();
+    assert_eq!(hear(&mut resumed), "two");
+    assert_eq!(server.peers().len(), 1);
+    assert_eq!(server.handler().events()[0], Event::Connected);
+    assert_eq!(server.handler().events().len(), 3);
+}
+
+#[test]
+fn sessions_expire_after_the_grace_window() {
+    let server = server(Some(Duration::from_millis(50)));
+    let conn = connect(&server);
+    conn.transport().shutdown().unwrap();
+    eventually(|| server.handler().events().len() == 2);
+    assert_eq!(
+        server.handler().events(),
+        vec![
+            Event::Connected,
+            Event::Disconnected(Some(ErrorKind::UnexpectedEof))
+        ]
+    );
+    assert!(server.peers().is_empty());
+
+    let e = loopback::resume(&server, &conn, &ClientConfig::new(ASH, Vec::new()))
+        .err()
+        .unwrap();
+    assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+    assert!(e.to_string().contains("The session has expired"));
+}
+
+#[test]
+fn resuming_requires_the_token() {
+    let server = server(Some(Duration::from_secs(10)));
+    let mut conn = connect(&server);
+    let session = conn.session().unwrap();
+    assert!(session.matches(&session.token()));
+
+    let (client, accepted) = loopback::pair();
+    server.spawn(accepted);
+    let mut intruder = Connection::new(client, Side::Client, PacketRegistry::pkmcom()).unwrap();
+    intruder
+        .send(&Resume {
+            client: ASH,
+            token: [0; 32],
+            received: 0,
+        })
+        .unwrap();
+    intruder.set_state(State::Login);
+    let packet = intruder.receive().unwrap().unwrap();
+    assert_eq!(
+        packet.downcast_ref::<LoginReject>().unwrap().reason,
+        TextComponent::RawText("Invalid session token".to_string())
+    );
+
+    // The session is unaffected
+    conn.send(&say("still here")).unwrap();
+    assert_eq!(hear(&mut conn), "still here");
+}
+
+#[test]
+fn acknowledged_packets_are_not_kept() {
+    let server = server(Some(Duration::from_secs(10)));
+    let mut conn = connect(&server);
+    let count = ACK_INTERVAL + 8;
+    for i in 0..count {
+        conn.send(&say(&i.to_string())).unwrap();
+    }
+    for i in 0..count {
+        assert_eq!(hear(&mut conn), i.to_string());
+    }
+    let session = conn.session().unwrap();
+    assert_eq!(session.sent(), count);
+    assert_eq!(session.received(), count);
+    // The server acknowledged the first ACK_INTERVAL packets, before echoing the last of them
+    assert_eq!(session.unacknowledged(), 8);
+}
+
+#[test]
+fn logging_in_again_replaces_a_lost_session() {
+    let server = server(Some(Duration::from_secs(10)));
+    let conn = connect(&server);
+    conn.transport().shutdown().unwrap();
+    eventually(|| is_detached(&server));
+
+    let mut conn = connect(&server);
+    conn.send(&say("back")).unwrap();
+    assert_eq!(hear(&mut conn), "back");
+    assert_eq!(
+        server.handler().events(),
+        vec![
+            Event::Connected,
+            Event::Disconnected(Some(ErrorKind::UnexpectedEof)),
+            Event::Connected,
+            Event::Message("back".to_string())
+        ]
+    );
+}
+
+#[test]
+fn sessions_are_only_given_when_both_sides_support_them() {
+    let server_without = server(None);
+    assert!(connect(&server_without).session().is_none());
+
+    let server = server(Some(Duration::from_secs(10)));
+    let mut config = ClientConfig::new(ASH, Vec::new());
+    config.resume = false;
+    let conn = loopback::connect(&server, &config, PacketRegistry::pkmcom()).unwrap();
+    assert!(conn.session().is_none());
+    assert_eq!(
+        loopback::resume(&server, &conn, &config)
+            .err()
+            .unwrap()
+            .kind(),
+        ErrorKind::NotConnected
+    );
+
+    // Without a session, a lost connection is disconnected at once
+    conn.transport().shutdown().unwrap();
+    eventually(|| server.handler().events().len() == 2);
+    assert_eq!(
+        server.handler().events(),
+        vec![Event::Connected, Event::Disconnected(None)]
+    );
+}
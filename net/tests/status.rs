@@ -0,0 +1,165 @@
+#![cfg(feature = "tcp")]
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use binary_io::uuid::UUID;
+use net::{
+    client::{ClientConfig, LoginError},
+    handshake::{ContentHash, State},
+    loopback,
+    packet::{AnyPacket, Packet, PacketRegistry},
+    server::{Handler, Peer, Server, ServerConfig},
+    status::{self, StatusPing, StatusRequest, StatusResponse, MAX_SAMPLE},
+    tcp::{self, TcpServer},
+};
+use text::TextComponent;
+
+const ASH: UUID = UUID::new(1, 1);
+const GARY: UUID = UUID::new(1, 2);
+
+// Shares the names of the players online, with a few made up to fill the sample
+#[derive(Default)]
+struct Lobby {
+    names: Mutex<Vec<String>>,
+}
+
+impl Handler for Lobby {
+    fn connected(&self, peer: &Peer) -> std::io::Result<()> {
+        self.names.lock().unwrap().push(peer.client().to_string());
+        Ok(())
+    }
+
+    fn packet(&self, _: &Peer, _: Box<dyn AnyPacket>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn status(&self, status: &mut StatusResponse) {
+        status
+            .sample
+            .extend(self.names.lock().unwrap().iter().cloned());
+        status
+            .sample
+            .extend((0..MAX_SAMPLE).map(|i| format!("Trainer {}", i)));
+    }
+}
+
+fn content() -> Vec<ContentHash> {
+    vec![ContentHash::of("pokemon", "Pikachu").unwrap()]
+}
+
+fn config() -> ServerConfig {
+    ServerConfig {
+        motd: TextComponent::RawText("Welcome to Pallet Town".to_string()),
+        max_players: 1,
+        content: content(),
+        ..Default::default()
+    }
+}
+
+// Waits for the server to call connected for a player
+fn joined(server: &Server<Lobby>) {
+    let start = Instant::now();
+    while server.handler().names.lock().unwrap().is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(5), "Timed out");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn status_is_answered_over_tcp_without_logging_in() {
+    let mut server = TcpServer::bind(
+        "127.0.0.1:0",
+        Server::new(config(), PacketRegistry::pkmcom(), Lobby::default()),
+    )
+    .unwrap();
+    let status = tcp::query(server.local_addr(), Duration::from_secs(5)).unwrap();
+    let response = status.response;
+    assert_eq!(
+        response.motd,
+        TextComponent::RawText("Welcome to Pallet Town".to_string())
+    );
+    assert_eq!(response.protocol, net::packet::PROTOCOL_VERSION);
+    assert_eq!(response.max_players, 1);
+    assert_eq!(response.online_players, 0);
+    assert_eq!(response.content.0, content());
+    assert_eq!(response.sample.len(), MAX_SAMPLE);
+    assert!(status.latency < Duration::from_secs(5));
+    // Asking for the status does not count as a player
+    assert!(server.server().peers().is_empty());
+    assert!(server.server().handler().names.lock().unwrap().is_empty());
+
+    let _conn = tcp::connect(
+        server.local_addr(),
+        Duration::from_secs(5),
+        &ClientConfig::new(ASH, content()),
+        PacketRegistry::pkmcom(),
+    )
+    .unwrap();
+    joined(server.server());
+    let response = tcp::query(server.local_addr(), Duration::from_secs(5))
+        .unwrap()
+        .response;
+    assert_eq!(response.online_players, 1);
+    assert_eq!(response.sample[0], ASH.to_string());
+    assert_eq!(response.sample[1], "Trainer 0");
+    server.shutdown(TextComponent::RawText("Closing".to_string()));
+}
+
+#[test]
+fn full_servers_turn_players_away() {
+    let server = Arc::new(Server::new(
+        config(),
+        PacketRegistry::pkmcom(),
+        Lobby::default(),
+    ));
+    let mut ash = loopback::connect(
+        &server,
+        &ClientConfig::new(ASH, content()),
+        PacketRegistry::pkmcom(),
+    )
+    .unwrap();
+    joined(&server);
+    // Players are rejected during login, rather than kicked after being accepted
+    let rejected = |client: UUID, reason: &str| {
+        let e = loopback::connect(
+            &server,
+            &ClientConfig::new(client, content()),
+            PacketRegistry::pkmcom(),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            e.get_ref().unwrap().downcast_ref::<LoginError>(),
+            Some(&LoginError::Rejected(TextComponent::RawText(
+                reason.to_string()
+            )))
+        );
+    };
+    rejected(GARY, "The server is full");
+    rejected(ASH, "A player with the same UUID is already connected");
+    assert_eq!(server.handler().names.lock().unwrap().len(), 1);
+
+    // The status still shows the player who got in
+    let (client, accepted) = loopback::pair();
+    server.spawn(accepted);
+    let status = status::query(client, Duration::from_secs(5)).unwrap();
+    assert_eq!(status.response.online_players, 1);
+    ash.sender()
+        .disconnect(TextComponent::RawText("Bye".to_string()))
+        .unwrap();
+    assert!(ash.receive().unwrap().is_none());
+}
+
+#[test]
+fn status_packets_are_only_valid_before_login() {
+    assert!(State::Handshaking.accepts(StatusRequest::ID));
+    assert!(State::Status.accepts(StatusPing::ID));
+    assert!(!State::Status.accepts(StatusRequest::ID));
+    for id in [StatusRequest::ID, StatusResponse::ID, StatusPing::ID] {
+        assert!(!State::Play.accepts(id));
+        assert!(!State::Login.accepts(id));
+    }
+}
@@ -0,0 +1,318 @@
+use std::sync::Mutex;
+
+use binary_io::nbt::{compound::NbtCompound, NbtTag};
+use net::{
+    digest::{ContentDigest, Digest},
+    packet::List,
+    sync::{
+        RegistryEntries, RegistrySync, RegistrySyncEnd, RegistrySyncStart, SyncError, SyncEvent,
+        SyncTarget, SyncedEntry,
+    },
+};
+use text::TextComponent;
+
+#[derive(Default)]
+struct Target(Mutex<Option<Vec<SyncedEntry>>>);
+
+impl SyncTarget for Target {
+    fn install(&self, registry: &str, entries: Vec<SyncedEntry>) -> Result<(), SyncError> {
+        let mut installed = self.0.lock().unwrap();
+        if installed.is_some() {
+            return Err(SyncError::Locked(registry.to_string()));
+        }
+        *installed = Some(entries);
+        Ok(())
+    }
+}
+
+fn entry(id: u32, name: &str, power: i32) -> SyncedEntry {
+    let mut data = NbtCompound::new();
+    data.insert("power".to_string(), NbtTag::Int(power));
+    SyncedEntry {
+        id,
+        name: name.to_string(),
+        data,
+    }
+}
+
+fn moves() -> Vec<SyncedEntry> {
+    vec![
+        entry(0, "pokemon:ember", 40),
+        entry(1, "pokemon:growl", 0),
+        entry(2, "pokemon:tackle", 40),
+    ]
+}
+
+fn start(count: u32) -> RegistrySyncStart {
+    RegistrySyncStart {
+        registry: "moves".to_string(),
+        count,
+    }
+}
+
+fn chunk(entries: Vec<SyncedEntry>) -> RegistryEntries {
+    RegistryEntries {
+        registry: "moves".to_string(),
+        entries: List(entries),
+    }
+}
+
+fn end(digest: Digest) -> RegistrySyncEnd {
+    RegistrySyncEnd {
+        registry: "moves".to_string(),
+        digest,
+    }
+}
+
+#[test]
+fn entries_are_installed_in_order_of_ids() {
+    let target = Target::default();
+    let mut sync = RegistrySync::new();
+    sync.add_target("moves", &target);
+    assert!(!sync.is_complete());
+
+    let entries = moves();
+    let digest = entries.digest().unwrap();
+    let mut reversed = entries.clone();
+    reversed.reverse();
+    assert_eq!(sync.receive(&start(3)), Ok(SyncEvent::Received));
+    assert_eq!(
+        sync.receive(&chunk(reversed[..1].to_vec())),
+        Ok(SyncEvent::Received)
+    );
+    assert_eq!(
+        sync.receive(&chunk(reversed[1..].to_vec())),
+        Ok(SyncEvent::Received)
+    );
+    // The digest covers the order the entries were sent in
+    assert_eq!(
+        sync.receive(&end(reversed.digest().unwrap())),
+        Ok(SyncEvent::Installed("moves".to_string()))
+    );
+    assert_ne!(reversed.digest().unwrap(), digest);
+    assert!(sync.is_complete());
+    assert_eq!(sync.installed().collect::<Vec<_>>(), vec!["moves"]);
+    assert_eq!(target.0.lock().unwrap().as_ref(), Some(&entries));
+}
+
+#[test]
+fn mismatched_entries_are_rejected() {
+    let target = Target::default();
+    let cases = vec![
+        (
+            vec![entry(0, "pokemon:ember", 40), entry(0, "pokemon:growl", 0)],
+            SyncError::DuplicateId {
+                registry: "moves".to_string(),
+                id: 0,
+            },
+        ),
+        (
+            vec![entry(0, "pokemon:ember", 40), entry(1, "pokemon:ember", 0)],
+            SyncError::DuplicateName {
+                registry: "moves".to_string(),
+                name: "pokemon:ember".to_string(),
+            },
+        ),
+        (
+            vec![entry(3, "pokemon:ember", 40)],
+            SyncError::InvalidId {
+                registry: "moves".to_string(),
+                id: 3,
+            },
+        ),
+        (
+            [moves(), vec![entry(3, "pokemon:bubble", 40)]].concat(),
+            SyncError::TooManyEntries {
+                registry: "moves".to_string(),
+                count: 3,
+            },
+        ),
+    ];
+    for (entries, error) in cases {
+        let mut sync = RegistrySync::new();
+        sync.add_target("moves", &target);
+        sync.receive(&start(3)).unwrap();
+        assert_eq!(sync.receive(&chunk(entries)), Err(error));
+    }
+    assert_eq!(*target.0.lock().unwrap(), None);
+}
+
+#[test]
+fn incomplete_or_altered_registries_are_rejected() {
+    let target = Target::default();
+    let mut sync = RegistrySync::new();
+    sync.add_target("moves", &target);
+    sync.receive(&start(3)).unwrap();
+    sync.receive(&chunk(moves()[..2].to_vec())).unwrap();
+    let e = sync.receive(&end(moves().digest().unwrap())).unwrap_err();
+    assert_eq!(
+        e,
+        SyncError::MissingEntries {
+            registry: "moves".to_string(),
+            expected: 3,
+            received: 2
+        }
+    );
+    assert_eq!(
+        e.reason(),
+        TextComponent::RawText(
+            "Registry synchronization failed: registry moves has 2 entries, expected 3".to_string()
+        )
+    );
+
+    let mut sync = RegistrySync::new();
+    sync.add_target("moves", &target);
+    sync.receive(&start(3)).unwrap();
+    let mut altered = moves();
+    altered[2] = entry(2, "pokemon:tackle", 50);
+    sync.receive(&chunk(altered)).unwrap();
+    assert_eq!(
+        sync.receive(&end(moves().digest().unwrap())),
+        Err(SyncError::DigestMismatch("moves".to_string()))
+    );
+    assert_eq!(*target.0.lock().unwrap(), None);
+}
+
+#[test]
+fn unexpected_registries_are_rejected() {
+    let target = Target::default();
+    let mut sync = RegistrySync::new();
+    sync.add_target("moves", &target);
+    assert_eq!(
+        sync.receive(&RegistrySyncStart {
+            registry: "types".to_string(),
+            count: 0
+        }),
+        Err(SyncError::UnknownRegistry("types".to_string()))
+    );
+    assert_eq!(
+        sync.receive(&chunk(moves())),
+        Err(SyncError::NotStarted("moves".to_string()))
+    );
+    sync.receive(&start(0)).unwrap();
+    assert_eq!(
+        sync.receive(&start(0)),
+        Err(SyncError::Duplicate("moves".to_string()))
+    );
+}
+
+#[cfg(feature = "tcp")]
+mod server {
+    use std::{
+        sync::{mpsc, Mutex},
+        time::Duration,
+    };
+
+    use binary_io::uuid::UUID;
+    use net::{
+        client::ClientConfig,
+        digest::Digest,
+        packet::{AnyPacket, ChatBroadcast, PacketRegistry},
+        server::{Handler, Peer, Server, ServerConfig},
+        sync::{
+            send_registry, send_registry_chunked, RegistryEntries, RegistrySync, RegistrySyncAck,
+            RegistrySyncEnd, RegistrySyncStart, SyncError,
+        },
+        tcp::{self, TcpServer},
+    };
+    use text::TextComponent;
+
+    use super::{moves, Target};
+
+    struct Authoritative {
+        tamper: bool,
+        acks: Mutex<mpsc::Sender<String>>,
+    }
+
+    impl Handler for Authoritative {
+        fn connected(&self, peer: &Peer) -> std::io::Result<()> {
+            peer.send(&ChatBroadcast {
+                sender: UUID::NIL,
+                message: TextComponent::RawText("welcome".to_string()),
+            })?;
+            if self.tamper {
+                peer.send(&RegistrySyncStart {
+                    registry: "moves".to_string(),
+                    count: 3,
+                })?;
+                peer.send(&RegistryEntries {
+                    registry: "moves".to_string(),
+                    entries: moves().into(),
+                })?;
+                peer.send(&RegistrySyncEnd {
+                    registry: "moves".to_string(),
+                    digest: Digest::default(),
+                })?;
+            } else {
+                // Small chunks, so the entries are split across packets
+                send_registry_chunked(peer.sender(), "moves", &moves(), 16)?;
+            }
+            send_registry(peer.sender(), "types", &[])
+        }
+
+        fn packet(&self, _: &Peer, packet: Box<dyn AnyPacket>) -> std::io::Result<()> {
+            let ack = packet.downcast::<RegistrySyncAck>().unwrap();
+            let _ = self.acks.lock().unwrap().send(ack.registry);
+            Ok(())
+        }
+    }
+
+    type Synced = (
+        std::io::Result<Vec<Box<dyn AnyPacket>>>,
+        Target,
+        mpsc::Receiver<String>,
+    );
+
+    fn connect(tamper: bool) -> Synced {
+        let (acks, received) = mpsc::channel();
+        let server = Server::new(
+            ServerConfig::default(),
+            PacketRegistry::pkmcom(),
+            Authoritative {
+                tamper,
+                acks: Mutex::new(acks),
+            },
+        );
+        let server = TcpServer::bind("127.0.0.1:0", server).unwrap();
+        let mut conn = tcp::connect(
+            server.local_addr(),
+            Duration::from_secs(5),
+            &ClientConfig::new(UUID::new(1, 1), Vec::new()),
+            PacketRegistry::pkmcom(),
+        )
+        .unwrap();
+
+        let moves = Target::default();
+        let types = Target::default();
+        let mut sync = RegistrySync::new();
+        sync.add_target("moves", &moves);
+        sync.add_target("types", &types);
+        let result = sync.run(&mut conn);
+        drop(sync);
+        let _ = conn.sender().close();
+        (result, moves, received)
+    }
+
+    #[test]
+    fn client_installs_and_acknowledges_registries() {
+        let (result, target, acks) = connect(false);
+        let acks = acks.iter().take(2).collect::<Vec<_>>();
+        let other = result.unwrap();
+        assert_eq!(other.len(), 1);
+        assert!(other[0].is::<ChatBroadcast>());
+        assert_eq!(target.0.lock().unwrap().as_ref(), Some(&moves()));
+        assert_eq!(acks, vec!["moves".to_string(), "types".to_string()]);
+    }
+
+    #[test]
+    fn client_disconnects_on_mismatch() {
+        let (result, target, _) = connect(true);
+        let e = result.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(
+            e.get_ref().and_then(|e| e.downcast_ref::<SyncError>()),
+            Some(&SyncError::DigestMismatch("moves".to_string()))
+        );
+        assert_eq!(*target.0.lock().unwrap(), None);
+    }
+}
@@ -0,0 +1,194 @@
+#![cfg(feature = "tcp")]
+
+mod common;
+
+use std::{
+    io::{ErrorKind, Read},
+    net::TcpStream,
+    sync::mpsc,
+    time::Duration,
+};
+
+use binary_io::{
+    data::{ByteOrder, DataOutputStream},
+    uuid::UUID,
+};
+use net::{
+    client::{ClientConfig, LoginError},
+    frame::{Frame, FrameCodec},
+    handshake::{ContentHash, Handshake},
+    packet::{ChatBroadcast, Disconnect, List, PacketRegistry},
+    server::{Server, ServerConfig},
+    tcp::{self, TcpServer},
+};
+use text::TextComponent;
+
+use common::{say, Echo};
+
+fn content() -> Vec<ContentHash> {
+    vec![
+        ContentHash::of("pokemon", "base").unwrap(),
+        ContentHash::of("moves", "base").unwrap(),
+    ]
+}
+
+fn start(config: ServerConfig) -> (TcpServer<Echo>, mpsc::Receiver<String>) {
+    let (echo, events) = Echo::new();
+    let server = Server::new(config, PacketRegistry::pkmcom(), echo);
+    (TcpServer::bind("127.0.0.1:0", server).unwrap(), events)
+}
+
+fn client(id: u64) -> ClientConfig {
+    ClientConfig::new(UUID::new(id, id), content())
+}
+
+#[test]
+fn login_and_dispatch() {
+    let (mut server, events) = start(ServerConfig {
+        content: content(),
+        ..Default::default()
+    });
+    let config = client(1);
+    let mut conn = tcp::connect(
+        server.local_addr(),
+        Duration::from_secs(5),
+        &config,
+        PacketRegistry::pkmcom(),
+    )
+    .unwrap();
+    assert_eq!(
+        events.recv_timeout(Duration::from_secs(5)).unwrap(),
+        format!("connected {}", config.client)
+    );
+
+    conn.send(&say("hello")).unwrap();
+    let reply = conn.receive().unwrap().unwrap();
+    assert_eq!(
+        reply.downcast_ref::<ChatBroadcast>(),
+        Some(&ChatBroadcast {
+            sender: config.client,
+            message: TextComponent::RawText("hello".to_string()),
+        })
+    );
+
+    server.shutdown(TextComponent::RawText("Bye".to_string()));
+    let packet = conn.receive().unwrap().unwrap();
+    assert_eq!(
+        packet.downcast_ref::<Disconnect>().map(|d| &d.reason),
+        Some(&TextComponent::RawText("Bye".to_string()))
+    );
+    assert!(events
+        .recv_timeout(Duration::from_secs(5))
+        .unwrap()
+        .starts_with("disconnected"));
+}
+
+#[test]
+fn content_mismatch_is_rejected() {
+    let (server, _events) = start(ServerConfig {
+        content: content(),
+        ..Default::default()
+    });
+    let mut config = client(2);
+    config.content[1] = ContentHash::of("moves", "modded").unwrap();
+    let err = tcp::connect(
+        server.local_addr(),
+        Duration::from_secs(5),
+        &config,
+        PacketRegistry::pkmcom(),
+    )
+    .err()
+    .unwrap();
+    assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    let reason = err.get_ref().unwrap().downcast_ref::<LoginError>().unwrap();
+    assert!(matches!(reason, LoginError::Rejected(text) if text.to_string().contains("moves")));
+}
+
+#[test]
+fn packets_outside_state_are_rejected() {
+    let (server, _events) = start(ServerConfig::default());
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    let codec = FrameCodec::default();
+    let mut output = DataOutputStream::new(&mut stream, ByteOrder::BigEndian);
+    codec.write_packet(&say("too early"), &mut output).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut bytes = Vec::new();
+    stream.read_to_end(&mut bytes).unwrap();
+    let mut decoder = net::frame::FrameDecoder::default();
+    decoder.feed(&bytes);
+    let frame = decoder.next_frame().unwrap().unwrap();
+    assert_eq!(frame.id, 0x0002);
+}
+
+#[test]
+fn idle_client_times_out() {
+    let (server, events) = start(ServerConfig {
+        keepalive_interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(300),
+        ..Default::default()
+    });
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    let handshake = Handshake {
+        protocol: net::packet::PROTOCOL_VERSION,
+        client: UUID::new(3, 3),
+        content: List::default(),
+        flags: 0,
+    };
+    let bytes = FrameCodec::default()
+        .encode(&Frame::from_packet(&handshake).unwrap())
+        .unwrap();
+    std::io::Write::write_all(&mut stream, &bytes).unwrap();
+    // Never answer the server's KeepAlives
+    assert!(events
+        .recv_timeout(Duration::from_secs(5))
+        .unwrap()
+        .starts_with("connected"));
+    assert_eq!(
+        events.recv_timeout(Duration::from_secs(5)).unwrap(),
+        format!(
+            "disconnected {} {:?}",
+            UUID::new(3, 3),
+            Some(ErrorKind::TimedOut)
+        )
+    );
+}
+
+#[test]
+fn keepalive_keeps_idle_client_connected() {
+    let (server, events) = start(ServerConfig {
+        keepalive_interval: Duration::from_millis(20),
+        timeout: Duration::from_millis(200),
+        ..Default::default()
+    });
+    let mut config = ClientConfig::new(UUID::new(4, 4), Vec::new());
+    config.keepalive_interval = Duration::from_millis(20);
+    config.timeout = Duration::from_millis(200);
+    let mut conn = tcp::connect(
+        server.local_addr(),
+        Duration::from_secs(5),
+        &config,
+        PacketRegistry::pkmcom(),
+    )
+    .unwrap();
+    assert!(events
+        .recv_timeout(Duration::from_secs(5))
+        .unwrap()
+        .starts_with("connected"));
+
+    // Receive for longer than the timeout, answering KeepAlives in the background
+    let reader = std::thread::spawn(move || {
+        let result = conn.receive();
+        (conn, result)
+    });
+    std::thread::sleep(Duration::from_millis(600));
+    assert!(events.try_recv().is_err());
+    let peer = server.server().peer(config.client).unwrap();
+    assert!(peer.sender().rtt().is_some());
+    peer.kick(TextComponent::RawText("done".to_string()))
+        .unwrap();
+    let (conn, result) = reader.join().unwrap();
+    assert!(result.unwrap().unwrap().is::<Disconnect>());
+    assert!(conn.sender().rtt().is_some());
+}
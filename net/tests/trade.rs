@@ -0,0 +1,448 @@
+#![cfg(feature = "tcp")]
+
+mod common;
+
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use binary_io::{
+    nbt::{compound::NbtCompound, NbtTag},
+    uuid::UUID,
+};
+use net::{
+    harness::Harness,
+    packet::{List, PacketRegistry},
+    server::{Server, ServerConfig},
+    trade::{
+        TradeAbort, TradeCommit, TradeConfig, TradeConfirm, TradeCounterOffer, TradeLock,
+        TradeOffer, TradePokemon, TradeService, TradeSessions, TradeStore,
+    },
+};
+use text::TextComponent;
+
+use common::{eventually, packets};
+
+const ASH: UUID = UUID::new(1, 1);
+const GARY: UUID = UUID::new(1, 2);
+const PIKACHU: UUID = UUID::new(2, 25);
+const EEVEE: UUID = UUID::new(2, 133);
+const MEW: UUID = UUID::new(2, 151);
+
+#[derive(Default)]
+struct State {
+    owners: HashMap<UUID, UUID>,
+    // The Pokémon reserved by each owner in each trade
+    reserved: HashMap<UUID, Vec<(UUID, Vec<UUID>)>>,
+    fail_commit: bool,
+}
+
+struct Store(Mutex<State>);
+
+impl Store {
+    fn new() -> Self {
+        let mut state = State::default();
+        state.owners.insert(PIKACHU, ASH);
+        state.owners.insert(EEVEE, GARY);
+        state.owners.insert(MEW, GARY);
+        Self(Mutex::new(state))
+    }
+
+    fn owner(&self, pokemon: UUID) -> UUID {
+        self.0.lock().unwrap().owners[&pokemon]
+    }
+
+    fn reserved(&self) -> usize {
+        self.0.lock().unwrap().reserved.len()
+    }
+}
+
+impl TradeStore for Store {
+    fn prepare(&self, trade: UUID, owner: UUID, pokemon: &[TradePokemon]) -> std::io::Result<()> {
+        let mut state = self.0.lock().unwrap();
+        for p in pokemon {
+            if state.owners.get(&p.id) != Some(&owner) {
+                return Err(std::io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("{} does not own {}", owner, p.id),
+                ));
+            }
+        }
+        state
+            .reserved
+            .entry(trade)
+            .or_default()
+            .push((owner, pokemon.iter().map(|p| p.id).collect()));
+        Ok(())
+    }
+
+    fn commit(&self, trade: UUID) -> std::io::Result<()> {
+        let mut state = self.0.lock().unwrap();
+        if state.fail_commit {
+            return Err(std::io::Error::other("storage is offline"));
+        }
+        let sides = state.reserved.remove(&trade).unwrap();
+        assert_eq!(sides.len(), 2);
+        for (i, (_, pokemon)) in sides.iter().enumerate() {
+            let receiver = sides[1 - i].0;
+            for p in pokemon {
+                state.owners.insert(*p, receiver);
+            }
+        }
+        Ok(())
+    }
+
+    fn rollback(&self, trade: UUID) {
+        self.0.lock().unwrap().reserved.remove(&trade);
+    }
+}
+
+fn pokemon(id: UUID, species: &str) -> TradePokemon {
+    let mut data = NbtCompound::new();
+    data.insert("species".to_string(), NbtTag::String(species.to_string()));
+    TradePokemon { id, data }
+}
+
+fn harness(config: ServerConfig, trades: TradeConfig) -> Harness<TradeService<Store>> {
+    let service = TradeService::new(Store::new(), trades);
+    let server = Server::new(config, PacketRegistry::pkmcom(), service);
+    let mut harness = Harness::new(server, PacketRegistry::pkmcom());
+    harness.join(ASH);
+    harness.join(GARY);
+    harness
+}
+
+fn lock(trade: UUID, revision: u32) -> TradeLock {
+    TradeLock {
+        trade,
+        from: UUID::NIL,
+        revision,
+    }
+}
+
+fn confirm(trade: UUID) -> TradeConfirm {
+    TradeConfirm {
+        trade,
+        from: UUID::NIL,
+        revision: 1,
+    }
+}
+
+// Opens a trade of Pikachu for Eevee, and locks it on both sides, returning the trade id
+fn open_and_lock(harness: &mut Harness<TradeService<Store>>) -> UUID {
+    harness
+        .send(
+            ASH,
+            &TradeOffer {
+                trade: UUID::NIL,
+                from: UUID::NIL,
+                to: GARY,
+                pokemon: List(vec![pokemon(PIKACHU, "pikachu")]),
+            },
+        )
+        .unwrap();
+    harness.settle();
+    let offer = harness.expect::<TradeOffer>(ASH);
+    assert_eq!(harness.expect::<TradeOffer>(GARY), offer);
+    assert_eq!((offer.from, offer.to), (ASH, GARY));
+    let trade = offer.trade;
+
+    harness
+        .send(
+            GARY,
+            &TradeCounterOffer {
+                trade,
+                from: UUID::NIL,
+                revision: 0,
+                pokemon: List(vec![pokemon(EEVEE, "eevee")]),
+            },
+        )
+        .unwrap();
+    harness.settle();
+    for player in [ASH, GARY] {
+        let counter = harness.expect::<TradeCounterOffer>(player);
+        assert_eq!((counter.from, counter.revision), (GARY, 1));
+    }
+
+    for player in [ASH, GARY] {
+        harness.send(player, &lock(trade, 1)).unwrap();
+    }
+    harness.settle();
+    for player in [ASH, GARY] {
+        assert_eq!(harness.expect::<TradeLock>(player).from, ASH);
+        assert_eq!(harness.expect::<TradeLock>(player).from, GARY);
+        assert_eq!(harness.expect::<TradeConfirm>(player), confirm(trade));
+    }
+    trade
+}
+
+#[test]
+fn pokemon_are_exchanged_when_both_players_confirm() {
+    let mut harness = harness(ServerConfig::default(), TradeConfig::default());
+    let trade = open_and_lock(&mut harness);
+    assert_eq!(harness.handler().store().reserved(), 1);
+
+    for player in [ASH, GARY] {
+        harness.send(player, &confirm(trade)).unwrap();
+    }
+    harness.settle();
+    for player in [ASH, GARY] {
+        assert_eq!(harness.expect::<TradeCommit>(player), TradeCommit { trade });
+    }
+    let store = harness.handler().store();
+    assert_eq!(store.owner(PIKACHU), GARY);
+    assert_eq!(store.owner(EEVEE), ASH);
+    assert_eq!(store.owner(MEW), GARY);
+    assert_eq!(store.reserved(), 0);
+    assert_eq!(harness.handler().trade_of(ASH), None);
+}
+
+#[test]
+fn disconnect_mid_trade_rolls_back() {
+    let mut harness = harness(ServerConfig::default(), TradeConfig::default());
+    let trade = open_and_lock(&mut harness);
+    harness.send(ASH, &confirm(trade)).unwrap();
+    harness.disconnect(GARY, TextComponent::RawText("Bye".to_string()));
+
+    let abort = harness.expect::<TradeAbort>(ASH);
+    assert_eq!(abort.trade, trade);
+    assert_eq!(
+        abort.reason,
+        TextComponent::RawText(format!("Trade cancelled: {} disconnected", GARY))
+    );
+    let store = harness.handler().store();
+    assert_eq!(store.owner(PIKACHU), ASH);
+    assert_eq!(store.owner(EEVEE), GARY);
+    assert_eq!(store.reserved(), 0);
+    assert_eq!(harness.handler().trade_of(ASH), None);
+}
+
+#[test]
+fn idle_trades_are_expired_by_the_server_tick() {
+    let config = ServerConfig {
+        tick_interval: Duration::from_millis(10),
+        ..Default::default()
+    };
+    let mut harness = harness(
+        config,
+        TradeConfig {
+            confirm_timeout: Duration::from_millis(100),
+            ..Default::default()
+        },
+    );
+    let trade = open_and_lock(&mut harness);
+
+    // Neither player sends anything more, so only the tick can notice the deadline
+    eventually(|| harness.handler().trade_of(ASH).is_none());
+    harness.settle();
+    for player in [ASH, GARY] {
+        assert_eq!(
+            harness.expect::<TradeAbort>(player),
+            TradeAbort {
+                trade,
+                reason: TextComponent::RawText("Trade cancelled: timed out".to_string())
+            }
+        );
+    }
+    assert_eq!(harness.handler().store().reserved(), 0);
+}
+
+#[test]
+fn unowned_pokemon_cancel_the_trade() {
+    let mut harness = harness(ServerConfig::default(), TradeConfig::default());
+    harness
+        .send(
+            ASH,
+            &TradeOffer {
+                trade: UUID::NIL,
+                from: UUID::NIL,
+                to: GARY,
+                pokemon: List(vec![pokemon(MEW, "mew")]),
+            },
+        )
+        .unwrap();
+    harness.settle();
+    let trade = harness.expect::<TradeOffer>(ASH).trade;
+    harness.expect::<TradeOffer>(GARY);
+    harness.send(ASH, &lock(trade, 0)).unwrap();
+    // Gary's lock completes the trade, which the store refuses to prepare
+    harness.send(GARY, &lock(trade, 0)).unwrap();
+    harness.settle();
+    for player in [ASH, GARY] {
+        assert_eq!(harness.expect::<TradeLock>(player).from, ASH);
+        let abort = harness.expect::<TradeAbort>(player);
+        assert_eq!(
+            abort.reason,
+            TextComponent::RawText(format!("Trade cancelled: {} does not own {}", ASH, MEW))
+        );
+    }
+    assert_eq!(harness.handler().store().owner(MEW), GARY);
+    assert_eq!(harness.handler().store().reserved(), 0);
+}
+
+#[test]
+fn offers_to_offline_players_are_refused() {
+    let mut harness = harness(ServerConfig::default(), TradeConfig::default());
+    let brock = UUID::new(1, 3);
+    harness
+        .send(
+            ASH,
+            &TradeOffer {
+                trade: UUID::NIL,
+                from: UUID::NIL,
+                to: brock,
+                pokemon: List(vec![pokemon(PIKACHU, "pikachu")]),
+            },
+        )
+        .unwrap();
+    harness.settle();
+    let abort = harness.expect::<TradeAbort>(ASH);
+    assert_eq!(abort.trade, UUID::NIL);
+    harness.expect_nothing(GARY);
+    assert_eq!(harness.handler().trade_of(ASH), None);
+}
+
+// Opens a trade of Pikachu for Eevee directly on the sessions, and locks it on both sides
+fn prepared(sessions: &mut TradeSessions, store: &Store, now: Instant) -> UUID {
+    let offer = TradeOffer {
+        trade: UUID::NIL,
+        from: UUID::NIL,
+        to: GARY,
+        pokemon: List(vec![pokemon(PIKACHU, "pikachu")]),
+    };
+    let out = sessions.receive(store, ASH, &offer, now);
+    let trade = packets::<TradeOffer>(&out, GARY)[0].trade;
+    let counter = TradeCounterOffer {
+        trade,
+        from: UUID::NIL,
+        revision: 0,
+        pokemon: List(vec![pokemon(EEVEE, "eevee")]),
+    };
+    sessions.receive(store, GARY, &counter, now);
+    for player in [ASH, GARY] {
+        sessions.receive(store, player, &lock(trade, 1), now);
+    }
+    assert_eq!(store.reserved(), 1);
+    trade
+}
+
+#[test]
+fn counter_offer_after_locking_releases_the_trade() {
+    let store = Store::new();
+    let mut sessions = TradeSessions::new(TradeConfig::default());
+    let now = Instant::now();
+    let trade = prepared(&mut sessions, &store, now);
+
+    // Gary swaps Eevee for Mew before confirming
+    let counter = TradeCounterOffer {
+        trade,
+        from: UUID::NIL,
+        revision: 1,
+        pokemon: List(vec![pokemon(MEW, "mew")]),
+    };
+    let out = sessions.receive(&store, GARY, &counter, now);
+    assert_eq!(packets::<TradeCounterOffer>(&out, ASH)[0].revision, 2);
+    assert_eq!(store.reserved(), 0);
+
+    // Ash's confirmation of the old revision does not count
+    assert!(sessions
+        .receive(&store, ASH, &confirm(trade), now)
+        .is_empty());
+    assert!(sessions
+        .receive(&store, ASH, &lock(trade, 1), now)
+        .is_empty());
+    assert_eq!(sessions.trade_of(ASH), Some(trade));
+    assert_eq!(store.owner(PIKACHU), ASH);
+}
+
+#[test]
+fn repeated_locks_of_a_prepared_trade_are_ignored() {
+    let store = Store::new();
+    let mut sessions = TradeSessions::new(TradeConfig::default());
+    let now = Instant::now();
+    let trade = prepared(&mut sessions, &store, now);
+
+    assert!(sessions
+        .receive(&store, ASH, &lock(trade, 1), now)
+        .is_empty());
+    assert_eq!(store.reserved(), 1);
+    let mut out = Vec::new();
+    for player in [ASH, GARY] {
+        out = sessions.receive(&store, player, &confirm(trade), now);
+    }
+    assert_eq!(
+        packets::<TradeCommit>(&out, ASH),
+        vec![TradeCommit { trade }]
+    );
+    assert_eq!(store.owner(PIKACHU), GARY);
+}
+
+#[test]
+fn unconfirmed_trade_times_out_and_rolls_back() {
+    let store = Store::new();
+    let config = TradeConfig::default();
+    let confirm_timeout = config.confirm_timeout;
+    let mut sessions = TradeSessions::new(config);
+    let now = Instant::now();
+    let trade = prepared(&mut sessions, &store, now);
+
+    assert!(sessions
+        .expire(&store, now + confirm_timeout - Duration::from_secs(1))
+        .is_empty());
+    let out = sessions.expire(&store, now + confirm_timeout);
+    for player in [ASH, GARY] {
+        assert_eq!(
+            packets::<TradeAbort>(&out, player),
+            vec![TradeAbort {
+                trade,
+                reason: TextComponent::RawText("Trade cancelled: timed out".to_string())
+            }]
+        );
+    }
+    assert!(sessions.is_empty());
+    assert_eq!(store.reserved(), 0);
+    assert_eq!(store.owner(PIKACHU), ASH);
+}
+
+#[test]
+fn failed_commit_rolls_back() {
+    let store = Store::new();
+    store.0.lock().unwrap().fail_commit = true;
+    let mut sessions = TradeSessions::new(TradeConfig::default());
+    let now = Instant::now();
+    let trade = prepared(&mut sessions, &store, now);
+    let mut out = Vec::new();
+    for player in [ASH, GARY] {
+        out = sessions.receive(&store, player, &confirm(trade), now);
+    }
+    assert!(packets::<TradeCommit>(&out, ASH).is_empty());
+    assert_eq!(packets::<TradeAbort>(&out, GARY).len(), 1);
+    assert_eq!(store.reserved(), 0);
+    assert_eq!(store.owner(PIKACHU), ASH);
+    assert_eq!(store.owner(EEVEE), GARY);
+}
+
+#[test]
+fn players_trade_one_at_a_time() {
+    let store = Store::new();
+    let mut sessions = TradeSessions::new(TradeConfig::default());
+    let now = Instant::now();
+    let trade = prepared(&mut sessions, &store, now);
+    let third = UUID::new(1, 3);
+    let offer = TradeOffer {
+        trade: UUID::NIL,
+        from: UUID::NIL,
+        to: GARY,
+        pokemon: List(Vec::new()),
+    };
+    let out = sessions.receive(&store, third, &offer, now);
+    assert_eq!(
+        packets::<TradeAbort>(&out, third)[0].reason,
+        TextComponent::RawText(format!("Trade cancelled: {} is already trading", GARY))
+    );
+    assert_eq!(sessions.trade_of(GARY), Some(trade));
+    assert_eq!(sessions.trade_of(third), None);
+}
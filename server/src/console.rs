@@ -0,0 +1,145 @@
+//!
+//! The commands of the admin console, which are read from the server's standard input, one per line.
+
+use std::{fmt::Display, io::ErrorKind, str::FromStr};
+
+///
+/// The usage of every command, shown by `help`
+pub const HELP: &str = "\
+help                    Shows this list
+list                    Lists the players online
+kick <player> [reason]  Disconnects a player, by name or UUID
+mute <player>           Stops a player from chatting
+unmute <player>         Allows a muted player to chat again
+say <message>           Sends a message to every player
+save                    Saves every player online
+stop                    Saves every player and stops the server";
+
+///
+/// A command entered on the admin console
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    ///
+    /// Shows the usage of each command
+    Help,
+    ///
+    /// Lists the players online
+    List,
+    ///
+    /// Disconnects a player, with the given reason or a default one
+    Kick {
+        ///
+        /// The name or UUID of the player
+        player: String,
+        ///
+        /// The reason shown to the player
+        reason: Option<String>,
+    },
+    ///
+    /// Stops a player from chatting
+    Mute(String),
+    ///
+    /// Allows a muted player to chat again
+    Unmute(String),
+    ///
+    /// Sends a message to every player
+    Say(String),
+    ///
+    /// Saves every player online
+    Save,
+    ///
+    /// Saves every player, and stops the server
+    Stop,
+}
+
+///
+/// The error parsing a command
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CommandError {
+    ///
+    /// The line was blank
+    Empty,
+    ///
+    /// There is no command with the given name
+    Unknown(String),
+    ///
+    /// The command requires an argument which was not given
+    MissingArgument {
+        ///
+        /// The name of the command
+        command: &'static str,
+        ///
+        /// The name of the missing argument
+        argument: &'static str,
+    },
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Empty => f.write_str("No command given"),
+            CommandError::Unknown(name) => f.write_fmt(format_args!(
+                "Unknown command {}. Type help for a list of commands",
+                name
+            )),
+            CommandError::MissingArgument { command, argument } => {
+                f.write_fmt(format_args!("Usage: {} <{}>", command, argument))
+            }
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<CommandError> for std::io::Error {
+    fn from(e: CommandError) -> Self {
+        std::io::Error::new(ErrorKind::InvalidInput, e)
+    }
+}
+
+// Splits the first word from the rest of the line
+fn split_word(line: &str) -> (&str, &str) {
+    let line = line.trim();
+    match line.find(char::is_whitespace) {
+        Some(end) => (&line[..end], line[end..].trim_start()),
+        None => (line, ""),
+    }
+}
+
+fn required(
+    command: &'static str,
+    argument: &'static str,
+    value: &str,
+) -> Result<String, CommandError> {
+    if value.is_empty() {
+        Err(CommandError::MissingArgument { command, argument })
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+impl FromStr for Command {
+    type Err = CommandError;
+
+    fn from_str(line: &str) -> Result<Self, CommandError> {
+        let (name, rest) = split_word(line);
+        match &*name.to_lowercase() {
+            "" => Err(CommandError::Empty),
+            "help" | "?" => Ok(Command::Help),
+            "list" => Ok(Command::List),
+            "kick" => {
+                let (player, reason) = split_word(rest);
+                Ok(Command::Kick {
+                    player: required("kick", "player", player)?,
+                    reason: Some(reason.to_string()).filter(|r| !r.is_empty()),
+                })
+            }
+            "mute" => Ok(Command::Mute(required("mute", "player", rest)?)),
+            "unmute" => Ok(Command::Unmute(required("unmute", "player", rest)?)),
+            "say" => Ok(Command::Say(required("say", "message", rest)?)),
+            "save" => Ok(Command::Save),
+            "stop" | "shutdown" => Ok(Command::Stop),
+            _ => Err(CommandError::Unknown(name.to_string())),
+        }
+    }
+}
@@ -0,0 +1,333 @@
+//!
+//! The content of the server, defined by Lua scripts in resource domains.
+//!
+//! Each directory in the resources directory is a resource domain, named by the directory.
+//! Each Lua file in a domain defines entries of the registry named by the file, so `pokemonsms/moves.lua` defines moves.
+//! A file returns a table of entries, each of which is a table with the name of the entry, such as
+//!
+//! ```lua
+//! return {
+//!     { name = "pokemonsms:tackle", type = "pokemonsms:normal", power = 40 },
+//! }
+//! ```
+//!
+//! The other fields of an entry are kept as its NBT data, which is what clients synchronize.
+//! Once every domain is loaded, the registries are locked, and each domain is hashed for the handshake.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use binary_io::{
+    data::DataOutput,
+    nbt::{compound::NbtCompound, list::NbtList, NbtTag},
+};
+use net::{
+    digest::{ContentDigest, ContentSummary, DomainSummary},
+    handshake::ContentHash,
+    hashsum::Hashcode,
+};
+use pokemonsms_core::{
+    registry::{NbtEntry, Registry, RegistryEntry},
+    resource::ResourceLocation,
+};
+use rlua::{Context, FromLua, Lua, Table, Value};
+
+///
+/// An entry of a registry, defined by a Lua script
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    name: ResourceLocation,
+    data: NbtCompound,
+}
+
+impl Entry {
+    ///
+    /// Creates an entry with the given name and data
+    pub fn new(name: ResourceLocation, data: NbtCompound) -> Self {
+        Self { name, data }
+    }
+
+    ///
+    /// Returns the fields of the entry, other than its name
+    pub fn data(&self) -> &NbtCompound {
+        &self.data
+    }
+}
+
+impl RegistryEntry for Entry {
+    fn registry_name(&self) -> &ResourceLocation {
+        &self.name
+    }
+}
+
+impl NbtEntry for Entry {
+    fn to_nbt(&self) -> NbtCompound {
+        self.data.clone()
+    }
+
+    fn from_nbt(name: ResourceLocation, nbt: &NbtCompound) -> std::io::Result<Self> {
+        Ok(Self::new(name, nbt.clone()))
+    }
+}
+
+impl ContentDigest for Entry {
+    fn write_canonical<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        self.data.write_canonical(output)
+    }
+}
+
+impl Hashcode for Entry {
+    fn hashcode(&self) -> i32 {
+        self.name
+            .hashcode()
+            .wrapping_mul(31)
+            .wrapping_add(self.data.hashcode())
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Nil => "nil",
+        Value::Boolean(_) => "boolean",
+        Value::LightUserData(_) => "lightuserdata",
+        Value::Integer(_) => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Table(_) => "table",
+        Value::Function(_) => "function",
+        Value::Thread(_) => "thread",
+        Value::UserData(_) => "userdata",
+        Value::Error(_) => "error",
+    }
+}
+
+fn invalid(from: &'static str, message: String) -> rlua::Error {
+    rlua::Error::FromLuaConversionError {
+        from,
+        to: "NbtTag",
+        message: Some(message),
+    }
+}
+
+// Sequences become lists, and other tables become compounds
+fn to_nbt(value: Value) -> rlua::Result<NbtTag> {
+    match value {
+        Value::Boolean(b) => Ok(NbtTag::Byte(b as u8)),
+        Value::Integer(i) => Ok(NbtTag::Long(i)),
+        Value::Number(n) => Ok(NbtTag::Double(n)),
+        Value::String(s) => Ok(NbtTag::String(s.to_str()?.to_string())),
+        Value::Table(table) if table.raw_len() > 0 => {
+            let len = table.raw_len();
+            let mut list = NbtList::new();
+            for i in 1..=len {
+                list.insert(to_nbt(table.raw_get(i)?)?)
+                    .map_err(|e| invalid("table", format!("element {}: {}", i, e)))?;
+            }
+            if table.pairs::<Value, Value>().count() as i64 != len {
+                return Err(invalid(
+                    "table",
+                    "lists cannot have fields other than their elements".to_string(),
+                ));
+            }
+            Ok(NbtTag::List(list))
+        }
+        Value::Table(table) => Ok(NbtTag::Compound(to_compound(table)?)),
+        value => Err(invalid(
+            type_name(&value),
+            "only booleans, numbers, strings and tables can be stored".to_string(),
+        )),
+    }
+}
+
+fn to_compound(table: Table) -> rlua::Result<NbtCompound> {
+    let mut compound = NbtCompound::new();
+    for pair in table.pairs::<String, Value>() {
+        let (key, value) = pair?;
+        compound.insert(key, to_nbt(value)?);
+    }
+    Ok(compound)
+}
+
+impl<'lua> FromLua<'lua> for Entry {
+    fn from_lua(value: Value<'lua>, ctx: Context<'lua>) -> rlua::Result<Self> {
+        let table = match value {
+            Value::Table(table) => table,
+            value => {
+                return Err(rlua::Error::FromLuaConversionError {
+                    from: type_name(&value),
+                    to: "Entry",
+                    message: Some("entries must be tables".to_string()),
+                })
+            }
+        };
+        let name = String::from_lua(table.get("name")?, ctx)?;
+        let name =
+            name.parse::<ResourceLocation>()
+                .map_err(|e| rlua::Error::FromLuaConversionError {
+                    from: "string",
+                    to: "Entry",
+                    message: Some(e.to_string()),
+                })?;
+        let mut data = to_compound(table)?;
+        data.remove("name");
+        Ok(Self::new(name, data))
+    }
+}
+
+fn invalid_content(path: &Path, e: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Failed to load {}: {}", path.display(), e),
+    )
+}
+
+// The paths in a directory, in order of their names
+fn sorted_paths(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut paths = std::fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.sort();
+    Ok(paths)
+}
+
+///
+/// The resource domains and registries loaded by the server
+#[derive(Default)]
+pub struct Content {
+    domains: Vec<String>,
+    registries: BTreeMap<String, Registry<Entry>>,
+}
+
+impl Content {
+    ///
+    /// Creates content with no domains
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Loads every resource domain in dir, in order of their names, and locks the registries
+    pub fn load<P: AsRef<Path>>(dir: P) -> std::io::Result<Self> {
+        let mut content = Self::new();
+        for path in sorted_paths(dir.as_ref())? {
+            if path.is_dir() {
+                content.load_domain(&path)?;
+            }
+        }
+        content.lock();
+        Ok(content)
+    }
+
+    ///
+    /// Loads the resource domain in dir, which is named by the directory.
+    /// Each script of the domain is run in the same Lua state, in order of their names
+    pub fn load_domain(&mut self, dir: &Path) -> std::io::Result<()> {
+        let domain = dir
+            .file_name()
+            .and_then(OsStr::to_str)
+            .filter(|name| {
+                format!("{}:domain", name)
+                    .parse::<ResourceLocation>()
+                    .is_ok()
+            })
+            .ok_or_else(|| invalid_content(dir, "invalid resource domain name"))?
+            .to_string();
+        let lua = Lua::new();
+        for path in sorted_paths(dir)? {
+            if path.extension() != Some(OsStr::new("lua")) {
+                continue;
+            }
+            let kind = path
+                .file_stem()
+                .and_then(OsStr::to_str)
+                .ok_or_else(|| invalid_content(&path, "invalid registry name"))?;
+            let registry = self.registries.entry(kind.to_string()).or_default();
+            if registry.is_locked() {
+                return Err(invalid_content(&path, "the registry is locked"));
+            }
+            let source = std::fs::read(&path)?;
+            let loaded = lua
+                .context(|ctx| {
+                    let chunk = ctx
+                        .load(&source)
+                        .set_name(&format!("{}/{}", domain, kind))?
+                        .into_function()?;
+                    registry.load_from_chunk(chunk, ctx)
+                })
+                .map_err(|e| invalid_content(&path, e))?;
+            if !loaded {
+                return Err(invalid_content(&path, "an entry is defined more than once"));
+            }
+        }
+        self.domains.push(domain);
+        Ok(())
+    }
+
+    ///
+    /// Locks every registry, so that no more entries can be registered
+    pub fn lock(&self) {
+        for registry in self.registries.values() {
+            registry.lock();
+        }
+    }
+
+    ///
+    /// Returns the names of the loaded resource domains, in the order they were loaded
+    pub fn domains(&self) -> &[String] {
+        &self.domains
+    }
+
+    ///
+    /// Returns the registry of the given kind, if any domain defines entries of it
+    pub fn registry(&self, kind: &str) -> Option<&Registry<Entry>> {
+        self.registries.get(kind)
+    }
+
+    ///
+    /// Returns each registry with its kind, in order of their kinds
+    pub fn registries(&self) -> impl Iterator<Item = (&str, &Registry<Entry>)> + '_ {
+        self.registries
+            .iter()
+            .map(|(kind, registry)| (&**kind, registry))
+    }
+
+    ///
+    /// Summarizes every entry, named by its kind and name
+    pub fn summary(&self) -> std::io::Result<ContentSummary> {
+        let mut summary = ContentSummary::new();
+        for (kind, registry) in &self.registries {
+            registry.summarize(kind, &mut summary)?;
+        }
+        Ok(summary)
+    }
+
+    ///
+    /// Returns the hash of each domain, in the order they were loaded.
+    /// The hashcode of a domain is the list hashcode of its entries, in order of their kinds and names
+    pub fn hashes(&self) -> std::io::Result<Vec<ContentHash>> {
+        let summary = self.summary()?;
+        let empty = DomainSummary::new();
+        Ok(self
+            .domains
+            .iter()
+            .map(|domain| {
+                let mut entries = Vec::new();
+                for registry in self.registries.values() {
+                    let mut hashes = registry
+                        .iter()
+                        .filter(|e| e.registry_name().domain() == domain)
+                        .map(|e| (e.registry_name(), e.hashcode()))
+                        .collect::<Vec<_>>();
+                    hashes.sort();
+                    entries.extend(hashes.into_iter().map(|(_, hash)| hash));
+                }
+                let summary = summary.domain(domain).unwrap_or(&empty);
+                ContentHash::with_summary(domain, entries.hashcode(), summary)
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,357 @@
+//!
+//! A headless dedicated server for PokemonSMS.
+//!
+//! The server loads its [`Content`] from the resource domains in its resources directory, accepts PkmCom connections over TCP,
+//!  and saves the data of each player in the [`PlayerStore`]. It is configured by [`ServerSettings`], and administered with console [`Command`]s.
+//!
+//! If a metrics address is set, the traffic of every connection is counted, and served there for Prometheus to scrape.
+//!
+//! Shutting the server down disconnects every player, and saves each of them before returning.
+
+pub mod console;
+pub mod content;
+pub mod players;
+pub mod settings;
+
+use std::{
+    fmt::Display,
+    io::ErrorKind,
+    net::SocketAddr,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, RwLock,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use binary_io::uuid::UUID;
+use client_core::player::Player;
+use net::{
+    chat::ChatService,
+    metrics::{Metrics, PrometheusEndpoint},
+    packet::{AnyPacket, PacketRegistry},
+    server::{Handler, Peer, Server},
+    tcp::TcpServer,
+};
+use text::TextComponent;
+
+pub use console::Command;
+pub use content::Content;
+pub use players::PlayerStore;
+pub use settings::ServerSettings;
+
+///
+/// The time shutdown waits for players to be disconnected and saved, before saving the rest itself
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+///
+/// A failure to save players in the background, as one leaves or when they are autosaved
+#[derive(Debug)]
+pub struct SaveError {
+    ///
+    /// The player who left, or None for an autosave
+    pub player: Option<UUID>,
+    ///
+    /// The error saving them
+    pub error: std::io::Error,
+}
+
+impl Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.player {
+            Some(player) => f.write_fmt(format_args!("Failed to save {}: {}", player, self.error)),
+            None => f.write_fmt(format_args!("Failed to save players: {}", self.error)),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+type SaveHook = Box<dyn Fn(&SaveError) + Send + Sync>;
+
+///
+/// The handler of the dedicated server's connections
+pub struct Game {
+    chat: ChatService<PlayerStore>,
+    on_save_error: RwLock<SaveHook>,
+}
+
+impl Game {
+    ///
+    /// Creates a handler for players saved in players
+    pub fn new(players: PlayerStore, settings: &ServerSettings) -> Self {
+        Self {
+            chat: ChatService::new(players, settings.chat_config()),
+            on_save_error: RwLock::new(Box::new(|_| {})),
+        }
+    }
+
+    ///
+    /// Sets the function called when players cannot be saved in the background, replacing the last.
+    /// Until one is set, such failures are ignored
+    pub fn on_save_error<F: Fn(&SaveError) + Send + Sync + 'static>(&self, hook: F) {
+        *self.on_save_error.write().unwrap() = Box::new(hook);
+    }
+
+    fn save_failed(&self, player: Option<UUID>, error: std::io::Error) {
+        (self.on_save_error.read().unwrap())(&SaveError { player, error });
+    }
+
+    ///
+    /// Returns the chat service, which names players as they are saved
+    pub fn chat(&self) -> &ChatService<PlayerStore> {
+        &self.chat
+    }
+
+    ///
+    /// Returns the saved data of the players
+    pub fn players(&self) -> &PlayerStore {
+        self.chat.roster()
+    }
+}
+
+impl Handler for Game {
+    fn connected(&self, peer: &Peer) -> std::io::Result<()> {
+        self.players().join(peer.client())?;
+        self.chat.connected(peer)
+    }
+
+    fn packet(&self, peer: &Peer, packet: Box<dyn AnyPacket>) -> std::io::Result<()> {
+        self.chat.handle(peer, &*packet);
+        Ok(())
+    }
+
+    fn disconnected(&self, peer: &Peer, error: Option<&std::io::Error>) {
+        self.chat.disconnected(peer, error);
+        if let Err(e) = self.players().leave(peer.client()) {
+            self.save_failed(Some(peer.client()), e);
+        }
+    }
+}
+
+// Saves every online player each interval, until told to stop
+struct Autosave {
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Autosave {
+    fn start(server: Arc<Server<Game>>, interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if let Err(e) = server.handler().players().save_all() {
+                    server.handler().save_failed(None, e);
+                }
+            }
+        });
+        Self { stop, thread }
+    }
+
+    fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+}
+
+///
+/// A running dedicated server
+pub struct DedicatedServer {
+    settings: ServerSettings,
+    content: Content,
+    tcp: TcpServer<Game>,
+    autosave: Option<Autosave>,
+    metrics: Option<PrometheusEndpoint>,
+}
+
+impl DedicatedServer {
+    ///
+    /// Loads the content in the resources directory, and starts the server
+    pub fn start(settings: ServerSettings) -> std::io::Result<Self> {
+        let content = Content::load(&settings.resources)?;
+        Self::with_content(settings, content)
+    }
+
+    ///
+    /// Starts a server which serves content, ignoring the resources directory
+    pub fn with_content(settings: ServerSettings, content: Content) -> std::io::Result<Self> {
+        let players = PlayerStore::new(
+            settings.data.join("players"),
+            settings.backups,
+            settings.pc_sprite.clone(),
+        )?;
+        let server = Server::new(
+            settings.server_config(&content)?,
+            PacketRegistry::pkmcom(),
+            Game::new(players, &settings),
+        );
+        let tcp = TcpServer::bind(&*settings.address, server)?;
+        let autosave = settings
+            .autosave_interval()
+            .map(|interval| Autosave::start(tcp.server().clone(), interval));
+        let metrics = match (&settings.metrics, &tcp.server().config().metrics) {
+            (Some(addr), Some(metrics)) => {
+                Some(PrometheusEndpoint::bind(&**addr, metrics.clone())?)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            settings,
+            content,
+            tcp,
+            autosave,
+            metrics,
+        })
+    }
+
+    ///
+    /// Returns the settings of the server
+    pub fn settings(&self) -> &ServerSettings {
+        &self.settings
+    }
+
+    ///
+    /// Returns the content served
+    pub fn content(&self) -> &Content {
+        &self.content
+    }
+
+    ///
+    /// Returns the address the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.tcp.local_addr()
+    }
+
+    ///
+    /// Returns the metrics of the server's connections, if they are counted
+    pub fn metrics(&self) -> Option<&Metrics> {
+        self.server().config().metrics.as_ref()
+    }
+
+    ///
+    /// Returns the address metrics are served on, if they are counted
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics.as_ref().map(PrometheusEndpoint::local_addr)
+    }
+
+    ///
+    /// Returns the PkmCom server
+    pub fn server(&self) -> &Arc<Server<Game>> {
+        self.tcp.server()
+    }
+
+    ///
+    /// Returns the handler of the server's connections
+    pub fn game(&self) -> &Game {
+        self.server().handler()
+    }
+
+    ///
+    /// Returns the players online, in order of their names
+    pub fn players(&self) -> Vec<(UUID, Player)> {
+        let mut players = self.game().players().online().players();
+        players.sort_by_key(|(_, player)| player.name().to_string());
+        players
+    }
+
+    ///
+    /// Finds the online player with the given name or UUID
+    pub fn find(&self, player: &str) -> std::io::Result<UUID> {
+        self.players()
+            .into_iter()
+            .find(|(id, p)| id.to_string() == player || p.name().to_string() == player)
+            .map(|(id, _)| id)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("No player named {} is online", player),
+                )
+            })
+    }
+
+    ///
+    /// Disconnects a player with the given reason
+    pub fn kick(&self, player: UUID, reason: TextComponent) -> std::io::Result<()> {
+        match self.server().peer(player) {
+            Some(peer) => peer.kick(reason),
+            None => Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("{} is not online", player),
+            )),
+        }
+    }
+
+    ///
+    /// Saves every player online, returning the number saved
+    pub fn save(&self) -> std::io::Result<usize> {
+        self.game().players().save_all()
+    }
+
+    ///
+    /// Runs a console command, returning its output.
+    /// [`Command::Stop`] does not stop the server, which is left to the caller by calling [`DedicatedServer::shutdown`]
+    pub fn execute(&self, command: &Command) -> std::io::Result<String> {
+        match command {
+            Command::Help => Ok(console::HELP.to_string()),
+            Command::List => {
+                let names = self
+                    .players()
+                    .iter()
+                    .map(|(_, player)| player.name().to_string())
+                    .collect::<Vec<_>>();
+                Ok(format!(
+                    "{} of {} players online: {}",
+                    names.len(),
+                    self.settings.max_players,
+                    names.join(", ")
+                ))
+            }
+            Command::Kick { player, reason } => {
+                let id = self.find(player)?;
+                let reason = reason.as_deref().unwrap_or("Kicked by an operator");
+                self.kick(id, TextComponent::RawText(reason.to_string()))?;
+                Ok(format!("Kicked {}", player))
+            }
+            Command::Mute(player) => {
+                self.game().chat().mute(self.find(player)?);
+                Ok(format!("Muted {}", player))
+            }
+            Command::Unmute(player) => {
+                self.game().chat().unmute(self.find(player)?);
+                Ok(format!("Unmuted {}", player))
+            }
+            Command::Say(message) => {
+                self.game()
+                    .chat()
+                    .announce(TextComponent::RawText(format!("[Server] {}", message)));
+                Ok(format!("[Server] {}", message))
+            }
+            Command::Save => Ok(format!("Saved {} players", self.save()?)),
+            Command::Stop => Ok("Stopping the server".to_string()),
+        }
+    }
+
+    ///
+    /// Stops accepting connections, disconnects every player, and saves them.
+    /// Returns the number of players saved after the players which left in time
+    pub fn shutdown(mut self, reason: TextComponent) -> std::io::Result<usize> {
+        if let Some(autosave) = self.autosave.take() {
+            autosave.stop();
+        }
+        if let Some(mut metrics) = self.metrics.take() {
+            metrics.stop();
+        }
+        self.tcp.shutdown(reason);
+        // Each player is saved as they are disconnected
+        let start = Instant::now();
+        while !self.game().players().online().is_empty() && start.elapsed() < SHUTDOWN_TIMEOUT {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        self.save()
+    }
+}
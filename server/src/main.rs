@@ -0,0 +1,87 @@
+use std::{
+    io::{BufRead, ErrorKind},
+    process::exit,
+    sync::mpsc,
+};
+
+use pokemonsms_server::{Command, DedicatedServer, ServerSettings};
+use text::TextComponent;
+
+enum Event {
+    Line(String),
+    Stop,
+}
+
+fn main() {
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "server.json".to_string());
+    let settings = match ServerSettings::load(&path) {
+        Ok(settings) => settings,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            eprintln!("{} does not exist, using the default settings", path);
+            ServerSettings::default()
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1)
+        }
+    };
+    let server = match DedicatedServer::start(settings) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Failed to start the server: {}", e);
+            exit(1)
+        }
+    };
+    server.game().on_save_error(|e| eprintln!("{}", e));
+    println!(
+        "Loaded {} resource domains, listening on {}",
+        server.content().domains().len(),
+        server.local_addr()
+    );
+    if let Some(addr) = server.metrics_addr() {
+        println!("Serving metrics on http://{}/metrics", addr);
+    }
+
+    let (events, received) = mpsc::channel();
+    let stop = events.clone();
+    if let Err(e) = ctrlc::set_handler(move || {
+        let _ = stop.send(Event::Stop);
+    }) {
+        eprintln!("Failed to handle termination signals: {}", e);
+    }
+    // The console stops reading at the end of its input, but the server keeps running, so that it can be run without one
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+            if events.send(Event::Line(line)).is_err() {
+                break;
+            }
+        }
+    });
+
+    while let Ok(Event::Line(line)) = received.recv() {
+        let command = match line.parse::<Command>() {
+            Ok(command) => command,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        match server.execute(&command) {
+            Ok(output) => println!("{}", output),
+            Err(e) => eprintln!("{}", e),
+        }
+        if command == Command::Stop {
+            break;
+        }
+    }
+
+    match server.shutdown(TextComponent::RawText("Server closed".to_string())) {
+        Ok(saved) => println!("Saved {} players, stopped", saved),
+        Err(e) => {
+            eprintln!("Failed to save players: {}", e);
+            exit(1)
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//!
+//! The data of each player, saved as a ShadeNBT file named by the player's UUID.
+//!
+//! A player's file is loaded when they join, and saved when they leave, as well as whenever every online player is saved.
+//! Each file is written atomically, and keeps backups of previous saves to fall back to if it cannot be read.
+//! Files are stamped with [`PLAYER_DATA_VERSION`] when saved, and upgraded by [`migrations`] when loaded.
+
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use binary_io::{
+    nbt::NbtTag,
+    shade::{migration::Migrations, save::SaveFile, ShadeFile},
+    uuid::UUID,
+};
+use client_core::player::{Player, PlayerList};
+use net::chat::Roster;
+use pokemonsms_core::resource::ResourceLocation;
+use text::TextComponent;
+
+///
+/// The version of the data stored in player files.
+/// Each change to the data of a player increments it, and registers a migration from the previous version in [`migrations`]
+pub const PLAYER_DATA_VERSION: i32 = 0;
+
+///
+/// Returns the migrations applied to player files when they are loaded
+pub fn migrations() -> Migrations {
+    Migrations::with_data_version(PLAYER_DATA_VERSION)
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn string<'a>(file: &'a ShadeFile, key: &str) -> std::io::Result<&'a str> {
+    match file.get(key) {
+        Some(NbtTag::String(value)) => Ok(value),
+        _ => Err(invalid(format!("Missing {} of player", key))),
+    }
+}
+
+///
+/// Converts a player to a ShadeNBT file
+pub fn to_shade(player: &Player) -> std::io::Result<ShadeFile> {
+    let mut file = ShadeFile::new();
+    let name = serde_json::to_string(player.name())?;
+    file.insert("Name".to_string(), NbtTag::String(name));
+    file.insert(
+        "PcSprite".to_string(),
+        NbtTag::String(player.pc_sprite().to_string()),
+    );
+    Ok(file)
+}
+
+///
+/// Reads a player from a ShadeNBT file
+pub fn from_shade(file: &ShadeFile) -> std::io::Result<Player> {
+    let name = serde_json::from_str::<TextComponent>(string(file, "Name")?)?;
+    let pc_sprite = string(file, "PcSprite")?
+        .parse::<ResourceLocation>()
+        .map_err(|e| invalid(e.to_string()))?;
+    Ok(Player::new(name, pc_sprite))
+}
+
+///
+/// The saved data of every player, and the players which are online
+pub struct PlayerStore {
+    dir: PathBuf,
+    backups: usize,
+    migrations: Arc<Migrations>,
+    pc_sprite: ResourceLocation,
+    online: PlayerList,
+    // Held while saving, so that a player is never saved twice at once
+    saving: Mutex<()>,
+}
+
+impl PlayerStore {
+    ///
+    /// Creates a store which saves players in dir, keeping the given number of backups of each.
+    /// New players are given pc_sprite. Creates dir if it does not exist
+    pub fn new<P: Into<PathBuf>>(
+        dir: P,
+        backups: usize,
+        pc_sprite: ResourceLocation,
+    ) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            backups,
+            migrations: Arc::new(migrations()),
+            pc_sprite,
+            online: PlayerList::new(),
+            saving: Mutex::new(()),
+        })
+    }
+
+    ///
+    /// Returns the directory players are saved in
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    ///
+    /// Returns the file the given player is saved in
+    pub fn file(&self, id: UUID) -> SaveFile {
+        let name = format!("{:032x}.shade", u128::from(id));
+        SaveFile::with_backups(self.dir.join(name), self.backups)
+            .with_migrations(self.migrations.clone())
+    }
+
+    ///
+    /// Loads the saved data of a player, upgrading it to the current data version, or creates a new player named by their UUID if they have never been saved
+    pub fn load(&self, id: UUID) -> std::io::Result<Player> {
+        match self.file(id).load() {
+            Ok(loaded) => from_shade(&loaded.value),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Player::new(
+                TextComponent::RawText(id.to_string()),
+                self.pc_sprite.clone(),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    ///
+    /// Saves the data of a player
+    pub fn save(&self, id: UUID, player: &Player) -> std::io::Result<()> {
+        let file = to_shade(player)?;
+        let _saving = self.saving.lock().unwrap();
+        self.file(id).save(&file)
+    }
+
+    ///
+    /// Loads a player which has joined, and adds them to the online players
+    pub fn join(&self, id: UUID) -> std::io::Result<Player> {
+        let player = self.load(id)?;
+        self.online.insert(id, player.clone());
+        Ok(player)
+    }
+
+    ///
+    /// Saves a player which has left, and removes them from the online players
+    pub fn leave(&self, id: UUID) -> std::io::Result<()> {
+        // The player is only removed once saved, so that a player who is not online is never unsaved
+        let result = match self.online.get(id) {
+            Some(player) => self.save(id, &player),
+            None => Ok(()),
+        };
+        self.online.remove(id);
+        result
+    }
+
+    ///
+    /// Saves every online player, returning the number saved.
+    /// Every player is saved even if some fail, in which case the first error is returned
+    pub fn save_all(&self) -> std::io::Result<usize> {
+        let mut result = Ok(0);
+        for (id, player) in self.online.players() {
+            match (self.save(id, &player), &mut result) {
+                (Ok(()), Ok(saved)) => *saved += 1,
+                (Err(e), Ok(_)) => result = Err(e),
+                (_, Err(_)) => {}
+            }
+        }
+        result
+    }
+
+    ///
+    /// Returns the players which are online
+    pub fn online(&self) -> &PlayerList {
+        &self.online
+    }
+}
+
+impl Roster for PlayerStore {
+    fn name(&self, player: UUID) -> Option<TextComponent> {
+        self.online.name(player)
+    }
+}
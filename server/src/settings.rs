@@ -0,0 +1,150 @@
+//!
+//! The configuration of a dedicated server, read from a JSON file.
+//!
+//! Every setting is optional, and settings missing from the file take their default values. For example,
+//!
+//! ```json
+//! {
+//!     "address": "0.0.0.0:24781",
+//!     "motd": "Welcome to Pallet Town",
+//!     "max_players": 20,
+//!     "autosave": 120
+//! }
+//! ```
+
+use std::{io::ErrorKind, path::PathBuf, time::Duration};
+
+use net::{chat::ChatConfig, limit::RateLimits, metrics::Metrics, server::ServerConfig};
+use pokemonsms_core::resource::ResourceLocation;
+use serde::{Deserialize, Serialize};
+use text::TextComponent;
+
+use crate::content::Content;
+
+///
+/// The address the server listens on by default
+pub const DEFAULT_ADDRESS: &str = "0.0.0.0:24781";
+
+///
+/// The settings of a dedicated server
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerSettings {
+    ///
+    /// The address to accept PkmCom connections on
+    pub address: String,
+    ///
+    /// The message of the day, shown to clients which ask for the server's status
+    pub motd: TextComponent,
+    ///
+    /// The most players which may be online at once
+    pub max_players: u32,
+    ///
+    /// The directory containing a directory for each resource domain to load
+    pub resources: PathBuf,
+    ///
+    /// The directory player data is saved in
+    pub data: PathBuf,
+    ///
+    /// The interval in seconds between saves of every online player, or 0 to only save players when they leave
+    pub autosave: u64,
+    ///
+    /// The number of previous saves kept for each player
+    pub backups: usize,
+    ///
+    /// The sprite shown for new players
+    pub pc_sprite: ResourceLocation,
+    ///
+    /// Whether clients with mismatched content are asked to synchronize it, rather than being rejected
+    pub allow_sync: bool,
+    ///
+    /// Whether clients are held to the default [`RateLimits`], rather than not being limited
+    pub rate_limits: bool,
+    ///
+    /// How long in seconds the session of a player whose connection is lost is kept, or None to not keep sessions
+    pub session_grace: Option<u64>,
+    ///
+    /// The most characters in a chat message
+    pub max_message_length: usize,
+    ///
+    /// The address to serve metrics of the server's connections on, for Prometheus to scrape, or None to not count them
+    pub metrics: Option<String>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            address: DEFAULT_ADDRESS.to_string(),
+            motd: TextComponent::RawText("A PokemonSMS Server".to_string()),
+            max_players: 100,
+            resources: PathBuf::from("resources"),
+            data: PathBuf::from("data"),
+            autosave: 300,
+            backups: 2,
+            pc_sprite: ResourceLocation::new("pokemonsms".to_string(), "player".to_string()),
+            allow_sync: true,
+            rate_limits: true,
+            session_grace: None,
+            max_message_length: ChatConfig::default().max_length,
+            metrics: None,
+        }
+    }
+}
+
+impl ServerSettings {
+    ///
+    /// Reads the settings from the JSON file at path
+    pub fn load<P: Into<PathBuf>>(path: P) -> std::io::Result<Self> {
+        let path = path.into();
+        let text = std::fs::read_to_string(&path)?;
+        Self::parse(&text).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("Invalid settings in {}: {}", path.display(), e),
+            )
+        })
+    }
+
+    ///
+    /// Parses the settings from JSON
+    pub fn parse(text: &str) -> std::io::Result<Self> {
+        serde_json::from_str(text).map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))
+    }
+
+    ///
+    /// Returns the interval between saves, or None if players are only saved when they leave
+    pub fn autosave_interval(&self) -> Option<Duration> {
+        match self.autosave {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    ///
+    /// Returns the chat configuration of the server
+    pub fn chat_config(&self) -> ChatConfig {
+        ChatConfig {
+            max_length: self.max_message_length,
+        }
+    }
+
+    ///
+    /// Returns the configuration of the PkmCom server, which serves the given content
+    pub fn server_config(&self, content: &Content) -> std::io::Result<ServerConfig> {
+        Ok(ServerConfig {
+            motd: self.motd.clone(),
+            max_players: self.max_players,
+            content: content.hashes()?,
+            summary: Some(content.summary()?),
+            allow_sync: self.allow_sync,
+            limits: if self.rate_limits {
+                RateLimits::default()
+            } else {
+                RateLimits::unlimited()
+            },
+            session_grace: self.session_grace.map(Duration::from_secs),
+            metrics: self.metrics.as_ref().map(|_| Metrics::new()),
+            ..Default::default()
+        })
+    }
+}
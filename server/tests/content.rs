@@ -0,0 +1,166 @@
+use std::{io::ErrorKind, path::PathBuf};
+
+use binary_io::nbt::{compound::NbtCompound, NbtTag};
+use pokemonsms_core::resource::ResourceLocation;
+use pokemonsms_server::content::{Content, Entry};
+
+// A resources directory, removed when dropped
+struct Resources(PathBuf);
+
+impl Resources {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "pokemonsms-content-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    fn write(&self, file: &str, source: &str) -> &Self {
+        let path = self.0.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, source).unwrap();
+        self
+    }
+}
+
+impl Drop for Resources {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn name(name: &str) -> ResourceLocation {
+    name.parse().unwrap()
+}
+
+const MOVES: &str = r#"
+local function move(name, power)
+    return { name = "pokemonsms:" .. name, power = power, contact = true, flags = { "protect", "mirror" } }
+end
+return { move("tackle", 40), move("scratch", 40) }
+"#;
+
+#[test]
+fn registries_are_loaded_from_each_domain_and_locked() {
+    let resources = Resources::new("load");
+    resources
+        .write("pokemonsms/moves.lua", MOVES)
+        .write(
+            "pokemonsms/types.lua",
+            r#"return { { name = "pokemonsms:normal", effectiveness = { ghost = 0.0 } } }"#,
+        )
+        .write(
+            "extra/moves.lua",
+            r#"return { { name = "extra:splash", power = 0 } }"#,
+        )
+        .write("extra/readme.txt", "Not a script");
+    let content = Content::load(&resources.0).unwrap();
+    assert_eq!(content.domains(), ["extra", "pokemonsms"]);
+    assert_eq!(
+        content
+            .registries()
+            .map(|(kind, registry)| (kind, registry.len()))
+            .collect::<Vec<_>>(),
+        vec![("moves", 3), ("types", 1)]
+    );
+
+    let moves = content.registry("moves").unwrap();
+    assert!(moves.is_locked());
+    let tackle = moves.get(&name("pokemonsms:tackle")).unwrap().data();
+    assert_eq!(tackle.get("power"), Some(&NbtTag::Long(40)));
+    assert_eq!(tackle.get("contact"), Some(&NbtTag::Byte(1)));
+    assert!(tackle.get("name").is_none());
+    match tackle.get("flags") {
+        Some(NbtTag::List(flags)) => {
+            assert_eq!(flags.get(1), Some(&NbtTag::String("mirror".to_string())))
+        }
+        tag => panic!("Expected a list, got {:?}", tag),
+    }
+    match content
+        .registry("types")
+        .unwrap()
+        .get(&name("pokemonsms:normal"))
+        .unwrap()
+        .data()
+        .get("effectiveness")
+    {
+        Some(NbtTag::Compound(effectiveness)) => {
+            assert_eq!(effectiveness.get("ghost"), Some(&NbtTag::Double(0.0)))
+        }
+        tag => panic!("Expected a compound, got {:?}", tag),
+    }
+    // Nothing more can be registered once the content is loaded
+    assert!(moves
+        .register(Entry::new(name("pokemonsms:pound"), NbtCompound::new()))
+        .is_some());
+}
+
+#[test]
+fn domains_are_hashed_by_their_entries() {
+    let resources = Resources::new("hash");
+    resources.write("pokemonsms/moves.lua", MOVES).write(
+        "extra/moves.lua",
+        r#"return { { name = "extra:splash", power = 0 } }"#,
+    );
+    let content = Content::load(&resources.0).unwrap();
+    let hashes = content.hashes().unwrap();
+    assert_eq!(
+        hashes.iter().map(|h| &*h.domain).collect::<Vec<_>>(),
+        ["extra", "pokemonsms"]
+    );
+    let summary = content.summary().unwrap();
+    assert_eq!(
+        hashes[1].digest,
+        summary.domain("pokemonsms").unwrap().root()
+    );
+    assert!(summary
+        .domain("pokemonsms")
+        .unwrap()
+        .get("moves/pokemonsms:tackle")
+        .is_some());
+
+    // Changing an entry of one domain changes only its hash
+    let changed = Resources::new("hash-changed");
+    changed
+        .write("pokemonsms/moves.lua", &MOVES.replace("40)", "50)"))
+        .write(
+            "extra/moves.lua",
+            r#"return { { name = "extra:splash", power = 0 } }"#,
+        );
+    let rehashed = Content::load(&changed.0).unwrap().hashes().unwrap();
+    assert_eq!(rehashed[0], hashes[0]);
+    assert_ne!(rehashed[1].hash, hashes[1].hash);
+    assert_ne!(rehashed[1].digest, hashes[1].digest);
+}
+
+#[test]
+fn invalid_scripts_are_rejected() {
+    for (name, source) in [
+        ("syntax", "return {"),
+        ("not-a-table", "return 4"),
+        ("unnamed", "return { { power = 40 } }"),
+        ("bad-name", r#"return { { name = "Tackle" } }"#),
+        (
+            "duplicate",
+            r#"return { { name = "pokemonsms:tackle" }, { name = "pokemonsms:tackle" } }"#,
+        ),
+        (
+            "mixed-list",
+            r#"return { { name = "pokemonsms:tackle", flags = { 1, "two" } } }"#,
+        ),
+        (
+            "function",
+            r#"return { { name = "pokemonsms:tackle", effect = function() end } }"#,
+        ),
+    ] {
+        let resources = Resources::new(name);
+        resources.write("pokemonsms/moves.lua", source);
+        let e = Content::load(&resources.0).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidData, "{}", name);
+        assert!(e.to_string().contains("moves.lua"), "{}: {}", name, e);
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    sync::{mpsc, Mutex},
+    time::{Duration, Instant},
+};
+
+use binary_io::{
+    nbt::NbtTag,
+    shade::{migration::DATA_VERSION_KEY, save::SaveFile},
+    uuid::UUID,
+};
+use client_core::player::Player;
+use net::{
+    client::ClientConfig,
+    packet::{ChatBroadcast, Disconnect, PacketRegistry},
+    tcp,
+};
+use pokemonsms_core::resource::ResourceLocation;
+use pokemonsms_server::{
+    console::CommandError, players::PLAYER_DATA_VERSION, Command, Content, DedicatedServer,
+    PlayerStore, ServerSettings,
+};
+use text::TextComponent;
+
+const ASH: UUID = UUID::new(1, 1);
+const GARY: UUID = UUID::new(1, 2);
+
+// A data directory, removed when dropped
+struct Data(PathBuf);
+
+impl Data {
+    fn new(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("pokemonsms-server-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        Self(dir)
+    }
+
+    fn store(&self) -> PlayerStore {
+        PlayerStore::new(self.0.join("players"), 2, sprite()).unwrap()
+    }
+}
+
+impl Drop for Data {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn sprite() -> ResourceLocation {
+    "pokemonsms:player".parse().unwrap()
+}
+
+fn text(text: &str) -> TextComponent {
+    TextComponent::RawText(text.to_string())
+}
+
+fn eventually<F: Fn() -> bool>(f: F) {
+    let start = Instant::now();
+    while !f() {
+        assert!(start.elapsed() < Duration::from_secs(5), "Timed out");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn start(data: &Data) -> DedicatedServer {
+    let settings = ServerSettings {
+        address: "127.0.0.1:0".to_string(),
+        data: data.0.clone(),
+        autosave: 0,
+        ..Default::default()
+    };
+    DedicatedServer::with_content(settings, Content::new()).unwrap()
+}
+
+#[test]
+fn settings_missing_from_the_file_are_defaulted() {
+    let settings = ServerSettings::parse(
+        r#"{ "motd": "Welcome to Pallet Town", "max_players": 20, "pc_sprite": "kanto:red" }"#,
+    )
+    .unwrap();
+    assert_eq!(
+        settings,
+        ServerSettings {
+            motd: text("Welcome to Pallet Town"),
+            max_players: 20,
+            pc_sprite: "kanto:red".parse().unwrap(),
+            ..Default::default()
+        }
+    );
+    assert_eq!(settings.autosave_interval(), Some(Duration::from_secs(300)));
+
+    let e = ServerSettings::parse(r#"{ "max_players": "many" }"#).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    let e = ServerSettings::load("does-not-exist.json").unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn console_commands_are_parsed() {
+    assert_eq!("  LIST ".parse(), Ok(Command::List));
+    assert_eq!(
+        "kick Gary   no cheating".parse(),
+        Ok(Command::Kick {
+            player: "Gary".to_string(),
+            reason: Some("no cheating".to_string())
+        })
+    );
+    assert_eq!(
+        "kick Gary".parse(),
+        Ok(Command::Kick {
+            player: "Gary".to_string(),
+            reason: None
+        })
+    );
+    assert_eq!(
+        "say Gym opens at noon".parse(),
+        Ok(Command::Say("Gym opens at noon".to_string()))
+    );
+    assert_eq!("shutdown".parse(), Ok(Command::Stop));
+    assert_eq!("".parse::<Command>(), Err(CommandError::Empty));
+    assert_eq!(
+        "mute".parse::<Command>(),
+        Err(CommandError::MissingArgument {
+            command: "mute",
+            argument: "player"
+        })
+    );
+    assert_eq!(
+        "fly".parse::<Command>(),
+        Err(CommandError::Unknown("fly".to_string()))
+    );
+}
+
+#[test]
+fn players_are_saved_as_shade_files() {
+    let data = Data::new("store");
+    let store = data.store();
+    // Players who were never saved are named by their UUID
+    assert_eq!(store.load(ASH).unwrap().name(), &text(&ASH.to_string()));
+
+    let gary = Player::new(text("Gary"), "kanto:blue".parse().unwrap());
+    store.save(GARY, &gary).unwrap();
+    store.save(GARY, &gary).unwrap();
+    assert!(store.file(GARY).backup_path(1).exists());
+    let loaded = data.store().load(GARY).unwrap();
+    assert_eq!(loaded.name(), &text("Gary"));
+    assert_eq!(loaded.pc_sprite(), gary.pc_sprite());
+    let file = SaveFile::new(store.file(GARY).path()).load().unwrap().value;
+    assert_eq!(
+        file.get(DATA_VERSION_KEY),
+        Some(&NbtTag::Int(PLAYER_DATA_VERSION))
+    );
+
+    store.join(GARY).unwrap();
+    assert_eq!(store.online().len(), 1);
+    assert_eq!(store.save_all().unwrap(), 1);
+    store.leave(GARY).unwrap();
+    assert!(store.online().is_empty());
+    assert!(!store.file(ASH).path().exists());
+}
+
+#[test]
+fn the_console_administers_connected_players() {
+    let data = Data::new("console");
+    data.store()
+        .save(GARY, &Player::new(text("Gary"), sprite()))
+        .unwrap();
+    let server = start(&data);
+    let connect = |client| {
+        tcp::connect(
+            server.local_addr(),
+            Duration::from_secs(5),
+            &ClientConfig::new(client, server.server().config().content.clone()),
+            PacketRegistry::pkmcom(),
+        )
+        .unwrap()
+    };
+    let mut ash = connect(ASH);
+    let mut gary = connect(GARY);
+    eventually(|| server.players().len() == 2);
+    assert_eq!(
+        server.execute(&Command::List).unwrap(),
+        format!("2 of 100 players online: {}, Gary", ASH)
+    );
+
+    server
+        .execute(&"say Gym opens at noon".parse().unwrap())
+        .unwrap();
+    for conn in [&mut ash, &mut gary] {
+        let packet = conn.receive().unwrap().unwrap();
+        assert_eq!(
+            packet.downcast_ref::<ChatBroadcast>().unwrap().message,
+            text("[Server] Gym opens at noon")
+        );
+    }
+
+    server
+        .execute(&"kick Gary no cheating".parse().unwrap())
+        .unwrap();
+    let packet = gary.receive().unwrap().unwrap();
+    assert_eq!(
+        packet.downcast_ref::<Disconnect>().unwrap().reason,
+        text("no cheating")
+    );
+    eventually(|| server.players().len() == 1);
+    let e = server.execute(&"mute Gary".parse().unwrap()).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+    assert_eq!(server.execute(&Command::Save).unwrap(), "Saved 1 players");
+    assert!(data.store().file(ASH).path().exists());
+    drop(ash);
+}
+
+#[test]
+fn shutdown_disconnects_and_saves_every_player() {
+    let data = Data::new("shutdown");
+    let server = start(&data);
+    let mut ash = tcp::connect(
+        server.local_addr(),
+        Duration::from_secs(5),
+        &ClientConfig::new(ASH, Vec::new()),
+        PacketRegistry::pkmcom(),
+    )
+    .unwrap();
+    eventually(|| server.players().len() == 1);
+    let addr = server.local_addr();
+
+    assert_eq!(server.shutdown(text("Server closed")).unwrap(), 0);
+    let packet = ash.receive().unwrap().unwrap();
+    assert_eq!(
+        packet.downcast_ref::<Disconnect>().unwrap().reason,
+        text("Server closed")
+    );
+    assert_eq!(
+        data.store().load(ASH).unwrap().name(),
+        &text(&ASH.to_string())
+    );
+    assert!(data.store().file(ASH).path().exists());
+    assert!(tcp::connect(
+        addr,
+        Duration::from_secs(1),
+        &ClientConfig::new(GARY, Vec::new()),
+        PacketRegistry::pkmcom(),
+    )
+    .is_err());
+}
+
+#[test]
+fn players_who_cannot_be_saved_are_reported() {
+    let data = Data::new("unsaved");
+    let server = start(&data);
+    let (failures, failed) = mpsc::channel();
+    let failures = Mutex::new(failures);
+    server.game().on_save_error(move |e| {
+        let _ = failures.lock().unwrap().send((e.player, e.to_string()));
+    });
+    let ash = tcp::connect(
+        server.local_addr(),
+        Duration::from_secs(5),
+        &ClientConfig::new(ASH, Vec::new()),
+        PacketRegistry::pkmcom(),
+    )
+    .unwrap();
+    eventually(|| server.players().len() == 1);
+
+    // The players directory is replaced by a file, so Ash cannot be saved as they leave
+    let players = data.0.join("players");
+    std::fs::remove_dir_all(&players).unwrap();
+    std::fs::write(&players, b"").unwrap();
+    ash.sender().disconnect(text("Bye")).unwrap();
+    let (player, message) = failed.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(player, Some(ASH));
+    assert!(message.starts_with(&format!("Failed to save {}: ", ASH)));
+    assert!(server.players().is_empty());
+}
+
+#[test]
+fn connections_are_counted_and_served_to_prometheus() {
+    let data = Data::new("metrics");
+    let settings = ServerSettings {
+        address: "127.0.0.1:0".to_string(),
+        data: data.0.clone(),
+        autosave: 0,
+        metrics: Some("127.0.0.1:0".to_string()),
+        ..Default::default()
+    };
+    let server = DedicatedServer::with_content(settings, Content::new()).unwrap();
+    let _ash = tcp::connect(
+        server.local_addr(),
+        Duration::from_secs(5),
+        &ClientConfig::new(ASH, Vec::new()),
+        PacketRegistry::pkmcom(),
+    )
+    .unwrap();
+    eventually(|| server.players().len() == 1);
+    let snapshot = server.metrics().unwrap().snapshot();
+    assert_eq!(snapshot.connections[0].client, Some(ASH));
+
+    let mut stream = TcpStream::connect(server.metrics_addr().unwrap()).unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.contains("\npkmcom_connections_open 1\n"));
+    assert!(response.contains(&format!("client=\"{}\"", ASH)));
+
+    // Servers without a metrics address count nothing
+    assert!(start(&Data::new("no-metrics")).metrics().is_none());
+}
@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{fmt::Display, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
@@ -24,7 +24,7 @@ impl From<Duration> for TextDelay {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum TextComponent {
     RawText(String),
@@ -35,7 +35,22 @@ pub enum TextComponent {
     Group { group: Vec<TextComponent> },
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+// Displays the plain text of the component, without styles or commands.
+// Translations are displayed as their key
+impl Display for TextComponent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TextComponent::RawText(text) | TextComponent::Text { text, .. } => f.write_str(text),
+            TextComponent::Translation { translate } => f.write_str(translate),
+            TextComponent::Command(_) => Ok(()),
+            TextComponent::ImplicitGroup(group) | TextComponent::Group { group } => {
+                group.iter().try_for_each(|c| c.fmt(f))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "command")]
 #[serde(rename_all = "snake_case")]
 pub enum TextCommand {
@@ -44,7 +59,7 @@ pub enum TextCommand {
     DelayScroll(TextDelay),
 }
 
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Style {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -85,7 +100,7 @@ pub mod embed_json {
         S: serde::Serializer,
     {
         let string =
-            serde_json::to_string(value).map_err(|e| <S::Error as serde::ser::Error>::custom(e))?;
+            serde_json::to_string(value).map_err(<S::Error as serde::ser::Error>::custom)?;
         string.serialize(ser)
     }
 
@@ -94,6 +109,52 @@ pub mod embed_json {
         D: serde::Deserializer<'de>,
     {
         let str = <&str as Deserialize>::deserialize(de)?;
-        serde_json::from_str(str).map_err(|e| <D::Error as serde::de::Error>::custom(e))
+        serde_json::from_str(str).map_err(<D::Error as serde::de::Error>::custom)
+    }
+}
+
+#[cfg(feature = "lcs4")]
+mod lcs4 {
+    use std::io::ErrorKind;
+
+    use binary_io::data::{
+        read_bytes, DataInput, DataOutput, DeserializeCopy, Deserializeable, OutOfRange,
+        Serializeable,
+    };
+
+    use super::TextComponent;
+
+    // Text Components are serialized as JSON, prefixed by a 32-bit length,
+    // since a component with many children can easily exceed the 16-bit length of an LCS4 String
+
+    impl Serializeable for TextComponent {
+        fn serialize<W: DataOutput + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+            let json = serde_json::to_string(self)
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+            if json.len() > (u32::MAX as usize) {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    OutOfRange(json.len()),
+                ));
+            }
+            (json.len() as u32).serialize(output)?;
+            output.write_bytes(json.as_bytes())
+        }
+    }
+
+    impl Deserializeable for TextComponent {
+        fn deserialize<R: DataInput + ?Sized>(&mut self, input: &mut R) -> std::io::Result<()> {
+            *self = Self::deserialize_copy(input)?;
+            Ok(())
+        }
+    }
+
+    impl DeserializeCopy for TextComponent {
+        fn deserialize_copy<R: DataInput + ?Sized>(input: &mut R) -> std::io::Result<Self> {
+            let len = u32::deserialize_copy(input)? as usize;
+            let bytes = read_bytes(input, len)?;
+            serde_json::from_slice(&bytes)
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))
+        }
     }
 }